@@ -205,6 +205,36 @@ impl Default for Options {
     }
 }
 
+/// A client of the bucket that the databases are backed up to, to store other objects next to the
+/// backups.
+#[derive(Clone)]
+pub struct BucketClient {
+    client: Client,
+    bucket: String,
+}
+
+impl BucketClient {
+    pub async fn new(options: &Options) -> Self {
+        Self {
+            client: Client::from_conf(options.client_config().await),
+            bucket: options.bucket_name.clone(),
+        }
+    }
+
+    /// Uploads the file at `path` as the object `key`.
+    pub async fn put_file(&self, key: &str, path: &Path) -> Result<()> {
+        let body = ByteStream::from_path(path).await?;
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .body(body)
+            .send()
+            .await?;
+        Ok(())
+    }
+}
+
 impl Replicator {
     pub const UNSET_PAGE_SIZE: usize = usize::MAX;
 
@@ -856,27 +886,10 @@ impl Replicator {
             .open(&self.db_path)
             .await?;
         // If the db file is not present, the database could have been empty
-
-        let main_db_path = match self.use_compression {
-            CompressionKind::None => format!("{}-{}/db.db", self.db_name, generation),
-            CompressionKind::Gzip => format!("{}-{}/db.gz", self.db_name, generation),
-        };
-
-        if let Ok(db_file) = self.get_object(main_db_path).send().await {
-            let mut body_reader = db_file.body.into_async_read();
-            let db_size = match self.use_compression {
-                CompressionKind::None => {
-                    tokio::io::copy(&mut body_reader, &mut main_db_writer).await?
-                }
-                CompressionKind::Gzip => {
-                    let mut decompress_reader = async_compression::tokio::bufread::GzipDecoder::new(
-                        tokio::io::BufReader::new(body_reader),
-                    );
-                    tokio::io::copy(&mut decompress_reader, &mut main_db_writer).await?
-                }
-            };
-            main_db_writer.flush().await?;
-
+        if let Some(db_size) = self
+            .download_main_db_file(&generation, &mut main_db_writer)
+            .await?
+        {
             let page_size = Self::read_page_size(&mut main_db_writer).await?;
             self.set_page_size(page_size)?;
             tracing::info!("Restored the main database file ({} bytes)", db_size);
@@ -934,6 +947,7 @@ impl Replicator {
                                     && !key.ends_with(".db")
                                     && !key.ends_with(".meta")
                                     && !key.ends_with(".changecounter")
+                                    && !key.ends_with(".replication_index")
                                 {
                                     tracing::warn!("Failed to parse frame/page from key {}", key);
                                 }
@@ -1017,6 +1031,38 @@ impl Replicator {
         }
     }
 
+    // Downloads the main database file of given generation, without applying the WAL frames of
+    // the generation. Returns the size of the file, or None if the generation has no main
+    // database file.
+    pub async fn download_main_db_file(
+        &self,
+        generation: &Uuid,
+        writer: &mut tokio::fs::File,
+    ) -> Result<Option<u64>> {
+        let main_db_path = match self.use_compression {
+            CompressionKind::None => format!("{}-{}/db.db", self.db_name, generation),
+            CompressionKind::Gzip => format!("{}-{}/db.gz", self.db_name, generation),
+        };
+
+        let db_file = match self.get_object(main_db_path).send().await {
+            Ok(db_file) => db_file,
+            Err(_) => return Ok(None),
+        };
+        let mut body_reader = db_file.body.into_async_read();
+        let db_size = match self.use_compression {
+            CompressionKind::None => tokio::io::copy(&mut body_reader, writer).await?,
+            CompressionKind::Gzip => {
+                let mut decompress_reader = async_compression::tokio::bufread::GzipDecoder::new(
+                    tokio::io::BufReader::new(body_reader),
+                );
+                tokio::io::copy(&mut decompress_reader, writer).await?
+            }
+        };
+        writer.flush().await?;
+
+        Ok(Some(db_size))
+    }
+
     // Restores the database state from newest remote generation
     pub async fn restore(
         &mut self,
@@ -1144,6 +1190,64 @@ impl Replicator {
             Ok(None)
         }
     }
+
+    // Stores the position of the main database snapshot of the current generation in the
+    // replication log of the database: the id of the log, and the frame_no following the
+    // snapshot. Replicas use it to bootstrap from the snapshot, and replicate the rest of the log.
+    pub async fn store_replication_index(&self, log_id: Uuid, next_frame_no: u64) -> Result<()> {
+        let key = format!(
+            "{}-{}/.replication_index",
+            self.db_name,
+            self.generation.load()
+        );
+        tracing::debug!(
+            "Storing replication index at '{}': log id - {}, next frame - {}",
+            key,
+            log_id,
+            next_frame_no
+        );
+        let mut body = BytesMut::with_capacity(24);
+        body.extend_from_slice(log_id.as_bytes());
+        body.extend_from_slice(next_frame_no.to_be_bytes().as_slice());
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .body(ByteStream::from(body.freeze()))
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    pub async fn get_replication_index(&self, generation: &Uuid) -> Result<Option<(Uuid, u64)>> {
+        let key = format!("{}-{}/.replication_index", self.db_name, generation);
+        if let Ok(obj) = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+        {
+            let mut data = obj.body.collect().await?;
+            if data.remaining() != 24 {
+                return Err(anyhow!(
+                    "invalid replication index of generation {}",
+                    generation
+                ));
+            }
+            let log_id = Uuid::from_u128(data.get_u128());
+            let next_frame_no = data.get_u64();
+            Ok(Some((log_id, next_frame_no)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    // Returns the generation this replicator currently backs up to
+    pub fn generation(&self) -> Uuid {
+        **self.generation.load()
+    }
 }
 
 async fn put_metadata_obj(
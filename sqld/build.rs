@@ -15,7 +15,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .field_attribute(".proxy.ProgramReq.namespace", "#[cfg_attr(test, arbitrary(with = crate::connection::write_proxy::test::arbitrary_bytes))]")
         .compile_with_config(
             config,
-            &["proto/replication_log.proto", "proto/proxy.proto"],
+            &[
+                "proto/replication_log.proto",
+                "proto/proxy.proto",
+                "proto/cluster.proto",
+            ],
             &["proto"],
         )?;
 
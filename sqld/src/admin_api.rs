@@ -1,25 +1,102 @@
 use anyhow::Context as _;
-use axum::{extract::State, Json};
-use serde::Deserialize;
+use axum::extract::{Path, State};
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
+use axum::Json;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
 use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
 
 use crate::connection::config::{DatabaseConfig, DatabaseConfigStore};
+use crate::connection::query_stats::{QueryStats, SlowQuery, StatementStats};
+use crate::database::Database;
+use crate::metrics;
+use crate::namespace::{MakeNamespace, Namespace, NamespaceStore};
+use crate::replication::inspect::RestorePoint;
+use crate::replication::{FrameNo, ReplicationLogger, ReplicationStatus, SnapshotInfo};
+use crate::snapshot_sink::{SnapshotSinkConfig, SnapshotSinkStatus, SnapshotSinks};
+use crate::webhook::{Delivery, WebhookConfig, Webhooks};
 
-struct AppState {
-    db_config_store: Arc<DatabaseConfigStore>,
+struct AppState<F: MakeNamespace> {
+    namespaces: Arc<NamespaceStore<F>>,
+    /// Requests the promotion of this replica to primary. Unset on a primary.
+    promote: Option<mpsc::Sender<()>>,
+    /// The config of the server, whose blocks apply to every namespace.
+    server_config: Arc<DatabaseConfigStore>,
 }
 
-pub async fn run_admin_api(
+pub async fn run_admin_api<F: MakeNamespace>(
     addr: SocketAddr,
-    db_config_store: Arc<DatabaseConfigStore>,
+    namespaces: Arc<NamespaceStore<F>>,
+    promote: Option<mpsc::Sender<()>>,
+    server_config: Arc<DatabaseConfigStore>,
 ) -> anyhow::Result<()> {
-    use axum::routing::{get, post};
+    use axum::routing::{delete, get, post};
     let router = axum::Router::new()
         .route("/", get(handle_get_index))
-        .route("/v1/config", get(handle_get_config))
-        .route("/v1/block", post(handle_post_block))
-        .with_state(Arc::new(AppState { db_config_store }));
+        .route("/metrics", get(handle_get_metrics))
+        .route("/v1/config", get(handle_get_server_config))
+        .route("/v1/block", post(handle_post_server_block))
+        .route("/v1/promote", post(handle_post_promote))
+        .route("/v1/namespaces", get(handle_get_namespaces))
+        .route("/v1/namespaces/:namespace", delete(handle_delete_namespace))
+        .route(
+            "/v1/namespaces/:namespace/config",
+            get(handle_get_config).post(handle_post_config),
+        )
+        .route("/v1/namespaces/:namespace/block", post(handle_post_block))
+        .route(
+            "/v1/namespaces/:namespace/restore",
+            post(handle_post_restore),
+        )
+        .route(
+            "/v1/namespaces/:namespace/snapshots",
+            get(handle_get_snapshots),
+        )
+        .route(
+            "/v1/namespaces/:namespace/snapshots/merge",
+            post(handle_post_merge_snapshots),
+        )
+        .route(
+            "/v1/namespaces/:namespace/snapshot_sinks",
+            get(handle_get_snapshot_sinks).post(handle_post_snapshot_sinks),
+        )
+        .route(
+            "/v1/namespaces/:namespace/snapshot_sinks/status",
+            get(handle_get_snapshot_sinks_status),
+        )
+        .route(
+            "/v1/namespaces/:namespace/snapshot_sinks/failed",
+            delete(handle_delete_failed_snapshot_deliveries),
+        )
+        .route(
+            "/v1/namespaces/:namespace/stats/statements",
+            get(handle_get_statement_stats).delete(handle_delete_statement_stats),
+        )
+        .route(
+            "/v1/namespaces/:namespace/stats/slow_queries",
+            get(handle_get_slow_queries),
+        )
+        .route(
+            "/v1/namespaces/:namespace/replication",
+            get(handle_get_replication_status),
+        )
+        .route(
+            "/v1/namespaces/:namespace/webhooks",
+            get(handle_get_webhooks).post(handle_post_webhooks),
+        )
+        .route(
+            "/v1/namespaces/:namespace/webhooks/dead_letters",
+            get(handle_get_dead_letters).delete(handle_delete_dead_letters),
+        )
+        .with_state(Arc::new(AppState {
+            namespaces,
+            promote,
+            server_config,
+        }));
 
     let server = hyper::Server::try_bind(&addr)
         .context("Could not bind admin HTTP API server")?
@@ -37,8 +114,45 @@ async fn handle_get_index() -> &'static str {
     "Welcome to the sqld admin API"
 }
 
-async fn handle_get_config(State(app_state): State<Arc<AppState>>) -> Json<Arc<DatabaseConfig>> {
-    Json(app_state.db_config_store.get())
+async fn handle_get_metrics<F: MakeNamespace>(
+    State(app_state): State<Arc<AppState<F>>>,
+) -> impl IntoResponse {
+    let mut databases = Vec::new();
+    app_state
+        .namespaces
+        .for_each_loaded(|name, ns| {
+            let name = String::from_utf8_lossy(name).into_owned();
+            databases.push((name, ns.db.metrics()));
+        })
+        .await;
+
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics::render(&databases),
+    )
+}
+
+async fn handle_get_server_config<F: MakeNamespace>(
+    State(app_state): State<Arc<AppState<F>>>,
+) -> Json<Arc<DatabaseConfig>> {
+    Json(app_state.server_config.get())
+}
+
+async fn handle_get_config<F: MakeNamespace>(
+    State(app_state): State<Arc<AppState<F>>>,
+    Path(namespace): Path<String>,
+) -> Result<Json<Arc<DatabaseConfig>>, (StatusCode, String)> {
+    let store = namespace_config_store(&app_state, namespace).await?;
+    Ok(Json(store.get_own()))
+}
+
+async fn handle_post_config<F: MakeNamespace>(
+    State(app_state): State<Arc<AppState<F>>>,
+    Path(namespace): Path<String>,
+    Json(config): Json<DatabaseConfig>,
+) -> Result<&'static str, (StatusCode, String)> {
+    let store = namespace_config_store(&app_state, namespace).await?;
+    store_config(&store, config)
 }
 
 #[derive(Debug, Deserialize)]
@@ -49,20 +163,374 @@ struct BlockReq {
     block_reason: Option<String>,
 }
 
-async fn handle_post_block(
-    State(app_state): State<Arc<AppState>>,
+async fn handle_post_server_block<F: MakeNamespace>(
+    State(app_state): State<Arc<AppState<F>>>,
     Json(req): Json<BlockReq>,
-) -> (axum::http::StatusCode, &'static str) {
-    let mut config = (*app_state.db_config_store.get()).clone();
+) -> Result<&'static str, (StatusCode, String)> {
+    store_block(&app_state.server_config, req)
+}
+
+async fn handle_post_block<F: MakeNamespace>(
+    State(app_state): State<Arc<AppState<F>>>,
+    Path(namespace): Path<String>,
+    Json(req): Json<BlockReq>,
+) -> Result<&'static str, (StatusCode, String)> {
+    let store = namespace_config_store(&app_state, namespace).await?;
+    store_block(&store, req)
+}
+
+fn store_block(
+    store: &DatabaseConfigStore,
+    req: BlockReq,
+) -> Result<&'static str, (StatusCode, String)> {
+    let mut config = (*store.get_own()).clone();
     config.block_reads = req.block_reads;
     config.block_writes = req.block_writes;
     config.block_reason = req.block_reason;
 
-    match app_state.db_config_store.store(config) {
-        Ok(()) => (axum::http::StatusCode::OK, "OK"),
+    store_config(store, config)
+}
+
+async fn handle_post_promote<F: MakeNamespace>(
+    State(app_state): State<Arc<AppState<F>>>,
+) -> Result<(StatusCode, &'static str), (StatusCode, String)> {
+    let Some(promote) = &app_state.promote else {
+        return Err((StatusCode::BAD_REQUEST, "Only replicas can be promoted".into()));
+    };
+
+    match promote.try_send(()) {
+        // a full channel means that a promotion is already pending
+        Ok(()) | Err(TrySendError::Full(())) => Ok((StatusCode::ACCEPTED, "Promotion started")),
+        Err(TrySendError::Closed(())) => Err(internal_error(anyhow::anyhow!(
+            "the server is shutting down"
+        ))),
+    }
+}
+
+async fn handle_get_namespaces<F: MakeNamespace>(
+    State(app_state): State<Arc<AppState<F>>>,
+) -> Result<Json<Vec<String>>, (StatusCode, String)> {
+    let namespaces = app_state.namespaces.list().await.map_err(internal_error)?;
+    Ok(Json(
+        namespaces
+            .iter()
+            .map(|name| String::from_utf8_lossy(name).into_owned())
+            .collect(),
+    ))
+}
+
+async fn handle_delete_namespace<F: MakeNamespace>(
+    State(app_state): State<Arc<AppState<F>>>,
+    Path(namespace): Path<String>,
+) -> Result<&'static str, (StatusCode, String)> {
+    let deleted = app_state
+        .namespaces
+        .destroy(namespace.clone().into())
+        .await
+        .map_err(internal_error)?;
+    if !deleted {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("namespace `{namespace}` doesn't exist"),
+        ));
+    }
+
+    Ok("OK")
+}
+
+#[derive(Debug, Deserialize)]
+struct RestoreReq {
+    /// Namespace to create with the restored database.
+    to: String,
+    #[serde(default)]
+    frame_no: Option<FrameNo>,
+    /// RFC 3339 UTC timestamp.
+    #[serde(default)]
+    utc_time: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct RestoreResp {
+    frame_no: FrameNo,
+}
+
+async fn handle_post_restore<F: MakeNamespace>(
+    State(app_state): State<Arc<AppState<F>>>,
+    Path(namespace): Path<String>,
+    Json(req): Json<RestoreReq>,
+) -> Result<Json<RestoreResp>, (StatusCode, String)> {
+    if app_state.promote.is_some() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Namespaces can only be restored on the primary".into(),
+        ));
+    }
+    let point = match (req.frame_no, req.utc_time) {
+        (Some(_), Some(_)) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Only one of `frame_no` and `utc_time` can be set".into(),
+            ))
+        }
+        (Some(frame_no), None) => RestorePoint::FrameNo(frame_no),
+        (None, Some(utc_time)) => {
+            let utc_time: DateTime<Utc> = utc_time
+                .parse()
+                .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid `utc_time`: {e}")))?;
+            RestorePoint::Time(utc_time.into())
+        }
+        (None, None) => RestorePoint::Latest,
+    };
+
+    let frame_no = app_state
+        .namespaces
+        .restore(namespace.into(), req.to.into(), point)
+        .await
+        .map_err(internal_error)?;
+
+    Ok(Json(RestoreResp { frame_no }))
+}
+
+async fn handle_get_snapshots<F: MakeNamespace>(
+    State(app_state): State<Arc<AppState<F>>>,
+    Path(namespace): Path<String>,
+) -> Result<Json<Vec<SnapshotInfo>>, (StatusCode, String)> {
+    let logger = namespace_logger(&app_state, namespace).await?;
+    let snapshots = tokio::task::spawn_blocking(move || logger.snapshots())
+        .await
+        .map_err(|e| internal_error(e.into()))?
+        .map_err(internal_error)?;
+    Ok(Json(snapshots))
+}
+
+#[derive(Debug, Serialize)]
+struct MergeSnapshotsResp {
+    /// Name of the merged snapshot, unset if there was nothing to merge.
+    snapshot: Option<String>,
+}
+
+async fn handle_post_merge_snapshots<F: MakeNamespace>(
+    State(app_state): State<Arc<AppState<F>>>,
+    Path(namespace): Path<String>,
+) -> Result<Json<MergeSnapshotsResp>, (StatusCode, String)> {
+    let logger = namespace_logger(&app_state, namespace).await?;
+    let snapshot = tokio::task::spawn_blocking(move || logger.merge_snapshots())
+        .await
+        .map_err(|e| internal_error(e.into()))?
+        .map_err(internal_error)?;
+    Ok(Json(MergeSnapshotsResp { snapshot }))
+}
+
+fn store_config(
+    store: &DatabaseConfigStore,
+    config: DatabaseConfig,
+) -> Result<&'static str, (StatusCode, String)> {
+    match store.store(config) {
+        Ok(()) => Ok("OK"),
         Err(err) => {
             tracing::warn!("Could not store database config: {err}");
-            (axum::http::StatusCode::INTERNAL_SERVER_ERROR, "Failed")
+            Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed".into()))
         }
     }
 }
+
+/// Calls `f` with an existing namespace. Responds with 404 if the namespace doesn't exist,
+/// rather than creating it.
+async fn with_namespace<F: MakeNamespace, R>(
+    app_state: &AppState<F>,
+    namespace: String,
+    f: impl FnOnce(&Namespace<F::Database>) -> R,
+) -> Result<R, (StatusCode, String)> {
+    app_state
+        .namespaces
+        .with_existing(namespace.clone().into(), f)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| {
+            (
+                StatusCode::NOT_FOUND,
+                format!("namespace `{namespace}` doesn't exist"),
+            )
+        })
+}
+
+async fn namespace_config_store<F: MakeNamespace>(
+    app_state: &AppState<F>,
+    namespace: String,
+) -> Result<Arc<DatabaseConfigStore>, (StatusCode, String)> {
+    with_namespace(app_state, namespace, |ns| ns.db.config_store()).await
+}
+
+async fn namespace_logger<F: MakeNamespace>(
+    app_state: &AppState<F>,
+    namespace: String,
+) -> Result<Arc<ReplicationLogger>, (StatusCode, String)> {
+    with_namespace(app_state, namespace.clone(), |ns| {
+        ns.db.log_source().map(|log_source| log_source.logger)
+    })
+    .await?
+    .ok_or_else(|| {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("namespace `{namespace}` has no replication log"),
+        )
+    })
+}
+
+async fn namespace_query_stats<F: MakeNamespace>(
+    app_state: &AppState<F>,
+    namespace: String,
+) -> Result<Arc<QueryStats>, (StatusCode, String)> {
+    with_namespace(app_state, namespace, |ns| ns.db.query_stats()).await
+}
+
+#[derive(Debug, Serialize)]
+struct StatementStatsResp {
+    fingerprint: String,
+    #[serde(flatten)]
+    stats: StatementStats,
+}
+
+async fn handle_get_statement_stats<F: MakeNamespace>(
+    State(app_state): State<Arc<AppState<F>>>,
+    Path(namespace): Path<String>,
+) -> Result<Json<Vec<StatementStatsResp>>, (StatusCode, String)> {
+    let query_stats = namespace_query_stats(&app_state, namespace).await?;
+    let statements = query_stats
+        .statements()
+        .into_iter()
+        .map(|(fingerprint, stats)| StatementStatsResp { fingerprint, stats })
+        .collect();
+    Ok(Json(statements))
+}
+
+async fn handle_delete_statement_stats<F: MakeNamespace>(
+    State(app_state): State<Arc<AppState<F>>>,
+    Path(namespace): Path<String>,
+) -> Result<&'static str, (StatusCode, String)> {
+    let query_stats = namespace_query_stats(&app_state, namespace).await?;
+    query_stats.reset();
+    Ok("OK")
+}
+
+async fn handle_get_slow_queries<F: MakeNamespace>(
+    State(app_state): State<Arc<AppState<F>>>,
+    Path(namespace): Path<String>,
+) -> Result<Json<Vec<SlowQuery>>, (StatusCode, String)> {
+    let query_stats = namespace_query_stats(&app_state, namespace).await?;
+    Ok(Json(query_stats.slow_queries()))
+}
+
+async fn handle_get_replication_status<F: MakeNamespace>(
+    State(app_state): State<Arc<AppState<F>>>,
+    Path(namespace): Path<String>,
+) -> Result<Json<ReplicationStatus>, (StatusCode, String)> {
+    let status = with_namespace(&app_state, namespace, |ns| ns.db.replication_status()).await?;
+    Ok(Json(status))
+}
+
+async fn namespace_webhooks<F: MakeNamespace>(
+    app_state: &AppState<F>,
+    namespace: String,
+) -> Result<Arc<Webhooks>, (StatusCode, String)> {
+    let webhooks = with_namespace(app_state, namespace, |ns| ns.db.webhooks()).await?;
+    webhooks.ok_or_else(|| {
+        (
+            StatusCode::BAD_REQUEST,
+            "Webhooks are only available on the primary".into(),
+        )
+    })
+}
+
+fn internal_error(err: anyhow::Error) -> (StatusCode, String) {
+    tracing::warn!("Admin API request failed: {err}");
+    (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+}
+
+async fn handle_get_webhooks<F: MakeNamespace>(
+    State(app_state): State<Arc<AppState<F>>>,
+    Path(namespace): Path<String>,
+) -> Result<Json<Arc<Vec<WebhookConfig>>>, (StatusCode, String)> {
+    let webhooks = namespace_webhooks(&app_state, namespace).await?;
+    Ok(Json(webhooks.config()))
+}
+
+async fn handle_post_webhooks<F: MakeNamespace>(
+    State(app_state): State<Arc<AppState<F>>>,
+    Path(namespace): Path<String>,
+    Json(req): Json<Vec<WebhookConfig>>,
+) -> Result<&'static str, (StatusCode, String)> {
+    let webhooks = namespace_webhooks(&app_state, namespace).await?;
+    webhooks.set_config(req).map_err(internal_error)?;
+    Ok("OK")
+}
+
+async fn handle_get_dead_letters<F: MakeNamespace>(
+    State(app_state): State<Arc<AppState<F>>>,
+    Path(namespace): Path<String>,
+) -> Result<Json<Vec<Delivery>>, (StatusCode, String)> {
+    let webhooks = namespace_webhooks(&app_state, namespace).await?;
+    let dead_letters = webhooks.dead_letters().map_err(internal_error)?;
+    Ok(Json(dead_letters))
+}
+
+async fn handle_delete_dead_letters<F: MakeNamespace>(
+    State(app_state): State<Arc<AppState<F>>>,
+    Path(namespace): Path<String>,
+) -> Result<Json<usize>, (StatusCode, String)> {
+    let webhooks = namespace_webhooks(&app_state, namespace).await?;
+    let count = webhooks.clear_dead_letters().map_err(internal_error)?;
+    Ok(Json(count))
+}
+
+async fn namespace_snapshot_sinks<F: MakeNamespace>(
+    app_state: &AppState<F>,
+    namespace: String,
+) -> Result<Arc<SnapshotSinks>, (StatusCode, String)> {
+    let snapshot_sinks = with_namespace(app_state, namespace, |ns| ns.db.snapshot_sinks()).await?;
+    snapshot_sinks.ok_or_else(|| {
+        (
+            StatusCode::BAD_REQUEST,
+            "Snapshot sinks are only available on the primary".into(),
+        )
+    })
+}
+
+async fn handle_get_snapshot_sinks<F: MakeNamespace>(
+    State(app_state): State<Arc<AppState<F>>>,
+    Path(namespace): Path<String>,
+) -> Result<Json<Arc<Vec<SnapshotSinkConfig>>>, (StatusCode, String)> {
+    let snapshot_sinks = namespace_snapshot_sinks(&app_state, namespace).await?;
+    Ok(Json(snapshot_sinks.config()))
+}
+
+async fn handle_post_snapshot_sinks<F: MakeNamespace>(
+    State(app_state): State<Arc<AppState<F>>>,
+    Path(namespace): Path<String>,
+    Json(req): Json<Vec<SnapshotSinkConfig>>,
+) -> Result<&'static str, (StatusCode, String)> {
+    let snapshot_sinks = namespace_snapshot_sinks(&app_state, namespace).await?;
+    snapshot_sinks
+        .validate_config(&req)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    snapshot_sinks.set_config(req).map_err(internal_error)?;
+    Ok("OK")
+}
+
+async fn handle_get_snapshot_sinks_status<F: MakeNamespace>(
+    State(app_state): State<Arc<AppState<F>>>,
+    Path(namespace): Path<String>,
+) -> Result<Json<Vec<SnapshotSinkStatus>>, (StatusCode, String)> {
+    let snapshot_sinks = namespace_snapshot_sinks(&app_state, namespace).await?;
+    let status = snapshot_sinks.status().map_err(internal_error)?;
+    Ok(Json(status))
+}
+
+async fn handle_delete_failed_snapshot_deliveries<F: MakeNamespace>(
+    State(app_state): State<Arc<AppState<F>>>,
+    Path(namespace): Path<String>,
+) -> Result<Json<usize>, (StatusCode, String)> {
+    let snapshot_sinks = namespace_snapshot_sinks(&app_state, namespace).await?;
+    let count = snapshot_sinks.clear_failed().map_err(internal_error)?;
+    Ok(Json(count))
+}
@@ -0,0 +1,589 @@
+//! Leader election between the nodes of a cluster.
+//!
+//! The nodes of a cluster elect a leader among themselves, Raft-style: a node that doesn't hear
+//! from a leader for an election timeout starts an election for a new term, and becomes the leader
+//! if a majority of the nodes vote for it. A node votes at most once per term, and only for a
+//! candidate whose databases are at least as up to date as its own, so that the new leader has
+//! every write that reached a majority of the nodes. A copy of a database is more up to date than
+//! another if it has frames of a later generation of the primary, or more frames of the same
+//! generation or of an unknown one; copies of different databases are never up to date with each
+//! other.
+//!
+//! The leader runs as a primary and the other nodes as its replicas. The leader holds a lease that
+//! it renews every time a majority of the nodes acknowledge its heartbeat. The lease is shorter
+//! than the election timeout, and the primary checks it before every commit, so that a deposed
+//! leader stops accepting writes before a new one can be elected, even before it steps down.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::join_all;
+use parking_lot::Mutex;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tokio::time::Instant;
+use tonic::transport::Channel;
+
+use crate::replication::replica::database_progress;
+use crate::rpc::cluster::rpc::cluster_client::ClusterClient;
+use crate::rpc::cluster::rpc::{
+    DatabaseProgress, HeartbeatRequest, HeartbeatResponse, VoteRequest, VoteResponse,
+};
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(250);
+const MIN_ELECTION_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_ELECTION_TIMEOUT: Duration = Duration::from_secs(4);
+/// Must be shorter than `MIN_ELECTION_TIMEOUT`, with enough margin for the leader to notice that
+/// its lease expired.
+const LEASE_DURATION: Duration = Duration::from_secs(1);
+const RPC_TIMEOUT: Duration = Duration::from_millis(200);
+
+const STATE_FILE_NAME: &str = "cluster.json";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Role {
+    /// No leader is known, this node neither accepts writes nor replicates.
+    Unknown,
+    Leader,
+    Follower {
+        leader_url: String,
+    },
+}
+
+/// The state that must survive restarts, so that a node never votes twice in the same term.
+#[derive(Serialize, Deserialize, Default, Debug)]
+struct PersistentState {
+    term: u64,
+    voted_for: Option<String>,
+}
+
+struct State {
+    persistent: PersistentState,
+    /// When the leader of the current term was last heard from.
+    last_leader_contact: Option<Instant>,
+    election_deadline: Instant,
+}
+
+/// The lease of the leader. The primary checks it before committing a write, so that the writes
+/// stop as soon as the lease expires, without waiting for the node to step down and stop serving
+/// as a primary.
+#[derive(Debug, Default)]
+pub struct Lease {
+    /// When the lease of this node expires, if it is the leader.
+    expires_at: Mutex<Option<Instant>>,
+}
+
+impl Lease {
+    pub fn is_valid(&self) -> bool {
+        self.expires_at.lock().map_or(false, |t| t > Instant::now())
+    }
+
+    fn set(&self, expires_at: Option<Instant>) {
+        *self.expires_at.lock() = expires_at;
+    }
+}
+
+pub struct Cluster {
+    node_url: String,
+    peers: Vec<ClusterClient<Channel>>,
+    db_path: PathBuf,
+    state: Mutex<State>,
+    lease: Arc<Lease>,
+    role: watch::Sender<Role>,
+}
+
+impl Cluster {
+    /// Creates the cluster state of this node. `channels` are the connections to the peers.
+    pub fn new(node_url: String, channels: Vec<Channel>, db_path: &Path) -> anyhow::Result<Self> {
+        let state_path = db_path.join(STATE_FILE_NAME);
+        let persistent = if state_path.try_exists()? {
+            serde_json::from_slice(&std::fs::read(&state_path)?)?
+        } else {
+            PersistentState::default()
+        };
+        tracing::info!(
+            "joining cluster as {node_url} at term {}, with {} peers",
+            persistent.term,
+            channels.len()
+        );
+
+        let (role, _) = watch::channel(Role::Unknown);
+        Ok(Self {
+            node_url,
+            peers: channels.into_iter().map(ClusterClient::new).collect(),
+            db_path: db_path.to_owned(),
+            state: Mutex::new(State {
+                persistent,
+                last_leader_contact: None,
+                election_deadline: Instant::now() + random_election_timeout(),
+            }),
+            lease: Default::default(),
+            role,
+        })
+    }
+
+    pub fn role(&self) -> Role {
+        self.role.borrow().clone()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<Role> {
+        self.role.subscribe()
+    }
+
+    /// Returns the lease that the writes of this node are fenced with.
+    pub fn lease(&self) -> Arc<Lease> {
+        self.lease.clone()
+    }
+
+    fn majority(&self) -> usize {
+        (self.peers.len() + 1) / 2 + 1
+    }
+
+    /// Runs the election and heartbeat loop of this node.
+    pub async fn run(self: Arc<Self>) -> anyhow::Result<()> {
+        let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let is_leader = self.role() == Role::Leader;
+            let election_due = Instant::now() >= self.state.lock().election_deadline;
+            if is_leader {
+                self.send_heartbeats().await;
+            } else if election_due {
+                self.run_election().await?;
+            }
+        }
+    }
+
+    async fn run_election(&self) -> anyhow::Result<()> {
+        let started_at = Instant::now();
+        let progress = local_progress(&self.db_path)?;
+        let term = {
+            let mut state = self.state.lock();
+            state.persistent.term += 1;
+            state.persistent.voted_for = Some(self.node_url.clone());
+            state.last_leader_contact = None;
+            state.election_deadline = Instant::now() + random_election_timeout();
+            self.persist(&state)?;
+            state.persistent.term
+        };
+        tracing::info!("starting election for term {term}");
+
+        let req = VoteRequest {
+            term,
+            candidate_url: self.node_url.clone(),
+            progress,
+        };
+        let responses = join_all(self.peers.iter().map(|peer| {
+            let mut peer = peer.clone();
+            let req = req.clone();
+            async move { tokio::time::timeout(RPC_TIMEOUT, peer.request_vote(req)).await }
+        }))
+        .await;
+
+        // this node votes for itself
+        let mut votes = 1;
+        for resp in responses {
+            let Ok(Ok(resp)) = resp else { continue };
+            let resp = resp.into_inner();
+            if resp.term > term {
+                return self.observe_term(&mut self.state.lock(), resp.term);
+            }
+            if resp.granted {
+                votes += 1;
+            }
+        }
+
+        let elected = {
+            let mut state = self.state.lock();
+            // another node may have been elected meanwhile
+            let elected = state.persistent.term == term
+                && state.last_leader_contact.is_none()
+                && votes >= self.majority();
+            if elected {
+                // the votes were all cast after the election started
+                self.lease.set(Some(started_at + LEASE_DURATION));
+            }
+            elected
+        };
+
+        if elected {
+            tracing::info!("elected leader for term {term} with {votes} votes");
+            self.set_role(Role::Leader);
+            // assert leadership right away
+            self.send_heartbeats().await;
+        } else {
+            tracing::info!("lost election for term {term} with {votes} votes");
+        }
+
+        Ok(())
+    }
+
+    async fn send_heartbeats(&self) {
+        let started_at = Instant::now();
+        let term = self.state.lock().persistent.term;
+        let req = HeartbeatRequest {
+            term,
+            leader_url: self.node_url.clone(),
+        };
+        let responses = join_all(self.peers.iter().map(|peer| {
+            let mut peer = peer.clone();
+            let req = req.clone();
+            async move { tokio::time::timeout(RPC_TIMEOUT, peer.heartbeat(req)).await }
+        }))
+        .await;
+
+        let mut state = self.state.lock();
+        let mut acks = 1;
+        for resp in responses {
+            let Ok(Ok(resp)) = resp else { continue };
+            let resp = resp.into_inner();
+            if resp.term > state.persistent.term {
+                if let Err(e) = self.observe_term(&mut state, resp.term) {
+                    tracing::error!("failed to persist cluster state: {e}");
+                }
+                return;
+            }
+            if resp.accepted {
+                acks += 1;
+            }
+        }
+
+        if state.persistent.term != term || *self.role.borrow() != Role::Leader {
+            return;
+        }
+
+        if acks >= self.majority() {
+            // the peers acknowledged after `started_at`, so they won't elect another leader
+            // before `started_at + MIN_ELECTION_TIMEOUT`
+            self.lease.set(Some(started_at + LEASE_DURATION));
+        } else if !self.lease.is_valid() {
+            tracing::warn!("lost the lease for term {term}, stepping down");
+            self.step_down(&mut state);
+        }
+    }
+
+    pub fn handle_vote(&self, req: VoteRequest) -> anyhow::Result<VoteResponse> {
+        // read from disk before locking, not to hold up the heartbeats
+        let local_progress = local_progress(&self.db_path)?;
+        let mut state = self.state.lock();
+        let now = Instant::now();
+
+        // A node that is partitioned from the leader mustn't be able to depose it.
+        let leader_alive = state
+            .last_leader_contact
+            .map_or(false, |t| now.duration_since(t) < MIN_ELECTION_TIMEOUT)
+            || (*self.role.borrow() == Role::Leader && self.lease.is_valid());
+        if leader_alive || req.term < state.persistent.term {
+            return Ok(VoteResponse {
+                term: state.persistent.term,
+                granted: false,
+            });
+        }
+
+        self.observe_term(&mut state, req.term)?;
+
+        let can_vote = match state.persistent.voted_for {
+            Some(ref candidate) => *candidate == req.candidate_url,
+            None => true,
+        };
+        let granted = can_vote && is_up_to_date(&req.progress, &local_progress);
+        if granted {
+            state.persistent.voted_for = Some(req.candidate_url);
+            state.election_deadline = now + random_election_timeout();
+            self.persist(&state)?;
+        }
+
+        Ok(VoteResponse {
+            term: state.persistent.term,
+            granted,
+        })
+    }
+
+    pub fn handle_heartbeat(&self, req: HeartbeatRequest) -> anyhow::Result<HeartbeatResponse> {
+        let mut state = self.state.lock();
+        if req.term < state.persistent.term {
+            return Ok(HeartbeatResponse {
+                term: state.persistent.term,
+                accepted: false,
+            });
+        }
+
+        self.observe_term(&mut state, req.term)?;
+        let now = Instant::now();
+        state.last_leader_contact = Some(now);
+        state.election_deadline = now + random_election_timeout();
+        drop(state);
+        self.set_role(Role::Follower {
+            leader_url: req.leader_url,
+        });
+
+        Ok(HeartbeatResponse {
+            term: req.term,
+            accepted: true,
+        })
+    }
+
+    /// Moves to `term` if it is newer than the current term. The leader of the previous term
+    /// steps down; followers keep following it until they hear from the new leader.
+    fn observe_term(&self, state: &mut State, term: u64) -> anyhow::Result<()> {
+        if term > state.persistent.term {
+            state.persistent.term = term;
+            state.persistent.voted_for = None;
+            state.last_leader_contact = None;
+            self.persist(state)?;
+            if *self.role.borrow() == Role::Leader {
+                tracing::info!("observed term {term}, stepping down");
+                self.step_down(state);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn step_down(&self, state: &mut State) {
+        self.lease.set(None);
+        state.election_deadline = Instant::now() + random_election_timeout();
+        self.set_role(Role::Unknown);
+    }
+
+    fn set_role(&self, role: Role) {
+        self.role.send_if_modified(|current| {
+            if *current != role {
+                tracing::info!("cluster role changed from {current:?} to {role:?}");
+                *current = role;
+                true
+            } else {
+                false
+            }
+        });
+    }
+
+    fn persist(&self, state: &State) -> anyhow::Result<()> {
+        let path = self.db_path.join(STATE_FILE_NAME);
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, serde_json::to_vec(&state.persistent)?)?;
+        std::fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+}
+
+fn random_election_timeout() -> Duration {
+    rand::thread_rng().gen_range(MIN_ELECTION_TIMEOUT..MAX_ELECTION_TIMEOUT)
+}
+
+/// Returns the progress of each database of this node with frames, by namespace.
+fn local_progress(db_path: &Path) -> anyhow::Result<HashMap<String, DatabaseProgress>> {
+    let mut progress = HashMap::new();
+    let dbs_path = db_path.join("dbs");
+    if !dbs_path.try_exists()? {
+        return Ok(progress);
+    }
+
+    for entry in std::fs::read_dir(dbs_path)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            let Some(name) = entry.file_name().to_str().map(ToOwned::to_owned) else {
+                continue;
+            };
+            if let Some(db) = database_progress(&entry.path())? {
+                progress.insert(
+                    name,
+                    DatabaseProgress {
+                        database_id: db.database_id.to_string(),
+                        generation_id: db
+                            .generation_id
+                            .map(|id| id.to_string())
+                            .unwrap_or_default(),
+                        generation_start_frame_no: db.generation_start_frame_no,
+                        next_frame_no: db.next_frame_no,
+                    },
+                );
+            }
+        }
+    }
+
+    Ok(progress)
+}
+
+/// Whether a candidate has at least the frames this node has, in every database.
+fn is_up_to_date(
+    candidate: &HashMap<String, DatabaseProgress>,
+    local: &HashMap<String, DatabaseProgress>,
+) -> bool {
+    local.iter().all(|(ns, local)| {
+        let Some(candidate) = candidate.get(ns) else {
+            return false;
+        };
+        if candidate.database_id != local.database_id {
+            return false;
+        }
+        // a primary doesn't know which generation its frames belong to once it stops
+        let unknown_generation =
+            candidate.generation_id.is_empty() || local.generation_id.is_empty();
+        if unknown_generation || candidate.generation_id == local.generation_id {
+            candidate.next_frame_no >= local.next_frame_no
+        } else {
+            (candidate.generation_start_frame_no, candidate.next_frame_no)
+                >= (local.generation_start_frame_no, local.next_frame_no)
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn vote_request(term: u64, candidate: &str) -> VoteRequest {
+        VoteRequest {
+            term,
+            candidate_url: candidate.into(),
+            progress: HashMap::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn votes_once_per_term() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cluster = Cluster::new("http://a".into(), Vec::new(), tmp.path()).unwrap();
+
+        assert!(
+            cluster
+                .handle_vote(vote_request(1, "http://b"))
+                .unwrap()
+                .granted
+        );
+        assert!(
+            !cluster
+                .handle_vote(vote_request(1, "http://c"))
+                .unwrap()
+                .granted
+        );
+        // a stale candidate is told the current term
+        let resp = cluster.handle_vote(vote_request(0, "http://c")).unwrap();
+        assert!(!resp.granted);
+        assert_eq!(resp.term, 1);
+        assert!(
+            cluster
+                .handle_vote(vote_request(2, "http://c"))
+                .unwrap()
+                .granted
+        );
+
+        // the vote survives a restart
+        drop(cluster);
+        let cluster = Cluster::new("http://a".into(), Vec::new(), tmp.path()).unwrap();
+        assert!(
+            !cluster
+                .handle_vote(vote_request(2, "http://b"))
+                .unwrap()
+                .granted
+        );
+    }
+
+    #[tokio::test]
+    async fn heartbeat_sets_leader() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cluster = Cluster::new("http://a".into(), Vec::new(), tmp.path()).unwrap();
+
+        let resp = cluster
+            .handle_heartbeat(HeartbeatRequest {
+                term: 3,
+                leader_url: "http://b".into(),
+            })
+            .unwrap();
+        assert!(resp.accepted);
+        assert_eq!(
+            cluster.role(),
+            Role::Follower {
+                leader_url: "http://b".into()
+            }
+        );
+
+        // the leader is alive, so the node refuses to vote for another candidate
+        assert!(
+            !cluster
+                .handle_vote(vote_request(4, "http://c"))
+                .unwrap()
+                .granted
+        );
+
+        let resp = cluster
+            .handle_heartbeat(HeartbeatRequest {
+                term: 2,
+                leader_url: "http://c".into(),
+            })
+            .unwrap();
+        assert!(!resp.accepted);
+        assert_eq!(resp.term, 3);
+    }
+
+    fn progress(
+        db: &str,
+        generation: &str,
+        generation_start: u64,
+        next_frame_no: u64,
+    ) -> DatabaseProgress {
+        DatabaseProgress {
+            database_id: db.into(),
+            generation_id: generation.into(),
+            generation_start_frame_no: generation_start,
+            next_frame_no,
+        }
+    }
+
+    #[test]
+    fn up_to_date() {
+        let local = HashMap::from([
+            ("default".to_string(), progress("a", "g1", 0, 10)),
+            ("other".to_string(), progress("b", "g1", 0, 5)),
+        ]);
+        let ahead = HashMap::from([
+            ("default".to_string(), progress("a", "g1", 0, 12)),
+            ("other".to_string(), progress("b", "g1", 0, 5)),
+        ]);
+        let behind = HashMap::from([
+            ("default".to_string(), progress("a", "g1", 0, 12)),
+            ("other".to_string(), progress("b", "g1", 0, 4)),
+        ]);
+        let missing = HashMap::from([("default".to_string(), progress("a", "g1", 0, 12))]);
+
+        assert!(is_up_to_date(&ahead, &local));
+        assert!(!is_up_to_date(&behind, &local));
+        assert!(!is_up_to_date(&missing, &local));
+        assert!(is_up_to_date(&local, &HashMap::new()));
+    }
+
+    #[test]
+    fn up_to_date_across_generations() {
+        let local = HashMap::from([("default".to_string(), progress("a", "g1", 0, 10))]);
+
+        // another database is never up to date, even with more frames
+        let other_db = HashMap::from([("default".to_string(), progress("b", "g1", 0, 20))]);
+        assert!(!is_up_to_date(&other_db, &local));
+
+        // the frames of a later generation replace the frames past its start
+        let later = HashMap::from([("default".to_string(), progress("a", "g2", 8, 9))]);
+        assert!(is_up_to_date(&later, &local));
+        assert!(!is_up_to_date(&local, &later));
+
+        // the frames of a demoted primary are compared by count
+        let demoted = HashMap::from([("default".to_string(), progress("a", "", 0, 10))]);
+        assert!(is_up_to_date(&demoted, &local));
+        assert!(is_up_to_date(&local, &demoted));
+        assert!(!is_up_to_date(&later, &demoted));
+    }
+
+    #[test]
+    fn lease_fences_writes() {
+        let lease = Lease::default();
+        assert!(!lease.is_valid());
+        lease.set(Some(Instant::now() + LEASE_DURATION));
+        assert!(lease.is_valid());
+        lease.set(Some(Instant::now()));
+        assert!(!lease.is_valid());
+    }
+}
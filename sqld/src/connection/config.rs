@@ -2,15 +2,23 @@ use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 use std::{fs, io};
 
 use crate::error::Error;
+use crate::replication::frame::Frame;
+use crate::replication::MergePolicy;
 use crate::Result;
 
+/// Duration after which an interactive transaction is rolled back, unless configured otherwise.
+pub const DEFAULT_TXN_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub struct DatabaseConfigStore {
     config_path: PathBuf,
     tmp_config_path: PathBuf,
     config: Mutex<Arc<DatabaseConfig>>,
+    /// The config of the server, whose blocks apply to every namespace.
+    server: Option<Arc<DatabaseConfigStore>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -22,6 +30,105 @@ pub struct DatabaseConfig {
     /// The reason why operations are blocked. This will be included in [`Error::Blocked`].
     #[serde(default)]
     pub block_reason: Option<String>,
+    /// Duration, in seconds, after which an interactive transaction is rolled back. Defaults to
+    /// [`DEFAULT_TXN_TIMEOUT`].
+    #[serde(default)]
+    pub txn_timeout_s: Option<u64>,
+    /// The longest transaction timeout, in seconds, that clients may request for their streams
+    /// and requests. When unset, clients may only request shorter timeouts.
+    #[serde(default)]
+    pub max_txn_timeout_s: Option<u64>,
+    /// Statements that take at least this long, in milliseconds, are logged and kept in the slow
+    /// query log. Slow queries are not logged when unset.
+    #[serde(default)]
+    pub slow_query_threshold_ms: Option<u64>,
+    /// On replicas, reads are forwarded to the primary when the replica last caught up with the
+    /// primary longer than this ago, in milliseconds.
+    #[serde(default)]
+    pub max_staleness_ms: Option<u64>,
+    /// On replicas, reads are forwarded to the primary when the replica is more than this many
+    /// frames behind it.
+    #[serde(default)]
+    pub max_staleness_frames: Option<u64>,
+    /// Snapshots of the replication log are merged when there are more than this many. Defaults
+    /// to 32.
+    #[serde(default)]
+    pub max_snapshot_count: Option<usize>,
+    /// Snapshots of the replication log are merged when they take more than this many MB. By
+    /// default, they are merged when they take twice the size of the database.
+    #[serde(default)]
+    pub max_snapshots_size_mb: Option<u64>,
+    /// Snapshots that end with a commit made less than this long ago, in seconds, are not merged,
+    /// so that the database can be restored as of any commit since then. Defaults to
+    /// `--history-retention`.
+    #[serde(default)]
+    pub min_snapshot_age_s: Option<u64>,
+}
+
+/// How far behind the primary a replica may be to serve a read locally. Reads on a replica that
+/// exceeds either bound are forwarded to the primary.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MaxStaleness {
+    pub time: Option<Duration>,
+    pub frames: Option<u64>,
+}
+
+impl MaxStaleness {
+    pub fn is_bounded(&self) -> bool {
+        self.time.is_some() || self.frames.is_some()
+    }
+
+    /// Returns the bounds of `self`, falling back to those of `other` for the unset ones.
+    pub fn or(self, other: Self) -> Self {
+        Self {
+            time: self.time.or(other.time),
+            frames: self.frames.or(other.frames),
+        }
+    }
+}
+
+impl DatabaseConfig {
+    /// Returns the transaction timeout to apply to a client that requested `requested`.
+    pub fn txn_timeout(&self, requested: Option<Duration>) -> Duration {
+        let default = self
+            .txn_timeout_s
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_TXN_TIMEOUT);
+        let max = self
+            .max_txn_timeout_s
+            .map(Duration::from_secs)
+            .unwrap_or(default)
+            .max(default);
+        requested.map_or(default, |requested| requested.min(max))
+    }
+
+    pub fn slow_query_threshold(&self) -> Option<Duration> {
+        self.slow_query_threshold_ms.map(Duration::from_millis)
+    }
+
+    pub fn max_staleness(&self) -> MaxStaleness {
+        MaxStaleness {
+            time: self.max_staleness_ms.map(Duration::from_millis),
+            frames: self.max_staleness_frames,
+        }
+    }
+
+    /// Returns the policy the snapshots are merged with, keeping the snapshots younger than
+    /// `default_min_age` unless the database sets its own minimum age.
+    pub fn snapshot_merge_policy(&self, default_min_age: Option<Duration>) -> MergePolicy {
+        MergePolicy {
+            max_count: self
+                .max_snapshot_count
+                .unwrap_or(MergePolicy::default().max_count),
+            max_frames: self
+                .max_snapshots_size_mb
+                .map(|size| size * 1_000_000 / Frame::SIZE as u64),
+            min_age: self
+                .min_snapshot_age_s
+                .map(Duration::from_secs)
+                .or(default_min_age),
+        }
+    }
 }
 
 impl DatabaseConfigStore {
@@ -39,19 +146,45 @@ impl DatabaseConfigStore {
             config_path,
             tmp_config_path,
             config: Mutex::new(Arc::new(config)),
+            server: None,
         })
     }
 
+    /// Makes the blocks of the `server` config apply to this config.
+    pub fn with_server(mut self, server: Arc<DatabaseConfigStore>) -> Self {
+        self.server = Some(server);
+        self
+    }
+
     #[cfg(test)]
     pub fn new_test() -> Self {
         Self {
             config_path: "".into(),
             tmp_config_path: "".into(),
             config: Mutex::new(Arc::new(DatabaseConfig::default())),
+            server: None,
         }
     }
 
+    /// Returns the config in effect, blocked if either this config or the server config is.
     pub fn get(&self) -> Arc<DatabaseConfig> {
+        let config = self.get_own();
+        let Some(server) = self.server.as_ref().map(|server| server.get()) else {
+            return config;
+        };
+        if !server.block_reads && !server.block_writes {
+            return config;
+        }
+
+        let mut config = (*config).clone();
+        config.block_reads |= server.block_reads;
+        config.block_writes |= server.block_writes;
+        config.block_reason = server.block_reason.clone().or(config.block_reason);
+        Arc::new(config)
+    }
+
+    /// Returns the config as stored, without the blocks of the server config.
+    pub fn get_own(&self) -> Arc<DatabaseConfig> {
         self.config.lock().clone()
     }
 
@@ -63,3 +196,101 @@ impl DatabaseConfigStore {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn txn_timeout() {
+        let config = DatabaseConfig::default();
+        assert_eq!(config.txn_timeout(None), DEFAULT_TXN_TIMEOUT);
+        assert_eq!(
+            config.txn_timeout(Some(Duration::from_secs(1))),
+            Duration::from_secs(1)
+        );
+        assert_eq!(
+            config.txn_timeout(Some(Duration::from_secs(60))),
+            DEFAULT_TXN_TIMEOUT
+        );
+
+        let config = DatabaseConfig {
+            txn_timeout_s: Some(10),
+            max_txn_timeout_s: Some(30),
+            ..Default::default()
+        };
+        assert_eq!(config.txn_timeout(None), Duration::from_secs(10));
+        assert_eq!(
+            config.txn_timeout(Some(Duration::from_secs(20))),
+            Duration::from_secs(20)
+        );
+        assert_eq!(
+            config.txn_timeout(Some(Duration::from_secs(60))),
+            Duration::from_secs(30)
+        );
+    }
+
+    #[test]
+    fn snapshot_merge_policy() {
+        let config = DatabaseConfig::default();
+        assert_eq!(config.snapshot_merge_policy(None), MergePolicy::default());
+        assert_eq!(
+            config
+                .snapshot_merge_policy(Some(Duration::from_secs(60)))
+                .min_age,
+            Some(Duration::from_secs(60))
+        );
+
+        let config = DatabaseConfig {
+            max_snapshot_count: Some(4),
+            max_snapshots_size_mb: Some(1),
+            min_snapshot_age_s: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(
+            config.snapshot_merge_policy(Some(Duration::from_secs(60))),
+            MergePolicy {
+                max_count: 4,
+                max_frames: Some(1_000_000 / Frame::SIZE as u64),
+                min_age: Some(Duration::from_secs(10)),
+            }
+        );
+    }
+
+    #[test]
+    fn server_blocks_apply_to_namespaces() {
+        let tmp = tempfile::tempdir().unwrap();
+        let server = Arc::new(DatabaseConfigStore::load(tmp.path()).unwrap());
+        let namespace_path = tmp.path().join("dbs").join("default");
+        fs::create_dir_all(&namespace_path).unwrap();
+        let store = DatabaseConfigStore::load(&namespace_path)
+            .unwrap()
+            .with_server(server.clone());
+        store
+            .store(DatabaseConfig {
+                block_writes: true,
+                block_reason: Some("namespace".into()),
+                txn_timeout_s: Some(10),
+                ..Default::default()
+            })
+            .unwrap();
+        assert!(!store.get().block_reads);
+
+        server
+            .store(DatabaseConfig {
+                block_reads: true,
+                block_reason: Some("server".into()),
+                ..Default::default()
+            })
+            .unwrap();
+        let config = store.get();
+        assert!(config.block_reads && config.block_writes);
+        assert_eq!(config.block_reason.as_deref(), Some("server"));
+        assert_eq!(config.txn_timeout_s, Some(10));
+        // the namespace config itself is left as it was
+        assert!(!store.get_own().block_reads);
+
+        server.store(DatabaseConfig::default()).unwrap();
+        assert_eq!(store.get().block_reason.as_deref(), Some("namespace"));
+    }
+}
@@ -29,7 +29,7 @@ impl DumpLoader {
 
         let (ok_snd, ok_rcv) = oneshot::channel::<anyhow::Result<()>>();
         tokio::task::spawn_blocking(move || {
-            let mut ctx = ReplicationLoggerHookCtx::new(logger, bottomless_replicator);
+            let mut ctx = ReplicationLoggerHookCtx::new(logger, bottomless_replicator, None, None);
             let mut retries = 0;
             let db = loop {
                 match open_db(&path, &REPLICATION_METHODS, &mut ctx, None) {
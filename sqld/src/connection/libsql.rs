@@ -1,9 +1,12 @@
+use std::collections::HashSet;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use crossbeam::channel::RecvTimeoutError;
-use rusqlite::{ErrorCode, OpenFlags, StatementStatus};
+use parking_lot::Mutex;
+use rusqlite::hooks::Action;
+use rusqlite::{ErrorCode, InterruptHandle, OpenFlags, StatementStatus};
 use sqld_libsql_bindings::wal_hook::WalMethodsHook;
 use tokio::sync::oneshot;
 use tracing::warn;
@@ -14,12 +17,15 @@ use crate::libsql::wal_hook::WalHook;
 use crate::query::Query;
 use crate::query_analysis::{State, StmtKind};
 use crate::query_result_builder::{QueryBuilderConfig, QueryResultBuilder};
+use crate::replication::primary::quorum;
 use crate::stats::Stats;
 use crate::Result;
 
 use super::config::DatabaseConfigStore;
 use super::program::{Cond, DescribeCol, DescribeParam, DescribeResponse, DescribeResult};
-use super::{MakeConnection, Program, Step, TXN_TIMEOUT};
+use super::query_stats::{Execution, QueryStats};
+use super::table_changes::{CapturedRows, ChangeOp, RowChange, TableChanges, MAX_CAPTURED_ROWS};
+use super::{MakeConnection, Program, Step};
 
 /// Internal message used to communicate between the database thread and the `LibSqlDb` handle.
 type ExecCallback = Box<dyn FnOnce(Result<&mut Connection>) -> anyhow::Result<()> + Send + 'static>;
@@ -30,9 +36,11 @@ pub struct LibSqlDbFactory<W: WalHook + 'static> {
     ctx_builder: Box<dyn Fn() -> W::Context + Sync + Send + 'static>,
     stats: Stats,
     config_store: Arc<DatabaseConfigStore>,
+    query_stats: Arc<QueryStats>,
     extensions: Vec<PathBuf>,
     max_response_size: u64,
     max_total_response_size: u64,
+    table_changes: Option<Arc<TableChanges>>,
     /// In wal mode, closing the last database takes time, and causes other databases creation to
     /// return sqlite busy. To mitigate that, we hold on to one connection
     _db: Option<LibSqlConnection>,
@@ -50,9 +58,11 @@ where
         ctx_builder: F,
         stats: Stats,
         config_store: Arc<DatabaseConfigStore>,
+        query_stats: Arc<QueryStats>,
         extensions: Vec<PathBuf>,
         max_response_size: u64,
         max_total_response_size: u64,
+        table_changes: Option<Arc<TableChanges>>,
     ) -> Result<Self>
     where
         F: Fn() -> W::Context + Sync + Send + 'static,
@@ -63,9 +73,11 @@ where
             ctx_builder: Box::new(ctx_builder),
             stats,
             config_store,
+            query_stats,
             extensions,
             max_response_size,
             max_total_response_size,
+            table_changes,
             _db: None,
         };
 
@@ -112,10 +124,12 @@ where
             (self.ctx_builder)(),
             self.stats.clone(),
             self.config_store.clone(),
+            self.query_stats.clone(),
             QueryBuilderConfig {
                 max_size: Some(self.max_response_size),
                 max_total_size: Some(self.max_total_response_size),
             },
+            self.table_changes.clone(),
         )
         .await
     }
@@ -137,6 +151,9 @@ where
 #[derive(Clone)]
 pub struct LibSqlConnection {
     sender: crossbeam::channel::Sender<ExecCallback>,
+    /// Used to interrupt the statement running on the connection thread when the caller stops
+    /// waiting for its result.
+    interrupt_handle: Arc<InterruptHandle>,
 }
 
 pub fn open_db<'a, W>(
@@ -166,7 +183,9 @@ impl LibSqlConnection {
         hook_ctx: W::Context,
         stats: Stats,
         config_store: Arc<DatabaseConfigStore>,
+        query_stats: Arc<QueryStats>,
         builder_config: QueryBuilderConfig,
+        table_changes: Option<Arc<TableChanges>>,
     ) -> crate::Result<Self>
     where
         W: WalHook,
@@ -184,10 +203,13 @@ impl LibSqlConnection {
                 &mut ctx,
                 stats,
                 config_store,
+                query_stats,
                 builder_config,
+                table_changes,
             ) {
                 Ok(conn) => {
-                    let Ok(_) = init_sender.send(Ok(())) else { return };
+                    let handle = conn.conn.get_interrupt_handle();
+                    let Ok(_) = init_sender.send(Ok(handle)) else { return };
                     conn
                 }
                 Err(e) => {
@@ -198,12 +220,12 @@ impl LibSqlConnection {
 
             loop {
                 let exec = match connection.timeout_deadline {
-                    Some(deadline) => match receiver.recv_deadline(deadline) {
+                    Some((deadline, timeout)) => match receiver.recv_deadline(deadline) {
                         Ok(msg) => msg,
                         Err(RecvTimeoutError::Timeout) => {
-                            warn!("transaction timed out");
+                            warn!("transaction timed out after {timeout:?}");
                             connection.rollback();
-                            connection.timed_out = true;
+                            connection.timed_out = Some(timeout);
                             connection.timeout_deadline = None;
                             continue;
                         }
@@ -215,10 +237,9 @@ impl LibSqlConnection {
                     },
                 };
 
-                let maybe_conn = if !connection.timed_out {
-                    Ok(&mut connection)
-                } else {
-                    Err(Error::LibSqlTxTimeout)
+                let maybe_conn = match connection.timed_out {
+                    None => Ok(&mut connection),
+                    Some(timeout) => Err(Error::LibSqlTxTimeout(timeout)),
                 };
 
                 if exec(maybe_conn).is_err() {
@@ -228,19 +249,73 @@ impl LibSqlConnection {
             }
         });
 
-        init_receiver.await??;
+        let interrupt_handle = Arc::new(init_receiver.await??);
 
-        Ok(Self { sender })
+        Ok(Self {
+            sender,
+            interrupt_handle,
+        })
+    }
+}
+
+/// Interrupts the statement running on the connection when dropped, unless it was disarmed first.
+///
+/// It is held while waiting for the result of a callback, so that dropping the future (because
+/// the client went away, or a deadline passed) stops the work on the connection thread.
+struct InterruptGuard<'a> {
+    handle: &'a InterruptHandle,
+    armed: bool,
+}
+
+impl<'a> InterruptGuard<'a> {
+    fn new(handle: &'a InterruptHandle) -> Self {
+        Self {
+            handle,
+            armed: true,
+        }
+    }
+
+    fn disarm(mut self) {
+        self.armed = false;
+    }
+}
+
+impl Drop for InterruptGuard<'_> {
+    fn drop(&mut self) {
+        if self.armed {
+            tracing::debug!("interrupting cancelled statement");
+            self.handle.interrupt();
+        }
     }
 }
 
 struct Connection<'a> {
-    timeout_deadline: Option<Instant>,
+    /// Deadline of the open transaction, and the timeout it was computed from.
+    timeout_deadline: Option<(Instant, Duration)>,
     conn: sqld_libsql_bindings::Connection<'a>,
-    timed_out: bool,
+    /// Set to the timeout that fired, once the transaction was rolled back because of it.
+    timed_out: Option<Duration>,
+    /// Transaction timeout requested by the client of this connection.
+    requested_txn_timeout: Option<Duration>,
     stats: Stats,
     config_store: Arc<DatabaseConfigStore>,
+    query_stats: Arc<QueryStats>,
     builder_config: QueryBuilderConfig,
+    /// Changes made by the current transaction, only tracked if somebody is interested in them.
+    pending_changes: Option<Arc<Mutex<PendingChanges>>>,
+    table_changes: Option<Arc<TableChanges>>,
+}
+
+#[derive(Default)]
+struct PendingChanges {
+    tables: HashSet<String>,
+    schema_changed: bool,
+    /// Only collected when the table changes tracker captures rows.
+    rows: CapturedRows,
+    /// The rows of the transaction that is being committed, set by the commit hook. They are
+    /// forwarded to the table changes tracker once the statement that commits returns, since the
+    /// commit may still fail when its frames are written.
+    committing_rows: Option<CapturedRows>,
 }
 
 impl<'a> Connection<'a> {
@@ -251,17 +326,29 @@ impl<'a> Connection<'a> {
         hook_ctx: &'a mut W::Context,
         stats: Stats,
         config_store: Arc<DatabaseConfigStore>,
+        query_stats: Arc<QueryStats>,
         builder_config: QueryBuilderConfig,
+        table_changes: Option<Arc<TableChanges>>,
     ) -> Result<Self> {
         let this = Self {
             conn: open_db(path, wal_methods, hook_ctx, None)?,
             timeout_deadline: None,
-            timed_out: false,
+            timed_out: None,
+            requested_txn_timeout: None,
             stats,
             config_store,
+            query_stats,
             builder_config,
+            pending_changes: table_changes.is_some().then(Default::default),
+            table_changes: table_changes.clone(),
         };
 
+        if let (Some(table_changes), Some(pending_changes)) =
+            (table_changes, this.pending_changes.clone())
+        {
+            this.track_changes(table_changes, pending_changes);
+        }
+
         for ext in extensions {
             unsafe {
                 let _guard = rusqlite::LoadExtensionGuard::new(&this.conn).unwrap();
@@ -289,7 +376,13 @@ impl<'a> Connection<'a> {
 
         // A transaction is still open, set up a timeout
         if is_autocommit_before && !self.conn.is_autocommit() {
-            self.timeout_deadline = Some(Instant::now() + TXN_TIMEOUT)
+            let timeout = self
+                .config_store
+                .get()
+                .txn_timeout(self.requested_txn_timeout);
+            self.timeout_deadline = Some((Instant::now() + timeout, timeout))
+        } else if self.conn.is_autocommit() {
+            self.timeout_deadline = None;
         }
 
         builder.finish()?;
@@ -340,6 +433,33 @@ impl<'a> Connection<'a> {
         query: &Query,
         builder: &mut impl QueryResultBuilder,
     ) -> Result<(u64, Option<i64>)> {
+        let start = Instant::now();
+        let res = self.execute_query_inner(query, builder);
+        let (rows_read, rows_written) = match res {
+            Ok((_, _, rows_read, rows_written)) => (rows_read, rows_written),
+            Err(_) => (0, 0),
+        };
+        let exec = Execution {
+            elapsed: start.elapsed(),
+            rows_read,
+            rows_written,
+            failed: res.is_err(),
+        };
+        let slow_threshold = self.config_store.get().slow_query_threshold();
+        self.query_stats.record(&query.stmt, exec, slow_threshold);
+
+        res.map(|(affected_row_count, last_insert_rowid, _, _)| {
+            (affected_row_count, last_insert_rowid)
+        })
+    }
+
+    /// Executes the query, and returns the affected row count, the last inserted rowid and the
+    /// number of rows read and written.
+    fn execute_query_inner(
+        &self,
+        query: &Query,
+        builder: &mut impl QueryResultBuilder,
+    ) -> Result<(u64, Option<i64>, u64, u64)> {
         tracing::trace!("executing query: {}", query.stmt.stmt);
 
         let config = self.config_store.get();
@@ -354,6 +474,14 @@ impl<'a> Connection<'a> {
 
         let mut stmt = self.conn.prepare(&query.stmt.stmt)?;
 
+        // schema changes are not reported by the update hook, so we flag them before they are
+        // committed.
+        if let Some(ref pending_changes) = self.pending_changes {
+            if query.stmt.kind == StmtKind::Write && !query.stmt.is_iud {
+                pending_changes.lock().schema_changed = true;
+            }
+        }
+
         let cols = stmt.columns();
         let cols_count = cols.len();
         builder.cols_description(cols.iter())?;
@@ -364,6 +492,10 @@ impl<'a> Connection<'a> {
             .bind(&mut stmt)
             .map_err(Error::LibSqlInvalidQueryParams)?;
 
+        // only the commit of this statement must wait for the replicas
+        quorum::clear_pending_commit();
+        // and only its rows are delivered if it commits
+        self.take_committing_rows();
         let mut qresult = stmt.raw_query();
         builder.begin_rows()?;
         while let Some(row) = qresult.next()? {
@@ -375,8 +507,21 @@ impl<'a> Connection<'a> {
             builder.finish_row()?;
         }
 
+        // the statement ran to completion, so the transaction it committed, if any, is durable
+        if let (Some(rows), Some(table_changes)) =
+            (self.take_committing_rows(), &self.table_changes)
+        {
+            table_changes.record_rows(rows);
+        }
+
         builder.finish_rows()?;
 
+        // the write lock is released once the statement committed, so that other writers don't
+        // wait for the replicas too
+        if !quorum::wait_pending_commit() {
+            return Err(Error::WriteQuorumTimeout);
+        }
+
         // sqlite3_changes() is only modified for INSERT, UPDATE or DELETE; it is not reset for SELECT,
         // but we want to return 0 in that case.
         let affected_row_count = match query.stmt.is_iud {
@@ -393,16 +538,96 @@ impl<'a> Connection<'a> {
 
         drop(qresult);
 
-        self.update_stats(&stmt);
+        let (rows_read, rows_written) = self.update_stats(&stmt);
 
-        Ok((affected_row_count, last_insert_rowid))
+        Ok((
+            affected_row_count,
+            last_insert_rowid,
+            rows_read,
+            rows_written,
+        ))
     }
 
     fn rollback(&self) {
         let _ = self.conn.execute("ROLLBACK", ());
     }
 
-    fn update_stats(&self, stmt: &rusqlite::Statement) {
+    /// Takes the rows of the transaction committed by the last statement.
+    fn take_committing_rows(&self) -> Option<CapturedRows> {
+        self.pending_changes
+            .as_ref()
+            .and_then(|pending| pending.lock().committing_rows.take())
+    }
+
+    /// Installs the hooks that collect the tables modified by each transaction, and report them
+    /// to `table_changes` when it commits.
+    ///
+    /// The commit hook runs before the transaction frames are appended to the WAL, so the changes
+    /// are always recorded before the replication logger notifies about the new frames. The
+    /// captured rows are only staged by the hook, because appending the frames may still fail and
+    /// roll the transaction back: they are forwarded once the commit returned.
+    fn track_changes(
+        &self,
+        table_changes: Arc<TableChanges>,
+        pending_changes: Arc<Mutex<PendingChanges>>,
+    ) {
+        self.conn.update_hook(Some({
+            let pending_changes = pending_changes.clone();
+            let table_changes = table_changes.clone();
+            move |action, db_name: &str, table: &str, rowid| {
+                if db_name != "main" {
+                    return;
+                }
+                let mut pending = pending_changes.lock();
+                if !pending.tables.contains(table) {
+                    pending.tables.insert(table.to_owned());
+                }
+                if table_changes.captures_rows() {
+                    let op = match action {
+                        Action::SQLITE_INSERT => ChangeOp::Insert,
+                        Action::SQLITE_UPDATE => ChangeOp::Update,
+                        Action::SQLITE_DELETE => ChangeOp::Delete,
+                        _ => return,
+                    };
+                    if pending.rows.rows.len() < MAX_CAPTURED_ROWS {
+                        pending.rows.rows.push(RowChange {
+                            table: table.to_owned(),
+                            op,
+                            rowid,
+                        });
+                    } else {
+                        pending.rows.truncated = true;
+                    }
+                }
+            }
+        }));
+
+        self.conn.commit_hook(Some({
+            let pending_changes = pending_changes.clone();
+            move || {
+                let mut pending = pending_changes.lock();
+                let tables = std::mem::take(&mut pending.tables);
+                let schema_changed = std::mem::take(&mut pending.schema_changed);
+                let rows = std::mem::take(&mut pending.rows);
+                if !rows.rows.is_empty() {
+                    pending.committing_rows = Some(rows);
+                }
+                drop(pending);
+                if !tables.is_empty() || schema_changed {
+                    table_changes.record(tables, schema_changed);
+                }
+                // don't turn the commit into a rollback
+                false
+            }
+        }));
+
+        self.conn.rollback_hook(Some(move || {
+            *pending_changes.lock() = PendingChanges::default();
+        }));
+    }
+
+    /// Updates the stats with the rows read and written by `stmt`, and returns them.
+    fn update_stats(&self, stmt: &rusqlite::Statement) -> (u64, u64) {
         let rows_read = stmt.get_status(StatementStatus::RowsRead);
         let rows_written = stmt.get_status(StatementStatus::RowsWritten);
         let rows_read = if rows_read == 0 && rows_written == 0 {
@@ -412,6 +637,7 @@ impl<'a> Connection<'a> {
         };
         self.stats.inc_rows_read(rows_read as u64);
         self.stats.inc_rows_written(rows_written as u64);
+        (rows_read as u64, rows_written as u64)
     }
 
     fn describe(&self, sql: &str) -> DescribeResult {
@@ -508,52 +734,89 @@ impl super::Connection for LibSqlConnection {
         check_program_auth(auth, &pgm)?;
         let (resp, receiver) = oneshot::channel();
         let cb = Box::new(move |maybe_conn: Result<&mut Connection>| {
-            let res = maybe_conn.and_then(|c| {
-                let b = c.run(pgm, builder)?;
-                let state = if c.conn.is_autocommit() {
-                    State::Init
-                } else {
-                    State::Txn
-                };
-
-                Ok((b, state))
-            });
+            // the caller gave up before we got a chance to run the program
+            if resp.is_closed() {
+                return Ok(());
+            }
 
-            if resp.send(res).is_err() {
-                anyhow::bail!("connection closed");
+            match maybe_conn {
+                Ok(c) => {
+                    let res = c.run(pgm, builder).map(|b| {
+                        let state = if c.conn.is_autocommit() {
+                            State::Init
+                        } else {
+                            State::Txn
+                        };
+                        (b, state)
+                    });
+
+                    // the caller gave up while the program was running, so it can't know what
+                    // state the connection is in: roll back whatever the program left open.
+                    if resp.send(res).is_err() {
+                        c.rollback();
+                        c.timeout_deadline = None;
+                    }
+                }
+                Err(e) => {
+                    let _: Result<_, _> = resp.send(Err(e));
+                }
             }
 
             Ok(())
         });
 
+        let guard = InterruptGuard::new(&self.interrupt_handle);
         let _: Result<_, _> = self.sender.send(cb);
+        let res = receiver.await;
+        guard.disarm();
 
-        Ok(receiver.await??)
+        Ok(res??)
     }
 
     async fn describe(&self, sql: String, auth: Authenticated) -> Result<DescribeResult> {
         check_describe_auth(auth)?;
         let (resp, receiver) = oneshot::channel();
         let cb = Box::new(move |maybe_conn: Result<&mut Connection>| {
-            let res = maybe_conn.and_then(|c| c.describe(&sql));
-
-            if resp.send(res).is_err() {
-                anyhow::bail!("connection closed");
+            if resp.is_closed() {
+                return Ok(());
             }
 
+            let res = maybe_conn.and_then(|c| c.describe(&sql));
+            let _: Result<_, _> = resp.send(res);
+
             Ok(())
         });
 
+        let guard = InterruptGuard::new(&self.interrupt_handle);
         let _: Result<_, _> = self.sender.send(cb);
+        let res = receiver.await;
+        guard.disarm();
 
-        Ok(receiver.await?)
+        Ok(res?)
+    }
+
+    fn set_txn_timeout(&self, timeout: Option<Duration>) {
+        let cb = Box::new(move |maybe_conn: Result<&mut Connection>| {
+            if let Ok(c) = maybe_conn {
+                c.requested_txn_timeout = timeout;
+            }
+            Ok(())
+        });
+
+        let _: Result<_, _> = self.sender.send(cb);
     }
 }
 
 #[cfg(test)]
 mod test {
+    use std::ffi::{c_int, CStr};
+    use std::sync::atomic::{AtomicBool, Ordering};
+
     use itertools::Itertools;
+    use sqld_libsql_bindings::init_static_wal_method;
 
+    use crate::connection::table_changes::RowListener;
+    use crate::libsql::ffi::{types::XWalFrameFn, PgHdr, Wal, SQLITE_IOERR};
     use crate::query_result_builder::{test::test_driver, IgnoreResult};
 
     use super::*;
@@ -562,10 +825,14 @@ mod test {
         let mut conn = Connection {
             timeout_deadline: None,
             conn: sqld_libsql_bindings::Connection::test(ctx),
-            timed_out: false,
+            timed_out: None,
+            requested_txn_timeout: None,
             stats: Stats::default(),
             config_store: Arc::new(DatabaseConfigStore::new_test()),
+            query_stats: Default::default(),
             builder_config: QueryBuilderConfig::default(),
+            pending_changes: None,
+            table_changes: None,
         };
 
         let stmts = std::iter::once("create table test (x)")
@@ -584,4 +851,99 @@ mod test {
             conn.run(Program::seq(&["select * from test"]), b)
         })
     }
+
+    init_static_wal_method!(FAILING_COMMIT_METHODS, FailingCommitHook);
+
+    /// Fails the commits while its context is set, after the commit hook ran.
+    enum FailingCommitHook {}
+
+    unsafe impl WalHook for FailingCommitHook {
+        type Context = Arc<AtomicBool>;
+
+        fn name() -> &'static CStr {
+            CStr::from_bytes_with_nul(b"failing_commit_hook\0").unwrap()
+        }
+
+        fn on_frames(
+            wal: &mut Wal,
+            page_size: c_int,
+            page_headers: *mut PgHdr,
+            size_after: u32,
+            is_commit: c_int,
+            sync_flags: c_int,
+            orig: XWalFrameFn,
+        ) -> c_int {
+            if is_commit != 0 && Self::wal_extract_ctx(wal).load(Ordering::Relaxed) {
+                return SQLITE_IOERR;
+            }
+            unsafe {
+                orig(
+                    wal,
+                    page_size,
+                    page_headers,
+                    size_after,
+                    is_commit,
+                    sync_flags,
+                )
+            }
+        }
+    }
+
+    #[derive(Default)]
+    struct Recorder {
+        rows: Mutex<Vec<CapturedRows>>,
+    }
+
+    impl RowListener for Recorder {
+        fn captures_rows(&self) -> bool {
+            true
+        }
+
+        fn record_rows(&self, rows: CapturedRows) {
+            self.rows.lock().push(rows);
+        }
+    }
+
+    #[test]
+    fn rows_are_recorded_once_committed() {
+        let tmp = tempfile::tempdir().unwrap();
+        let recorder = Arc::new(Recorder::default());
+        let table_changes = Arc::new(TableChanges::with_row_listener(recorder.clone()));
+        let fail_commits = Arc::new(AtomicBool::new(false));
+        let mut ctx = fail_commits.clone();
+        let mut conn = Connection::new(
+            tmp.path(),
+            Vec::new(),
+            &FAILING_COMMIT_METHODS,
+            &mut ctx,
+            Stats::default(),
+            Arc::new(DatabaseConfigStore::new_test()),
+            Default::default(),
+            QueryBuilderConfig::default(),
+            Some(table_changes),
+        )
+        .unwrap();
+
+        conn.run(
+            Program::seq(&["create table t (x)", "insert into t values (1)"]),
+            IgnoreResult,
+        )
+        .unwrap();
+        assert_eq!(recorder.rows.lock().len(), 1);
+
+        // the commit hook runs, but the frames of the transaction are never written
+        fail_commits.store(true, Ordering::Relaxed);
+        conn.run(Program::seq(&["insert into t values (2)"]), IgnoreResult)
+            .unwrap();
+        fail_commits.store(false, Ordering::Relaxed);
+        conn.run(Program::seq(&["select * from t"]), IgnoreResult)
+            .unwrap();
+        assert_eq!(recorder.rows.lock().len(), 1);
+
+        conn.run(Program::seq(&["insert into t values (3)"]), IgnoreResult)
+            .unwrap();
+        let rows = recorder.rows.lock();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[1].rows[0].rowid, 2);
+    }
 }
@@ -4,24 +4,27 @@ use std::time::Duration;
 
 use futures::Future;
 use tokio::{sync::Semaphore, time::timeout};
+use tracing::Instrument as _;
 
 use crate::auth::Authenticated;
 use crate::error::Error;
 use crate::query::{Params, Query};
 use crate::query_analysis::{State, Statement};
 use crate::query_result_builder::{IgnoreResult, QueryResultBuilder};
+use crate::replication::FrameNo;
 use crate::Result;
 
+use self::config::MaxStaleness;
 use self::program::{Cond, DescribeResult, Program, Step};
 
 pub mod config;
 pub mod dump;
 pub mod libsql;
 pub mod program;
+pub mod query_stats;
+pub mod table_changes;
 pub mod write_proxy;
 
-const TXN_TIMEOUT: Duration = Duration::from_secs(5);
-
 #[async_trait::async_trait]
 pub trait Connection: Send + Sync + 'static {
     /// Executes a query program
@@ -99,6 +102,23 @@ pub trait Connection: Send + Sync + 'static {
 
     /// Parse the SQL statement and return information about it.
     async fn describe(&self, sql: String, auth: Authenticated) -> Result<DescribeResult>;
+
+    /// Requests the duration after which an interactive transaction opened on this connection is
+    /// rolled back. The request is capped by the database config, and `None` restores the
+    /// default timeout of the database.
+    fn set_txn_timeout(&self, timeout: Option<Duration>);
+
+    /// Requests how far behind the primary a replica may be to serve the reads of this connection,
+    /// instead of forwarding them to the primary. Bounds left unset fall back to the database
+    /// config. Only relevant to replicas.
+    fn set_max_staleness(&self, _max_staleness: MaxStaleness) {}
+
+    /// Returns the frame_no of the last write performed through this connection, when it is not
+    /// reflected in the frame notifier of the database yet, as for writes that a replica forwarded
+    /// to the primary.
+    fn last_write_frame_no(&self) -> Option<FrameNo> {
+        None
+    }
 }
 
 fn make_batch_program(batch: Vec<Query>) -> Vec<Step> {
@@ -124,16 +144,29 @@ pub trait MakeConnection: Send + Sync + 'static {
     /// Create a new connection of type Self::Connection
     async fn create(&self) -> Result<Self::Connection, Error>;
 
+    /// Returns how many connections can be created without waiting, and how many callers are
+    /// waiting for one, if this maker limits the number of connections.
+    fn usage(&self) -> Option<ConnectionUsage> {
+        None
+    }
+
     fn throttled(
         self,
         conccurency: usize,
         timeout: Option<Duration>,
         max_total_response_size: u64,
+        statement_timeout: Option<Duration>,
     ) -> MakeThrottledConnection<Self>
     where
         Self: Sized,
     {
-        MakeThrottledConnection::new(conccurency, self, timeout, max_total_response_size)
+        MakeThrottledConnection::new(
+            conccurency,
+            self,
+            timeout,
+            max_total_response_size,
+            statement_timeout,
+        )
     }
 }
 
@@ -152,6 +185,12 @@ where
     }
 }
 
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionUsage {
+    pub available_permits: usize,
+    pub waiters: usize,
+}
+
 pub struct MakeThrottledConnection<F> {
     semaphore: Arc<Semaphore>,
     connection_maker: F,
@@ -160,6 +199,8 @@ pub struct MakeThrottledConnection<F> {
     // will result in reducing concurrency to prevent out-of-memory errors.
     max_total_response_size: u64,
     waiters: AtomicUsize,
+    /// Maximum duration of a single program or describe on the created connections.
+    statement_timeout: Option<Duration>,
 }
 
 impl<F> MakeThrottledConnection<F> {
@@ -168,6 +209,7 @@ impl<F> MakeThrottledConnection<F> {
         connection_maker: F,
         timeout: Option<Duration>,
         max_total_response_size: u64,
+        statement_timeout: Option<Duration>,
     ) -> Self {
         Self {
             semaphore: Arc::new(Semaphore::new(conccurency)),
@@ -175,6 +217,7 @@ impl<F> MakeThrottledConnection<F> {
             timeout,
             max_total_response_size,
             waiters: AtomicUsize::new(0),
+            statement_timeout,
         }
     }
 
@@ -227,7 +270,11 @@ impl<F: MakeConnection> MakeConnection for MakeThrottledConnection<F> {
         if waiters_guard.waiters.load(Ordering::Relaxed) >= 128 {
             return Err(Error::TooManyRequests);
         }
-        let fut = self.semaphore.clone().acquire_many_owned(units);
+        let fut = self
+            .semaphore
+            .clone()
+            .acquire_many_owned(units)
+            .instrument(tracing::info_span!("acquire_connection_permit", units));
         let mut permit = match self.timeout {
             Some(t) => timeout(t, fut).await.map_err(|_| Error::DbCreateTimeout)?,
             None => fut.await,
@@ -247,7 +294,37 @@ impl<F: MakeConnection> MakeConnection for MakeThrottledConnection<F> {
         }
 
         let inner = self.connection_maker.create().await?;
-        Ok(TrackedConnection { permit, inner })
+        Ok(TrackedConnection {
+            permit,
+            inner,
+            statement_timeout: self.statement_timeout,
+        })
+    }
+
+    fn usage(&self) -> Option<ConnectionUsage> {
+        Some(ConnectionUsage {
+            available_permits: self.semaphore.available_permits(),
+            waiters: self.waiters.load(Ordering::Relaxed),
+        })
+    }
+}
+
+/// Runs `fut` to completion, or fails with [`Error::StatementTimeout`] if it takes longer than
+/// `timeout`. The future is dropped on timeout, which interrupts the statement that it was
+/// waiting for.
+pub async fn with_statement_timeout<T, E>(
+    timeout: Option<Duration>,
+    fut: impl Future<Output = Result<T, E>>,
+) -> Result<T, E>
+where
+    E: From<Error>,
+{
+    match timeout {
+        Some(t) => match tokio::time::timeout(t, fut).await {
+            Ok(res) => res,
+            Err(_) => Err(Error::StatementTimeout.into()),
+        },
+        None => fut.await,
     }
 }
 
@@ -255,6 +332,7 @@ pub struct TrackedConnection<DB> {
     inner: DB,
     #[allow(dead_code)] // just hold on to it
     permit: tokio::sync::OwnedSemaphorePermit,
+    statement_timeout: Option<Duration>,
 }
 
 #[async_trait::async_trait]
@@ -266,12 +344,33 @@ impl<DB: Connection> Connection for TrackedConnection<DB> {
         auth: Authenticated,
         builder: B,
     ) -> crate::Result<(B, State)> {
-        self.inner.execute_program(pgm, auth, builder).await
+        let span = tracing::info_span!("execute_program", steps = pgm.steps.len());
+        with_statement_timeout(
+            self.statement_timeout,
+            self.inner.execute_program(pgm, auth, builder),
+        )
+        .instrument(span)
+        .await
     }
 
     #[inline]
     async fn describe(&self, sql: String, auth: Authenticated) -> crate::Result<DescribeResult> {
-        self.inner.describe(sql, auth).await
+        with_statement_timeout(self.statement_timeout, self.inner.describe(sql, auth)).await
+    }
+
+    #[inline]
+    fn set_txn_timeout(&self, timeout: Option<Duration>) {
+        self.inner.set_txn_timeout(timeout)
+    }
+
+    #[inline]
+    fn set_max_staleness(&self, max_staleness: MaxStaleness) {
+        self.inner.set_max_staleness(max_staleness)
+    }
+
+    #[inline]
+    fn last_write_frame_no(&self) -> Option<FrameNo> {
+        self.inner.last_write_frame_no()
     }
 }
 
@@ -299,12 +398,18 @@ mod test {
         ) -> crate::Result<DescribeResult> {
             unreachable!()
         }
+
+        fn set_txn_timeout(&self, _timeout: Option<Duration>) {}
     }
 
     #[tokio::test]
     async fn throttle_db_creation() {
-        let factory =
-            (|| async { Ok(DummyDb) }).throttled(10, Some(Duration::from_millis(100)), u64::MAX);
+        let factory = (|| async { Ok(DummyDb) }).throttled(
+            10,
+            Some(Duration::from_millis(100)),
+            u64::MAX,
+            None,
+        );
 
         let mut conns = Vec::with_capacity(10);
         for _ in 0..10 {
@@ -317,4 +422,49 @@ mod test {
 
         assert!(factory.create().await.is_ok());
     }
+
+    struct SlowDb;
+
+    #[async_trait::async_trait]
+    impl Connection for SlowDb {
+        async fn execute_program<B: QueryResultBuilder>(
+            &self,
+            _pgm: Program,
+            _auth: Authenticated,
+            builder: B,
+        ) -> crate::Result<(B, State)> {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok((builder, State::Init))
+        }
+
+        async fn describe(
+            &self,
+            _sql: String,
+            _auth: Authenticated,
+        ) -> crate::Result<DescribeResult> {
+            unreachable!()
+        }
+
+        fn set_txn_timeout(&self, _timeout: Option<Duration>) {}
+    }
+
+    #[tokio::test]
+    async fn statement_timeout() {
+        let factory = (|| async { Ok(SlowDb) }).throttled(
+            10,
+            None,
+            u64::MAX,
+            Some(Duration::from_millis(10)),
+        );
+
+        let conn = factory.create().await.unwrap();
+        let res = conn
+            .execute_program(
+                Program::seq(&["select 1"]),
+                Authenticated::Anonymous,
+                IgnoreResult,
+            )
+            .await;
+        assert!(matches!(res, Err(Error::StatementTimeout)));
+    }
 }
@@ -0,0 +1,565 @@
+//! Per-statement statistics and slow query log.
+//!
+//! Statements are grouped by fingerprint: their text, as rendered from the parsed AST, with the
+//! literals and parameters replaced by `?`, and the lists of values of `IN` collapsed to a single
+//! `?`. Statements that only differ by their literals, by the number of values they look up or by
+//! formatting share the same statistics. The fingerprint is computed once, when the statement is
+//! parsed.
+
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use parking_lot::Mutex;
+use serde::Serialize;
+
+use sqlite3_parser::ast::{
+    Cmd, Expr, FromClause, InsertBody, JoinConstraint, Literal, OneSelect, ResultColumn, Select,
+    SelectTable, Set, SortedColumn, Stmt, UnaryOperator, UpsertDo, With,
+};
+
+use crate::query_analysis::Statement;
+
+/// Maximum number of fingerprints that are tracked. When a new fingerprint comes in and the
+/// limit is reached, the least called one is evicted, the least recently called first among
+/// equals.
+const MAX_TRACKED_STATEMENTS: usize = 5000;
+/// Number of entries kept in the slow query log.
+const SLOW_QUERY_LOG_SIZE: usize = 100;
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StatementStats {
+    pub calls: u64,
+    /// Number of calls that failed.
+    pub errors: u64,
+    pub total_time_us: u64,
+    pub max_time_us: u64,
+    pub rows_read: u64,
+    pub rows_written: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SlowQuery {
+    pub fingerprint: String,
+    /// When the statement finished, in milliseconds since the unix epoch.
+    pub timestamp_ms: u64,
+    pub time_us: u64,
+    pub rows_read: u64,
+    pub rows_written: u64,
+    pub failed: bool,
+}
+
+/// The outcome of the execution of a statement.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Execution {
+    pub elapsed: Duration,
+    pub rows_read: u64,
+    pub rows_written: u64,
+    pub failed: bool,
+}
+
+/// Statistics of the statements executed on a database.
+#[derive(Default)]
+pub struct QueryStats {
+    inner: Mutex<QueryStatsInner>,
+}
+
+#[derive(Default)]
+struct QueryStatsInner {
+    statements: HashMap<String, TrackedStatement>,
+    /// The tracked fingerprints, by number of calls and then by last call, in eviction order.
+    eviction_order: BTreeSet<(u64, u64, String)>,
+    /// Incremented on each call, to order the calls.
+    call_seq: u64,
+    slow_queries: VecDeque<SlowQuery>,
+}
+
+struct TrackedStatement {
+    stats: StatementStats,
+    last_call_seq: u64,
+}
+
+impl QueryStats {
+    /// Records an execution of `stmt`. It is also added to the slow query log if it took at least
+    /// `slow_threshold`.
+    pub fn record(&self, stmt: &Statement, exec: Execution, slow_threshold: Option<Duration>) {
+        let fingerprint = &stmt.fingerprint;
+        let time_us = exec.elapsed.as_micros() as u64;
+        let is_slow = slow_threshold.map_or(false, |threshold| exec.elapsed >= threshold);
+        if is_slow {
+            tracing::warn!(
+                "slow query ({:?}, {} rows read, {} rows written): {fingerprint}",
+                exec.elapsed,
+                exec.rows_read,
+                exec.rows_written
+            );
+        }
+
+        let mut inner = self.inner.lock();
+        if !inner.statements.contains_key(fingerprint)
+            && inner.statements.len() >= MAX_TRACKED_STATEMENTS
+        {
+            inner.evict_least_called();
+        }
+
+        let stats = inner.track_call(fingerprint);
+        stats.calls += 1;
+        stats.errors += exec.failed as u64;
+        stats.total_time_us += time_us;
+        stats.max_time_us = stats.max_time_us.max(time_us);
+        stats.rows_read += exec.rows_read;
+        stats.rows_written += exec.rows_written;
+
+        if is_slow {
+            if inner.slow_queries.len() >= SLOW_QUERY_LOG_SIZE {
+                inner.slow_queries.pop_front();
+            }
+            let timestamp_ms = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64;
+            inner.slow_queries.push_back(SlowQuery {
+                fingerprint: fingerprint.clone(),
+                timestamp_ms,
+                time_us,
+                rows_read: exec.rows_read,
+                rows_written: exec.rows_written,
+                failed: exec.failed,
+            });
+        }
+    }
+
+    /// Returns the statistics of each fingerprint, by decreasing total time.
+    pub fn statements(&self) -> Vec<(String, StatementStats)> {
+        let inner = self.inner.lock();
+        let mut statements = inner
+            .statements
+            .iter()
+            .map(|(fingerprint, tracked)| (fingerprint.clone(), tracked.stats.clone()))
+            .collect::<Vec<_>>();
+        statements.sort_by(|(_, a), (_, b)| b.total_time_us.cmp(&a.total_time_us));
+        statements
+    }
+
+    /// Returns the slow query log, oldest first.
+    pub fn slow_queries(&self) -> Vec<SlowQuery> {
+        self.inner.lock().slow_queries.iter().cloned().collect()
+    }
+
+    /// Clears the statistics and the slow query log.
+    pub fn reset(&self) {
+        *self.inner.lock() = QueryStatsInner::default();
+    }
+}
+
+impl QueryStatsInner {
+    /// Moves `fingerprint` to its position in the eviction order after one more call, and returns
+    /// its statistics, to record the call in.
+    fn track_call(&mut self, fingerprint: &str) -> &mut StatementStats {
+        self.call_seq += 1;
+        let tracked = self
+            .statements
+            .entry(fingerprint.to_string())
+            .or_insert_with(|| TrackedStatement {
+                stats: StatementStats::default(),
+                last_call_seq: 0,
+            });
+        if tracked.last_call_seq != 0 {
+            self.eviction_order.remove(&(
+                tracked.stats.calls,
+                tracked.last_call_seq,
+                fingerprint.to_string(),
+            ));
+        }
+        tracked.last_call_seq = self.call_seq;
+        self.eviction_order.insert((
+            tracked.stats.calls + 1,
+            self.call_seq,
+            fingerprint.to_string(),
+        ));
+
+        &mut tracked.stats
+    }
+
+    fn evict_least_called(&mut self) {
+        if let Some((_, _, fingerprint)) = self.eviction_order.pop_first() {
+            self.statements.remove(&fingerprint);
+        }
+    }
+}
+
+/// Returns the fingerprint of a parsed statement.
+pub fn fingerprint(mut cmd: Cmd) -> String {
+    normalize_cmd(&mut cmd);
+    cmd.to_string()
+}
+
+/// Replaces the literals and parameters of a statement by `?`, and collapses its `IN` lists.
+fn normalize_cmd(cmd: &mut Cmd) {
+    let (Cmd::Explain(stmt) | Cmd::ExplainQueryPlan(stmt) | Cmd::Stmt(stmt)) = cmd;
+    match stmt {
+        Stmt::Select(select) => normalize_select(select),
+        Stmt::Insert { with, body, .. } => {
+            normalize_with(with);
+            if let InsertBody::Select(select, upsert) = body {
+                normalize_select(select);
+                if let Some(upsert) = upsert {
+                    if let UpsertDo::Set { sets, where_clause } = &mut upsert.do_clause {
+                        normalize_sets(sets);
+                        normalize_opt(where_clause);
+                    }
+                }
+            }
+        }
+        Stmt::Update {
+            with,
+            sets,
+            from,
+            where_clause,
+            ..
+        } => {
+            normalize_with(with);
+            normalize_sets(sets);
+            if let Some(from) = from {
+                normalize_from(from);
+            }
+            normalize_opt(where_clause);
+        }
+        Stmt::Delete {
+            with, where_clause, ..
+        } => {
+            normalize_with(with);
+            normalize_opt(where_clause);
+        }
+        Stmt::CreateView { select, .. } => normalize_select(select),
+        _ => (),
+    }
+}
+
+fn normalize_with(with: &mut Option<With>) {
+    for cte in with.iter_mut().flat_map(|with| &mut with.ctes) {
+        normalize_select(&mut cte.select);
+    }
+}
+
+fn normalize_select(select: &mut Select) {
+    normalize_with(&mut select.with);
+    normalize_one_select(&mut select.body.select);
+    for compound in select.body.compounds.iter_mut().flatten() {
+        normalize_one_select(&mut compound.select);
+    }
+    normalize_sorted(&mut select.order_by);
+    if let Some(limit) = &mut select.limit {
+        normalize_expr(&mut limit.expr);
+        normalize_opt(&mut limit.offset);
+    }
+}
+
+fn normalize_one_select(select: &mut OneSelect) {
+    match select {
+        OneSelect::Select {
+            columns,
+            from,
+            where_clause,
+            group_by,
+            ..
+        } => {
+            for column in columns {
+                if let ResultColumn::Expr(expr, _) = column {
+                    normalize_expr(expr);
+                }
+            }
+            if let Some(from) = from {
+                normalize_from(from);
+            }
+            normalize_opt(where_clause);
+            if let Some(group_by) = group_by {
+                normalize_exprs(&mut group_by.exprs);
+                if let Some(having) = &mut group_by.having {
+                    normalize_expr(having);
+                }
+            }
+        }
+        OneSelect::Values(rows) => {
+            for row in rows {
+                normalize_exprs(row);
+            }
+        }
+    }
+}
+
+fn normalize_from(from: &mut FromClause) {
+    if let Some(table) = &mut from.select {
+        normalize_table(table);
+    }
+    for join in from.joins.iter_mut().flatten() {
+        normalize_table(&mut join.table);
+        if let Some(JoinConstraint::On(expr)) = &mut join.constraint {
+            normalize_expr(expr);
+        }
+    }
+}
+
+fn normalize_table(table: &mut SelectTable) {
+    match table {
+        SelectTable::TableCall(_, Some(args), _) => normalize_exprs(args),
+        SelectTable::Select(select, _) => normalize_select(select),
+        SelectTable::Sub(from, _) => normalize_from(from),
+        _ => (),
+    }
+}
+
+fn normalize_sets(sets: &mut [Set]) {
+    for set in sets {
+        normalize_expr(&mut set.expr);
+    }
+}
+
+fn normalize_sorted(columns: &mut Option<Vec<SortedColumn>>) {
+    for column in columns.iter_mut().flatten() {
+        normalize_expr(&mut column.expr);
+    }
+}
+
+fn normalize_opt(expr: &mut Option<Expr>) {
+    if let Some(expr) = expr {
+        normalize_expr(expr);
+    }
+}
+
+fn normalize_exprs(exprs: &mut [Expr]) {
+    exprs.iter_mut().for_each(normalize_expr);
+}
+
+/// Replaces the literals and parameters of `expr` by `?`, and its `IN` lists of values by a single
+/// `?`.
+fn normalize_expr(expr: &mut Expr) {
+    if is_value(expr) {
+        *expr = placeholder();
+        return;
+    }
+    match expr {
+        Expr::InList { lhs, rhs, .. } => {
+            normalize_expr(lhs);
+            if let Some(list) = rhs {
+                if list.iter().all(is_value) {
+                    *list = vec![placeholder()];
+                } else {
+                    normalize_exprs(list);
+                }
+            }
+        }
+        Expr::Between {
+            lhs, start, end, ..
+        } => {
+            normalize_expr(lhs);
+            normalize_expr(start);
+            normalize_expr(end);
+        }
+        Expr::Binary(lhs, _, rhs) => {
+            normalize_expr(lhs);
+            normalize_expr(rhs);
+        }
+        Expr::Case {
+            base,
+            when_then_pairs,
+            else_expr,
+        } => {
+            if let Some(base) = base {
+                normalize_expr(base);
+            }
+            for (when, then) in when_then_pairs {
+                normalize_expr(when);
+                normalize_expr(then);
+            }
+            if let Some(else_expr) = else_expr {
+                normalize_expr(else_expr);
+            }
+        }
+        Expr::Cast { expr, .. }
+        | Expr::Collate(expr, _)
+        | Expr::IsNull(expr)
+        | Expr::NotNull(expr)
+        | Expr::Unary(_, expr) => normalize_expr(expr),
+        Expr::Exists(select) | Expr::Subquery(select) => normalize_select(select),
+        Expr::FunctionCall {
+            args: Some(args), ..
+        }
+        | Expr::Parenthesized(args) => normalize_exprs(args),
+        Expr::InSelect { lhs, rhs, .. } => {
+            normalize_expr(lhs);
+            normalize_select(rhs);
+        }
+        Expr::InTable { lhs, args, .. } => {
+            normalize_expr(lhs);
+            if let Some(args) = args {
+                normalize_exprs(args);
+            }
+        }
+        Expr::Like {
+            lhs, rhs, escape, ..
+        } => {
+            normalize_expr(lhs);
+            normalize_expr(rhs);
+            if let Some(escape) = escape {
+                normalize_expr(escape);
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Whether `expr` is a literal or a parameter, possibly signed. `NULL` and the keywords such as
+/// `CURRENT_TIME` are not values, they are kept in the fingerprint.
+fn is_value(expr: &Expr) -> bool {
+    match expr {
+        Expr::Literal(Literal::Numeric(_) | Literal::String(_) | Literal::Blob(_))
+        | Expr::Variable(_) => true,
+        Expr::Unary(UnaryOperator::Negative | UnaryOperator::Positive, expr) => is_value(expr),
+        _ => false,
+    }
+}
+
+/// An anonymous parameter, which is rendered as `?`.
+fn placeholder() -> Expr {
+    Expr::Variable(String::new())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    fn fingerprint_sql(sql: &str) -> String {
+        Statement::parse(sql).next().unwrap().unwrap().fingerprint
+    }
+
+    #[test]
+    fn fingerprint_strips_literals() {
+        let a = fingerprint_sql("select * from t1 where a = 1 and b = 'it''s' and c = x'00ff'");
+        let b = fingerprint_sql("SELECT *   FROM t1 WHERE a = 42.5e-3 AND b = '' AND c = X'01'");
+        assert_eq!(a, b);
+        assert!(a.contains("t1"));
+        assert!(!a.contains("it"));
+        assert!(!a.contains("42"));
+        assert!(!a.contains("ff"));
+    }
+
+    #[test]
+    fn fingerprint_strips_parameters() {
+        let a = fingerprint_sql("insert into t (a, b) values (?, :b)");
+        let b = fingerprint_sql("insert into t (a, b) values (?2, @other)");
+        assert_eq!(a, b);
+        assert!(!a.contains(":b"));
+    }
+
+    #[test]
+    fn fingerprint_collapses_in_lists() {
+        let a = fingerprint_sql(
+            "select * from t where id in (1, -2, 3) and x in (select y from u where z in (?, ?))",
+        );
+        let b = fingerprint_sql(
+            "select * from t where id in (7) and x in (select y from u where z in (:z))",
+        );
+        assert_eq!(a, b);
+
+        // lists of columns are kept
+        let c = fingerprint_sql("select * from t where id in (a, b)");
+        assert_ne!(c, fingerprint_sql("select * from t where id in (a)"));
+    }
+
+    #[test]
+    fn fingerprint_strips_signed_literals() {
+        let a = fingerprint_sql("select * from t where a > -1 limit 10 offset +5");
+        assert_eq!(
+            a,
+            fingerprint_sql("select * from t where a > 2 limit ? offset 0")
+        );
+        assert!(!a.contains('-'));
+        assert!(!a.contains('+'));
+    }
+
+    #[test]
+    fn fingerprint_keeps_identifiers() {
+        let a = fingerprint_sql(r#"select "col 1" from t2"#);
+        assert!(a.contains(r#""col 1""#));
+        assert!(a.contains("t2"));
+        assert_ne!(a, fingerprint_sql("select b from t2"));
+    }
+
+    #[test]
+    fn record_stats() {
+        let stats = QueryStats::default();
+        let slow = Statement::parse("select * from slow")
+            .next()
+            .unwrap()
+            .unwrap();
+        let fast = Statement::parse("select * from fast")
+            .next()
+            .unwrap()
+            .unwrap();
+        let threshold = Some(Duration::from_millis(100));
+
+        stats.record(
+            &slow,
+            Execution {
+                elapsed: Duration::from_millis(200),
+                rows_read: 10,
+                ..Default::default()
+            },
+            threshold,
+        );
+        stats.record(
+            &fast,
+            Execution {
+                elapsed: Duration::from_millis(1),
+                failed: true,
+                ..Default::default()
+            },
+            threshold,
+        );
+
+        let statements = stats.statements();
+        assert_eq!(statements.len(), 2);
+        assert_eq!(statements[0].0, slow.fingerprint);
+        assert_eq!(statements[0].1.rows_read, 10);
+        assert_eq!(statements[1].1.errors, 1);
+
+        let slow_queries = stats.slow_queries();
+        assert_eq!(slow_queries.len(), 1);
+        assert_eq!(slow_queries[0].fingerprint, slow.fingerprint);
+
+        stats.reset();
+        assert!(stats.statements().is_empty());
+        assert!(stats.slow_queries().is_empty());
+    }
+
+    #[test]
+    fn evict_least_called() {
+        let stats = QueryStats::default();
+        let record = |sql: &str| {
+            let stmt = Statement::parse(sql).next().unwrap().unwrap();
+            stats.record(&stmt, Execution::default(), None);
+        };
+        for i in 0..MAX_TRACKED_STATEMENTS {
+            record(&format!("select * from t{i}"));
+        }
+        record("select * from t0");
+
+        // t1 and t2 are the least recently called among the least called statements
+        record("select * from new1");
+        record("select * from new2");
+        let fingerprints = stats
+            .statements()
+            .into_iter()
+            .map(|(fingerprint, _)| fingerprint)
+            .collect::<HashSet<_>>();
+        assert_eq!(fingerprints.len(), MAX_TRACKED_STATEMENTS);
+        assert!(fingerprints.contains(&fingerprint_sql("select * from t0")));
+        assert!(!fingerprints.contains(&fingerprint_sql("select * from t1")));
+        assert!(!fingerprints.contains(&fingerprint_sql("select * from t2")));
+        assert!(fingerprints.contains(&fingerprint_sql("select * from t3")));
+        assert!(fingerprints.contains(&fingerprint_sql("select * from new1")));
+        assert!(fingerprints.contains(&fingerprint_sql("select * from new2")));
+    }
+}
@@ -0,0 +1,183 @@
+//! Tracking of the tables modified by committed transactions.
+//!
+//! Every committed write bumps a sequence number, and records that sequence number against each
+//! table it modified. Readers that depend on a set of tables (such as live queries) remember the
+//! sequence number they last observed, and can then cheaply tell whether they need to refresh.
+//!
+//! When a row listener is installed and captures rows, the individual rows modified by each
+//! committed transaction are also forwarded to the listener, once their commit succeeded.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+/// The maximum number of rows captured for a single transaction. Changes past that limit are
+/// dropped, and the batch is flagged as truncated.
+pub const MAX_CAPTURED_ROWS: usize = 10_000;
+
+#[derive(Default)]
+pub struct TableChanges {
+    inner: Mutex<Inner>,
+    row_listener: Option<Arc<dyn RowListener>>,
+}
+
+/// Receives the rows modified by committed transactions.
+pub trait RowListener: Send + Sync {
+    /// Returns whether connections should capture the rows they modify.
+    fn captures_rows(&self) -> bool;
+
+    /// Called with the rows modified by a transaction once it committed, so the listener can
+    /// persist them before the transaction is acknowledged. The write lock of the database is
+    /// released by then, so that other writers don't wait for the listener.
+    fn record_rows(&self, rows: CapturedRows);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeOp {
+    Insert,
+    Update,
+    Delete,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RowChange {
+    pub table: String,
+    pub op: ChangeOp,
+    pub rowid: i64,
+}
+
+/// The rows modified by a committed transaction.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CapturedRows {
+    pub rows: Vec<RowChange>,
+    /// Set if the transaction modified more than [`MAX_CAPTURED_ROWS`] rows.
+    pub truncated: bool,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Sequence number of the last recorded change.
+    seq: u64,
+    /// Sequence number of the last change to each table, by lowercased table name.
+    tables: HashMap<String, u64>,
+    /// Sequence number of the last schema change. Schema changes may affect any query, so they
+    /// are treated as a change to every table.
+    schema_seq: u64,
+}
+
+impl TableChanges {
+    /// Creates a tracker that forwards the captured rows of each committed transaction to
+    /// `listener`.
+    pub fn with_row_listener(listener: Arc<dyn RowListener>) -> Self {
+        Self {
+            row_listener: Some(listener),
+            ..Default::default()
+        }
+    }
+
+    /// Returns whether connections should capture the rows they modify.
+    pub fn captures_rows(&self) -> bool {
+        self.row_listener
+            .as_ref()
+            .map_or(false, |listener| listener.captures_rows())
+    }
+
+    /// Forwards the rows modified by a committed transaction to the row listener.
+    pub fn record_rows(&self, rows: CapturedRows) {
+        if let Some(ref listener) = self.row_listener {
+            listener.record_rows(rows);
+        }
+    }
+
+    /// Records a committed transaction that modified `tables`, and possibly the schema.
+    pub fn record(&self, tables: impl IntoIterator<Item = String>, schema_changed: bool) {
+        let mut inner = self.inner.lock();
+        inner.seq += 1;
+        let seq = inner.seq;
+        for table in tables {
+            inner.tables.insert(table.to_lowercase(), seq);
+        }
+        if schema_changed {
+            inner.schema_seq = seq;
+        }
+    }
+
+    /// Returns the sequence number of the last recorded change.
+    pub fn current(&self) -> u64 {
+        self.inner.lock().seq
+    }
+
+    /// Returns whether any of `tables` (lowercased) was modified after the change `seq`.
+    pub fn changed_since(&self, tables: &HashSet<String>, seq: u64) -> bool {
+        let inner = self.inner.lock();
+        if inner.schema_seq > seq {
+            return true;
+        }
+        tables
+            .iter()
+            .any(|table| inner.tables.get(table).map_or(false, |s| *s > seq))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn detect_changes() {
+        let changes = TableChanges::default();
+        let deps: HashSet<String> = ["users".to_string()].into();
+        let seq = changes.current();
+
+        changes.record(["Orders".to_string()], false);
+        assert!(!changes.changed_since(&deps, seq));
+
+        changes.record(["Users".to_string()], false);
+        assert!(changes.changed_since(&deps, seq));
+
+        let seq = changes.current();
+        assert!(!changes.changed_since(&deps, seq));
+
+        changes.record([], true);
+        assert!(changes.changed_since(&deps, seq));
+    }
+
+    #[derive(Default)]
+    struct Recorder {
+        rows: Mutex<Vec<CapturedRows>>,
+    }
+
+    impl RowListener for Recorder {
+        fn captures_rows(&self) -> bool {
+            true
+        }
+
+        fn record_rows(&self, rows: CapturedRows) {
+            self.rows.lock().push(rows);
+        }
+    }
+
+    #[test]
+    fn row_capture_requires_listener() {
+        let changes = TableChanges::default();
+        assert!(!changes.captures_rows());
+
+        let recorder = Arc::new(Recorder::default());
+        let changes = TableChanges::with_row_listener(recorder.clone());
+        assert!(changes.captures_rows());
+
+        let rows = CapturedRows {
+            rows: vec![RowChange {
+                table: "users".into(),
+                op: ChangeOp::Insert,
+                rowid: 1,
+            }],
+            truncated: false,
+        };
+        changes.record_rows(rows.clone());
+        assert_eq!(*recorder.rows.lock(), [rows]);
+    }
+}
@@ -1,5 +1,6 @@
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
 use bytes::Bytes;
 use parking_lot::Mutex as PMutex;
@@ -7,6 +8,7 @@ use rusqlite::types::ValueRef;
 use sqld_libsql_bindings::wal_hook::TRANSPARENT_METHODS;
 use tokio::sync::{watch, Mutex};
 use tonic::transport::Channel;
+use tracing::Instrument as _;
 use uuid::Uuid;
 
 use crate::auth::{Authenticated, Authorized};
@@ -16,16 +18,19 @@ use crate::query_analysis::State;
 use crate::query_result_builder::{
     Column, QueryBuilderConfig, QueryResultBuilder, QueryResultBuilderError,
 };
-use crate::replication::FrameNo;
+use crate::replication::replica::ReplicationLag;
+use crate::replication::{current_frame_no, FrameNo};
 use crate::rpc::proxy::rpc::proxy_client::ProxyClient;
 use crate::rpc::proxy::rpc::query_result::RowResult;
 use crate::rpc::proxy::rpc::{DisconnectMessage, ExecuteResults};
 use crate::stats::Stats;
+use crate::telemetry;
 use crate::Result;
 
-use super::config::DatabaseConfigStore;
+use super::config::{DatabaseConfigStore, MaxStaleness};
 use super::libsql::LibSqlConnection;
 use super::program::DescribeResult;
+use super::query_stats::QueryStats;
 use super::Connection;
 use super::{MakeConnection, Program};
 
@@ -36,7 +41,9 @@ pub struct MakeWriteProxyConnection {
     extensions: Vec<PathBuf>,
     stats: Stats,
     config_store: Arc<DatabaseConfigStore>,
+    query_stats: Arc<QueryStats>,
     applied_frame_no_receiver: watch::Receiver<FrameNo>,
+    lag: Arc<ReplicationLag>,
     max_response_size: u64,
     max_total_response_size: u64,
     namespace: Bytes,
@@ -51,7 +58,9 @@ impl MakeWriteProxyConnection {
         uri: tonic::transport::Uri,
         stats: Stats,
         config_store: Arc<DatabaseConfigStore>,
+        query_stats: Arc<QueryStats>,
         applied_frame_no_receiver: watch::Receiver<FrameNo>,
+        lag: Arc<ReplicationLag>,
         max_response_size: u64,
         max_total_response_size: u64,
         namespace: Bytes,
@@ -63,7 +72,9 @@ impl MakeWriteProxyConnection {
             extensions,
             stats,
             config_store,
+            query_stats,
             applied_frame_no_receiver,
+            lag,
             max_response_size,
             max_total_response_size,
             namespace,
@@ -81,7 +92,9 @@ impl MakeConnection for MakeWriteProxyConnection {
             self.extensions.clone(),
             self.stats.clone(),
             self.config_store.clone(),
+            self.query_stats.clone(),
             self.applied_frame_no_receiver.clone(),
+            self.lag.clone(),
             QueryBuilderConfig {
                 max_size: Some(self.max_response_size),
                 max_total_size: Some(self.max_total_response_size),
@@ -104,10 +117,16 @@ pub struct WriteProxyConnection {
     last_write_frame_no: PMutex<FrameNo>,
     /// Notifier from the repliator of the currently applied frameno
     applied_frame_no_receiver: watch::Receiver<FrameNo>,
+    lag: Arc<ReplicationLag>,
+    config_store: Arc<DatabaseConfigStore>,
     builder_config: QueryBuilderConfig,
     stats: Stats,
     /// bytes representing the namespace name
     namespace: Bytes,
+    /// Transaction timeout requested by the client, forwarded to the primary with each program.
+    txn_timeout: PMutex<Option<Duration>>,
+    /// Staleness bound requested by the client, taking precedence over the database config.
+    max_staleness: PMutex<MaxStaleness>,
 }
 
 fn execute_results_to_builder<B: QueryResultBuilder>(
@@ -163,7 +182,9 @@ impl WriteProxyConnection {
         extensions: Vec<PathBuf>,
         stats: Stats,
         config_store: Arc<DatabaseConfigStore>,
+        query_stats: Arc<QueryStats>,
         applied_frame_no_receiver: watch::Receiver<FrameNo>,
+        lag: Arc<ReplicationLag>,
         builder_config: QueryBuilderConfig,
         namespace: Bytes,
     ) -> Result<Self> {
@@ -173,8 +194,10 @@ impl WriteProxyConnection {
             &TRANSPARENT_METHODS,
             (),
             stats.clone(),
-            config_store,
+            config_store.clone(),
+            query_stats,
             builder_config,
+            None,
         )
         .await?;
         Ok(Self {
@@ -184,9 +207,13 @@ impl WriteProxyConnection {
             client_id: Uuid::new_v4(),
             last_write_frame_no: PMutex::new(FrameNo::MAX),
             applied_frame_no_receiver,
+            lag,
+            config_store,
             builder_config,
             stats,
             namespace,
+            txn_timeout: PMutex::new(None),
+            max_staleness: PMutex::new(MaxStaleness::default()),
         })
     }
 
@@ -209,8 +236,12 @@ impl WriteProxyConnection {
             client_id: self.client_id.to_string(),
             pgm: Some(pgm.into()),
             authorized,
+            txn_timeout_ms: self.txn_timeout.lock().map(|t| t.as_millis() as u64),
         };
-        match client.execute(req).await {
+        let span = tracing::info_span!("proxy_execute");
+        let mut req = tonic::Request::new(req);
+        telemetry::inject_context(&span, &mut req);
+        match client.execute(req).instrument(span).await {
             Ok(r) => {
                 let execute_result = r.into_inner();
                 *state = execute_result.state().into();
@@ -225,6 +256,9 @@ impl WriteProxyConnection {
                 // Set state to invalid, so next call is sent to remote, and we have a chance
                 // to recover state.
                 *state = State::Invalid;
+                if e.code() == tonic::Code::DeadlineExceeded {
+                    return Err(Error::StatementTimeout);
+                }
                 Err(Error::RpcQueryExecutionError(e))
             }
         }
@@ -237,6 +271,17 @@ impl WriteProxyConnection {
         }
     }
 
+    /// Returns whether the replica is too far behind the primary to serve reads, according to the
+    /// bound requested by the client or else the one of the database config.
+    fn is_too_stale(&self) -> bool {
+        let max_staleness = self
+            .max_staleness
+            .lock()
+            .or(self.config_store.get().max_staleness());
+        let applied = current_frame_no(&self.applied_frame_no_receiver);
+        self.lag.exceeds(max_staleness, applied)
+    }
+
     /// wait for the replicator to have caught up with our current write frame_no
     async fn wait_replication_sync(&self) -> Result<()> {
         let current_frame_no = *self.last_write_frame_no.lock();
@@ -269,8 +314,10 @@ impl Connection for WriteProxyConnection {
         builder: B,
     ) -> Result<(B, State)> {
         let mut state = self.state.lock().await;
-        if *state == State::Init && pgm.is_read_only() {
-            self.wait_replication_sync().await?;
+        if *state == State::Init && pgm.is_read_only() && !self.is_too_stale() {
+            self.wait_replication_sync()
+                .instrument(tracing::info_span!("wait_replication_sync"))
+                .await?;
             // We know that this program won't perform any writes. We attempt to run it on the
             // replica. If it leaves an open transaction, then this program is an interactive
             // transaction, so we rollback the replica, and execute again on the primary.
@@ -290,9 +337,25 @@ impl Connection for WriteProxyConnection {
     }
 
     async fn describe(&self, sql: String, auth: Authenticated) -> Result<DescribeResult> {
-        self.wait_replication_sync().await?;
+        self.wait_replication_sync()
+            .instrument(tracing::info_span!("wait_replication_sync"))
+            .await?;
         self.read_db.describe(sql, auth).await
     }
+
+    fn set_txn_timeout(&self, timeout: Option<Duration>) {
+        *self.txn_timeout.lock() = timeout;
+        self.read_db.set_txn_timeout(timeout);
+    }
+
+    fn set_max_staleness(&self, max_staleness: MaxStaleness) {
+        *self.max_staleness.lock() = max_staleness;
+    }
+
+    fn last_write_frame_no(&self) -> Option<FrameNo> {
+        let frame_no = *self.last_write_frame_no.lock();
+        (frame_no != FrameNo::MAX).then_some(frame_no)
+    }
 }
 
 impl Drop for WriteProxyConnection {
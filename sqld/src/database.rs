@@ -1,20 +1,120 @@
 use std::sync::Arc;
 
+use tokio::sync::watch;
+
+use crate::connection::config::DatabaseConfigStore;
 use crate::connection::libsql::LibSqlConnection;
+use crate::connection::query_stats::QueryStats;
+use crate::connection::table_changes::TableChanges;
 use crate::connection::write_proxy::WriteProxyConnection;
 use crate::connection::{Connection, MakeConnection, TrackedConnection};
-use crate::replication::ReplicationLogger;
+use crate::metrics::DatabaseMetrics;
+use crate::replication::primary::replicas::ConnectedReplicas;
+use crate::replication::replica::{ReplicaLog, ReplicationLag};
+use crate::replication::{
+    current_frame_no, FrameNo, ReplicaStatus, ReplicationLogger, ReplicationStatus,
+};
+use crate::snapshot_sink::SnapshotSinks;
+use crate::webhook::Webhooks;
 
 pub trait Database: Sync + Send + 'static {
     /// The connection type of the database
     type Connection: Connection;
 
     fn connection_maker(&self) -> Arc<dyn MakeConnection<Connection = Self::Connection>>;
+
+    /// Returns a receiver that is notified with the last frame_no every time new frames are
+    /// committed (on a primary) or applied (on a replica).
+    fn frame_notifier(&self) -> watch::Receiver<FrameNo>;
+
+    /// Returns the config store of this database.
+    fn config_store(&self) -> Arc<DatabaseConfigStore>;
+
+    /// Returns the statistics of the statements executed on this database.
+    fn query_stats(&self) -> Arc<QueryStats>;
+
+    /// Reads the current state of the database, for the metrics endpoint.
+    fn metrics(&self) -> DatabaseMetrics;
+
+    /// Reports how far behind its primary this database is, or how far behind it its replicas
+    /// are.
+    fn replication_status(&self) -> ReplicationStatus;
+
+    /// Returns whether this replica is recovering from a database that can't replicate from the
+    /// primary anymore, in which case its reads are stale.
+    fn is_recovering(&self) -> bool {
+        false
+    }
+
+    /// Returns the tracker of tables modified by committed writes, if this database keeps one.
+    fn table_changes(&self) -> Option<Arc<TableChanges>> {
+        None
+    }
+
+    /// Returns the webhooks of this database, if it supports them.
+    fn webhooks(&self) -> Option<Arc<Webhooks>> {
+        None
+    }
+
+    /// Returns the sinks that the snapshots of this database are delivered to, if it has any.
+    fn snapshot_sinks(&self) -> Option<Arc<SnapshotSinks>> {
+        None
+    }
+
+    /// Returns the replication log served to the replicas of this database, if it serves them.
+    fn log_source(&self) -> Option<LogSource> {
+        None
+    }
+}
+
+/// A replication log that replicas can replicate from.
+pub struct LogSource {
+    pub logger: Arc<ReplicationLogger>,
+    /// Generation of the log, as reported to replicas. A replica reports the generation of its
+    /// primary.
+    pub generation_id: String,
+    pub generation_start_index: FrameNo,
+    pub replicas: Arc<ConnectedReplicas>,
+}
+
+fn replica_statuses(
+    logger: &ReplicationLogger,
+    replicas: &ConnectedReplicas,
+) -> Vec<ReplicaStatus> {
+    let frame_no = *logger.new_frame_notifier.borrow();
+    replicas
+        .list()
+        .into_iter()
+        .map(|replica| ReplicaStatus {
+            lag_frames: replica
+                .next_offset
+                .map(|offset| frame_no.saturating_sub(offset)),
+            replica,
+        })
+        .collect()
 }
 
 pub struct ReplicaDatabase {
     pub connection_maker:
         Arc<dyn MakeConnection<Connection = TrackedConnection<WriteProxyConnection>>>,
+    pub applied_frame_no_receiver: watch::Receiver<FrameNo>,
+    pub lag: Arc<ReplicationLag>,
+    pub config_store: Arc<DatabaseConfigStore>,
+    pub query_stats: Arc<QueryStats>,
+    /// The log of the applied frames, when this replica serves other replicas.
+    pub replica_log: Option<Arc<ReplicaLog>>,
+    pub replicas: Arc<ConnectedReplicas>,
+    /// The tables modified by the transactions replicated from the primary.
+    pub table_changes: Arc<TableChanges>,
+}
+
+impl ReplicaDatabase {
+    fn replica_statuses(&self) -> Vec<ReplicaStatus> {
+        match self.replica_log.as_ref().and_then(|log| log.logger()) {
+            Some(logger) => replica_statuses(&logger, &self.replicas),
+            None => Vec::new(),
+        }
+    }
 }
 
 impl Database for ReplicaDatabase {
@@ -23,11 +123,89 @@ impl Database for ReplicaDatabase {
     fn connection_maker(&self) -> Arc<dyn MakeConnection<Connection = Self::Connection>> {
         self.connection_maker.clone()
     }
+
+    fn frame_notifier(&self) -> watch::Receiver<FrameNo> {
+        self.applied_frame_no_receiver.clone()
+    }
+
+    fn config_store(&self) -> Arc<DatabaseConfigStore> {
+        self.config_store.clone()
+    }
+
+    fn query_stats(&self) -> Arc<QueryStats> {
+        self.query_stats.clone()
+    }
+
+    fn metrics(&self) -> DatabaseMetrics {
+        let usage = self.connection_maker.usage();
+        let applied = current_frame_no(&self.applied_frame_no_receiver);
+        let logger = self.replica_log.as_ref().and_then(|log| log.logger());
+        let replicas = self.replica_statuses();
+        DatabaseMetrics {
+            connection_permits_available: usage.map(|u| u.available_permits),
+            connection_waiters: usage.map(|u| u.waiters),
+            replication_log_frames: logger.as_ref().map(|logger| logger.frame_count()),
+            replication_log_compactions: logger.as_ref().map(|logger| logger.compaction_count()),
+            connected_replicas: self.replica_log.as_ref().map(|_| replicas.len()),
+            max_replica_lag_frames: replicas.iter().filter_map(|r| r.lag_frames).max(),
+            applied_frame_no: applied,
+            primary_frame_no: self.lag.primary_next_frame_no(),
+            replica_lag_frames: self.lag.frames_behind(applied),
+            replica_lag_seconds: self.lag.time_behind(applied).map(|t| t.as_secs_f64()),
+            ..Default::default()
+        }
+    }
+
+    fn replication_status(&self) -> ReplicationStatus {
+        let applied = current_frame_no(&self.applied_frame_no_receiver);
+        ReplicationStatus::Replica {
+            applied_frame_no: applied,
+            generation_id: self.lag.generation_id(),
+            primary_frame_no: self.lag.primary_next_frame_no(),
+            lag_frames: self.lag.frames_behind(applied),
+            lag_ms: self.lag.time_behind(applied).map(|t| t.as_millis() as u64),
+            recovering: self.lag.is_recovering(),
+            invalid_frame: self.lag.invalid_frame(),
+            replicas: self.replica_statuses(),
+        }
+    }
+
+    fn is_recovering(&self) -> bool {
+        self.lag.is_recovering()
+    }
+
+    fn table_changes(&self) -> Option<Arc<TableChanges>> {
+        Some(self.table_changes.clone())
+    }
+
+    fn log_source(&self) -> Option<LogSource> {
+        let logger = self.replica_log.as_ref()?.logger()?;
+        let (generation_id, generation_start_index) = self.lag.generation()?;
+        Some(LogSource {
+            logger,
+            generation_id,
+            generation_start_index,
+            replicas: self.replicas.clone(),
+        })
+    }
 }
 
 pub struct PrimaryDatabase {
     pub logger: Arc<ReplicationLogger>,
     pub connection_maker: Arc<dyn MakeConnection<Connection = TrackedConnection<LibSqlConnection>>>,
+    pub table_changes: Arc<TableChanges>,
+    pub webhooks: Arc<Webhooks>,
+    pub snapshot_sinks: Arc<SnapshotSinks>,
+    pub config_store: Arc<DatabaseConfigStore>,
+    pub query_stats: Arc<QueryStats>,
+    pub bottomless_replicator: Option<Arc<std::sync::Mutex<bottomless::replicator::Replicator>>>,
+    pub replicas: Arc<ConnectedReplicas>,
+}
+
+impl PrimaryDatabase {
+    fn replica_statuses(&self) -> Vec<ReplicaStatus> {
+        replica_statuses(&self.logger, &self.replicas)
+    }
 }
 
 impl Database for PrimaryDatabase {
@@ -36,4 +214,64 @@ impl Database for PrimaryDatabase {
     fn connection_maker(&self) -> Arc<dyn MakeConnection<Connection = Self::Connection>> {
         self.connection_maker.clone()
     }
+
+    fn frame_notifier(&self) -> watch::Receiver<FrameNo> {
+        self.logger.committed_frame_notifier.subscribe()
+    }
+
+    fn config_store(&self) -> Arc<DatabaseConfigStore> {
+        self.config_store.clone()
+    }
+
+    fn query_stats(&self) -> Arc<QueryStats> {
+        self.query_stats.clone()
+    }
+
+    fn metrics(&self) -> DatabaseMetrics {
+        let usage = self.connection_maker.usage();
+        let bottomless_pending_frames = self
+            .bottomless_replicator
+            .as_ref()
+            .map(|replicator| replicator.lock().unwrap().pending_frames() as u64);
+        let replicas = self.replica_statuses();
+        DatabaseMetrics {
+            connection_permits_available: usage.map(|u| u.available_permits),
+            connection_waiters: usage.map(|u| u.waiters),
+            replication_log_frames: Some(self.logger.frame_count()),
+            replication_log_compactions: Some(self.logger.compaction_count()),
+            bottomless_pending_frames,
+            connected_replicas: Some(replicas.len()),
+            max_replica_lag_frames: replicas.iter().filter_map(|r| r.lag_frames).max(),
+            ..Default::default()
+        }
+    }
+
+    fn replication_status(&self) -> ReplicationStatus {
+        ReplicationStatus::Primary {
+            frame_no: *self.logger.new_frame_notifier.borrow(),
+            generation_id: self.logger.generation.id.to_string(),
+            replicas: self.replica_statuses(),
+        }
+    }
+
+    fn table_changes(&self) -> Option<Arc<TableChanges>> {
+        Some(self.table_changes.clone())
+    }
+
+    fn webhooks(&self) -> Option<Arc<Webhooks>> {
+        Some(self.webhooks.clone())
+    }
+
+    fn snapshot_sinks(&self) -> Option<Arc<SnapshotSinks>> {
+        Some(self.snapshot_sinks.clone())
+    }
+
+    fn log_source(&self) -> Option<LogSource> {
+        Some(LogSource {
+            logger: self.logger.clone(),
+            generation_id: self.logger.generation.id.to_string(),
+            generation_start_index: self.logger.generation.start_index,
+            replicas: self.replicas.clone(),
+        })
+    }
 }
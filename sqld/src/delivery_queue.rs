@@ -0,0 +1,461 @@
+//! A durable queue of deliveries to external services, shared by the webhooks and the snapshot
+//! sinks.
+//!
+//! Every delivery is stored as a JSON file in the `queue` directory, named after its id, so that
+//! the queue survives restarts. The deliveries to the same destination are made in order, by a
+//! task of their own, so that a slow or unreachable destination doesn't hold up the others, and
+//! every attempt is bounded by the timeout of the retry policy. Failed deliveries are retried with
+//! an exponential backoff, and moved to a directory of dead letters after too many attempts.
+
+use std::collections::{HashMap, HashSet};
+use std::io::Write as _;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use parking_lot::Mutex;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use tokio::task::JoinSet;
+
+const QUEUE_DIR: &str = "queue";
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Number of attempts after which a delivery is moved to the dead letters.
+    pub max_attempts: u32,
+    pub base_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Timeout of a single delivery attempt.
+    pub request_timeout: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 10,
+            base_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(300),
+            request_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn backoff(&self, attempts: u32) -> Duration {
+        let factor = 1u32
+            .checked_shl(attempts.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        self.base_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
+
+/// A pending (or dead) delivery of an item.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Delivery<T> {
+    pub id: u64,
+    #[serde(flatten)]
+    pub item: T,
+    pub attempts: u32,
+    /// Unix timestamp in milliseconds before which the delivery should not be attempted again.
+    pub next_attempt_ms: u64,
+    pub last_error: Option<String>,
+}
+
+/// Delivers the items of a queue.
+#[async_trait::async_trait]
+pub trait Deliver<T>: Send + Sync + 'static {
+    /// Returns the destination of an item. The deliveries to the same destination are made in
+    /// order.
+    fn destination(&self, item: &T) -> String;
+
+    async fn deliver(&self, delivery: &Delivery<T>) -> anyhow::Result<()>;
+
+    /// Called after deliveries left the queue, either delivered or dead.
+    async fn dequeued(&self) {}
+}
+
+pub struct DeliveryQueue<T> {
+    dir: PathBuf,
+    dead_dir: &'static str,
+    retry: RetryPolicy,
+    next_id: AtomicU64,
+    queue_changed: Notify,
+    /// The destinations that a task is currently delivering to.
+    busy: Mutex<HashSet<String>>,
+    _item: PhantomData<fn() -> T>,
+}
+
+impl<T> DeliveryQueue<T>
+where
+    T: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    /// Opens the queue stored in `dir`, with its dead letters in `dir/<dead_dir>`.
+    pub fn open(dir: &Path, dead_dir: &'static str, retry: RetryPolicy) -> anyhow::Result<Self> {
+        let mut last_id = 0;
+        for sub in [QUEUE_DIR, dead_dir] {
+            std::fs::create_dir_all(dir.join(sub))?;
+            for entry in std::fs::read_dir(dir.join(sub))? {
+                if let Some(id) = parse_delivery_name(&entry?.path()) {
+                    last_id = last_id.max(id);
+                }
+            }
+        }
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            dead_dir,
+            retry,
+            next_id: AtomicU64::new(last_id + 1),
+            queue_changed: Notify::new(),
+            busy: Mutex::new(HashSet::new()),
+            _item: PhantomData,
+        })
+    }
+
+    /// Persists the delivery of `item`, and returns its id. The delivery is attempted by `run`.
+    pub fn push(&self, item: T) -> anyhow::Result<u64> {
+        let delivery = Delivery {
+            id: self.next_id.fetch_add(1, Ordering::SeqCst),
+            item,
+            attempts: 0,
+            next_attempt_ms: 0,
+            last_error: None,
+        };
+        write_delivery(&self.dir.join(QUEUE_DIR), &delivery)?;
+        self.queue_changed.notify_one();
+        Ok(delivery.id)
+    }
+
+    /// Returns the deliveries that are still to be made, oldest first.
+    pub fn pending(&self) -> anyhow::Result<Vec<Delivery<T>>> {
+        read_deliveries(&self.dir.join(QUEUE_DIR))
+    }
+
+    /// Returns the deliveries that exhausted their attempts, oldest first.
+    pub fn dead_letters(&self) -> anyhow::Result<Vec<Delivery<T>>> {
+        read_deliveries(&self.dir.join(self.dead_dir))
+    }
+
+    /// Removes all the dead letters, and returns how many were removed.
+    pub fn clear_dead_letters(&self) -> anyhow::Result<usize> {
+        let mut count = 0;
+        for entry in std::fs::read_dir(self.dir.join(self.dead_dir))? {
+            let path = entry?.path();
+            if parse_delivery_name(&path).is_some() {
+                std::fs::remove_file(path)?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Delivers the queued items with `deliverer`, with a task for every destination.
+    pub async fn run<D: Deliver<T>>(self: Arc<Self>, deliverer: Arc<D>) -> anyhow::Result<()> {
+        // dropping the set aborts the deliveries in progress
+        let mut workers = JoinSet::new();
+        loop {
+            let notified = self.queue_changed.notified();
+            let next_wakeup = match self.dispatch(&deliverer, &mut workers).await {
+                Ok(next_wakeup) => next_wakeup,
+                Err(e) => {
+                    tracing::error!(
+                        "failed to process delivery queue {}: {e}",
+                        self.dir.display()
+                    );
+                    Some(self.retry.base_backoff)
+                }
+            };
+
+            let sleep = async {
+                match next_wakeup {
+                    Some(delay) => tokio::time::sleep(delay).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = notified => (),
+                _ = sleep => (),
+                Some(_) = workers.join_next(), if !workers.is_empty() => (),
+            }
+        }
+    }
+
+    /// Starts a task for every destination with due deliveries that no task is delivering to yet.
+    /// Returns how long to wait before the next delivery is due, if any.
+    async fn dispatch<D: Deliver<T>>(
+        self: &Arc<Self>,
+        deliverer: &Arc<D>,
+        workers: &mut JoinSet<()>,
+    ) -> anyhow::Result<Option<Duration>> {
+        let this = self.clone();
+        let deliveries = tokio::task::spawn_blocking(move || this.pending()).await??;
+
+        let now = now_ms();
+        let mut ready: HashMap<String, Vec<Delivery<T>>> = HashMap::new();
+        let mut blocked = self.busy.lock().clone();
+        let mut next_wakeup: Option<u64> = None;
+        for delivery in deliveries {
+            let destination = deliverer.destination(&delivery.item);
+            if blocked.contains(&destination) {
+                continue;
+            }
+
+            if delivery.next_attempt_ms > now {
+                let wait = delivery.next_attempt_ms - now;
+                next_wakeup = Some(next_wakeup.map_or(wait, |w| w.min(wait)));
+                blocked.insert(destination);
+                continue;
+            }
+
+            ready.entry(destination).or_default().push(delivery);
+        }
+
+        for (destination, deliveries) in ready {
+            self.busy.lock().insert(destination.clone());
+            let this = self.clone();
+            let deliverer = deliverer.clone();
+            workers.spawn(async move {
+                match this.deliver_in_order(&*deliverer, deliveries).await {
+                    Ok(true) => deliverer.dequeued().await,
+                    Ok(false) => (),
+                    Err(e) => tracing::error!("failed to deliver to {destination}: {e}"),
+                }
+                this.busy.lock().remove(&destination);
+            });
+        }
+
+        Ok(next_wakeup.map(Duration::from_millis))
+    }
+
+    /// Attempts the deliveries to a destination in order, until one fails and must be retried
+    /// later. Returns whether any delivery left the queue.
+    async fn deliver_in_order<D: Deliver<T>>(
+        &self,
+        deliverer: &D,
+        deliveries: Vec<Delivery<T>>,
+    ) -> anyhow::Result<bool> {
+        let mut dequeued = false;
+        for mut delivery in deliveries {
+            let path = self.dir.join(QUEUE_DIR).join(delivery_name(delivery.id));
+            let result =
+                tokio::time::timeout(self.retry.request_timeout, deliverer.deliver(&delivery))
+                    .await
+                    .unwrap_or_else(|_| {
+                        Err(anyhow::anyhow!(
+                            "delivery timed out after {:?}",
+                            self.retry.request_timeout
+                        ))
+                    });
+
+            match result {
+                Ok(()) => {
+                    tokio::fs::remove_file(&path).await?;
+                    dequeued = true;
+                }
+                Err(e) => {
+                    delivery.attempts += 1;
+                    delivery.last_error = Some(e.to_string());
+                    if delivery.attempts >= self.retry.max_attempts {
+                        tracing::warn!(
+                            "delivery {} to {} failed {} times, giving up: {e}",
+                            delivery.id,
+                            deliverer.destination(&delivery.item),
+                            delivery.attempts,
+                        );
+                        let dead_dir = self.dir.join(self.dead_dir);
+                        tokio::task::spawn_blocking(move || write_delivery(&dead_dir, &delivery))
+                            .await??;
+                        tokio::fs::remove_file(&path).await?;
+                        dequeued = true;
+                    } else {
+                        let backoff = self.retry.backoff(delivery.attempts);
+                        delivery.next_attempt_ms = now_ms() + backoff.as_millis() as u64;
+                        let queue_dir = self.dir.join(QUEUE_DIR);
+                        tokio::task::spawn_blocking(move || write_delivery(&queue_dir, &delivery))
+                            .await??;
+                        break;
+                    }
+                }
+            }
+        }
+
+        Ok(dequeued)
+    }
+}
+
+fn write_delivery<T: Serialize>(dir: &Path, delivery: &Delivery<T>) -> anyhow::Result<()> {
+    let path = dir.join(delivery_name(delivery.id));
+    let tmp_path = path.with_extension("json~");
+    let mut file = std::fs::File::create(&tmp_path)?;
+    file.write_all(&serde_json::to_vec(delivery)?)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, &path)?;
+    Ok(())
+}
+
+fn delivery_name(id: u64) -> String {
+    format!("{id:020}.json")
+}
+
+fn parse_delivery_name(path: &Path) -> Option<u64> {
+    let name = path.file_name()?.to_str()?;
+    name.strip_suffix(".json")?.parse().ok()
+}
+
+fn read_deliveries<T: DeserializeOwned>(dir: &Path) -> anyhow::Result<Vec<Delivery<T>>> {
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if let Some(id) = parse_delivery_name(&path) {
+            paths.push((id, path));
+        }
+    }
+    paths.sort_unstable_by_key(|(id, _)| *id);
+
+    let mut deliveries = Vec::with_capacity(paths.len());
+    for (_, path) in paths {
+        let data = std::fs::read(&path)?;
+        match serde_json::from_slice(&data) {
+            Ok(delivery) => deliveries.push(delivery),
+            Err(e) => tracing::warn!("ignoring corrupted delivery {}: {e}", path.display()),
+        }
+    }
+
+    Ok(deliveries)
+}
+
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+pub fn test_retry() -> RetryPolicy {
+    RetryPolicy {
+        max_attempts: 2,
+        base_backoff: Duration::from_millis(10),
+        max_backoff: Duration::from_millis(10),
+        request_timeout: Duration::from_secs(1),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::tempdir;
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+    struct Message {
+        to: String,
+        body: u32,
+    }
+
+    /// Records the messages it delivers, and never completes the deliveries to `hung`.
+    struct Recorder {
+        delivered: mpsc::UnboundedSender<Message>,
+    }
+
+    #[async_trait::async_trait]
+    impl Deliver<Message> for Recorder {
+        fn destination(&self, item: &Message) -> String {
+            item.to.clone()
+        }
+
+        async fn deliver(&self, delivery: &Delivery<Message>) -> anyhow::Result<()> {
+            if delivery.item.to == "hung" {
+                std::future::pending::<()>().await;
+            }
+            self.delivered.send(delivery.item.clone()).unwrap();
+            Ok(())
+        }
+    }
+
+    fn message(to: &str, body: u32) -> Message {
+        Message {
+            to: to.into(),
+            body,
+        }
+    }
+
+    #[tokio::test]
+    async fn hung_destination_does_not_block_others() {
+        let tmp = tempdir().unwrap();
+        let retry = RetryPolicy {
+            request_timeout: Duration::from_millis(50),
+            ..test_retry()
+        };
+        let queue = Arc::new(DeliveryQueue::open(tmp.path(), "dead", retry).unwrap());
+        let (sender, mut delivered) = mpsc::unbounded_channel();
+        tokio::spawn(queue.clone().run(Arc::new(Recorder { delivered: sender })));
+
+        queue.push(message("hung", 0)).unwrap();
+        for body in 1..=3 {
+            queue.push(message("ok", body)).unwrap();
+        }
+        for body in 1..=3 {
+            assert_eq!(delivered.recv().await.unwrap(), message("ok", body));
+        }
+
+        // every attempt times out
+        let dead_letters = loop {
+            let dead_letters = queue.dead_letters().unwrap();
+            if !dead_letters.is_empty() {
+                break dead_letters;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        assert_eq!(dead_letters[0].item, message("hung", 0));
+        assert_eq!(dead_letters[0].attempts, 2);
+        assert!(dead_letters[0]
+            .last_error
+            .as_ref()
+            .unwrap()
+            .contains("timed out"));
+        assert!(queue.pending().unwrap().is_empty());
+
+        assert_eq!(queue.clear_dead_letters().unwrap(), 1);
+        assert!(queue.dead_letters().unwrap().is_empty());
+    }
+
+    #[test]
+    fn queued_deliveries_survive_restart() {
+        let tmp = tempdir().unwrap();
+        let queue = DeliveryQueue::open(tmp.path(), "dead", test_retry()).unwrap();
+        queue.push(message("a", 1)).unwrap();
+        let id = queue.push(message("b", 2)).unwrap();
+        drop(queue);
+
+        let queue = DeliveryQueue::<Message>::open(tmp.path(), "dead", test_retry()).unwrap();
+        let pending = queue.pending().unwrap();
+        assert_eq!(
+            pending.iter().map(|d| d.item.clone()).collect::<Vec<_>>(),
+            [message("a", 1), message("b", 2)]
+        );
+        assert_eq!(queue.push(message("c", 3)).unwrap(), id + 1);
+    }
+
+    #[test]
+    fn backoff_is_exponential_and_capped() {
+        let retry = RetryPolicy {
+            max_attempts: 10,
+            base_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+            request_timeout: Duration::from_secs(1),
+        };
+        assert_eq!(retry.backoff(1), Duration::from_millis(100));
+        assert_eq!(retry.backoff(2), Duration::from_millis(200));
+        assert_eq!(retry.backoff(4), Duration::from_millis(800));
+        assert_eq!(retry.backoff(5), Duration::from_secs(1));
+        assert_eq!(retry.backoff(64), Duration::from_secs(1));
+    }
+}
@@ -8,8 +8,10 @@ use crate::{auth::AuthError, query_result_builder::QueryResultBuilderError};
 pub enum Error {
     #[error("LibSQL failed to bind provided query parameters: `{0}`")]
     LibSqlInvalidQueryParams(anyhow::Error),
-    #[error("Transaction timed-out")]
-    LibSqlTxTimeout,
+    #[error("Transaction timed-out after {0:?}")]
+    LibSqlTxTimeout(std::time::Duration),
+    #[error("Statement timed-out")]
+    StatementTimeout,
     #[error("Server can't handle additional transactions")]
     LibSqlTxBusy,
     #[error(transparent)]
@@ -53,6 +55,18 @@ pub enum Error {
     Anyhow(#[from] anyhow::Error),
     #[error("Invalid host header: `{0}`")]
     InvalidHost(String),
+    #[error("Invalid timeout header: `{0}`")]
+    InvalidTimeoutHeader(String),
+    #[error("Invalid frame_no header: `{0}`")]
+    InvalidFrameNoHeader(String),
+    #[error("Invalid staleness header: `{0}`")]
+    InvalidStalenessHeader(String),
+    #[error("Timed out waiting for the database to reach frame_no {0}")]
+    FrameNoTimeout(crate::replication::FrameNo),
+    #[error(
+        "The write was committed on the primary, but not acknowledged by enough replicas in time"
+    )]
+    WriteQuorumTimeout,
 }
 
 impl Error {
@@ -71,7 +85,8 @@ impl IntoResponse for Error {
             AuthError(_) => self.format_err(StatusCode::UNAUTHORIZED),
             Anyhow(_) => self.format_err(StatusCode::INTERNAL_SERVER_ERROR),
             LibSqlInvalidQueryParams(_) => self.format_err(StatusCode::BAD_REQUEST),
-            LibSqlTxTimeout => self.format_err(StatusCode::BAD_REQUEST),
+            LibSqlTxTimeout(_) => self.format_err(StatusCode::BAD_REQUEST),
+            StatementTimeout => self.format_err(StatusCode::REQUEST_TIMEOUT),
             LibSqlTxBusy => self.format_err(StatusCode::TOO_MANY_REQUESTS),
             IOError(_) => self.format_err(StatusCode::INTERNAL_SERVER_ERROR),
             RusqliteError(_) => self.format_err(StatusCode::INTERNAL_SERVER_ERROR),
@@ -89,6 +104,11 @@ impl IntoResponse for Error {
             TooManyRequests => self.format_err(StatusCode::TOO_MANY_REQUESTS),
             QueryError(_) => self.format_err(StatusCode::BAD_REQUEST),
             InvalidHost(_) => self.format_err(StatusCode::BAD_REQUEST),
+            InvalidTimeoutHeader(_) => self.format_err(StatusCode::BAD_REQUEST),
+            InvalidFrameNoHeader(_) => self.format_err(StatusCode::BAD_REQUEST),
+            InvalidStalenessHeader(_) => self.format_err(StatusCode::BAD_REQUEST),
+            FrameNoTimeout(_) => self.format_err(StatusCode::SERVICE_UNAVAILABLE),
+            WriteQuorumTimeout => self.format_err(StatusCode::GATEWAY_TIMEOUT),
         }
     }
 }
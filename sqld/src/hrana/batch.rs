@@ -19,8 +19,10 @@ use super::{proto, ProtocolError, Version};
 
 #[derive(thiserror::Error, Debug)]
 pub enum BatchError {
-    #[error("Transaction timed out")]
-    TransactionTimeout,
+    #[error("Transaction timed out after {timeout:?}")]
+    TransactionTimeout { timeout: std::time::Duration },
+    #[error("Statement timed out")]
+    StatementTimeout,
     #[error("Server cannot handle additional transactions")]
     TransactionBusy,
     #[error("Response is too large")]
@@ -156,7 +158,8 @@ fn catch_batch_error(sqld_error: SqldError) -> anyhow::Error {
 
 fn batch_error_from_sqld_error(sqld_error: SqldError) -> Result<BatchError, SqldError> {
     Ok(match sqld_error {
-        SqldError::LibSqlTxTimeout => BatchError::TransactionTimeout,
+        SqldError::LibSqlTxTimeout(timeout) => BatchError::TransactionTimeout { timeout },
+        SqldError::StatementTimeout => BatchError::StatementTimeout,
         SqldError::LibSqlTxBusy => BatchError::TransactionBusy,
         SqldError::BuilderError(QueryResultBuilderError::ResponseTooLarge(_)) => {
             BatchError::ResponseTooLarge
@@ -168,7 +171,8 @@ fn batch_error_from_sqld_error(sqld_error: SqldError) -> Result<BatchError, Sqld
 impl BatchError {
     pub fn code(&self) -> &'static str {
         match self {
-            Self::TransactionTimeout => "TRANSACTION_TIMEOUT",
+            Self::TransactionTimeout { .. } => "TRANSACTION_TIMEOUT",
+            Self::StatementTimeout => "STATEMENT_TIMEOUT",
             Self::TransactionBusy => "TRANSACTION_BUSY",
             Self::ResponseTooLarge => "RESPONSE_TOO_LARGE",
         }
@@ -1,12 +1,17 @@
 use std::sync::Arc;
+use std::time::Instant;
 
 use anyhow::{Context, Result};
 use parking_lot::Mutex;
 use serde::{de::DeserializeOwned, Serialize};
+use tracing::Instrument as _;
 
 use super::ProtocolError;
 use crate::auth::Authenticated;
+use crate::connection::config::MaxStaleness;
 use crate::connection::{Connection, MakeConnection};
+use crate::http::{RequestTimeouts, WriteFrameNo};
+use crate::metrics;
 mod proto;
 mod request;
 mod stream;
@@ -33,16 +38,27 @@ impl<C: Connection> Server<C> {
     pub async fn handle_pipeline(
         &self,
         auth: Authenticated,
+        timeouts: RequestTimeouts,
+        max_staleness: MaxStaleness,
+        namespace: &str,
         req: hyper::Request<hyper::Body>,
         connection_maker: Arc<dyn MakeConnection<Connection = C>>,
     ) -> Result<hyper::Response<hyper::Body>> {
-        handle_pipeline(self, connection_maker, auth, req)
-            .await
-            .or_else(|err| {
-                err.downcast::<stream::StreamError>()
-                    .map(stream_error_response)
-            })
-            .or_else(|err| err.downcast::<ProtocolError>().map(protocol_error_response))
+        handle_pipeline(
+            self,
+            connection_maker,
+            auth,
+            timeouts,
+            max_staleness,
+            namespace,
+            req,
+        )
+        .await
+        .or_else(|err| {
+            err.downcast::<stream::StreamError>()
+                .map(stream_error_response)
+        })
+        .or_else(|err| err.downcast::<ProtocolError>().map(protocol_error_response))
     }
 }
 
@@ -57,26 +73,54 @@ async fn handle_pipeline<D: Connection>(
     server: &Server<D>,
     connection_maker: Arc<dyn MakeConnection<Connection = D>>,
     auth: Authenticated,
+    timeouts: RequestTimeouts,
+    max_staleness: MaxStaleness,
+    namespace: &str,
     req: hyper::Request<hyper::Body>,
 ) -> Result<hyper::Response<hyper::Body>> {
     let req_body: proto::PipelineRequestBody = read_request_json(req).await?;
     let mut stream_guard =
         stream::acquire(server, req_body.baton.as_deref(), connection_maker).await?;
+    if let Ok(db) = stream_guard.get_db() {
+        if let Some(timeout) = timeouts.txn {
+            db.set_txn_timeout(Some(timeout));
+        }
+        db.set_max_staleness(max_staleness);
+    }
 
     let mut results = Vec::with_capacity(req_body.requests.len());
     for request in req_body.requests.into_iter() {
-        let result = request::handle(&mut stream_guard, auth, request)
+        let kind = request::request_kind(&request);
+        let start = Instant::now();
+        let result = request::handle(&mut stream_guard, auth, timeouts.statement, request)
+            .instrument(tracing::info_span!(
+                "hrana_request",
+                namespace,
+                transport = "http",
+                kind
+            ))
             .await
             .context("Could not execute a request in pipeline")?;
+        metrics::HRANA_REQUESTS.inc(&[namespace, "http", kind]);
+        metrics::HRANA_REQUEST_DURATION
+            .observe(&[namespace, "http", kind], start.elapsed().as_secs_f64());
         results.push(result);
     }
 
+    let write_frame_no = stream_guard
+        .get_db()
+        .ok()
+        .and_then(|db| db.last_write_frame_no());
     let resp_body = proto::PipelineResponseBody {
         baton: stream_guard.release(),
         base_url: server.self_url.clone(),
         results,
     };
-    Ok(json_response(hyper::StatusCode::OK, &resp_body))
+    let mut resp = json_response(hyper::StatusCode::OK, &resp_body);
+    if let Some(frame_no) = write_frame_no {
+        resp.extensions_mut().insert(WriteFrameNo(frame_no));
+    }
+    Ok(resp)
 }
 
 async fn read_request_json<T: DeserializeOwned>(req: hyper::Request<hyper::Body>) -> Result<T> {
@@ -1,9 +1,11 @@
+use std::time::Duration;
+
 use anyhow::{anyhow, bail, Result};
 
 use super::super::{batch, stmt, ProtocolError, Version};
 use super::{proto, stream};
 use crate::auth::Authenticated;
-use crate::connection::Connection;
+use crate::connection::{with_statement_timeout, Connection};
 
 /// An error from executing a [`proto::StreamRequest`]
 #[derive(thiserror::Error, Debug)]
@@ -19,9 +21,10 @@ pub enum StreamResponseError {
 pub async fn handle<D: Connection>(
     stream_guard: &mut stream::Guard<'_, D>,
     auth: Authenticated,
+    statement_timeout: Option<Duration>,
     request: proto::StreamRequest,
 ) -> Result<proto::StreamResult> {
-    let result = match try_handle(stream_guard, auth, request).await {
+    let result = match try_handle(stream_guard, auth, statement_timeout, request).await {
         Ok(response) => proto::StreamResult::Ok { response },
         Err(err) => {
             let resp_err = err.downcast::<StreamResponseError>()?;
@@ -35,9 +38,23 @@ pub async fn handle<D: Connection>(
     Ok(result)
 }
 
+/// The name of the request type, used to label metrics.
+pub fn request_kind(request: &proto::StreamRequest) -> &'static str {
+    match request {
+        proto::StreamRequest::Close(_) => "close",
+        proto::StreamRequest::Execute(_) => "execute",
+        proto::StreamRequest::Batch(_) => "batch",
+        proto::StreamRequest::Sequence(_) => "sequence",
+        proto::StreamRequest::Describe(_) => "describe",
+        proto::StreamRequest::StoreSql(_) => "store_sql",
+        proto::StreamRequest::CloseSql(_) => "close_sql",
+    }
+}
+
 async fn try_handle<D: Connection>(
     stream_guard: &mut stream::Guard<'_, D>,
     auth: Authenticated,
+    timeout: Option<Duration>,
     request: proto::StreamRequest,
 ) -> Result<proto::StreamResponse> {
     Ok(match request {
@@ -50,7 +67,7 @@ async fn try_handle<D: Connection>(
             let sqls = stream_guard.sqls();
             let query = stmt::proto_stmt_to_query(&req.stmt, sqls, Version::Hrana2)
                 .map_err(catch_stmt_error)?;
-            let result = stmt::execute_stmt(db, auth, query)
+            let result = with_statement_timeout(timeout, stmt::execute_stmt(db, auth, query))
                 .await
                 .map_err(catch_stmt_error)?;
             proto::StreamResponse::Execute(proto::ExecuteStreamResp { result })
@@ -59,7 +76,7 @@ async fn try_handle<D: Connection>(
             let db = stream_guard.get_db()?;
             let sqls = stream_guard.sqls();
             let pgm = batch::proto_batch_to_program(&req.batch, sqls, Version::Hrana2)?;
-            let result = batch::execute_batch(db, auth, pgm)
+            let result = with_statement_timeout(timeout, batch::execute_batch(db, auth, pgm))
                 .await
                 .map_err(catch_batch_error)?;
             proto::StreamResponse::Batch(proto::BatchStreamResp { result })
@@ -70,7 +87,7 @@ async fn try_handle<D: Connection>(
             let sql =
                 stmt::proto_sql_to_sql(req.sql.as_deref(), req.sql_id, sqls, Version::Hrana2)?;
             let pgm = batch::proto_sequence_to_program(sql).map_err(catch_stmt_error)?;
-            batch::execute_sequence(db, auth, pgm)
+            with_statement_timeout(timeout, batch::execute_sequence(db, auth, pgm))
                 .await
                 .map_err(catch_stmt_error)
                 .map_err(catch_batch_error)?;
@@ -81,7 +98,7 @@ async fn try_handle<D: Connection>(
             let sqls = stream_guard.sqls();
             let sql =
                 stmt::proto_sql_to_sql(req.sql.as_deref(), req.sql_id, sqls, Version::Hrana2)?;
-            let result = stmt::describe_stmt(db, auth, sql.into())
+            let result = with_statement_timeout(timeout, stmt::describe_stmt(db, auth, sql.into()))
                 .await
                 .map_err(catch_stmt_error)?;
             proto::StreamResponse::Describe(proto::DescribeStreamResp { result })
@@ -47,6 +47,9 @@ pub enum ProtocolError {
     #[error("SQL text {sql_id} already exists")]
     SqlExists { sql_id: i32 },
 
+    #[error("Subscription {subscription_id} already exists")]
+    SubscriptionExists { subscription_id: i32 },
+
     #[error("Invalid reference to step in a batch condition")]
     BatchCondBadStep,
 
@@ -26,8 +26,10 @@ pub enum StmtError {
     #[error("Specifying both positional and named arguments is not supported")]
     ArgsBothPositionalAndNamed,
 
-    #[error("Transaction timed out")]
-    TransactionTimeout,
+    #[error("Transaction timed out after {timeout:?}")]
+    TransactionTimeout { timeout: std::time::Duration },
+    #[error("Statement timed out")]
+    StatementTimeout,
     #[error("Server cannot handle additional transactions")]
     TransactionBusy,
     #[error("SQLite error: {message}")]
@@ -46,6 +48,10 @@ pub enum StmtError {
     Blocked { reason: Option<String> },
     #[error("Response is too large")]
     ResponseTooLarge,
+    #[error(
+        "The write was committed on the primary, but not acknowledged by enough replicas in time"
+    )]
+    WriteQuorumTimeout,
 }
 
 pub async fn execute_stmt(
@@ -197,12 +203,14 @@ fn catch_stmt_error(sqld_error: SqldError) -> anyhow::Error {
 pub fn stmt_error_from_sqld_error(sqld_error: SqldError) -> Result<StmtError, SqldError> {
     Ok(match sqld_error {
         SqldError::LibSqlInvalidQueryParams(source) => StmtError::ArgsInvalid { source },
-        SqldError::LibSqlTxTimeout => StmtError::TransactionTimeout,
+        SqldError::LibSqlTxTimeout(timeout) => StmtError::TransactionTimeout { timeout },
+        SqldError::StatementTimeout => StmtError::StatementTimeout,
         SqldError::LibSqlTxBusy => StmtError::TransactionBusy,
         SqldError::BuilderError(QueryResultBuilderError::ResponseTooLarge(_)) => {
             StmtError::ResponseTooLarge
         }
         SqldError::Blocked(reason) => StmtError::Blocked { reason },
+        SqldError::WriteQuorumTimeout => StmtError::WriteQuorumTimeout,
         SqldError::RusqliteError(rusqlite_error) => match rusqlite_error {
             rusqlite::Error::SqliteFailure(sqlite_error, Some(message)) => StmtError::SqliteError {
                 source: sqlite_error,
@@ -243,12 +251,14 @@ impl StmtError {
             Self::SqlManyStmts => "SQL_MANY_STATEMENTS",
             Self::ArgsInvalid { .. } => "ARGS_INVALID",
             Self::ArgsBothPositionalAndNamed => "ARGS_BOTH_POSITIONAL_AND_NAMED",
-            Self::TransactionTimeout => "TRANSACTION_TIMEOUT",
+            Self::TransactionTimeout { .. } => "TRANSACTION_TIMEOUT",
+            Self::StatementTimeout => "STATEMENT_TIMEOUT",
             Self::TransactionBusy => "TRANSACTION_BUSY",
             Self::SqliteError { source, .. } => sqlite_error_code(source.code),
             Self::SqlInputError { .. } => "SQL_INPUT_ERROR",
             Self::Blocked { .. } => "BLOCKED",
             Self::ResponseTooLarge => "RESPONSE_TOO_LARGE",
+            Self::WriteQuorumTimeout => "WRITE_QUORUM_TIMEOUT",
         }
     }
 }
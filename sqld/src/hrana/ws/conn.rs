@@ -6,21 +6,25 @@ use std::future::Future;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::Instant;
 
 use anyhow::{bail, Context as _, Result};
 use bytes::Bytes;
 use futures::stream::FuturesUnordered;
 use futures::{ready, FutureExt as _, StreamExt as _};
-use tokio::sync::oneshot;
+use tokio::sync::{mpsc, oneshot};
 use tokio_tungstenite::tungstenite;
+use tracing::Instrument as _;
 use tungstenite::protocol::frame::coding::CloseCode;
 
 use crate::connection::MakeConnection;
 use crate::database::Database;
+use crate::metrics;
 use crate::namespace::MakeNamespace;
 
 use super::super::{ProtocolError, Version};
 use super::handshake::WebSocket;
+use super::subscription::SubscriptionContext;
 use super::{handshake, proto, session, Server, Upgrade};
 
 /// State of a Hrana connection.
@@ -38,6 +42,13 @@ struct Conn<F: MakeNamespace> {
     /// Future responses to requests that we have received but are evaluating asynchronously.
     responses: FuturesUnordered<ResponseFuture>,
     connection_maker: Arc<dyn MakeConnection<Connection = <F::Database as Database>::Connection>>,
+    subscription_ctx: SubscriptionContext,
+    /// Receives the messages pushed by the subscriptions of the session.
+    subscription_rx: mpsc::Receiver<proto::ServerMsg>,
+    /// Receives the id and key of the subscriptions whose task ended.
+    subscription_ended_rx: mpsc::UnboundedReceiver<(i32, u64)>,
+    /// The namespace of the connection, used to label metrics.
+    namespace: String,
 }
 
 /// A `Future` that stores a handle to a future response to request which is being evaluated
@@ -47,6 +58,10 @@ struct ResponseFuture {
     request_id: i32,
     /// The future that will be resolved with the response.
     response_rx: futures::future::Fuse<oneshot::Receiver<Result<proto::Response>>>,
+    /// Labels and start time of the request, to record metrics when the response is ready.
+    namespace: String,
+    kind: &'static str,
+    start: Instant,
 }
 
 pub(super) async fn handle_tcp<F: MakeNamespace>(
@@ -78,10 +93,19 @@ async fn handle_ws<F: MakeNamespace>(
     conn_id: u64,
     namespace: Bytes,
 ) -> Result<()> {
-    let connection_maker = server
+    let namespace_label = String::from_utf8_lossy(&namespace).into_owned();
+    let (connection_maker, frame_notifier, table_changes) = server
         .namespaces
-        .with(namespace, |ns| ns.db.connection_maker())
+        .with(namespace, |ns| {
+            (
+                ns.db.connection_maker(),
+                ns.db.frame_notifier(),
+                ns.db.table_changes(),
+            )
+        })
         .await?;
+    let (updates_tx, subscription_rx) = mpsc::channel(16);
+    let (ended_tx, subscription_ended_rx) = mpsc::unbounded_channel();
     let mut conn = Conn {
         conn_id,
         server,
@@ -92,6 +116,15 @@ async fn handle_ws<F: MakeNamespace>(
         join_set: tokio::task::JoinSet::new(),
         responses: FuturesUnordered::new(),
         connection_maker,
+        subscription_ctx: SubscriptionContext {
+            frame_notifier,
+            table_changes,
+            updates_tx,
+            ended_tx,
+        },
+        subscription_rx,
+        subscription_ended_rx,
+        namespace: namespace_label,
     };
 
     loop {
@@ -129,6 +162,14 @@ async fn handle_ws<F: MakeNamespace>(
                 let response_msg = response_res?;
                 send_msg(&mut conn, &response_msg).await?;
             },
+            Some(update_msg) = conn.subscription_rx.recv() => {
+                send_msg(&mut conn, &update_msg).await?;
+            },
+            Some((subscription_id, key)) = conn.subscription_ended_rx.recv() => {
+                if let Some(session) = conn.session.as_mut() {
+                    session::handle_subscription_end(session, subscription_id, key);
+                }
+            },
             else => break,
         }
 
@@ -216,12 +257,21 @@ async fn handle_request_msg<F: MakeNamespace>(
         bail!(ProtocolError::RequestBeforeHello)
     };
 
+    let kind = request_kind(&request);
+    let start = Instant::now();
     let response_rx = session::handle_request(
         session,
         &mut conn.join_set,
         request,
         conn.connection_maker.clone(),
+        &conn.subscription_ctx,
     )
+    .instrument(tracing::info_span!(
+        "hrana_request",
+        namespace = conn.namespace.as_str(),
+        transport = "ws",
+        kind
+    ))
     .await
     .unwrap_or_else(|err| {
         // we got an error immediately, but let's treat it as a special case of the general
@@ -234,14 +284,40 @@ async fn handle_request_msg<F: MakeNamespace>(
     conn.responses.push(ResponseFuture {
         request_id,
         response_rx: response_rx.fuse(),
+        namespace: conn.namespace.clone(),
+        kind,
+        start,
     });
     Ok(true)
 }
 
+/// The name of the request type, used to label metrics.
+fn request_kind(request: &proto::Request) -> &'static str {
+    match request {
+        proto::Request::OpenStream(_) => "open_stream",
+        proto::Request::CloseStream(_) => "close_stream",
+        proto::Request::Execute(_) => "execute",
+        proto::Request::Batch(_) => "batch",
+        proto::Request::Sequence(_) => "sequence",
+        proto::Request::Describe(_) => "describe",
+        proto::Request::StoreSql(_) => "store_sql",
+        proto::Request::CloseSql(_) => "close_sql",
+        proto::Request::Subscribe(_) => "subscribe",
+        proto::Request::Unsubscribe(_) => "unsubscribe",
+    }
+}
+
 impl Future for ResponseFuture {
     type Output = Result<proto::ServerMsg>;
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
-        match ready!(Pin::new(&mut self.response_rx).poll(cx)) {
+        let response = ready!(Pin::new(&mut self.response_rx).poll(cx));
+        if response.is_ok() {
+            let labels = [self.namespace.as_str(), "ws", self.kind];
+            metrics::HRANA_REQUESTS.inc(&labels);
+            metrics::HRANA_REQUEST_DURATION.observe(&labels, self.start.elapsed().as_secs_f64());
+        }
+
+        match response {
             Ok(Ok(response)) => Poll::Ready(Ok(proto::ServerMsg::ResponseOk {
                 request_id: self.request_id,
                 response,
@@ -13,6 +13,7 @@ pub mod proto;
 mod conn;
 mod handshake;
 mod session;
+mod subscription;
 
 struct Server<F: MakeNamespace> {
     namespaces: Arc<NamespaceStore<F>>,
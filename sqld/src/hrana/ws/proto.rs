@@ -14,9 +14,25 @@ pub enum ClientMsg {
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ServerMsg {
     HelloOk {},
-    HelloError { error: Error },
-    ResponseOk { request_id: i32, response: Response },
-    ResponseError { request_id: i32, error: Error },
+    HelloError {
+        error: Error,
+    },
+    ResponseOk {
+        request_id: i32,
+        response: Response,
+    },
+    ResponseError {
+        request_id: i32,
+        error: Error,
+    },
+    SubscriptionUpdate {
+        subscription_id: i32,
+        result: StmtResult,
+    },
+    SubscriptionError {
+        subscription_id: i32,
+        error: Error,
+    },
 }
 
 #[derive(Deserialize, Debug)]
@@ -30,6 +46,8 @@ pub enum Request {
     Describe(DescribeReq),
     StoreSql(StoreSqlReq),
     CloseSql(CloseSqlReq),
+    Subscribe(SubscribeReq),
+    Unsubscribe(UnsubscribeReq),
 }
 
 #[derive(Serialize, Debug)]
@@ -43,11 +61,16 @@ pub enum Response {
     Describe(DescribeResp),
     StoreSql(StoreSqlResp),
     CloseSql(CloseSqlResp),
+    Subscribe(SubscribeResp),
+    Unsubscribe(UnsubscribeResp),
 }
 
 #[derive(Deserialize, Debug)]
 pub struct OpenStreamReq {
     pub stream_id: i32,
+    /// sqld extension: timeout of the interactive transactions on the stream.
+    #[serde(default)]
+    pub txn_timeout_ms: Option<u64>,
 }
 
 #[derive(Serialize, Debug)]
@@ -65,22 +88,34 @@ pub struct CloseStreamResp {}
 pub struct ExecuteReq {
     pub stream_id: i32,
     pub stmt: Stmt,
+    /// sqld extension: the request is not executed before the database reaches this frame_no.
+    #[serde(default)]
+    pub min_frame_no: Option<u64>,
 }
 
 #[derive(Serialize, Debug)]
 pub struct ExecuteResp {
     pub result: StmtResult,
+    /// sqld extension: the frame_no that the database reached after executing the request.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frame_no: Option<u64>,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct BatchReq {
     pub stream_id: i32,
     pub batch: Batch,
+    /// sqld extension, see [`ExecuteReq::min_frame_no`].
+    #[serde(default)]
+    pub min_frame_no: Option<u64>,
 }
 
 #[derive(Serialize, Debug)]
 pub struct BatchResp {
     pub result: BatchResult,
+    /// sqld extension, see [`ExecuteResp::frame_no`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frame_no: Option<u64>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -90,10 +125,17 @@ pub struct SequenceReq {
     pub sql: Option<String>,
     #[serde(default)]
     pub sql_id: Option<i32>,
+    /// sqld extension, see [`ExecuteReq::min_frame_no`].
+    #[serde(default)]
+    pub min_frame_no: Option<u64>,
 }
 
 #[derive(Serialize, Debug)]
-pub struct SequenceResp {}
+pub struct SequenceResp {
+    /// sqld extension, see [`ExecuteResp::frame_no`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frame_no: Option<u64>,
+}
 
 #[derive(Deserialize, Debug)]
 pub struct DescribeReq {
@@ -125,3 +167,23 @@ pub struct CloseSqlReq {
 
 #[derive(Serialize, Debug)]
 pub struct CloseSqlResp {}
+
+#[derive(Deserialize, Debug)]
+pub struct SubscribeReq {
+    pub subscription_id: i32,
+    pub stream_id: i32,
+    pub stmt: Stmt,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SubscribeResp {
+    pub result: StmtResult,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UnsubscribeReq {
+    pub subscription_id: i32,
+}
+
+#[derive(Serialize, Debug)]
+pub struct UnsubscribeResp {}
@@ -1,16 +1,22 @@
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::{anyhow, bail, Context as _, Result};
 use futures::future::BoxFuture;
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{mpsc, oneshot, watch};
+use tracing::Instrument as _;
 
 use super::super::{batch, stmt, ProtocolError, Version};
+use super::subscription::{self, SubscriptionContext};
 use super::{proto, Server};
 use crate::auth::{AuthError, Authenticated};
 use crate::connection::{Connection, MakeConnection};
 use crate::database::Database;
 use crate::namespace::MakeNamespace;
+use crate::query_analysis::StmtKind;
+use crate::replication::{self, FrameNo, FRAME_NO_WAIT_TIMEOUT};
 
 /// Session-level state of an authenticated Hrana connection.
 pub struct Session<D> {
@@ -18,10 +24,32 @@ pub struct Session<D> {
     version: Version,
     streams: HashMap<i32, StreamHandle<D>>,
     sqls: HashMap<i32, String>,
+    /// Live query subscriptions, by id. A subscription is removed when its task ends.
+    subscriptions: HashMap<i32, Subscription>,
+    /// The key of the next subscription.
+    next_subscription_key: u64,
+}
+
+struct Subscription {
+    /// The stream that the subscription was registered on, which ends it when closed.
+    stream_id: i32,
+    /// Tells apart the subscriptions that reuse the same id.
+    key: u64,
+    abort_hnd: tokio::task::AbortHandle,
 }
 
 struct StreamHandle<D> {
     job_tx: mpsc::Sender<StreamJob<D>>,
+    /// Set once the stream is closed, which cancels the job running on it.
+    closed_tx: watch::Sender<bool>,
+}
+
+impl<D> StreamHandle<D> {
+    /// Closes the stream: the job running on it is cancelled, which interrupts its statement, and
+    /// the connection is dropped. The jobs submitted afterwards fail.
+    fn close(&self) {
+        self.closed_tx.send_replace(true);
+    }
 }
 
 /// An arbitrary job that is executed on a [`Stream`].
@@ -33,6 +61,8 @@ struct StreamJob<D> {
     f: Box<dyn for<'s> FnOnce(&'s mut Stream<D>) -> BoxFuture<'s, Result<proto::Response>> + Send>,
     /// The result of `f` will be sent here.
     resp_tx: oneshot::Sender<Result<proto::Response>>,
+    /// The span of the request that submitted the job.
+    span: tracing::Span,
 }
 
 /// State of a Hrana stream, which corresponds to a standalone database connection.
@@ -50,12 +80,20 @@ pub enum ResponseError {
     Auth { source: AuthError },
     #[error("Stream {stream_id} has failed to open")]
     StreamNotOpen { stream_id: i32 },
+    #[error("Stream {stream_id} was closed")]
+    StreamClosed { stream_id: i32 },
     #[error("The server already stores {count} SQL texts, it cannot store more")]
     SqlTooMany { count: usize },
+    #[error("The connection already has {count} subscriptions, it cannot have more")]
+    SubscriptionTooMany { count: usize },
+    #[error("Only reading statements can be subscribed to")]
+    SubscriptionNotRead,
     #[error(transparent)]
     Stmt(stmt::StmtError),
     #[error(transparent)]
     Batch(batch::BatchError),
+    #[error("Timed out waiting for the database to reach frame_no {frame_no}")]
+    FrameNoTimeout { frame_no: FrameNo },
 }
 
 pub(super) fn handle_initial_hello<F: MakeNamespace>(
@@ -73,6 +111,8 @@ pub(super) fn handle_initial_hello<F: MakeNamespace>(
         version,
         streams: HashMap::new(),
         sqls: HashMap::new(),
+        subscriptions: HashMap::new(),
+        next_subscription_key: 0,
     })
 }
 
@@ -95,11 +135,21 @@ pub(super) fn handle_repeated_hello<F: MakeNamespace>(
     Ok(())
 }
 
+/// Removes a subscription whose task ended, unless its id was reused since.
+pub(super) fn handle_subscription_end<D>(session: &mut Session<D>, subscription_id: i32, key: u64) {
+    if let Entry::Occupied(entry) = session.subscriptions.entry(subscription_id) {
+        if entry.get().key == key {
+            entry.remove();
+        }
+    }
+}
+
 pub(super) async fn handle_request<D: Connection>(
     session: &mut Session<D>,
     join_set: &mut tokio::task::JoinSet<()>,
     req: proto::Request,
     connection_maker: Arc<dyn MakeConnection<Connection = D>>,
+    subscription_ctx: &SubscriptionContext,
 ) -> Result<oneshot::Receiver<Result<proto::Response>>> {
     // TODO: this function has rotten: it is too long and contains too much duplicated code. It
     // should be refactored at the next opportunity, together with code in stmt.rs and batch.rs
@@ -161,13 +211,17 @@ pub(super) async fn handle_request<D: Connection>(
                 bail!(ProtocolError::StreamExists { stream_id })
             }
 
-            let mut stream_hnd = stream_spawn(join_set, Stream { db: None });
+            let mut stream_hnd = stream_spawn(join_set, stream_id, Stream { db: None });
+            let txn_timeout = req.txn_timeout_ms.map(Duration::from_millis);
 
             stream_respond!(&mut stream_hnd, async move |stream| {
                 let db = connection_maker
                     .create()
                     .await
                     .context("Could not create a database connection")?;
+                if txn_timeout.is_some() {
+                    db.set_txn_timeout(txn_timeout);
+                }
                 stream.db = Some(db);
                 Ok(proto::Response::OpenStream(proto::OpenStreamResp {}))
             });
@@ -176,13 +230,19 @@ pub(super) async fn handle_request<D: Connection>(
         }
         proto::Request::CloseStream(req) => {
             let stream_id = req.stream_id;
-            let Some(mut stream_hnd) = session.streams.remove(&stream_id) else {
+            let Some(stream_hnd) = session.streams.remove(&stream_id) else {
                 bail!(ProtocolError::StreamNotFound { stream_id })
             };
 
-            stream_respond!(&mut stream_hnd, async move |_stream| {
-                Ok(proto::Response::CloseStream(proto::CloseStreamResp {}))
+            session.subscriptions.retain(|_, subscription| {
+                if subscription.stream_id == stream_id {
+                    subscription.abort_hnd.abort();
+                }
+                subscription.stream_id != stream_id
             });
+
+            stream_hnd.close();
+            respond!(proto::Response::CloseStream(proto::CloseStreamResp {}));
         }
         proto::Request::Execute(req) => {
             let stream_id = req.stream_id;
@@ -191,13 +251,20 @@ pub(super) async fn handle_request<D: Connection>(
             let query = stmt::proto_stmt_to_query(&req.stmt, &session.sqls, session.version)
                 .map_err(catch_stmt_error)?;
             let auth = session.authenticated;
+            let min_frame_no = req.min_frame_no;
+            let frame_notifier = subscription_ctx.frame_notifier.clone();
 
             stream_respond!(stream_hnd, async move |stream| {
                 let db = get_stream_db!(stream, stream_id);
+                wait_min_frame_no(&frame_notifier, min_frame_no).await?;
                 let result = stmt::execute_stmt(db, auth, query)
                     .await
                     .map_err(catch_stmt_error)?;
-                Ok(proto::Response::Execute(proto::ExecuteResp { result }))
+                let frame_no = response_frame_no(&frame_notifier, db);
+                Ok(proto::Response::Execute(proto::ExecuteResp {
+                    result,
+                    frame_no,
+                }))
             });
         }
         proto::Request::Batch(req) => {
@@ -207,13 +274,20 @@ pub(super) async fn handle_request<D: Connection>(
             let pgm = batch::proto_batch_to_program(&req.batch, &session.sqls, session.version)
                 .map_err(catch_stmt_error)?;
             let auth = session.authenticated;
+            let min_frame_no = req.min_frame_no;
+            let frame_notifier = subscription_ctx.frame_notifier.clone();
 
             stream_respond!(stream_hnd, async move |stream| {
                 let db = get_stream_db!(stream, stream_id);
+                wait_min_frame_no(&frame_notifier, min_frame_no).await?;
                 let result = batch::execute_batch(db, auth, pgm)
                     .await
                     .map_err(catch_batch_error)?;
-                Ok(proto::Response::Batch(proto::BatchResp { result }))
+                let frame_no = response_frame_no(&frame_notifier, db);
+                Ok(proto::Response::Batch(proto::BatchResp {
+                    result,
+                    frame_no,
+                }))
             });
         }
         proto::Request::Sequence(req) => {
@@ -229,14 +303,18 @@ pub(super) async fn handle_request<D: Connection>(
             )?;
             let pgm = batch::proto_sequence_to_program(sql).map_err(catch_stmt_error)?;
             let auth = session.authenticated;
+            let min_frame_no = req.min_frame_no;
+            let frame_notifier = subscription_ctx.frame_notifier.clone();
 
             stream_respond!(stream_hnd, async move |stream| {
                 let db = get_stream_db!(stream, stream_id);
+                wait_min_frame_no(&frame_notifier, min_frame_no).await?;
                 batch::execute_sequence(db, auth, pgm)
                     .await
                     .map_err(catch_stmt_error)
                     .map_err(catch_batch_error)?;
-                Ok(proto::Response::Sequence(proto::SequenceResp {}))
+                let frame_no = response_frame_no(&frame_notifier, db);
+                Ok(proto::Response::Sequence(proto::SequenceResp { frame_no }))
             });
         }
         proto::Request::Describe(req) => {
@@ -280,25 +358,94 @@ pub(super) async fn handle_request<D: Connection>(
             session.sqls.remove(&req.sql_id);
             respond!(proto::Response::CloseSql(proto::CloseSqlResp {}));
         }
+        proto::Request::Subscribe(req) => {
+            ensure_version!(Version::Hrana2, "The `subscribe` request");
+            let subscription_id = req.subscription_id;
+            if session.subscriptions.contains_key(&subscription_id) {
+                bail!(ProtocolError::SubscriptionExists { subscription_id })
+            } else if session.subscriptions.len() >= MAX_SUBSCRIPTION_COUNT {
+                bail!(ResponseError::SubscriptionTooMany {
+                    count: session.subscriptions.len()
+                })
+            }
+
+            let stream_id = req.stream_id;
+            if !session.streams.contains_key(&stream_id) {
+                bail!(ProtocolError::StreamNotFound { stream_id })
+            }
+
+            let query = stmt::proto_stmt_to_query(&req.stmt, &session.sqls, session.version)
+                .map_err(catch_stmt_error)?;
+            if query.stmt.kind != StmtKind::Read {
+                bail!(ResponseError::SubscriptionNotRead)
+            }
+            let auth = session.authenticated;
+            let key = session.next_subscription_key;
+            session.next_subscription_key += 1;
+
+            let abort_hnd = subscription::spawn(
+                join_set,
+                subscription_ctx,
+                connection_maker,
+                subscription_id,
+                key,
+                query,
+                auth,
+                resp_tx,
+            );
+            session.subscriptions.insert(
+                subscription_id,
+                Subscription {
+                    stream_id,
+                    key,
+                    abort_hnd,
+                },
+            );
+        }
+        proto::Request::Unsubscribe(req) => {
+            ensure_version!(Version::Hrana2, "The `unsubscribe` request");
+            // the subscription may have already ended on its own
+            if let Some(subscription) = session.subscriptions.remove(&req.subscription_id) {
+                subscription.abort_hnd.abort();
+            }
+            respond!(proto::Response::Unsubscribe(proto::UnsubscribeResp {}));
+        }
     }
     Ok(resp_rx)
 }
 
 const MAX_SQL_COUNT: usize = 150;
+const MAX_SUBSCRIPTION_COUNT: usize = 32;
 
 fn stream_spawn<D: Connection>(
     join_set: &mut tokio::task::JoinSet<()>,
+    stream_id: i32,
     stream: Stream<D>,
 ) -> StreamHandle<D> {
     let (job_tx, mut job_rx) = mpsc::channel::<StreamJob<D>>(8);
+    let (closed_tx, mut closed_rx) = watch::channel(false);
     join_set.spawn(async move {
         let mut stream = stream;
         while let Some(job) = job_rx.recv().await {
-            let res = (job.f)(&mut stream).await;
+            let res = if *closed_rx.borrow() {
+                Err(anyhow!(ResponseError::StreamClosed { stream_id }))
+            } else {
+                tokio::select! {
+                    res = (job.f)(&mut stream).instrument(job.span) => res,
+                    // dropping the job interrupts the statement that it runs
+                    _ = closed_rx.wait_for(|closed| *closed) => {
+                        Err(anyhow!(ResponseError::StreamClosed { stream_id }))
+                    }
+                }
+            };
+            if *closed_rx.borrow() {
+                // roll back the transaction of the stream, if any, and release the connection
+                stream.db = None;
+            }
             let _: Result<_, _> = job.resp_tx.send(res);
         }
     });
-    StreamHandle { job_tx }
+    StreamHandle { job_tx, closed_tx }
 }
 
 async fn stream_respond<F, D>(
@@ -312,11 +459,38 @@ async fn stream_respond<F, D>(
     let job = StreamJob {
         f: Box::new(f),
         resp_tx,
+        span: tracing::Span::current(),
     };
     let _: Result<_, _> = stream_hnd.job_tx.send(job).await;
 }
 
-fn catch_stmt_error(err: anyhow::Error) -> anyhow::Error {
+/// Waits until the database reaches the `min_frame_no` of a request, if any.
+async fn wait_min_frame_no(
+    frame_notifier: &watch::Receiver<FrameNo>,
+    min_frame_no: Option<FrameNo>,
+) -> Result<()> {
+    let Some(frame_no) = min_frame_no else {
+        return Ok(());
+    };
+    replication::wait_frame_no(frame_notifier.clone(), frame_no, FRAME_NO_WAIT_TIMEOUT)
+        .await
+        .map_err(|err| match err {
+            crate::Error::FrameNoTimeout(frame_no) => {
+                anyhow!(ResponseError::FrameNoTimeout { frame_no })
+            }
+            err => anyhow!(err),
+        })
+}
+
+/// Returns the frame_no to report in the response of a request executed on `db`.
+fn response_frame_no<D: Connection>(
+    frame_notifier: &watch::Receiver<FrameNo>,
+    db: &D,
+) -> Option<FrameNo> {
+    replication::current_frame_no(frame_notifier).max(db.last_write_frame_no())
+}
+
+pub(super) fn catch_stmt_error(err: anyhow::Error) -> anyhow::Error {
     match err.downcast::<stmt::StmtError>() {
         Ok(stmt_err) => anyhow!(ResponseError::Stmt(stmt_err)),
         Err(err) => err,
@@ -336,8 +510,205 @@ impl ResponseError {
             Self::Auth { source } => source.code(),
             Self::SqlTooMany { .. } => "SQL_STORE_TOO_MANY",
             Self::StreamNotOpen { .. } => "STREAM_NOT_OPEN",
+            Self::StreamClosed { .. } => "STREAM_CLOSED",
+            Self::SubscriptionTooMany { .. } => "SUBSCRIPTION_TOO_MANY",
+            Self::SubscriptionNotRead => "SUBSCRIPTION_NOT_READ",
+            Self::FrameNoTimeout { .. } => "FRAME_NO_TIMEOUT",
             Self::Stmt(err) => err.code(),
             Self::Batch(err) => err.code(),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use sqld_libsql_bindings::wal_hook::TRANSPARENT_METHODS;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::auth::Authorized;
+    use crate::connection::config::DatabaseConfigStore;
+    use crate::connection::libsql::{LibSqlConnection, MakeLibSqlConn};
+    use crate::hrana::proto::Stmt;
+    use crate::stats::Stats;
+
+    /// A session on a database in a temporary directory.
+    struct TestSession {
+        session: Session<LibSqlConnection>,
+        join_set: tokio::task::JoinSet<()>,
+        connection_maker: Arc<dyn MakeConnection<Connection = LibSqlConnection>>,
+        subscription_ctx: SubscriptionContext,
+        _frame_tx: watch::Sender<FrameNo>,
+        _tmp: TempDir,
+    }
+
+    impl TestSession {
+        async fn new() -> Self {
+            let tmp = tempfile::tempdir().unwrap();
+            let connection_maker = MakeLibSqlConn::new(
+                tmp.path().to_path_buf(),
+                &TRANSPARENT_METHODS,
+                || (),
+                Stats::default(),
+                Arc::new(DatabaseConfigStore::new_test()),
+                Default::default(),
+                Vec::new(),
+                u64::MAX,
+                u64::MAX,
+                None,
+            )
+            .await
+            .unwrap();
+
+            let (frame_tx, frame_notifier) = watch::channel(0);
+            let (updates_tx, _) = mpsc::channel(1);
+            let (ended_tx, _) = mpsc::unbounded_channel();
+            Self {
+                session: Session {
+                    authenticated: Authenticated::Authorized(Authorized::FullAccess),
+                    version: Version::Hrana2,
+                    streams: HashMap::new(),
+                    sqls: HashMap::new(),
+                    subscriptions: HashMap::new(),
+                    next_subscription_key: 0,
+                },
+                join_set: tokio::task::JoinSet::new(),
+                connection_maker: Arc::new(connection_maker),
+                subscription_ctx: SubscriptionContext {
+                    frame_notifier,
+                    table_changes: None,
+                    updates_tx,
+                    ended_tx,
+                },
+                _frame_tx: frame_tx,
+                _tmp: tmp,
+            }
+        }
+
+        /// Submits a request, and returns the receiver of its response.
+        async fn submit(
+            &mut self,
+            req: proto::Request,
+        ) -> oneshot::Receiver<Result<proto::Response>> {
+            handle_request(
+                &mut self.session,
+                &mut self.join_set,
+                req,
+                self.connection_maker.clone(),
+                &self.subscription_ctx,
+            )
+            .await
+            .unwrap()
+        }
+
+        async fn request(&mut self, req: proto::Request) -> Result<proto::Response> {
+            self.submit(req).await.await.unwrap()
+        }
+
+        async fn open_stream(&mut self, stream_id: i32) {
+            let req = proto::Request::OpenStream(proto::OpenStreamReq {
+                stream_id,
+                txn_timeout_ms: None,
+            });
+            self.request(req).await.unwrap();
+        }
+    }
+
+    fn stmt(sql: &str) -> Stmt {
+        Stmt {
+            sql: Some(sql.to_string()),
+            sql_id: None,
+            args: Vec::new(),
+            named_args: Vec::new(),
+            want_rows: Some(true),
+        }
+    }
+
+    fn execute_req(stream_id: i32, sql: &str) -> proto::Request {
+        proto::Request::Execute(proto::ExecuteReq {
+            stream_id,
+            stmt: stmt(sql),
+            min_frame_no: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn closing_a_stream_interrupts_its_statement() {
+        let mut session = TestSession::new().await;
+        session.open_stream(1).await;
+        session
+            .request(execute_req(1, "BEGIN IMMEDIATE"))
+            .await
+            .unwrap();
+
+        // a statement that never ends on its own, while the stream holds the write lock
+        let endless = execute_req(
+            1,
+            "WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c) SELECT count(*) FROM c",
+        );
+        let endless = session.submit(endless).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let close = proto::Request::CloseStream(proto::CloseStreamReq { stream_id: 1 });
+        session.request(close).await.unwrap();
+
+        let err = tokio::time::timeout(Duration::from_secs(5), endless)
+            .await
+            .expect("the statement was not cancelled")
+            .unwrap()
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ResponseError>(),
+            Some(ResponseError::StreamClosed { stream_id: 1 })
+        ));
+
+        // the statement stopped and the transaction was rolled back, so the write lock is
+        // released
+        session.open_stream(2).await;
+        let write = async {
+            while session
+                .request(execute_req(2, "CREATE TABLE t (x)"))
+                .await
+                .is_err()
+            {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), write)
+            .await
+            .expect("the write lock of the closed stream was not released");
+    }
+
+    #[tokio::test]
+    async fn subscriptions_only_see_committed_rows() {
+        let mut session = TestSession::new().await;
+        session.open_stream(1).await;
+        for sql in ["CREATE TABLE t (x)", "BEGIN", "INSERT INTO t VALUES (1)"] {
+            session.request(execute_req(1, sql)).await.unwrap();
+        }
+
+        let subscribe = proto::Request::Subscribe(proto::SubscribeReq {
+            subscription_id: 1,
+            stream_id: 1,
+            stmt: stmt("SELECT count(*) FROM t"),
+        });
+        let resp = tokio::time::timeout(Duration::from_secs(5), session.request(subscribe))
+            .await
+            .expect("the subscription waited for the transaction of the stream")
+            .unwrap();
+        let proto::Response::Subscribe(resp) = resp else {
+            panic!("unexpected response to a subscription");
+        };
+        assert!(matches!(
+            resp.result.rows[0][0],
+            crate::hrana::proto::Value::Integer { value: 0 }
+        ));
+
+        // the transaction of the stream is still open
+        session
+            .request(execute_req(1, "INSERT INTO t VALUES (2)"))
+            .await
+            .unwrap();
+        session.request(execute_req(1, "COMMIT")).await.unwrap();
+    }
+}
@@ -0,0 +1,269 @@
+//! Live query subscriptions.
+//!
+//! A subscription registers a read query on a stream. The query is executed once when the
+//! subscription is created, and then re-executed every time a commit may have changed its result.
+//! Fresh results are pushed to the client, but only when they differ from the last result that was
+//! sent.
+//!
+//! The query runs on a connection of its own, rather than on the connection of the stream: it
+//! only sees committed rows, even while the stream is in a transaction, and doesn't hold up the
+//! requests of the client on the stream. The stream only bounds the lifetime of the subscription.
+//!
+//! Commits are detected with the frame notifier of the database. When the database also tracks
+//! the tables modified by each commit, the query is only re-executed if one of the tables it
+//! reads from was modified. A primary records the tables written by its connections, and a replica
+//! those written by the frames it injects.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context as _, Result};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::AbortHandle;
+
+use super::super::stmt;
+use super::proto;
+use super::session::{catch_stmt_error, ResponseError};
+use crate::auth::Authenticated;
+use crate::connection::table_changes::TableChanges;
+use crate::connection::{Connection, MakeConnection};
+use crate::query::{Params, Query};
+use crate::query_analysis::Statement;
+use crate::replication::FrameNo;
+
+/// Everything a Hrana connection needs to run the subscriptions of its session.
+pub(super) struct SubscriptionContext {
+    pub frame_notifier: watch::Receiver<FrameNo>,
+    pub table_changes: Option<Arc<TableChanges>>,
+    /// Subscription updates and errors are sent here, to be forwarded to the client.
+    pub updates_tx: mpsc::Sender<proto::ServerMsg>,
+    /// The id and key of each subscription are sent here when its task ends, so that it can be
+    /// removed from the session.
+    pub ended_tx: mpsc::UnboundedSender<(i32, u64)>,
+}
+
+/// Reports the end of the task of a subscription when dropped, whether the task returned or was
+/// aborted.
+struct EndGuard {
+    ended_tx: mpsc::UnboundedSender<(i32, u64)>,
+    subscription_id: i32,
+    key: u64,
+}
+
+impl Drop for EndGuard {
+    fn drop(&mut self) {
+        let _: Result<_, _> = self.ended_tx.send((self.subscription_id, self.key));
+    }
+}
+
+/// Spawns the task that runs a subscription, on a connection created with `connection_maker`. The
+/// result of the first execution of the query is sent to `resp_tx`. `key` tells apart the
+/// subscriptions that reuse an id, when the end of the task is reported.
+#[allow(clippy::too_many_arguments)]
+pub(super) fn spawn<D: Connection>(
+    join_set: &mut tokio::task::JoinSet<()>,
+    ctx: &SubscriptionContext,
+    connection_maker: Arc<dyn MakeConnection<Connection = D>>,
+    subscription_id: i32,
+    key: u64,
+    query: Query,
+    auth: Authenticated,
+    resp_tx: oneshot::Sender<Result<proto::Response>>,
+) -> AbortHandle {
+    let mut frame_notifier = ctx.frame_notifier.clone();
+    let table_changes = ctx.table_changes.clone();
+    let updates_tx = ctx.updates_tx.clone();
+    let end_guard = EndGuard {
+        ended_tx: ctx.ended_tx.clone(),
+        subscription_id,
+        key,
+    };
+
+    join_set.spawn(async move {
+        let _end_guard = end_guard;
+        // we only care about the commits that happen from now on
+        frame_notifier.borrow_and_update();
+        let mut seen_changes = table_changes.as_ref().map_or(0, |c| c.current());
+
+        let db = connection_maker
+            .create()
+            .await
+            .context("Could not create a database connection");
+        let db = match db {
+            Ok(db) => db,
+            Err(err) => {
+                let _: Result<_, _> = resp_tx.send(Err(err));
+                return;
+            }
+        };
+
+        let with_deps = table_changes.is_some();
+        let (result, deps) = match execute(&db, query.clone(), auth, with_deps).await {
+            Ok(first) => first,
+            Err(err) => {
+                let _: Result<_, _> = resp_tx.send(Err(err));
+                return;
+            }
+        };
+
+        let mut last_hash = hash_result(&result);
+        let resp = proto::Response::Subscribe(proto::SubscribeResp { result });
+        if resp_tx.send(Ok(resp)).is_err() {
+            return;
+        }
+
+        loop {
+            if frame_notifier.changed().await.is_err() {
+                // the database is gone
+                return;
+            }
+
+            if let (Some(table_changes), Some(deps)) = (&table_changes, &deps) {
+                if !table_changes.changed_since(deps, seen_changes) {
+                    continue;
+                }
+            }
+            seen_changes = table_changes.as_ref().map_or(0, |c| c.current());
+
+            let msg = match execute(&db, query.clone(), auth, false).await {
+                Ok((result, _)) => {
+                    let hash = hash_result(&result);
+                    if hash == last_hash {
+                        continue;
+                    }
+                    last_hash = hash;
+                    proto::ServerMsg::SubscriptionUpdate {
+                        subscription_id,
+                        result,
+                    }
+                }
+                Err(err) => match err.downcast_ref::<ResponseError>() {
+                    Some(error) => {
+                        let error = proto::Error {
+                            message: error.to_string(),
+                            code: error.code().into(),
+                        };
+                        let _: Result<_, _> = updates_tx
+                            .send(proto::ServerMsg::SubscriptionError {
+                                subscription_id,
+                                error,
+                            })
+                            .await;
+                        return;
+                    }
+                    None => {
+                        tracing::error!("subscription {subscription_id} failed: {err:?}");
+                        return;
+                    }
+                },
+            };
+
+            if updates_tx.send(msg).await.is_err() {
+                return;
+            }
+        }
+    })
+}
+
+/// Executes the query, and optionally finds the tables that it depends on.
+async fn execute<D: Connection>(
+    db: &D,
+    query: Query,
+    auth: Authenticated,
+    with_deps: bool,
+) -> Result<(proto::StmtResult, Option<HashSet<String>>)> {
+    let deps = if with_deps {
+        query_dependencies(db, auth, &query).await?
+    } else {
+        None
+    };
+    let result = stmt::execute_stmt(db, auth, query)
+        .await
+        .map_err(catch_stmt_error)?;
+
+    Ok((result, deps))
+}
+
+/// Finds the tables that the query reads from, by looking at the b-trees opened by its bytecode.
+/// Views, subqueries and indexes are all resolved to the underlying tables this way.
+///
+/// Returns `None` if the dependencies cannot be determined, for example because the query reads
+/// from a virtual table.
+async fn query_dependencies<D: Connection>(
+    db: &D,
+    auth: Authenticated,
+    query: &Query,
+) -> Result<Option<HashSet<String>>> {
+    let explain = Query {
+        stmt: parse_single(&format!("EXPLAIN {}", query.stmt.stmt))?,
+        params: query.params.clone(),
+        want_rows: true,
+    };
+    let program = stmt::execute_stmt(db, auth, explain)
+        .await
+        .map_err(catch_stmt_error)?;
+
+    // the columns of EXPLAIN are: addr, opcode, p1, p2, p3, p4, p5, comment
+    let mut root_pages = Vec::new();
+    for row in program.rows.iter() {
+        match (row.get(1), row.get(3), row.get(4)) {
+            (Some(proto::Value::Text { value: opcode }), _, _) if &**opcode == "VOpen" => {
+                return Ok(None)
+            }
+            (
+                Some(proto::Value::Text { value: opcode }),
+                Some(proto::Value::Integer { value: root_page }),
+                Some(proto::Value::Integer { value: 0 }),
+            ) if &**opcode == "OpenRead" => root_pages.push(*root_page),
+            _ => (),
+        }
+    }
+
+    if root_pages.is_empty() {
+        return Ok(Some(HashSet::new()));
+    }
+
+    let pages = root_pages
+        .iter()
+        .map(|page| page.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let tables = Query {
+        stmt: parse_single(&format!(
+            "SELECT DISTINCT tbl_name FROM sqlite_schema WHERE rootpage IN ({pages})"
+        ))?,
+        params: Params::Positional(Vec::new()),
+        want_rows: true,
+    };
+    let tables = stmt::execute_stmt(db, auth, tables)
+        .await
+        .map_err(catch_stmt_error)?;
+
+    let mut deps = HashSet::new();
+    for row in tables.rows {
+        match row.into_iter().next() {
+            Some(proto::Value::Text { value }) => deps.insert(value.to_lowercase()),
+            _ => return Ok(None),
+        };
+    }
+
+    Ok(Some(deps))
+}
+
+fn parse_single(sql: &str) -> Result<Statement> {
+    match Statement::parse(sql).next() {
+        Some(stmt) => stmt,
+        None => Err(anyhow!("empty statement")),
+    }
+}
+
+fn hash_result(result: &proto::StmtResult) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    // the result is serialized to JSON anyway before being sent to the client
+    serde_json::to_vec(result)
+        .unwrap_or_default()
+        .hash(&mut hasher);
+    hasher.finish()
+}
@@ -5,10 +5,11 @@ use std::future::Future;
 use std::sync::Arc;
 
 use crate::auth::Authenticated;
-use crate::connection::{Connection, MakeConnection};
+use crate::connection::{with_statement_timeout, Connection, MakeConnection};
 use crate::hrana;
 
 use super::db_factory::MakeConnectionExtractor;
+use super::{RequestMaxStaleness, RequestTimeouts, WriteFrameNo};
 
 #[derive(thiserror::Error, Debug)]
 enum ResponseError {
@@ -27,6 +28,8 @@ pub async fn handle_index() -> hyper::Response<hyper::Body> {
 pub(crate) async fn handle_execute<D: Connection>(
     MakeConnectionExtractor(factory): MakeConnectionExtractor<D>,
     auth: Authenticated,
+    timeouts: RequestTimeouts,
+    RequestMaxStaleness(max_staleness): RequestMaxStaleness,
     req: hyper::Request<hyper::Body>,
 ) -> crate::Result<hyper::Response<hyper::Body>> {
     #[derive(Debug, Deserialize)]
@@ -40,17 +43,21 @@ pub(crate) async fn handle_execute<D: Connection>(
     }
 
     let res = handle_request(factory, req, |db, req_body: ReqBody| async move {
+        db.set_max_staleness(max_staleness);
         let query = hrana::stmt::proto_stmt_to_query(
             &req_body.stmt,
             &HashMap::new(),
             hrana::Version::Hrana1,
         )
         .map_err(catch_stmt_error)?;
-        hrana::stmt::execute_stmt(&db, auth, query)
-            .await
-            .map(|result| RespBody { result })
-            .map_err(catch_stmt_error)
-            .context("Could not execute statement")
+        with_statement_timeout(
+            timeouts.statement,
+            hrana::stmt::execute_stmt(&*db, auth, query),
+        )
+        .await
+        .map(|result| RespBody { result })
+        .map_err(catch_stmt_error)
+        .context("Could not execute statement")
     })
     .await?;
 
@@ -60,6 +67,8 @@ pub(crate) async fn handle_execute<D: Connection>(
 pub(crate) async fn handle_batch<D: Connection>(
     MakeConnectionExtractor(factory): MakeConnectionExtractor<D>,
     auth: Authenticated,
+    timeouts: RequestTimeouts,
+    RequestMaxStaleness(max_staleness): RequestMaxStaleness,
     req: hyper::Request<hyper::Body>,
 ) -> crate::Result<hyper::Response<hyper::Body>> {
     #[derive(Debug, Deserialize)]
@@ -73,16 +82,20 @@ pub(crate) async fn handle_batch<D: Connection>(
     }
 
     let res = handle_request(factory, req, |db, req_body: ReqBody| async move {
+        db.set_max_staleness(max_staleness);
         let pgm = hrana::batch::proto_batch_to_program(
             &req_body.batch,
             &HashMap::new(),
             hrana::Version::Hrana1,
         )
         .map_err(catch_stmt_error)?;
-        hrana::batch::execute_batch(&db, auth, pgm)
-            .await
-            .map(|result| RespBody { result })
-            .context("Could not execute batch")
+        with_statement_timeout(
+            timeouts.statement,
+            hrana::batch::execute_batch(&*db, auth, pgm),
+        )
+        .await
+        .map(|result| RespBody { result })
+        .context("Could not execute batch")
     })
     .await?;
 
@@ -97,7 +110,7 @@ async fn handle_request<ReqBody, RespBody, F, Fut, FT>(
 where
     ReqBody: DeserializeOwned,
     RespBody: Serialize,
-    F: FnOnce(FT::Connection, ReqBody) -> Fut,
+    F: FnOnce(Arc<FT::Connection>, ReqBody) -> Fut,
     Fut: Future<Output = Result<RespBody>>,
     FT: MakeConnection + ?Sized,
 {
@@ -110,9 +123,14 @@ where
             .create()
             .await
             .context("Could not create a database connection")?;
-        let resp_body = f(db, req_body).await?;
+        let db = Arc::new(db);
+        let resp_body = f(db.clone(), req_body).await?;
 
-        Ok(json_response(hyper::StatusCode::OK, &resp_body))
+        let mut resp = json_response(hyper::StatusCode::OK, &resp_body);
+        if let Some(frame_no) = db.last_write_frame_no() {
+            resp.extensions_mut().insert(WriteFrameNo(frame_no));
+        }
+        Ok(resp)
     }
     .await;
 
@@ -144,9 +162,11 @@ fn response_error_response(err: ResponseError) -> hyper::Response<hyper::Body> {
             | StmtError::ResponseTooLarge
             | StmtError::Blocked { .. } => hyper::StatusCode::BAD_REQUEST,
             StmtError::ArgsBothPositionalAndNamed => hyper::StatusCode::NOT_IMPLEMENTED,
-            StmtError::TransactionTimeout | StmtError::TransactionBusy => {
+            StmtError::TransactionTimeout { .. } | StmtError::TransactionBusy => {
                 hyper::StatusCode::SERVICE_UNAVAILABLE
             }
+            StmtError::StatementTimeout => hyper::StatusCode::REQUEST_TIMEOUT,
+            StmtError::WriteQuorumTimeout => hyper::StatusCode::GATEWAY_TIMEOUT,
             StmtError::SqliteError { .. } => hyper::StatusCode::INTERNAL_SERVER_ERROR,
         },
     };
@@ -8,11 +8,13 @@ mod types;
 use std::convert::Infallible;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::Context;
-use axum::extract::{FromRef, FromRequest, FromRequestParts, State as AxumState};
+use axum::extract::{FromRef, FromRequest, FromRequestParts, MatchedPath, State as AxumState};
 use axum::http::request::Parts;
 use axum::http::HeaderValue;
+use axum::middleware::Next;
 use axum::response::{Html, IntoResponse};
 use axum::routing::{get, post};
 use axum::Router;
@@ -30,18 +32,21 @@ use tonic::transport::Server;
 use tower::Service;
 use tower_http::trace::DefaultOnResponse;
 use tower_http::{compression::CompressionLayer, cors};
-use tracing::{Level, Span};
+use tracing::{Instrument as _, Level, Span};
 
 use crate::auth::{Auth, Authenticated};
-use crate::connection::Connection;
+use crate::connection::config::MaxStaleness;
+use crate::connection::{with_statement_timeout, Connection};
 use crate::database::Database;
 use crate::error::Error;
 use crate::hrana;
 use crate::http::types::HttpQuery;
+use crate::metrics;
 use crate::namespace::{MakeNamespace, NamespaceStore};
 use crate::query::{self, Query};
 use crate::query_analysis::{predict_final_state, State, Statement};
 use crate::query_result_builder::QueryResultBuilder;
+use crate::replication::{self, FrameNo, FRAME_NO_WAIT_TIMEOUT};
 use crate::stats::Stats;
 use crate::utils::services::idle_shutdown::IdleShutdownLayer;
 use crate::version;
@@ -114,21 +119,32 @@ fn parse_queries(queries: Vec<QueryObject>) -> crate::Result<Vec<Query>> {
 
 async fn handle_query<D: Connection>(
     auth: Authenticated,
+    timeouts: RequestTimeouts,
+    RequestMaxStaleness(max_staleness): RequestMaxStaleness,
     MakeConnectionExtractor(connection_maker): MakeConnectionExtractor<D>,
     Json(query): Json<HttpQuery>,
 ) -> Result<axum::response::Response, Error> {
     let batch = parse_queries(query.statements)?;
 
     let db = connection_maker.create().await?;
+    db.set_max_staleness(max_staleness);
 
     let builder = JsonHttpPayloadBuilder::new();
-    let (builder, _) = db.execute_batch_or_rollback(batch, auth, builder).await?;
+    let (builder, _) = with_statement_timeout(
+        timeouts.statement,
+        db.execute_batch_or_rollback(batch, auth, builder),
+    )
+    .await?;
 
-    let res = (
+    let mut res = (
         [(header::CONTENT_TYPE, "application/json")],
         builder.into_ret(),
-    );
-    Ok(res.into_response())
+    )
+        .into_response();
+    if let Some(frame_no) = db.last_write_frame_no() {
+        res.extensions_mut().insert(WriteFrameNo(frame_no));
+    }
+    Ok(res)
 }
 
 async fn show_console<F: MakeNamespace>(
@@ -183,15 +199,67 @@ async fn handle_hrana_v2<F: MakeNamespace>(
     >,
     AxumState(state): AxumState<AppState<F>>,
     auth: Authenticated,
+    timeouts: RequestTimeouts,
+    RequestMaxStaleness(max_staleness): RequestMaxStaleness,
     req: Request<Body>,
 ) -> Result<Response<Body>, Error> {
     let server = state.hrana_http_srv;
-
-    let res = server.handle_pipeline(auth, req, connection_maker).await?;
+    let namespace =
+        db_factory::namespace_from_headers(req.headers(), state.disable_default_namespace)?;
+    let namespace = String::from_utf8_lossy(&namespace);
+
+    let res = server
+        .handle_pipeline(
+            auth,
+            timeouts,
+            max_staleness,
+            &namespace,
+            req,
+            connection_maker,
+        )
+        .await?;
 
     Ok(res)
 }
 
+/// Records the count and duration of requests to the routes of the HTTP API, and traces them.
+///
+/// The namespace comes from the `Host` header, so it is only used as a label once the request
+/// has loaded it: requests to namespaces that don't exist are recorded as `unknown`, so that
+/// clients can't create arbitrary series.
+async fn record_request_metrics<F: MakeNamespace>(
+    AxumState(state): AxumState<AppState<F>>,
+    req: Request<Body>,
+    next: Next<Body>,
+) -> axum::response::Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_default();
+    let namespace =
+        db_factory::namespace_from_headers(req.headers(), state.disable_default_namespace).ok();
+
+    let span = tracing::info_span!(
+        "http_request",
+        namespace = %namespace.as_ref().map(|ns| String::from_utf8_lossy(ns)).unwrap_or_default(),
+        %route
+    );
+    let start = Instant::now();
+    let resp = next.run(req).instrument(span).await;
+
+    let namespace = match namespace {
+        Some(ns) if state.namespaces.is_loaded(&ns).await => {
+            String::from_utf8_lossy(&ns).into_owned()
+        }
+        _ => "unknown".to_owned(),
+    };
+    metrics::HTTP_REQUESTS.inc(&[&namespace, &route, resp.status().as_str()]);
+    metrics::HTTP_REQUEST_DURATION.observe(&[&namespace, &route], start.elapsed().as_secs_f64());
+
+    resp
+}
+
 async fn handle_fallback() -> impl IntoResponse {
     (StatusCode::NOT_FOUND).into_response()
 }
@@ -261,18 +329,30 @@ where
         tracing::debug!("got request: {} {}", req.method(), req.uri());
     }
 
+    let frame_no_layer = axum::middleware::from_fn_with_state(state.clone(), handle_frame_no::<F>);
+
     let app = Router::new()
-        .route("/", post(handle_query))
+        .route("/", post(handle_query).layer(frame_no_layer.clone()))
         .route("/", get(handle_upgrade))
         .route("/version", get(handle_version))
         .route("/console", get(show_console))
         .route("/health", get(handle_health))
         .route("/v1/stats", get(stats::handle_stats))
         .route("/v1", get(hrana_over_http_1::handle_index))
-        .route("/v1/execute", post(hrana_over_http_1::handle_execute))
-        .route("/v1/batch", post(hrana_over_http_1::handle_batch))
+        .route(
+            "/v1/execute",
+            post(hrana_over_http_1::handle_execute).layer(frame_no_layer.clone()),
+        )
+        .route(
+            "/v1/batch",
+            post(hrana_over_http_1::handle_batch).layer(frame_no_layer.clone()),
+        )
         .route("/v2", get(crate::hrana::http::handle_index))
-        .route("/v2/pipeline", post(handle_hrana_v2))
+        .route("/v2/pipeline", post(handle_hrana_v2).layer(frame_no_layer))
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            record_request_metrics::<F>,
+        ))
         .with_state(state);
 
     let layered_app = app
@@ -335,6 +415,158 @@ where
     }
 }
 
+/// Header used by clients to set a timeout on the statements of a request, in milliseconds.
+const STATEMENT_TIMEOUT_HEADER: &str = "x-statement-timeout-ms";
+/// Header used by clients to set the timeout of the interactive transactions of a Hrana stream,
+/// in milliseconds.
+const TXN_TIMEOUT_HEADER: &str = "x-txn-timeout-ms";
+
+/// Timeouts requested by the client of a request with the [`STATEMENT_TIMEOUT_HEADER`] and
+/// [`TXN_TIMEOUT_HEADER`] headers.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct RequestTimeouts {
+    /// Can only make statements fail earlier than the server-wide statement timeout.
+    pub statement: Option<Duration>,
+    /// Capped by the transaction timeout limit of the database config.
+    pub txn: Option<Duration>,
+}
+
+#[tonic::async_trait]
+impl<S> FromRequestParts<S> for RequestTimeouts
+where
+    S: Send + Sync,
+{
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self {
+            statement: timeout_header(parts, STATEMENT_TIMEOUT_HEADER)?,
+            txn: timeout_header(parts, TXN_TIMEOUT_HEADER)?,
+        })
+    }
+}
+
+fn timeout_header(parts: &Parts, name: &str) -> Result<Option<Duration>, Error> {
+    let Some(value) = parts.headers.get(name) else {
+        return Ok(None);
+    };
+
+    let timeout = value
+        .to_str()
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .filter(|ms| *ms > 0)
+        .ok_or_else(|| Error::InvalidTimeoutHeader(format!("{name}: {value:?}")))?;
+
+    Ok(Some(Duration::from_millis(timeout)))
+}
+
+/// Header used by clients to bound the time since a replica last caught up with the primary for
+/// their reads to be served by the replica, in milliseconds.
+const MAX_STALENESS_MS_HEADER: &str = "x-max-staleness-ms";
+/// Header used by clients to bound how many frames a replica may be behind the primary for their
+/// reads to be served by the replica.
+const MAX_STALENESS_FRAMES_HEADER: &str = "x-max-staleness-frames";
+
+/// Staleness bounds requested by the client of a request with the [`MAX_STALENESS_MS_HEADER`] and
+/// [`MAX_STALENESS_FRAMES_HEADER`] headers. Reads that a replica can't serve within these bounds
+/// are forwarded to the primary.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct RequestMaxStaleness(pub MaxStaleness);
+
+#[tonic::async_trait]
+impl<S> FromRequestParts<S> for RequestMaxStaleness
+where
+    S: Send + Sync,
+{
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self(MaxStaleness {
+            time: staleness_header(parts, MAX_STALENESS_MS_HEADER)?.map(Duration::from_millis),
+            frames: staleness_header(parts, MAX_STALENESS_FRAMES_HEADER)?,
+        }))
+    }
+}
+
+fn staleness_header(parts: &Parts, name: &str) -> Result<Option<u64>, Error> {
+    let Some(value) = parts.headers.get(name) else {
+        return Ok(None);
+    };
+
+    let bound = value
+        .to_str()
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .ok_or_else(|| Error::InvalidStalenessHeader(format!("{name}: {value:?}")))?;
+
+    Ok(Some(bound))
+}
+
+/// Header of a request that must not be served before the database reaches this frame_no.
+const MIN_FRAME_NO_HEADER: &str = "x-min-frame-no";
+/// Header of a response with the frame_no the database reached after serving the request.
+const FRAME_NO_HEADER: &str = "x-frame-no";
+/// Header of a response served by a replica that is being recovered, and whose reads are stale.
+const REPLICA_RECOVERING_HEADER: &str = "x-replica-recovering";
+
+/// Response extension with the frame_no of a write that the handler performed through a
+/// connection, see [`Connection::last_write_frame_no`].
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct WriteFrameNo(pub FrameNo);
+
+/// Implements consistency tokens for the routes that execute statements: the request waits until
+/// the database reaches the frame_no of the [`MIN_FRAME_NO_HEADER`] header, and the response
+/// carries the frame_no reached after serving it in the [`FRAME_NO_HEADER`] header. Passing the
+/// frame_no of a response to a later request guarantees that the later request sees the writes of
+/// the earlier one, even if they are served by different replicas. Responses served while the
+/// replica is being recovered carry the [`REPLICA_RECOVERING_HEADER`] header.
+async fn handle_frame_no<F: MakeNamespace>(
+    AxumState(state): AxumState<AppState<F>>,
+    req: Request<Body>,
+    next: Next<Body>,
+) -> Result<axum::response::Response, Error> {
+    let min_frame_no = match req.headers().get(MIN_FRAME_NO_HEADER) {
+        Some(value) => Some(
+            value
+                .to_str()
+                .ok()
+                .and_then(|v| v.parse::<FrameNo>().ok())
+                .ok_or_else(|| {
+                    Error::InvalidFrameNoHeader(format!("{MIN_FRAME_NO_HEADER}: {value:?}"))
+                })?,
+        ),
+        None => None,
+    };
+    let namespace =
+        db_factory::namespace_from_headers(req.headers(), state.disable_default_namespace)?;
+    let (frame_notifier, recovering) = state
+        .namespaces
+        .with(namespace, |ns| {
+            (ns.db.frame_notifier(), ns.db.is_recovering())
+        })
+        .await?;
+
+    if let Some(min_frame_no) = min_frame_no {
+        replication::wait_frame_no(frame_notifier.clone(), min_frame_no, FRAME_NO_WAIT_TIMEOUT)
+            .await?;
+    }
+
+    let mut resp = next.run(req).await;
+
+    let write_frame_no = resp.extensions().get::<WriteFrameNo>().map(|f| f.0);
+    if let Some(frame_no) = replication::current_frame_no(&frame_notifier).max(write_frame_no) {
+        resp.headers_mut()
+            .insert(FRAME_NO_HEADER, HeaderValue::from(frame_no));
+    }
+    if recovering {
+        resp.headers_mut()
+            .insert(REPLICA_RECOVERING_HEADER, HeaderValue::from_static("true"));
+    }
+
+    Ok(resp)
+}
+
 impl<F: MakeNamespace> FromRef<AppState<F>> for Arc<Auth> {
     fn from_ref(input: &AppState<F>) -> Self {
         input.auth.clone()
@@ -3,26 +3,26 @@
 use std::convert::Infallible;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::{Path, PathBuf};
-use std::process::Command;
 use std::sync::mpsc::RecvTimeoutError;
 use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::Context as AnyhowContext;
-use bytes::Bytes;
+use cluster::{Cluster, Role};
 use enclose::enclose;
 use futures::never::Never;
 use hyper::Request;
 use libsql::wal_hook::TRANSPARENT_METHODS;
 use namespace::{
-    MakeNamespace, NamespaceStore, PrimaryNamespaceConfig, PrimaryNamespaceMaker,
-    ReplicaNamespaceConfig, ReplicaNamespaceMaker,
+    MakeNamespace, NamespaceEvent, NamespaceStore, PrimaryNamespaceConfig, PrimaryNamespaceMaker,
+    ReplicaLogConfig, ReplicaNamespaceConfig, ReplicaNamespaceMaker,
 };
-use replication::{NamespacedSnapshotCallback, ReplicationLogger};
+use replication::replica::MultiplexedReplication;
+use replication::ReplicationLogger;
 use rpc::replication_log::ReplicationLogService;
-use rpc::{run_rpc_server, ReplicationLogServer};
-use tokio::sync::mpsc;
-use tokio::task::JoinSet;
+use rpc::{run_cluster_rpc_server, run_rpc_server, ReplicationLogServer};
+use tokio::sync::{mpsc, watch};
+use tokio::task::{JoinError, JoinHandle, JoinSet};
 use tonic::body::BoxBody;
 use tonic::transport::Channel;
 use tower::Service;
@@ -32,31 +32,41 @@ use self::connection::config::DatabaseConfigStore;
 use self::connection::libsql::open_db;
 use crate::auth::Auth;
 use crate::error::Error;
+use crate::snapshot_sink::{SnapshotSinkConfig, SnapshotSinkKind, SnapshotSinksOptions};
 use crate::stats::Stats;
 
 use sha256::try_digest;
 
+pub use replication::encryption::{FrameCipher, FrameEncryption};
+pub use replication::inspect;
+pub use replication::primary::quorum::{QuorumTimeoutPolicy, WriteQuorum};
 pub use sqld_libsql_bindings as libsql;
 
 mod admin_api;
 mod auth;
+mod cluster;
 pub mod connection;
 mod database;
+mod delivery_queue;
 mod error;
 mod heartbeat;
 mod hrana;
 mod http;
+mod metrics;
 mod namespace;
 mod query;
 mod query_analysis;
 mod query_result_builder;
 mod replication;
 pub mod rpc;
+mod snapshot_sink;
 mod stats;
+pub mod telemetry;
 #[cfg(test)]
 mod test;
 mod utils;
 pub mod version;
+mod webhook;
 
 const MAX_CONCURRENT_DBS: usize = 128;
 const DB_CREATE_TIMEOUT: Duration = Duration::from_secs(1);
@@ -91,12 +101,24 @@ pub struct Config {
     pub rpc_server_cert: Option<PathBuf>,
     pub rpc_server_key: Option<PathBuf>,
     pub rpc_server_ca_cert: Option<PathBuf>,
+    pub cluster_node_url: Option<String>,
+    pub cluster_peers: Vec<String>,
     pub bottomless_replication: Option<bottomless::replicator::Options>,
+    /// Bottomless backups that fresh replicas bootstrap from, instead of the snapshot of the
+    /// primary.
+    pub bootstrap_from_bottomless: Option<bottomless::replicator::Options>,
+    /// Replicate the namespaces of a replica over a single stream.
+    pub multiplex_replication: bool,
+    /// Replicate all the namespaces of the primary, rather than only the ones that are used.
+    pub replicate_all_namespaces: bool,
     pub idle_shutdown_timeout: Option<Duration>,
     pub initial_idle_shutdown_timeout: Option<Duration>,
     pub load_from_dump: Option<PathBuf>,
     pub max_log_size: u64,
     pub max_log_duration: Option<f32>,
+    /// How long the history of the databases is kept, so that they can be restored as of a
+    /// transaction committed since then.
+    pub history_retention: Option<Duration>,
     pub heartbeat_url: Option<String>,
     pub heartbeat_auth: Option<String>,
     pub heartbeat_period: Duration,
@@ -104,8 +126,15 @@ pub struct Config {
     pub hard_heap_limit_mb: Option<usize>,
     pub max_response_size: u64,
     pub max_total_response_size: u64,
+    pub statement_timeout: Option<Duration>,
     pub snapshot_exec: Option<String>,
+    /// JSON file with the snapshot sinks that namespaces can enable through the admin API.
+    pub snapshot_sinks_file: Option<PathBuf>,
     pub disable_default_namespace: bool,
+    /// Replicas that must acknowledge a commit before it is acknowledged to the client.
+    pub write_quorum: Option<WriteQuorum>,
+    /// Encryption of the frames of the replication logs and snapshots.
+    pub log_encryption: FrameEncryption,
 }
 
 impl Default for Config {
@@ -131,12 +160,18 @@ impl Default for Config {
             rpc_server_cert: None,
             rpc_server_key: None,
             rpc_server_ca_cert: None,
+            cluster_node_url: None,
+            cluster_peers: Vec::new(),
             bottomless_replication: None,
+            bootstrap_from_bottomless: None,
+            multiplex_replication: false,
+            replicate_all_namespaces: false,
             idle_shutdown_timeout: None,
             initial_idle_shutdown_timeout: None,
             load_from_dump: None,
             max_log_size: 200,
             max_log_duration: None,
+            history_retention: None,
             heartbeat_url: None,
             heartbeat_auth: None,
             heartbeat_period: Duration::from_secs(30),
@@ -144,8 +179,12 @@ impl Default for Config {
             hard_heap_limit_mb: None,
             max_response_size: 10 * 1024 * 1024,       // 10MiB
             max_total_response_size: 32 * 1024 * 1024, // 32MiB
+            statement_timeout: None,
             snapshot_exec: None,
+            snapshot_sinks_file: None,
             disable_default_namespace: false,
+            write_quorum: None,
+            log_encryption: FrameEncryption::default(),
         }
     }
 }
@@ -156,8 +195,9 @@ async fn run_service<F, S>(
     join_set: &mut JoinSet<anyhow::Result<()>>,
     idle_shutdown_layer: Option<IdleShutdownLayer>,
     stats: Stats,
-    db_config_store: Arc<DatabaseConfigStore>,
     replication_service: Option<S>,
+    promote: Option<mpsc::Sender<()>>,
+    server_config: Arc<DatabaseConfigStore>,
 ) -> anyhow::Result<()>
 where
     F: MakeNamespace,
@@ -197,7 +237,7 @@ where
         join_set.spawn(http::run_http(
             addr,
             auth,
-            namespaces,
+            namespaces.clone(),
             hrana_upgrade_tx,
             hrana_http_srv.clone(),
             config.enable_http_console,
@@ -221,7 +261,12 @@ where
     }
 
     if let Some(addr) = config.admin_addr {
-        join_set.spawn(admin_api::run_admin_api(addr, db_config_store));
+        join_set.spawn(admin_api::run_admin_api(
+            addr,
+            namespaces,
+            promote,
+            server_config,
+        ));
     }
 
     match &config.heartbeat_url {
@@ -278,7 +323,16 @@ fn get_auth(config: &Config) -> anyhow::Result<Arc<Auth>> {
 }
 
 fn configure_rpc(config: &Config) -> anyhow::Result<(Channel, tonic::transport::Uri)> {
-    let mut endpoint = Channel::from_shared(config.writer_rpc_addr.clone().unwrap())?;
+    let addr = config.writer_rpc_addr.clone().unwrap();
+    let channel = configure_channel(config, &addr)?;
+    let uri = tonic::transport::Uri::from_maybe_shared(addr)?;
+
+    Ok((channel, uri))
+}
+
+/// Creates a channel to another node, with the TLS settings used to connect to the primary.
+fn configure_channel(config: &Config, addr: &str) -> anyhow::Result<Channel> {
+    let mut endpoint = Channel::from_shared(addr.to_owned())?;
     if config.writer_rpc_tls {
         let cert_pem = std::fs::read_to_string(config.writer_rpc_cert.clone().unwrap())?;
         let key_pem = std::fs::read_to_string(config.writer_rpc_key.clone().unwrap())?;
@@ -294,10 +348,7 @@ fn configure_rpc(config: &Config) -> anyhow::Result<(Channel, tonic::transport::
         endpoint = endpoint.tls_config(tls_config)?;
     }
 
-    let channel = endpoint.connect_lazy();
-    let uri = tonic::transport::Uri::from_maybe_shared(config.writer_rpc_addr.clone().unwrap())?;
-
-    Ok((channel, uri))
+    Ok(endpoint.connect_lazy())
 }
 
 async fn start_replica(
@@ -305,25 +356,64 @@ async fn start_replica(
     join_set: &mut JoinSet<anyhow::Result<()>>,
     idle_shutdown_layer: Option<IdleShutdownLayer>,
     stats: Stats,
-    db_config_store: Arc<DatabaseConfigStore>,
-) -> anyhow::Result<()> {
+    promote: Option<mpsc::Sender<()>>,
+    cluster: Option<Arc<Cluster>>,
+    server_config: Arc<DatabaseConfigStore>,
+) -> anyhow::Result<Arc<NamespaceStore<ReplicaNamespaceMaker>>> {
     let (channel, uri) = configure_rpc(config)?;
     let extensions = validate_extensions(config.extensions_path.clone())?;
     let (hard_reset_snd, mut hard_reset_rcv) = mpsc::channel(1);
+    let multiplex = config.multiplex_replication.then(|| {
+        MultiplexedReplication::new(
+            channel.clone(),
+            uri.clone(),
+            config.replicate_all_namespaces,
+        )
+    });
     let conf = ReplicaNamespaceConfig {
         base_path: config.db_path.to_owned(),
         channel,
         uri,
         extensions,
         stats: stats.clone(),
-        config_store: db_config_store.clone(),
         max_response_size: config.max_response_size,
         max_total_response_size: config.max_total_response_size,
+        statement_timeout: config.statement_timeout,
         hard_reset: hard_reset_snd,
+        // a replica keeps a replication log only when it serves other replicas
+        replica_log: config.rpc_server_addr.map(|_| ReplicaLogConfig {
+            max_log_size: config.max_log_size,
+            max_log_duration: config.max_log_duration.map(Duration::from_secs_f32),
+            log_encryption: config.log_encryption.clone(),
+        }),
+        bootstrap_from_bottomless: config.bootstrap_from_bottomless.clone(),
+        multiplex: multiplex.as_ref().map(|(multiplex, _)| multiplex.clone()),
+        server_config: server_config.clone(),
     };
     let factory = ReplicaNamespaceMaker::new(conf);
     let namespaces = Arc::new(NamespaceStore::new(factory));
 
+    if let Some((multiplex, namespace_events)) = multiplex {
+        join_set.spawn(multiplex.run());
+        join_set.spawn(sync_replica_namespaces(
+            namespaces.clone(),
+            namespace_events,
+        ));
+    }
+
+    if let Some(ref addr) = config.rpc_server_addr {
+        join_set.spawn(run_rpc_server(
+            *addr,
+            config.rpc_server_tls,
+            config.rpc_server_cert.clone(),
+            config.rpc_server_key.clone(),
+            config.rpc_server_ca_cert.clone(),
+            idle_shutdown_layer.clone(),
+            namespaces.clone(),
+            cluster,
+        ));
+    }
+
     // start the hard reset monitor
     join_set.spawn({
         let namespaces = namespaces.clone();
@@ -340,17 +430,84 @@ async fn start_replica(
         }
     });
 
+    let logger_service = match config.rpc_server_addr {
+        Some(_) => Some(ReplicationLogServer::new(ReplicationLogService::new(
+            namespaces.clone(),
+            idle_shutdown_layer.clone(),
+            Some(get_auth(config)?),
+        ))),
+        None => None,
+    };
     run_service(
-        namespaces,
+        namespaces.clone(),
         config,
         join_set,
         idle_shutdown_layer,
         stats,
-        db_config_store,
-        None::<ReplicationLogServer<ReplicationLogService>>,
+        logger_service,
+        promote,
+        server_config,
     )
     .await?;
 
+    Ok(namespaces)
+}
+
+/// Creates and deletes the namespaces of a replica as they are on the primary, so that they are
+/// replicated before they receive requests.
+async fn sync_replica_namespaces(
+    namespaces: Arc<NamespaceStore<ReplicaNamespaceMaker>>,
+    mut events: mpsc::UnboundedReceiver<NamespaceEvent>,
+) -> anyhow::Result<()> {
+    while let Some(event) = events.recv().await {
+        let res = match event {
+            NamespaceEvent::Created(name) => namespaces.with(name, |_| ()).await,
+            NamespaceEvent::Deleted(name) => namespaces.destroy(name).await.map(|_| ()),
+        };
+        if let Err(e) = res {
+            tracing::error!("failed to sync replica namespace: {e}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Prepares the databases of a replica to be opened by a primary.
+fn promote_databases(config: &Config) -> anyhow::Result<()> {
+    let dbs_path = config.db_path.join("dbs");
+    if !dbs_path.try_exists()? {
+        return Ok(());
+    }
+
+    for entry in std::fs::read_dir(dbs_path)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            replication::replica::prepare_promotion(
+                &entry.path(),
+                config.max_log_size,
+                config.max_log_duration.map(Duration::from_secs_f32),
+                config.log_encryption.clone(),
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Prepares the databases of a primary to be opened by a replica of another node.
+fn demote_databases(config: &Config) -> anyhow::Result<()> {
+    let dbs_path = config.db_path.join("dbs");
+    if !dbs_path.try_exists()? {
+        return Ok(());
+    }
+
+    for entry in std::fs::read_dir(dbs_path)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            replication::replica::prepare_demotion(&entry.path())?;
+        }
+    }
+
     Ok(())
 }
 
@@ -433,29 +590,68 @@ pub async fn init_bottomless_replicator(
     Ok((replicator, did_recover))
 }
 
+/// Makes sure that the current bottomless generation records where its snapshot is in the
+/// replication log, so that replicas can bootstrap from it. Otherwise, the database is
+/// checkpointed and a new generation is started from it. Must be called before any write to the
+/// database.
+pub async fn record_bottomless_replication_index(
+    replicator: &mut bottomless::replicator::Replicator,
+    db_path: &Path,
+    logger: &ReplicationLogger,
+) -> anyhow::Result<()> {
+    let log_id = logger.database_id()?;
+    let generation = replicator.generation();
+    match replicator.get_replication_index(&generation).await {
+        Ok(Some((id, _))) if id == log_id => return Ok(()),
+        Ok(_) => (),
+        Err(e) => tracing::warn!("invalid replication index for generation {generation}: {e}"),
+    }
+
+    tracing::info!("starting a new bottomless generation that replicas can bootstrap from");
+    let data_path = db_path.join("data");
+    let wal_path = db_path.join("data-wal");
+    if wal_path.metadata().map_or(false, |m| m.len() > 0) {
+        replication::primary::logger::checkpoint_wal(&data_path)?;
+    }
+    replicator.new_generation();
+    replicator.snapshot_main_db_file().await?;
+    let next_frame_no = *logger.new_frame_notifier.borrow();
+    replicator
+        .store_replication_index(log_id, next_frame_no)
+        .await?;
+
+    Ok(())
+}
+
 async fn start_primary(
     config: &Config,
     join_set: &mut JoinSet<anyhow::Result<()>>,
     idle_shutdown_layer: Option<IdleShutdownLayer>,
     stats: Stats,
-    config_store: Arc<DatabaseConfigStore>,
     db_is_dirty: bool,
-    snapshot_callback: NamespacedSnapshotCallback,
-) -> anyhow::Result<()> {
+    snapshot_sinks: Arc<SnapshotSinksOptions>,
+    cluster: Option<Arc<Cluster>>,
+    server_config: Arc<DatabaseConfigStore>,
+) -> anyhow::Result<Arc<NamespaceStore<PrimaryNamespaceMaker>>> {
     let extensions = validate_extensions(config.extensions_path.clone())?;
     let conf = PrimaryNamespaceConfig {
         base_path: config.db_path.to_owned(),
         max_log_size: config.max_log_size,
         db_is_dirty,
         max_log_duration: config.max_log_duration.map(Duration::from_secs_f32),
-        snapshot_callback,
+        history_retention: config.history_retention,
+        snapshot_sinks,
         bottomless_replication: config.bottomless_replication.clone(),
         extensions,
         stats: stats.clone(),
-        config_store: config_store.clone(),
         max_response_size: config.max_response_size,
         load_from_dump: None,
         max_total_response_size: config.max_total_response_size,
+        statement_timeout: config.statement_timeout,
+        write_quorum: config.write_quorum,
+        log_encryption: config.log_encryption.clone(),
+        lease: cluster.as_ref().map(|cluster| cluster.lease()),
+        server_config: server_config.clone(),
     };
     let factory = PrimaryNamespaceMaker::new(conf);
     let namespaces = Arc::new(NamespaceStore::new(factory));
@@ -469,6 +665,7 @@ async fn start_primary(
             config.rpc_server_ca_cert.clone(),
             idle_shutdown_layer.clone(),
             namespaces.clone(),
+            cluster,
         ));
     }
 
@@ -483,12 +680,13 @@ async fn start_primary(
         join_set,
         idle_shutdown_layer,
         stats,
-        config_store,
         Some(ReplicationLogServer::new(logger_service)),
+        None,
+        server_config,
     )
     .await?;
 
-    Ok(())
+    Ok(namespaces)
 }
 
 async fn run_periodic_compactions(logger: Arc<ReplicationLogger>) -> anyhow::Result<()> {
@@ -557,6 +755,38 @@ async fn run_storage_monitor(db_path: PathBuf, stats: Stats) -> anyhow::Result<(
     Ok(())
 }
 
+/// The namespaces served by the node, in its current role.
+enum Namespaces {
+    Primary(Arc<NamespaceStore<PrimaryNamespaceMaker>>),
+    Replica(Arc<NamespaceStore<ReplicaNamespaceMaker>>),
+}
+
+impl Namespaces {
+    async fn shutdown(&self) {
+        match self {
+            Namespaces::Primary(namespaces) => namespaces.shutdown().await,
+            Namespaces::Replica(namespaces) => namespaces.shutdown().await,
+        }
+    }
+}
+
+/// Resolves when the role of the node in its cluster changes, never if it isn't in a cluster.
+async fn wait_role_change(
+    receiver: &mut Option<watch::Receiver<Role>>,
+) -> Result<(), watch::error::RecvError> {
+    match receiver {
+        Some(receiver) => receiver.changed().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn wait_task<T>(task: &mut Option<JoinHandle<T>>) -> Option<Result<T, JoinError>> {
+    match task {
+        Some(handle) => Some(handle.await),
+        None => std::future::pending().await,
+    }
+}
+
 fn sentinel_file_path(path: &Path) -> PathBuf {
     path.join(".sentinel")
 }
@@ -575,7 +805,7 @@ fn init_sentinel_file(path: &Path) -> anyhow::Result<bool> {
     Ok(false)
 }
 
-pub async fn run_server(config: Config) -> anyhow::Result<()> {
+pub async fn run_server(mut config: Config) -> anyhow::Result<()> {
     tracing::trace!("Backend: {:?}", config.backend);
 
     if config.bottomless_replication.is_some() {
@@ -595,12 +825,53 @@ pub async fn run_server(config: Config) -> anyhow::Result<()> {
         };
     }
 
+    if !config.db_path.exists() {
+        std::fs::create_dir_all(&config.db_path)?;
+    }
+
+    // The election outlives the changes of role of the node, so it doesn't run in the join set.
+    let (cluster, mut cluster_task) = match config.cluster_node_url {
+        Some(ref node_url) => {
+            anyhow::ensure!(
+                config.rpc_server_addr.is_some(),
+                "cluster mode requires the RPC server to listen"
+            );
+            let channels = config
+                .cluster_peers
+                .iter()
+                .map(|peer| configure_channel(&config, peer))
+                .collect::<anyhow::Result<_>>()?;
+            let cluster = Arc::new(Cluster::new(node_url.clone(), channels, &config.db_path)?);
+            let task = tokio::spawn(cluster.clone().run());
+            (Some(cluster), Some(task))
+        }
+        None => (None, None),
+    };
+    let mut role_receiver = cluster.as_ref().map(|cluster| cluster.subscribe());
+
     loop {
         if !config.db_path.exists() {
             std::fs::create_dir_all(&config.db_path)?;
         }
         let mut join_set = JoinSet::new();
 
+        // The role of a node of a cluster decides whether it runs as a primary or a replica.
+        let role = match role_receiver {
+            Some(ref mut receiver) => Some(receiver.borrow_and_update().clone()),
+            None => None,
+        };
+        match role {
+            Some(Role::Leader) => {
+                promote_databases(&config).context("Could not promote databases")?;
+                config.writer_rpc_addr = None;
+            }
+            Some(Role::Follower { ref leader_url }) => {
+                demote_databases(&config).context("Could not demote databases")?;
+                config.writer_rpc_addr = Some(leader_url.clone());
+            }
+            Some(Role::Unknown) | None => (),
+        }
+
         let (shutdown_sender, mut shutdown_receiver) = tokio::sync::mpsc::channel::<()>(1);
 
         join_set.spawn({
@@ -623,18 +894,26 @@ pub async fn run_server(config: Config) -> anyhow::Result<()> {
 
         let db_is_dirty = init_sentinel_file(&config.db_path)?;
 
-        let snapshot_exec = config.snapshot_exec.clone();
-        let snapshot_callback = Arc::new(move |snapshot_file: &Path, namespace: &Bytes| {
-            if let Some(exec) = snapshot_exec.as_ref() {
-                let ns = std::str::from_utf8(namespace)?;
-                let status = Command::new(exec).arg(snapshot_file).arg(ns).status()?;
-                anyhow::ensure!(
-                    status.success(),
-                    "Snapshot exec process failed with status {status}"
-                );
-            }
-            Ok(())
-        });
+        // `--snapshot-exec` is a snapshot sink of every namespace
+        let mut snapshot_sinks = SnapshotSinksOptions {
+            default_sinks: config
+                .snapshot_exec
+                .iter()
+                .map(|command| SnapshotSinkConfig {
+                    name: "snapshot-exec".into(),
+                    kind: SnapshotSinkKind::Exec {
+                        command: command.clone(),
+                    },
+                })
+                .collect(),
+            declared_sinks: Vec::new(),
+        };
+        if let Some(path) = &config.snapshot_sinks_file {
+            snapshot_sinks.read_declared_sinks(path).with_context(|| {
+                format!("Could not read snapshot sinks from {}", path.display())
+            })?;
+        }
+        let snapshot_sinks = Arc::new(snapshot_sinks);
 
         let idle_shutdown_layer = config.idle_shutdown_timeout.map(|d| {
             IdleShutdownLayer::new(
@@ -646,34 +925,52 @@ pub async fn run_server(config: Config) -> anyhow::Result<()> {
 
         let stats = Stats::new(&config.db_path)?;
 
-        let db_config_store = Arc::new(
+        // the config at the root of the data directory blocks every namespace
+        let server_config = Arc::new(
             DatabaseConfigStore::load(&config.db_path).context("Could not load database config")?,
         );
 
-        match config.writer_rpc_addr {
-            Some(_) => {
+        let (promote_sender, mut promote_receiver) = mpsc::channel(1);
+        let mut namespaces = match (&role, &config.writer_rpc_addr) {
+            (Some(Role::Unknown), _) => {
+                // until a leader is elected, the node only takes part in the election
+                join_set.spawn(run_cluster_rpc_server(
+                    config.rpc_server_addr.unwrap(),
+                    config.rpc_server_tls,
+                    config.rpc_server_cert.clone(),
+                    config.rpc_server_key.clone(),
+                    config.rpc_server_ca_cert.clone(),
+                    cluster.clone().unwrap(),
+                ));
+                None
+            }
+            (_, Some(_)) => Some(Namespaces::Replica(
                 start_replica(
                     &config,
                     &mut join_set,
                     idle_shutdown_layer,
                     stats.clone(),
-                    db_config_store,
+                    // the leader of a cluster is elected, not promoted
+                    cluster.is_none().then_some(promote_sender),
+                    cluster.clone(),
+                    server_config,
                 )
-                .await?
-            }
-            None => {
+                .await?,
+            )),
+            (_, None) => Some(Namespaces::Primary(
                 start_primary(
                     &config,
                     &mut join_set,
                     idle_shutdown_layer,
                     stats.clone(),
-                    db_config_store,
                     db_is_dirty,
-                    snapshot_callback,
+                    snapshot_sinks,
+                    cluster.clone(),
+                    server_config,
                 )
-                .await?
-            }
-        }
+                .await?,
+            )),
+        };
 
         if config.heartbeat_url.is_some() {
             join_set.spawn(run_storage_monitor(config.db_path.clone(), stats));
@@ -687,6 +984,40 @@ pub async fn run_server(config: Config) -> anyhow::Result<()> {
                     std::fs::remove_file(sentinel_file_path(&config.db_path))?;
                     return Ok(())
                 }
+                Some(()) = promote_receiver.recv() => {
+                    tracing::info!("promoting replica to primary");
+                    join_set.shutdown().await;
+                    if let Some(namespaces) = namespaces.take() {
+                        namespaces.shutdown().await;
+                    }
+                    // the replica was shut down cleanly
+                    std::fs::remove_file(sentinel_file_path(&config.db_path))?;
+                    match promote_databases(&config) {
+                        // start over as a primary
+                        Ok(()) => config.writer_rpc_addr = None,
+                        Err(e) => {
+                            tracing::error!("could not promote replica, restarting as a replica: {e:?}");
+                            // the databases promoted so far resume replicating where they stopped
+                            demote_databases(&config)
+                                .context("Could not restore replica after failed promotion")?;
+                        }
+                    }
+                    break;
+                }
+                Ok(()) = wait_role_change(&mut role_receiver) => {
+                    // Stop serving with the previous role before taking the new one. This is what
+                    // keeps a deposed leader from accepting writes.
+                    join_set.shutdown().await;
+                    if let Some(namespaces) = namespaces.take() {
+                        namespaces.shutdown().await;
+                    }
+                    std::fs::remove_file(sentinel_file_path(&config.db_path))?;
+                    break;
+                }
+                Some(res) = wait_task(&mut cluster_task) => {
+                    res.context("Cluster election crashed")??;
+                    return Ok(())
+                }
                 Some(res) = join_set.join_next() => {
                     res??;
                 },
@@ -9,7 +9,10 @@ use anyhow::{bail, Context as _, Result};
 use bytesize::ByteSize;
 use clap::Parser;
 use mimalloc::MiMalloc;
-use sqld::{connection::dump::exporter::export_dump, version::Version, Config};
+use sqld::{
+    connection::dump::exporter::export_dump, inspect, inspect::RestorePoint, version::Version,
+    Config, FrameCipher, FrameEncryption, QuorumTimeoutPolicy, WriteQuorum,
+};
 use tracing_subscriber::filter::LevelFilter;
 use tracing_subscriber::prelude::__tracing_subscriber_SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
@@ -48,6 +51,11 @@ struct Cli {
     #[clap(long, env = "SQLD_ADMIN_LISTEN_ADDR")]
     admin_listen_addr: Option<SocketAddr>,
 
+    /// The URL of an OpenTelemetry collector to export traces to over OTLP/gRPC, such as
+    /// `http://localhost:4317`. Traces are not exported by default.
+    #[clap(long, env = "SQLD_OTLP_ENDPOINT")]
+    otlp_endpoint: Option<String>,
+
     /// Path to a file with a JWT decoding key used to authenticate clients in the Hrana and HTTP
     /// APIs. The key is either a PKCS#8-encoded Ed25519 public key in PEM, or just plain bytes of
     /// the Ed25519 public key in URL-safe base64.
@@ -65,11 +73,8 @@ struct Cli {
     http_self_url: Option<String>,
 
     /// The address and port the inter-node RPC protocol listens to. Example: `0.0.0.0:5001`.
-    #[clap(
-        long,
-        conflicts_with = "primary_grpc_url",
-        env = "SQLD_GRPC_LISTEN_ADDR"
-    )]
+    /// On a replica, this serves the replication log to other replicas.
+    #[clap(long, env = "SQLD_GRPC_LISTEN_ADDR")]
     grpc_listen_addr: Option<SocketAddr>,
     #[clap(
         long,
@@ -102,6 +107,27 @@ struct Cli {
     #[clap(long)]
     primary_grpc_ca_cert_file: Option<PathBuf>,
 
+    /// Run as a node of a cluster that elects its primary, at the given gRPC URL through which the
+    /// other nodes reach this node. Example: `http://10.0.0.1:5001`. Requires
+    /// `--grpc-listen-addr`. The `--primary-grpc-*` TLS options are used to connect to the other
+    /// nodes.
+    #[clap(
+        long,
+        env = "SQLD_CLUSTER_NODE_URL",
+        requires = "grpc_listen_addr",
+        conflicts_with = "primary_grpc_url",
+        conflicts_with = "load_from_dump"
+    )]
+    cluster_node_url: Option<String>,
+    /// The gRPC URLs of the other nodes of the cluster, separated by commas.
+    #[clap(
+        long,
+        env = "SQLD_CLUSTER_PEERS",
+        value_delimiter = ',',
+        requires = "cluster_node_url"
+    )]
+    cluster_peers: Vec<String>,
+
     #[clap(
         long,
         short,
@@ -116,6 +142,23 @@ struct Cli {
     no_welcome: bool,
     #[clap(long, env = "SQLD_ENABLE_BOTTOMLESS_REPLICATION")]
     enable_bottomless_replication: bool,
+    /// On a replica, bootstrap fresh databases from the bottomless backups of the primary, rather
+    /// than from a snapshot sent by the primary. Bottomless is configured with the same
+    /// environment variables as on the primary.
+    #[clap(long, env = "SQLD_BOOTSTRAP_FROM_BOTTOMLESS")]
+    bootstrap_from_bottomless: bool,
+    /// On a replica, replicate all the namespaces over a single stream to the primary, rather than
+    /// each namespace over its own.
+    #[clap(long, env = "SQLD_MULTIPLEX_REPLICATION")]
+    multiplex_replication: bool,
+    /// On a replica, replicate all the namespaces of the primary, as they are created, rather than
+    /// only the namespaces that receive requests. Requires `--multiplex-replication`.
+    #[clap(
+        long,
+        env = "SQLD_REPLICATE_ALL_NAMESPACES",
+        requires = "multiplex_replication"
+    )]
+    replicate_all_namespaces: bool,
     /// The duration, in second, after which to shutdown the server if no request have been
     /// received.
     /// By default, the server doesn't shutdown when idle.
@@ -142,6 +185,34 @@ struct Cli {
     /// `--max-log-size`.
     #[clap(long, env = "SQLD_MAX_LOG_DURATION")]
     max_log_duration: Option<f32>,
+    /// How long the history of the databases is kept (in seconds), so that they can be restored
+    /// as of any transaction committed since then. By default, snapshots are merged as soon as
+    /// they take too much space, and the database can only be restored as of the last merge.
+    #[clap(
+        long,
+        env = "SQLD_HISTORY_RETENTION",
+        conflicts_with = "primary_grpc_url"
+    )]
+    history_retention: Option<u64>,
+
+    /// Number of replicas that must confirm they applied a commit before the primary acknowledges
+    /// it to the client (semi-synchronous replication). By default, commits don't wait for the
+    /// replicas.
+    #[clap(long, env = "SQLD_WRITE_QUORUM", conflicts_with = "primary_grpc_url")]
+    write_quorum: Option<usize>,
+    /// How long, in milliseconds, a commit waits for the replicas of `--write-quorum`.
+    #[clap(long, env = "SQLD_WRITE_QUORUM_TIMEOUT_MS", default_value = "1000")]
+    write_quorum_timeout_ms: u64,
+    /// What to do when the replicas don't acknowledge a commit in time: `fail` reports an error to
+    /// the client (the write stays committed on the primary), `async` acknowledges the commit and
+    /// stops waiting for the replicas until they catch up.
+    #[clap(
+        long,
+        value_enum,
+        env = "SQLD_WRITE_QUORUM_ON_TIMEOUT",
+        default_value = "async"
+    )]
+    write_quorum_on_timeout: QuorumTimeoutPolicy,
 
     #[clap(subcommand)]
     utils: Option<UtilsSubcommands>,
@@ -179,9 +250,33 @@ struct Cli {
     #[clap(long, env = "SQLD_MAX_TOTAL_RESPONSE_SIZE", default_value = "32MB")]
     max_total_response_size: ByteSize,
 
-    /// Set a command to execute when a snapshot file is generated.
+    /// The duration, in milliseconds, after which a running statement is interrupted and fails with
+    /// a timeout error. By default, statements can run for as long as they need.
+    #[clap(long, env = "SQLD_STATEMENT_TIMEOUT_MS")]
+    statement_timeout_ms: Option<u64>,
+
+    /// Path to a file with the base64 encoded 256 bits key that the frames of the replication logs
+    /// and snapshots are encrypted with. The key can also be passed with the
+    /// `SQLD_LOG_ENCRYPTION_KEY` env variable.
+    #[clap(long, env = "SQLD_LOG_ENCRYPTION_KEY_FILE")]
+    log_encryption_key_file: Option<PathBuf>,
+
+    /// Path to a file with the key that the replication logs and snapshots were encrypted with
+    /// before the key was rotated, so that they can be encrypted again with the new key. The key
+    /// can also be passed with the `SQLD_PREVIOUS_LOG_ENCRYPTION_KEY` env variable.
+    #[clap(long, env = "SQLD_PREVIOUS_LOG_ENCRYPTION_KEY_FILE")]
+    previous_log_encryption_key_file: Option<PathBuf>,
+
+    /// Set a command to execute when a snapshot file is generated, with the path of the snapshot
+    /// file and the namespace as arguments. It is a snapshot sink of every namespace.
     #[clap(long, env = "SQLD_SNAPSHOT_EXEC")]
     snapshot_exec: Option<String>,
+
+    /// Path to a JSON file with the snapshot sinks that namespaces can enable through the admin
+    /// API, by name. Sinks that copy snapshots to a directory or run a command can only be
+    /// declared there.
+    #[clap(long, env = "SQLD_SNAPSHOT_SINKS_FILE")]
+    snapshot_sinks_file: Option<PathBuf>,
     /// By default, all request for which a namespace can't be determined fallaback to the default
     /// namespace `default`. This flag disables that.
     #[clap(long)]
@@ -197,6 +292,39 @@ enum UtilsSubcommands {
         #[clap(long)]
         namespace: String,
     },
+    /// Print the header of the replication log of a namespace
+    LogHeader {
+        #[clap(long)]
+        namespace: String,
+    },
+    /// List the frames of the replication log of a namespace, and verify their checksums
+    LogFrames {
+        #[clap(long)]
+        namespace: String,
+    },
+    /// List the snapshots of a namespace
+    Snapshots {
+        #[clap(long)]
+        namespace: String,
+    },
+    /// Write the database of a namespace, as of a frame or a point in time, to a new SQLite
+    /// database file, from its snapshots and replication log
+    Materialize {
+        #[clap(long)]
+        namespace: String,
+        /// Path at which to write the database
+        #[clap(long)]
+        path: PathBuf,
+        /// Frame at which to materialize the database: the database contains the transactions
+        /// committed at or before it. Defaults to the last frame of the log.
+        #[clap(long)]
+        frame_no: Option<u64>,
+        /// UTC timestamp at which to materialize the database: the database contains the
+        /// transactions committed at or before it, up to the end of the last snapshot before it if
+        /// it falls in a snapshot.
+        #[clap(long, conflicts_with = "frame_no")]
+        utc_time: Option<chrono::DateTime<chrono::Utc>>,
+    },
 }
 
 impl Cli {
@@ -229,10 +357,16 @@ impl Cli {
 
         eprint!("\t- mode: ");
         match (&self.grpc_listen_addr, &self.primary_grpc_url) {
+            (Some(addr), None) if self.cluster_node_url.is_some() => eprintln!(
+                "cluster node ({addr}), with {} peers",
+                self.cluster_peers.len()
+            ),
             (None, None) => eprintln!("standalone"),
             (Some(addr), None) => eprintln!("primary ({addr})"),
             (None, Some(url)) => eprintln!("replica (primary at {url})"),
-            _ => unreachable!("invalid configuration!"),
+            (Some(addr), Some(url)) => {
+                eprintln!("replica (primary at {url}), serving replicas ({addr})")
+            }
         };
         eprintln!("\t- database path: {}", self.db_path.display());
         let extensions_str = self.extensions_path.clone().map_or("<disabled>".to_string(), |x| x.display().to_string());
@@ -256,6 +390,11 @@ fn config_from_args(args: Cli) -> Result<Config> {
         }
     };
 
+    let log_encryption = log_encryption_from_args(
+        args.log_encryption_key_file,
+        args.previous_log_encryption_key_file,
+    )?;
+
     Ok(Config {
         db_path: args.db_path,
         extensions_path: args.extensions_path,
@@ -277,11 +416,20 @@ fn config_from_args(args: Cli) -> Result<Config> {
         rpc_server_cert: args.grpc_cert_file,
         rpc_server_key: args.grpc_key_file,
         rpc_server_ca_cert: args.grpc_ca_cert_file,
+        cluster_node_url: args.cluster_node_url,
+        cluster_peers: args.cluster_peers,
         bottomless_replication: if args.enable_bottomless_replication {
             Some(bottomless::replicator::Options::from_env()?)
         } else {
             None
         },
+        bootstrap_from_bottomless: if args.bootstrap_from_bottomless {
+            Some(bottomless::replicator::Options::from_env()?)
+        } else {
+            None
+        },
+        multiplex_replication: args.multiplex_replication,
+        replicate_all_namespaces: args.replicate_all_namespaces,
         idle_shutdown_timeout: args.idle_shutdown_timeout_s.map(Duration::from_secs),
         initial_idle_shutdown_timeout: args
             .initial_idle_shutdown_timeout_s
@@ -289,6 +437,7 @@ fn config_from_args(args: Cli) -> Result<Config> {
         load_from_dump: args.load_from_dump,
         max_log_size: args.max_log_size,
         max_log_duration: args.max_log_duration,
+        history_retention: args.history_retention.map(Duration::from_secs),
         heartbeat_url: args.heartbeat_url,
         heartbeat_auth: args.heartbeat_auth,
         heartbeat_period: Duration::from_secs(args.heartbeat_period_s),
@@ -296,11 +445,53 @@ fn config_from_args(args: Cli) -> Result<Config> {
         hard_heap_limit_mb: args.hard_heap_limit_mb,
         max_response_size: args.max_response_size.0,
         max_total_response_size: args.max_total_response_size.0,
+        statement_timeout: args.statement_timeout_ms.map(Duration::from_millis),
         snapshot_exec: args.snapshot_exec,
+        snapshot_sinks_file: args.snapshot_sinks_file,
         disable_default_namespace: args.disable_default_namespace,
+        write_quorum: args.write_quorum.map(|replicas| WriteQuorum {
+            replicas,
+            timeout: Duration::from_millis(args.write_quorum_timeout_ms),
+            on_timeout: args.write_quorum_on_timeout,
+        }),
+        log_encryption,
     })
 }
 
+fn log_encryption_from_args(
+    key_file: Option<PathBuf>,
+    previous_key_file: Option<PathBuf>,
+) -> Result<FrameEncryption> {
+    let encryption = match read_log_encryption_key(key_file, "SQLD_LOG_ENCRYPTION_KEY")? {
+        Some(key) => FrameEncryption::new(
+            FrameCipher::from_base64(&key).context("Invalid log encryption key")?,
+        ),
+        None => FrameEncryption::default(),
+    };
+    match read_log_encryption_key(previous_key_file, "SQLD_PREVIOUS_LOG_ENCRYPTION_KEY")? {
+        Some(key) => Ok(encryption.with_previous_key(
+            FrameCipher::from_base64(&key).context("Invalid previous log encryption key")?,
+        )),
+        None => Ok(encryption),
+    }
+}
+
+fn read_log_encryption_key(key_file: Option<PathBuf>, env_var: &str) -> Result<Option<String>> {
+    if let Some(file_path) = key_file {
+        let data =
+            fs::read_to_string(file_path).context("Could not read file with log encryption key")?;
+        return Ok(Some(data));
+    }
+
+    match env::var(env_var) {
+        Ok(key) => Ok(Some(key)),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(env::VarError::NotUnicode(_)) => {
+            bail!("Env variable {env_var} does not contain a valid Unicode value")
+        }
+    }
+}
+
 fn perform_dump(dump_path: Option<&Path>, db_path: &Path) -> anyhow::Result<()> {
     let out: Box<dyn Write> = match dump_path {
         Some(path) => {
@@ -337,8 +528,17 @@ fn enable_libsql_logging() {
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let args = Cli::parse();
+
     let registry = tracing_subscriber::registry();
 
+    let otlp_layer = args
+        .otlp_endpoint
+        .as_deref()
+        .map(sqld::telemetry::otlp_layer)
+        .transpose()?;
+    let registry = registry.with(otlp_layer);
+
     #[cfg(feature = "debug-tools")]
     let registry = registry.with(console_subscriber::spawn());
 
@@ -357,8 +557,6 @@ async fn main() -> Result<()> {
         )
         .init();
 
-    let args = Cli::parse();
-
     match args.utils {
         Some(UtilsSubcommands::Dump { path, namespace }) => {
             if let Some(ref path) = path {
@@ -375,10 +573,57 @@ async fn main() -> Result<()> {
 
             perform_dump(path.as_deref(), &db_path)
         }
+        Some(UtilsSubcommands::LogHeader { namespace }) => {
+            let db_path = inspect::namespace_db_path(&args.db_path, &namespace)?;
+            inspect::print_log_header(&db_path, &mut stdout())
+        }
+        Some(UtilsSubcommands::LogFrames { namespace }) => {
+            let db_path = inspect::namespace_db_path(&args.db_path, &namespace)?;
+            let encryption = log_encryption_from_args(
+                args.log_encryption_key_file,
+                args.previous_log_encryption_key_file,
+            )?;
+            inspect::print_log_frames(&db_path, encryption, &mut stdout())
+        }
+        Some(UtilsSubcommands::Snapshots { namespace }) => {
+            let db_path = inspect::namespace_db_path(&args.db_path, &namespace)?;
+            let encryption = log_encryption_from_args(
+                args.log_encryption_key_file,
+                args.previous_log_encryption_key_file,
+            )?;
+            inspect::print_snapshots(&db_path, encryption, &mut stdout())
+        }
+        Some(UtilsSubcommands::Materialize {
+            namespace,
+            path,
+            frame_no,
+            utc_time,
+        }) => {
+            let db_path = inspect::namespace_db_path(&args.db_path, &namespace)?;
+            let encryption = log_encryption_from_args(
+                args.log_encryption_key_file,
+                args.previous_log_encryption_key_file,
+            )?;
+            let point = match (frame_no, utc_time) {
+                (Some(frame_no), _) => RestorePoint::FrameNo(frame_no),
+                (None, Some(utc_time)) => RestorePoint::Time(utc_time.into()),
+                (None, None) => RestorePoint::Latest,
+            };
+            let frame_no = inspect::materialize(&db_path, encryption, point, &path)?;
+            eprintln!(
+                "Materialized database {} at frame {frame_no} to {}",
+                db_path.display(),
+                path.display()
+            );
+
+            Ok(())
+        }
         None => {
             args.print_welcome_message();
             let config = config_from_args(args)?;
-            sqld::run_server(config).await?;
+            let res = sqld::run_server(config).await;
+            sqld::telemetry::shutdown();
+            res?;
 
             Ok(())
         }
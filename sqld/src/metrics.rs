@@ -0,0 +1,416 @@
+//! Prometheus metrics.
+//!
+//! Request metrics are recorded as requests are served, in the statics of this module. The state
+//! of each database (connection usage, replication progress...) is instead read when the metrics
+//! are scraped. The admin API renders both in the Prometheus text format on `/metrics`.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::atomic::Ordering;
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+use crate::query_result_builder::TOTAL_RESPONSE_SIZE;
+use crate::replication::FrameNo;
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+const DURATION_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+pub static HTTP_REQUESTS: Lazy<Counter> = Lazy::new(|| {
+    Counter::new(
+        "sqld_http_requests_total",
+        "Number of HTTP requests served, by route and response status.",
+        &["namespace", "route", "status"],
+    )
+});
+
+pub static HTTP_REQUEST_DURATION: Lazy<Histogram> = Lazy::new(|| {
+    Histogram::new(
+        "sqld_http_request_duration_seconds",
+        "Time spent serving HTTP requests, by route.",
+        &["namespace", "route"],
+    )
+});
+
+pub static HRANA_REQUESTS: Lazy<Counter> = Lazy::new(|| {
+    Counter::new(
+        "sqld_hrana_requests_total",
+        "Number of Hrana requests served, by transport and request type.",
+        &["namespace", "transport", "request"],
+    )
+});
+
+pub static HRANA_REQUEST_DURATION: Lazy<Histogram> = Lazy::new(|| {
+    Histogram::new(
+        "sqld_hrana_request_duration_seconds",
+        "Time spent serving Hrana requests, by transport and request type.",
+        &["namespace", "transport", "request"],
+    )
+});
+
+/// Removes the series of a deleted namespace from the request metrics.
+pub fn forget_namespace(namespace: &str) {
+    HTTP_REQUESTS.remove(&[("namespace", namespace)]);
+    HTTP_REQUEST_DURATION.remove(&[("namespace", namespace)]);
+    HRANA_REQUESTS.remove(&[("namespace", namespace)]);
+    HRANA_REQUEST_DURATION.remove(&[("namespace", namespace)]);
+}
+
+/// The state of a database, read when metrics are scraped. Fields that don't apply to the
+/// database are left unset.
+#[derive(Debug, Default)]
+pub struct DatabaseMetrics {
+    pub connection_permits_available: Option<usize>,
+    pub connection_waiters: Option<usize>,
+    pub replication_log_frames: Option<u64>,
+    pub replication_log_compactions: Option<u64>,
+    pub applied_frame_no: Option<FrameNo>,
+    pub bottomless_pending_frames: Option<u64>,
+    pub connected_replicas: Option<usize>,
+    pub max_replica_lag_frames: Option<u64>,
+    pub primary_frame_no: Option<FrameNo>,
+    pub replica_lag_frames: Option<u64>,
+    pub replica_lag_seconds: Option<f64>,
+}
+
+/// Renders all the metrics, given the state of each loaded database by namespace.
+pub fn render(databases: &[(String, DatabaseMetrics)]) -> String {
+    let mut out = String::new();
+    HTTP_REQUESTS.render(&mut out);
+    HTTP_REQUEST_DURATION.render(&mut out);
+    HRANA_REQUESTS.render(&mut out);
+    HRANA_REQUEST_DURATION.render(&mut out);
+
+    header(
+        &mut out,
+        "sqld_response_size_bytes",
+        "Total size of the responses being built.",
+        "gauge",
+    );
+    sample(
+        &mut out,
+        "sqld_response_size_bytes",
+        &[],
+        &[],
+        None,
+        TOTAL_RESPONSE_SIZE.load(Ordering::Relaxed) as f64,
+    );
+
+    type Getter = fn(&DatabaseMetrics) -> Option<f64>;
+    let database_metrics: [(&str, &str, &str, Getter); 11] = [
+        (
+            "sqld_connection_permits_available",
+            "Number of database connections that can be opened without waiting.",
+            "gauge",
+            |m| m.connection_permits_available.map(|v| v as f64),
+        ),
+        (
+            "sqld_connection_waiters",
+            "Number of requests waiting for a database connection.",
+            "gauge",
+            |m| m.connection_waiters.map(|v| v as f64),
+        ),
+        (
+            "sqld_replication_log_frames",
+            "Number of frames in the replication log.",
+            "gauge",
+            |m| m.replication_log_frames.map(|v| v as f64),
+        ),
+        (
+            "sqld_replication_log_compactions_total",
+            "Number of compactions of the replication log.",
+            "counter",
+            |m| m.replication_log_compactions.map(|v| v as f64),
+        ),
+        (
+            "sqld_replica_applied_frame_no",
+            "Last frame_no applied by the replica.",
+            "gauge",
+            |m| m.applied_frame_no.map(|v| v as f64),
+        ),
+        (
+            "sqld_bottomless_pending_frames",
+            "Number of frames waiting to be backed up by bottomless.",
+            "gauge",
+            |m| m.bottomless_pending_frames.map(|v| v as f64),
+        ),
+        (
+            "sqld_replication_connected_replicas",
+            "Number of replicas replicating from the primary.",
+            "gauge",
+            |m| m.connected_replicas.map(|v| v as f64),
+        ),
+        (
+            "sqld_replication_max_replica_lag_frames",
+            "Number of frames not sent yet to the replica that is the furthest behind.",
+            "gauge",
+            |m| m.max_replica_lag_frames.map(|v| v as f64),
+        ),
+        (
+            "sqld_replica_primary_frame_no",
+            "Last frame_no reported by the primary to the replica.",
+            "gauge",
+            |m| m.primary_frame_no.map(|v| v as f64),
+        ),
+        (
+            "sqld_replica_lag_frames",
+            "Number of frames of the primary that the replica did not apply yet.",
+            "gauge",
+            |m| m.replica_lag_frames.map(|v| v as f64),
+        ),
+        (
+            "sqld_replica_lag_seconds",
+            "Time since the replica was last known to be up to date with the primary.",
+            "gauge",
+            |m| m.replica_lag_seconds,
+        ),
+    ];
+
+    for (name, help, kind, get) in database_metrics {
+        header(&mut out, name, help, kind);
+        for (namespace, metrics) in databases {
+            if let Some(value) = get(metrics) {
+                sample(
+                    &mut out,
+                    name,
+                    &["namespace"],
+                    &[namespace.clone()],
+                    None,
+                    value,
+                );
+            }
+        }
+    }
+
+    out
+}
+
+/// A monotonic counter, with one series per combination of label values.
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    series: Mutex<BTreeMap<Vec<String>, u64>>,
+}
+
+impl Counter {
+    fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Self {
+        Self {
+            name,
+            help,
+            labels,
+            series: Default::default(),
+        }
+    }
+
+    /// Increments the series with the given label values, in the order of the label names.
+    pub fn inc(&self, values: &[&str]) {
+        debug_assert_eq!(values.len(), self.labels.len());
+        *self.series.lock().entry(to_owned(values)).or_default() += 1;
+    }
+
+    /// Removes the series that have all the given label values.
+    pub fn remove(&self, values: &[(&str, &str)]) {
+        let matches = matcher(self.labels, values);
+        self.series
+            .lock()
+            .retain(|series, _| !matches(series.as_slice()));
+    }
+
+    fn render(&self, out: &mut String) {
+        header(out, self.name, self.help, "counter");
+        for (values, count) in self.series.lock().iter() {
+            sample(out, self.name, self.labels, values, None, *count as f64);
+        }
+    }
+}
+
+/// A histogram of durations in seconds, with one series per combination of label values.
+pub struct Histogram {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    series: Mutex<BTreeMap<Vec<String>, HistogramSeries>>,
+}
+
+struct HistogramSeries {
+    /// Cumulative count of each bucket of [`DURATION_BUCKETS`].
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Self {
+        Self {
+            name,
+            help,
+            labels,
+            series: Default::default(),
+        }
+    }
+
+    /// Records a value in the series with the given label values, in the order of the label
+    /// names.
+    pub fn observe(&self, values: &[&str], value: f64) {
+        debug_assert_eq!(values.len(), self.labels.len());
+        let mut series = self.series.lock();
+        let series = series
+            .entry(to_owned(values))
+            .or_insert_with(|| HistogramSeries {
+                buckets: vec![0; DURATION_BUCKETS.len()],
+                sum: 0.0,
+                count: 0,
+            });
+        for (count, le) in series.buckets.iter_mut().zip(DURATION_BUCKETS) {
+            if value <= *le {
+                *count += 1;
+            }
+        }
+        series.sum += value;
+        series.count += 1;
+    }
+
+    /// Removes the series that have all the given label values.
+    pub fn remove(&self, values: &[(&str, &str)]) {
+        let matches = matcher(self.labels, values);
+        self.series
+            .lock()
+            .retain(|series, _| !matches(series.as_slice()));
+    }
+
+    fn render(&self, out: &mut String) {
+        header(out, self.name, self.help, "histogram");
+        let bucket_name = format!("{}_bucket", self.name);
+        let sum_name = format!("{}_sum", self.name);
+        let count_name = format!("{}_count", self.name);
+        for (values, series) in self.series.lock().iter() {
+            for (count, le) in series.buckets.iter().zip(DURATION_BUCKETS) {
+                let le = le.to_string();
+                sample(
+                    out,
+                    &bucket_name,
+                    self.labels,
+                    values,
+                    Some(&le),
+                    *count as f64,
+                );
+            }
+            sample(
+                out,
+                &bucket_name,
+                self.labels,
+                values,
+                Some("+Inf"),
+                series.count as f64,
+            );
+            sample(out, &sum_name, self.labels, values, None, series.sum);
+            sample(
+                out,
+                &count_name,
+                self.labels,
+                values,
+                None,
+                series.count as f64,
+            );
+        }
+    }
+}
+
+/// Returns a predicate on the label values of a series, that is true if the series has all the
+/// given label values.
+fn matcher<'a>(labels: &[&str], values: &[(&str, &'a str)]) -> impl Fn(&[String]) -> bool + 'a {
+    let values: Vec<_> = values
+        .iter()
+        .filter_map(|(label, value)| Some((labels.iter().position(|l| l == label)?, *value)))
+        .collect();
+    move |series| values.iter().all(|(i, value)| series[*i] == *value)
+}
+
+fn to_owned(values: &[&str]) -> Vec<String> {
+    values.iter().map(|v| v.to_string()).collect()
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn sample(
+    out: &mut String,
+    name: &str,
+    labels: &[&str],
+    values: &[String],
+    le: Option<&str>,
+    value: f64,
+) {
+    let mut pairs = labels
+        .iter()
+        .zip(values)
+        .map(|(label, value)| format!("{label}=\"{}\"", escape(value)))
+        .collect::<Vec<_>>();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{le}\""));
+    }
+
+    if pairs.is_empty() {
+        let _ = writeln!(out, "{name} {value}");
+    } else {
+        let _ = writeln!(out, "{name}{{{}}} {value}", pairs.join(","));
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn render_counter() {
+        let counter = Counter::new("requests_total", "Requests.", &["namespace", "route"]);
+        counter.inc(&["db1", "/v1/execute"]);
+        counter.inc(&["db1", "/v1/execute"]);
+        counter.inc(&["db\"2", "/"]);
+
+        let mut out = String::new();
+        counter.render(&mut out);
+        assert_eq!(
+            out,
+            "# HELP requests_total Requests.\n\
+             # TYPE requests_total counter\n\
+             requests_total{namespace=\"db\\\"2\",route=\"/\"} 1\n\
+             requests_total{namespace=\"db1\",route=\"/v1/execute\"} 2\n"
+        );
+
+        counter.remove(&[("namespace", "db1")]);
+        let mut out = String::new();
+        counter.render(&mut out);
+        assert!(!out.contains("db1"));
+        assert!(out.contains("requests_total{namespace=\"db\\\"2\",route=\"/\"} 1\n"));
+    }
+
+    #[test]
+    fn render_histogram() {
+        let histogram = Histogram::new("duration_seconds", "Durations.", &["route"]);
+        histogram.observe(&["/"], 0.25);
+        histogram.observe(&["/"], 20.0);
+
+        let mut out = String::new();
+        histogram.render(&mut out);
+        assert!(out.contains("duration_seconds_bucket{route=\"/\",le=\"0.1\"} 0\n"));
+        assert!(out.contains("duration_seconds_bucket{route=\"/\",le=\"0.25\"} 1\n"));
+        assert!(out.contains("duration_seconds_bucket{route=\"/\",le=\"10\"} 1\n"));
+        assert!(out.contains("duration_seconds_bucket{route=\"/\",le=\"+Inf\"} 2\n"));
+        assert!(out.contains("duration_seconds_sum{route=\"/\"} 20.25\n"));
+        assert!(out.contains("duration_seconds_count{route=\"/\"} 2\n"));
+    }
+}
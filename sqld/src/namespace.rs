@@ -3,26 +3,38 @@ use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
 
+use anyhow::Context as _;
 use async_lock::{RwLock, RwLockUpgradableReadGuard};
 use bytes::Bytes;
 use hyper::Uri;
-use tokio::sync::mpsc;
+use tokio::sync::{broadcast, mpsc};
 use tokio::task::JoinSet;
 use tonic::transport::Channel;
 
+use crate::cluster::Lease;
 use crate::connection::config::DatabaseConfigStore;
 use crate::connection::dump::loader::DumpLoader;
 use crate::connection::libsql::LibSqlDbFactory;
+use crate::connection::query_stats::QueryStats;
+use crate::connection::table_changes::TableChanges;
 use crate::connection::write_proxy::MakeWriteProxyConnection;
 use crate::connection::MakeConnection;
 use crate::database::{Database, PrimaryDatabase, ReplicaDatabase};
+use crate::delivery_queue::RetryPolicy;
+use crate::metrics;
+use crate::replication::encryption::FrameEncryption;
+use crate::replication::inspect::RestorePoint;
 use crate::replication::primary::logger::{ReplicationLoggerHookCtx, REPLICATION_METHODS};
-use crate::replication::replica::Replicator;
-use crate::replication::{NamespacedSnapshotCallback, ReplicationLogger};
+use crate::replication::primary::quorum::{QuorumWaiter, WriteQuorum};
+use crate::replication::primary::replicas::ConnectedReplicas;
+use crate::replication::replica::{MultiplexedReplication, ReplicaLog, Replicator};
+use crate::replication::{FrameNo, MergePolicyFn, ReplicationLogger};
+use crate::snapshot_sink::{SnapshotSinks, SnapshotSinksOptions};
 use crate::stats::Stats;
+use crate::webhook::Webhooks;
 use crate::{
-    check_fresh_db, init_bottomless_replicator, run_periodic_compactions, DB_CREATE_TIMEOUT,
-    MAX_CONCURRENT_DBS,
+    check_fresh_db, init_bottomless_replicator, record_bottomless_replication_index,
+    run_periodic_compactions, DB_CREATE_TIMEOUT, MAX_CONCURRENT_DBS,
 };
 
 /// Creates a new `Namespace` for database of the `Self::Database` type.
@@ -31,6 +43,9 @@ pub trait MakeNamespace: Sync + Send + 'static {
     type Database: Database;
 
     async fn create(&self, name: Bytes) -> anyhow::Result<Namespace<Self::Database>>;
+
+    /// Root path of the sqld directory, where the namespaces are stored.
+    fn base_path(&self) -> &Path;
 }
 
 /// Creates new primary `Namespace`
@@ -52,6 +67,10 @@ impl MakeNamespace for PrimaryNamespaceMaker {
     async fn create(&self, name: Bytes) -> anyhow::Result<Namespace<Self::Database>> {
         Namespace::new_primary(&self.config, name).await
     }
+
+    fn base_path(&self) -> &Path {
+        &self.config.base_path
+    }
 }
 
 /// Creates new replica `Namespace`
@@ -73,6 +92,17 @@ impl MakeNamespace for ReplicaNamespaceMaker {
     async fn create(&self, name: Bytes) -> anyhow::Result<Namespace<Self::Database>> {
         Namespace::new_replica(&self.config, name).await
     }
+
+    fn base_path(&self) -> &Path {
+        &self.config.base_path
+    }
+}
+
+/// Creation or deletion of a namespace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NamespaceEvent {
+    Created(Bytes),
+    Deleted(Bytes),
 }
 
 /// Stores and manage a set of namespaces.
@@ -80,6 +110,8 @@ pub struct NamespaceStore<F: MakeNamespace> {
     inner: RwLock<HashMap<Bytes, Namespace<F::Database>>>,
     /// The namespace factory, to create new namespaces.
     factory: F,
+    /// Notifies the creation and deletion of namespaces.
+    events: broadcast::Sender<NamespaceEvent>,
 }
 
 impl NamespaceStore<ReplicaNamespaceMaker> {
@@ -101,10 +133,168 @@ impl NamespaceStore<ReplicaNamespaceMaker> {
 
 impl<F: MakeNamespace> NamespaceStore<F> {
     pub fn new(factory: F) -> Self {
+        let (events, _) = broadcast::channel(NAMESPACE_EVENTS_CAPACITY);
         Self {
             inner: Default::default(),
             factory,
+            events,
+        }
+    }
+
+    /// Subscribes to the creation and deletion of namespaces.
+    pub fn subscribe_events(&self) -> broadcast::Receiver<NamespaceEvent> {
+        self.events.subscribe()
+    }
+
+    fn namespace_path(&self, namespace: &[u8]) -> anyhow::Result<PathBuf> {
+        let name = std::str::from_utf8(namespace)?;
+        // the name must not escape the directory of the namespaces
+        let mut components = Path::new(name).components();
+        anyhow::ensure!(
+            matches!(
+                (components.next(), components.next()),
+                (Some(std::path::Component::Normal(_)), None)
+            ),
+            "invalid namespace name: `{name}`"
+        );
+
+        Ok(namespaces_path(self.factory.base_path()).join(name))
+    }
+
+    /// Returns the names of all the namespaces, loaded or not.
+    pub async fn list(&self) -> anyhow::Result<Vec<Bytes>> {
+        let mut names = Vec::new();
+        let mut dir = match tokio::fs::read_dir(namespaces_path(self.factory.base_path())).await {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(names),
+            Err(e) => return Err(e.into()),
+        };
+        while let Some(entry) = dir.next_entry().await? {
+            if entry.file_type().await?.is_dir() {
+                if let Ok(name) = entry.file_name().into_string() {
+                    names.push(Bytes::from(name));
+                }
+            }
+        }
+        names.sort();
+
+        Ok(names)
+    }
+
+    /// Deletes a namespace and all its data. Returns false if the namespace doesn't exist.
+    pub async fn destroy(&self, namespace: Bytes) -> anyhow::Result<bool> {
+        let mut lock = self.inner.write().await;
+        match lock.remove(&namespace) {
+            Some(ns) => ns.destroy().await?,
+            None => {
+                let path = self.namespace_path(&namespace)?;
+                if !path.try_exists()? {
+                    return Ok(false);
+                }
+                tokio::fs::remove_dir_all(path).await?;
+            }
+        }
+        drop(lock);
+
+        metrics::forget_namespace(&String::from_utf8_lossy(&namespace));
+        tracing::info!("deleted namespace {}", String::from_utf8_lossy(&namespace));
+        let _ = self.events.send(NamespaceEvent::Deleted(namespace));
+
+        Ok(true)
+    }
+
+    /// Creates the namespace `to` with the database of the namespace `from` as of `point`,
+    /// rebuilt from the replication log and snapshots of `from`. Returns the frame_no of the last
+    /// frame of the restored database.
+    pub async fn restore(
+        &self,
+        from: Bytes,
+        to: Bytes,
+        point: RestorePoint,
+    ) -> anyhow::Result<FrameNo> {
+        let to_path = self.namespace_path(&to)?;
+        let to_name = String::from_utf8_lossy(&to).into_owned();
+        anyhow::ensure!(
+            !to_path.try_exists()?,
+            "namespace `{to_name}` already exists"
+        );
+        anyhow::ensure!(
+            self.namespace_path(&from)?.try_exists()?,
+            "namespace `{}` doesn't exist",
+            String::from_utf8_lossy(&from)
+        );
+        let logger = self
+            .with(from.clone(), |ns| {
+                ns.db.log_source().map(|log_source| log_source.logger)
+            })
+            .await?
+            .with_context(|| {
+                format!(
+                    "namespace `{}` has no replication log",
+                    String::from_utf8_lossy(&from)
+                )
+            })?;
+
+        // the database is written out of the namespaces directory, and moved to the namespace
+        // once complete
+        let tmp = tempfile::tempdir_in(self.factory.base_path())?;
+        let output = tmp.path().join("data");
+        let frame_no =
+            tokio::task::spawn_blocking(move || logger.materialize(point, &output)).await??;
+        tokio::fs::create_dir_all(namespaces_path(self.factory.base_path())).await?;
+        tokio::fs::rename(tmp.path(), &to_path).await?;
+
+        self.with(to.clone(), |_| ()).await?;
+        tracing::info!(
+            "restored namespace {to_name} from {} at frame {frame_no}",
+            String::from_utf8_lossy(&from)
+        );
+        let _ = self.events.send(NamespaceEvent::Created(to));
+
+        Ok(frame_no)
+    }
+
+    /// Unloads all the namespaces, waiting for their tasks to finish.
+    pub async fn shutdown(&self) {
+        let mut lock = self.inner.write().await;
+        for (_, mut ns) in lock.drain() {
+            ns.tasks.shutdown().await;
+        }
+    }
+
+    /// Returns whether the namespace is currently loaded.
+    pub async fn is_loaded(&self, namespace: &Bytes) -> bool {
+        self.inner.read().await.contains_key(namespace)
+    }
+
+    /// Calls `f` with each namespace that is currently loaded.
+    pub async fn for_each_loaded(&self, mut f: impl FnMut(&Bytes, &Namespace<F::Database>)) {
+        let lock = self.inner.read().await;
+        for (name, ns) in lock.iter() {
+            f(name, ns);
+        }
+    }
+
+    /// Calls `f` with the namespace, loading it if it exists. Unlike `with`, never creates the
+    /// namespace: returns `None` if it doesn't exist.
+    pub async fn with_existing<Fun, R>(&self, namespace: Bytes, f: Fun) -> anyhow::Result<Option<R>>
+    where
+        Fun: FnOnce(&Namespace<F::Database>) -> R,
+    {
+        let lock = self.inner.upgradable_read().await;
+        if let Some(ns) = lock.get(&namespace) {
+            return Ok(Some(f(ns)));
+        }
+        match self.namespace_path(&namespace) {
+            Ok(path) if path.try_exists()? => (),
+            _ => return Ok(None),
         }
+
+        let mut lock = RwLockUpgradableReadGuard::upgrade(lock).await;
+        let ns = self.factory.create(namespace.clone()).await?;
+        let ret = f(&ns);
+        lock.insert(namespace, ns);
+        Ok(Some(ret))
     }
 
     pub async fn with<Fun, R>(&self, namespace: Bytes, f: Fun) -> anyhow::Result<R>
@@ -116,14 +306,37 @@ impl<F: MakeNamespace> NamespaceStore<F> {
             Ok(f(ns))
         } else {
             let mut lock = RwLockUpgradableReadGuard::upgrade(lock).await;
+            let is_new = !self.namespace_path(&namespace)?.try_exists()?;
             let ns = self.factory.create(namespace.clone()).await?;
             let ret = f(&ns);
-            lock.insert(namespace, ns);
+            lock.insert(namespace.clone(), ns);
+            drop(lock);
+            if is_new {
+                let _ = self.events.send(NamespaceEvent::Created(namespace));
+            }
             Ok(ret)
         }
     }
 }
 
+/// Capacity of the channel of namespace events. Subscribers that fall further behind miss events.
+const NAMESPACE_EVENTS_CAPACITY: usize = 1024;
+
+/// Returns the merge policy of the snapshots of a namespace, read from its config whenever the
+/// snapshots are considered for a merge.
+fn snapshot_merge_policy(
+    config_store: &Arc<DatabaseConfigStore>,
+    default_min_age: Option<Duration>,
+) -> MergePolicyFn {
+    let config_store = config_store.clone();
+    Arc::new(move || config_store.get().snapshot_merge_policy(default_min_age))
+}
+
+/// Returns the directory of the namespaces of a sqld directory.
+fn namespaces_path(base_path: &Path) -> PathBuf {
+    base_path.join("dbs")
+}
+
 /// A namspace isolates the resources pertaining to a database of type T
 #[derive(Debug)]
 pub struct Namespace<T: Database> {
@@ -134,6 +347,15 @@ pub struct Namespace<T: Database> {
     path: PathBuf,
 }
 
+impl<T: Database> Namespace<T> {
+    /// Stops the tasks of the namespace, and deletes its data.
+    async fn destroy(mut self) -> anyhow::Result<()> {
+        self.tasks.shutdown().await;
+        tokio::fs::remove_dir_all(&self.path).await?;
+        Ok(())
+    }
+}
+
 pub struct ReplicaNamespaceConfig {
     /// root path of the sqld directory
     pub base_path: PathBuf,
@@ -145,22 +367,50 @@ pub struct ReplicaNamespaceConfig {
     pub extensions: Vec<PathBuf>,
     /// Stats monitor
     pub stats: Stats,
-    /// Reference to the config store
-    pub config_store: Arc<DatabaseConfigStore>,
     pub max_response_size: u64,
     pub max_total_response_size: u64,
+    /// Maximum duration of a statement, after which it is interrupted.
+    pub statement_timeout: Option<Duration>,
     /// hard reset sender.
     /// When a replica need to be wiped and recovered from scratch, its namespace
     /// is sent to this channel
     pub hard_reset: mpsc::Sender<Bytes>,
+    /// Replication log of the replica, when it serves other replicas.
+    pub replica_log: Option<ReplicaLogConfig>,
+    /// Bottomless backups that fresh databases are bootstrapped from.
+    pub bootstrap_from_bottomless: Option<bottomless::replicator::Options>,
+    /// Stream that all the namespaces are replicated over, rather than each over its own.
+    pub multiplex: Option<Arc<MultiplexedReplication>>,
+    /// The config of the server, whose blocks apply to every namespace.
+    pub server_config: Arc<DatabaseConfigStore>,
+}
+
+pub struct ReplicaLogConfig {
+    pub max_log_size: u64,
+    pub max_log_duration: Option<Duration>,
+    pub log_encryption: FrameEncryption,
 }
 
 impl Namespace<ReplicaDatabase> {
     async fn new_replica(config: &ReplicaNamespaceConfig, name: Bytes) -> anyhow::Result<Self> {
         let name_str = std::str::from_utf8(&name)?;
-        let db_path = config.base_path.join("dbs").join(name_str);
+        let db_path = namespaces_path(&config.base_path).join(name_str);
         tokio::fs::create_dir_all(&db_path).await?;
+        let config_store = Arc::new(
+            DatabaseConfigStore::load(&db_path)?.with_server(config.server_config.clone()),
+        );
+        let query_stats = Arc::new(QueryStats::default());
+        let replica_log = config.replica_log.as_ref().map(|log_config| {
+            Arc::new(ReplicaLog::new(
+                db_path.clone(),
+                log_config.max_log_size,
+                log_config.max_log_duration,
+                log_config.log_encryption.clone(),
+                snapshot_merge_policy(&config_store, None),
+            ))
+        });
         let mut join_set = JoinSet::new();
+        let table_changes = Arc::new(TableChanges::default());
         let replicator = Replicator::new(
             db_path.clone(),
             config.channel.clone(),
@@ -168,10 +418,18 @@ impl Namespace<ReplicaDatabase> {
             name.clone(),
             &mut join_set,
             config.hard_reset.clone(),
+            replica_log.clone(),
+            config.bootstrap_from_bottomless.clone().map(|mut options| {
+                options.db_id = Some(bottomless_db_id(&name));
+                options
+            }),
+            config.multiplex.clone(),
+            table_changes.clone(),
         )
         .await?;
 
         let applied_frame_no_receiver = replicator.current_frame_no_notifier.clone();
+        let lag = replicator.lag.clone();
 
         join_set.spawn(replicator.run());
 
@@ -181,8 +439,10 @@ impl Namespace<ReplicaDatabase> {
             config.channel.clone(),
             config.uri.clone(),
             config.stats.clone(),
-            config.config_store.clone(),
-            applied_frame_no_receiver,
+            config_store.clone(),
+            query_stats.clone(),
+            applied_frame_no_receiver.clone(),
+            lag.clone(),
             config.max_response_size,
             config.max_total_response_size,
             name.clone(),
@@ -191,22 +451,24 @@ impl Namespace<ReplicaDatabase> {
             MAX_CONCURRENT_DBS,
             Some(DB_CREATE_TIMEOUT),
             config.max_total_response_size,
+            config.statement_timeout,
         );
 
         Ok(Self {
             tasks: join_set,
             db: ReplicaDatabase {
                 connection_maker: Arc::new(connection_maker),
+                applied_frame_no_receiver,
+                lag,
+                config_store,
+                query_stats,
+                replica_log,
+                replicas: Default::default(),
+                table_changes,
             },
             path: db_path,
         })
     }
-
-    async fn destroy(mut self) -> anyhow::Result<()> {
-        self.tasks.shutdown().await;
-        tokio::fs::remove_dir_all(&self.path).await?;
-        Ok(())
-    }
 }
 
 pub struct PrimaryNamespaceConfig {
@@ -214,59 +476,91 @@ pub struct PrimaryNamespaceConfig {
     pub max_log_size: u64,
     pub db_is_dirty: bool,
     pub max_log_duration: Option<Duration>,
-    pub snapshot_callback: NamespacedSnapshotCallback,
+    /// How long the history of the database is kept, so that it can be restored as of any
+    /// transaction committed since then.
+    pub history_retention: Option<Duration>,
+    /// The snapshot sinks configured on the command line.
+    pub snapshot_sinks: Arc<SnapshotSinksOptions>,
     pub bottomless_replication: Option<bottomless::replicator::Options>,
     pub extensions: Vec<PathBuf>,
     pub stats: Stats,
-    pub config_store: Arc<DatabaseConfigStore>,
     pub max_response_size: u64,
     pub load_from_dump: Option<PathBuf>,
     pub max_total_response_size: u64,
+    /// Maximum duration of a statement, after which it is interrupted.
+    pub statement_timeout: Option<Duration>,
+    /// Replicas that must acknowledge a commit, with semi-synchronous replication.
+    pub write_quorum: Option<WriteQuorum>,
+    /// Encryption of the frames of the replication log and snapshots.
+    pub log_encryption: FrameEncryption,
+    /// The lease of this node, in cluster mode.
+    pub lease: Option<Arc<Lease>>,
+    /// The config of the server, whose blocks apply to every namespace.
+    pub server_config: Arc<DatabaseConfigStore>,
+}
+
+/// Returns the id of the database of a namespace in bottomless backups.
+fn bottomless_db_id(name: &[u8]) -> String {
+    format!("ns-{}", String::from_utf8_lossy(name))
 }
 
 impl Namespace<PrimaryDatabase> {
     async fn new_primary(config: &PrimaryNamespaceConfig, name: Bytes) -> anyhow::Result<Self> {
         let mut join_set = JoinSet::new();
         let name_str = std::str::from_utf8(&name)?;
-        let db_path = config.base_path.join("dbs").join(name_str);
+        let db_path = namespaces_path(&config.base_path).join(name_str);
         tokio::fs::create_dir_all(&db_path).await?;
         let mut is_dirty = config.db_is_dirty;
 
-        let bottomless_replicator = if let Some(options) = &config.bottomless_replication {
+        let mut bottomless_replicator = if let Some(options) = &config.bottomless_replication {
             let mut options = options.clone();
-            let db_id = format!("ns-{}", std::str::from_utf8(&name).unwrap());
-            options.db_id = Some(db_id);
+            options.db_id = Some(bottomless_db_id(&name));
             let (replicator, did_recover) =
                 init_bottomless_replicator(db_path.join("data"), options.clone()).await?;
             is_dirty |= did_recover;
-            Some(Arc::new(std::sync::Mutex::new(replicator)))
+            Some(replicator)
         } else {
             None
         };
 
         tokio::fs::create_dir_all(&db_path).await?;
         let is_fresh_db = check_fresh_db(&db_path);
+        let config_store = Arc::new(
+            DatabaseConfigStore::load(&db_path)?.with_server(config.server_config.clone()),
+        );
+        let query_stats = Arc::new(QueryStats::default());
+        let snapshot_sinks = Arc::new(SnapshotSinks::open(
+            &db_path.join("snapshot_sinks"),
+            &name,
+            config.snapshot_sinks.clone(),
+            config.bottomless_replication.clone(),
+            RetryPolicy::default(),
+        )?);
+        join_set.spawn(snapshot_sinks.clone().run());
         let logger = Arc::new(ReplicationLogger::open(
             &db_path,
             config.max_log_size,
             config.max_log_duration,
             is_dirty,
             Box::new({
-                let name = name.clone();
-                let cb = config.snapshot_callback.clone();
-                move |path: &Path| cb(path, &name)
+                let snapshot_sinks = snapshot_sinks.clone();
+                move |path: &Path| {
+                    // a sink must not stop the compaction of the log
+                    if let Err(e) = snapshot_sinks.enqueue(path) {
+                        tracing::error!("failed to enqueue snapshot for its sinks: {e}");
+                    }
+                    Ok(())
+                }
             }),
+            config.log_encryption.clone(),
+            snapshot_merge_policy(&config_store, config.history_retention),
         )?);
 
         join_set.spawn(run_periodic_compactions(logger.clone()));
 
-        // load dump is necessary
-        let dump_loader = DumpLoader::new(
-            db_path.clone(),
-            logger.clone(),
-            bottomless_replicator.clone(),
-        )
-        .await?;
+        // load dump is necessary. The dump is backed up by the bottomless generation that records
+        // the replication index below, since the generation starts with a snapshot of the database.
+        let dump_loader = DumpLoader::new(db_path.clone(), logger.clone(), None).await?;
         if let Some(ref path) = config.load_from_dump {
             if !is_fresh_db {
                 anyhow::bail!("cannot load from a dump if a database already exists.\nIf you're sure you want to load from a dump, delete your database folder at `{}`", db_path.display());
@@ -274,25 +568,57 @@ impl Namespace<PrimaryDatabase> {
             dump_loader.load_dump(path.into()).await?;
         }
 
+        // replicas bootstrap from the snapshot of the generation, so it must hold every frame
+        // before the index
+        if let Some(replicator) = &mut bottomless_replicator {
+            record_bottomless_replication_index(replicator, &db_path, &logger).await?;
+        }
+        let bottomless_replicator =
+            bottomless_replicator.map(|replicator| Arc::new(std::sync::Mutex::new(replicator)));
+
+        let webhooks = Arc::new(Webhooks::open(
+            &db_path.join("webhooks"),
+            &name,
+            RetryPolicy::default(),
+        )?);
+        join_set.spawn(webhooks.clone().run());
+        let table_changes = Arc::new(TableChanges::with_row_listener(webhooks.clone()));
+
+        let replicas = Arc::new(ConnectedReplicas::default());
+        let quorum = config
+            .write_quorum
+            .map(|quorum| Arc::new(QuorumWaiter::new(quorum, replicas.clone())));
+
         let connection_maker: Arc<_> = LibSqlDbFactory::new(
             db_path.clone(),
             &REPLICATION_METHODS,
             {
                 let logger = logger.clone();
                 let bottomless_replicator = bottomless_replicator.clone();
-                move || ReplicationLoggerHookCtx::new(logger.clone(), bottomless_replicator.clone())
+                let lease = config.lease.clone();
+                move || {
+                    ReplicationLoggerHookCtx::new(
+                        logger.clone(),
+                        bottomless_replicator.clone(),
+                        quorum.clone(),
+                        lease.clone(),
+                    )
+                }
             },
             config.stats.clone(),
-            config.config_store.clone(),
+            config_store.clone(),
+            query_stats.clone(),
             config.extensions.clone(),
             config.max_response_size,
             config.max_total_response_size,
+            Some(table_changes.clone()),
         )
         .await?
         .throttled(
             MAX_CONCURRENT_DBS,
             Some(DB_CREATE_TIMEOUT),
             config.max_total_response_size,
+            config.statement_timeout,
         )
         .into();
 
@@ -301,6 +627,13 @@ impl Namespace<PrimaryDatabase> {
             db: PrimaryDatabase {
                 logger,
                 connection_maker,
+                table_changes,
+                webhooks,
+                snapshot_sinks,
+                config_store,
+                query_stats,
+                bottomless_replicator,
+                replicas,
             },
             path: db_path,
         })
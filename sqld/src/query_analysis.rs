@@ -3,6 +3,8 @@ use fallible_iterator::FallibleIterator;
 use sqlite3_parser::ast::{Cmd, PragmaBody, QualifiedName, Stmt};
 use sqlite3_parser::lexer::sql::{Parser, ParserError};
 
+use crate::connection::query_stats;
+
 /// A group of statements to be executed together.
 #[derive(Debug, Clone)]
 pub struct Statement {
@@ -11,6 +13,9 @@ pub struct Statement {
     /// Is the statement an INSERT, UPDATE or DELETE?
     pub is_iud: bool,
     pub is_insert: bool,
+    /// The text of the statement with its values replaced by `?`, that groups it with similar
+    /// statements in the query statistics.
+    pub fingerprint: String,
 }
 
 impl Default for Statement {
@@ -204,6 +209,7 @@ impl Statement {
             kind: StmtKind::Read,
             is_iud: false,
             is_insert: false,
+            fingerprint: String::new(),
         }
     }
 
@@ -225,6 +231,7 @@ impl Statement {
                         kind,
                         is_iud: false,
                         is_insert: false,
+                        fingerprint: query_stats::fingerprint(c),
                     });
                 }
             }
@@ -240,6 +247,7 @@ impl Statement {
                 kind,
                 is_iud,
                 is_insert,
+                fingerprint: query_stats::fingerprint(c),
             })
         }
         // The parser needs to be boxed because it's large, and you don't want it on the stack.
@@ -0,0 +1,234 @@
+//! Compression of the frames sent to replicas.
+//!
+//! Replicas announce the compressions they support in their `Hello`, and the primary picks one,
+//! which the replica then requests frames with. Uncompressed messages carry a single frame, as
+//! they did before compression was introduced. Compressed messages carry batches of consecutive
+//! frames, compressed together, because pages compress better together than one by one.
+
+use std::io::{Read, Write};
+
+use bytes::Bytes;
+
+use crate::replication::frame::Frame;
+use crate::rpc::replication_log::rpc::{Compression, Frame as RpcFrame};
+
+/// Compressions supported by this node, by order of preference.
+pub const SUPPORTED_COMPRESSIONS: [Compression; 2] = [Compression::Zstd, Compression::Gzip];
+
+/// Maximum number of frames compressed together in a message (~1MiB of pages).
+pub const MAX_FRAMES_PER_MESSAGE: usize = 256;
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// Picks the compression to send frames with, among the ones the replica supports.
+pub fn negotiate(supported_by_replica: impl Iterator<Item = Compression>) -> Compression {
+    let supported_by_replica = supported_by_replica.collect::<Vec<_>>();
+    SUPPORTED_COMPRESSIONS
+        .into_iter()
+        .find(|c| supported_by_replica.contains(c))
+        .unwrap_or(Compression::None)
+}
+
+/// Encodes frames into messages.
+pub fn encode_frames(frames: &[Frame], compression: Compression) -> anyhow::Result<Vec<RpcFrame>> {
+    if compression == Compression::None {
+        return Ok(frames
+            .iter()
+            .map(|frame| RpcFrame {
+                data: frame.bytes(),
+                compression: Compression::None.into(),
+                snapshot_chunk: None,
+            })
+            .collect());
+    }
+
+    frames
+        .chunks(MAX_FRAMES_PER_MESSAGE)
+        .map(|chunk| {
+            let mut buf = Vec::with_capacity(chunk.len() * Frame::SIZE);
+            for frame in chunk {
+                buf.extend_from_slice(&frame.bytes());
+            }
+            Ok(RpcFrame {
+                data: compress(&buf, compression)?,
+                compression: compression.into(),
+                snapshot_chunk: None,
+            })
+        })
+        .collect()
+}
+
+/// Decodes the frames of a message.
+pub fn decode_frames(message: RpcFrame) -> anyhow::Result<Vec<Frame>> {
+    let data = match message.compression() {
+        Compression::None => return Ok(vec![Frame::try_from_bytes(message.data)?]),
+        compression => decompress(&message.data, compression)?,
+    };
+    anyhow::ensure!(
+        data.len() % Frame::SIZE == 0,
+        "invalid size for a batch of frames: {}",
+        data.len()
+    );
+
+    (0..data.len())
+        .step_by(Frame::SIZE)
+        .map(|offset| Frame::try_from_bytes(data.slice(offset..offset + Frame::SIZE)))
+        .collect()
+}
+
+fn compress(data: &[u8], compression: Compression) -> anyhow::Result<Bytes> {
+    let compressed = match compression {
+        Compression::None => data.to_vec(),
+        Compression::Gzip => {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data)?;
+            encoder.finish()?
+        }
+        Compression::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL)?,
+    };
+
+    Ok(compressed.into())
+}
+
+/// Decompresses a message, which must not hold more than `MAX_FRAMES_PER_MESSAGE` frames, so that
+/// a corrupted or malicious message can't exhaust the memory.
+fn decompress(data: &[u8], compression: Compression) -> anyhow::Result<Bytes> {
+    const MAX_SIZE: usize = MAX_FRAMES_PER_MESSAGE * Frame::SIZE;
+    // one more byte than allowed, to detect the messages that are too large
+    let limit = MAX_SIZE as u64 + 1;
+    let mut decompressed = Vec::with_capacity(MAX_SIZE);
+    match compression {
+        Compression::None => decompressed.extend_from_slice(data),
+        Compression::Gzip => {
+            flate2::read::GzDecoder::new(data)
+                .take(limit)
+                .read_to_end(&mut decompressed)?;
+        }
+        Compression::Zstd => {
+            zstd::stream::read::Decoder::new(data)?
+                .take(limit)
+                .read_to_end(&mut decompressed)?;
+        }
+    }
+    anyhow::ensure!(
+        decompressed.len() <= MAX_SIZE,
+        "decompressed message exceeds {MAX_SIZE} bytes"
+    );
+
+    Ok(decompressed.into())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::replication::frame::FrameHeader;
+    use crate::replication::WAL_PAGE_SIZE;
+
+    use super::*;
+
+    /// Returns the pages of a database holding some typical rows.
+    fn database_frames() -> Vec<Frame> {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("data");
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT, email TEXT, created_at INTEGER);",
+        )
+        .unwrap();
+        for i in 0..2000 {
+            conn.execute(
+                "INSERT INTO users VALUES (?1, ?2, ?3, ?4)",
+                rusqlite::params![
+                    i,
+                    format!("user {i}"),
+                    format!("user{i}@example.com"),
+                    1_697_000_000 + i
+                ],
+            )
+            .unwrap();
+        }
+        drop(conn);
+
+        std::fs::read(&path)
+            .unwrap()
+            .chunks(WAL_PAGE_SIZE as usize)
+            .enumerate()
+            .map(|(i, page)| {
+                let header = FrameHeader {
+                    frame_no: i as u64,
+                    checksum: 0,
+                    page_no: i as u32 + 1,
+                    size_after: 0,
+                };
+                Frame::from_parts(&header, page)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn negotiate_compression() {
+        assert_eq!(
+            negotiate([Compression::Gzip, Compression::Zstd].into_iter()),
+            Compression::Zstd
+        );
+        assert_eq!(
+            negotiate([Compression::Gzip].into_iter()),
+            Compression::Gzip
+        );
+        assert_eq!(negotiate(std::iter::empty()), Compression::None);
+    }
+
+    #[test]
+    fn roundtrip_and_ratio() {
+        let frames = database_frames();
+        let raw_size = frames.len() * Frame::SIZE;
+
+        for compression in [Compression::None, Compression::Gzip, Compression::Zstd] {
+            let messages = encode_frames(&frames, compression).unwrap();
+            let encoded_size = messages.iter().map(|m| m.data.len()).sum::<usize>();
+
+            let decoded = messages
+                .into_iter()
+                .flat_map(|m| decode_frames(m).unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(decoded.len(), frames.len());
+            for (a, b) in decoded.iter().zip(&frames) {
+                assert_eq!(a.bytes(), b.bytes());
+            }
+
+            if compression != Compression::None {
+                // these pages compress at least 2x
+                assert!(
+                    encoded_size * 2 < raw_size,
+                    "{compression:?}: {encoded_size} bytes for {raw_size} bytes of frames"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_batch() {
+        let message = RpcFrame {
+            data: compress(&[0; 100], Compression::Zstd).unwrap(),
+            compression: Compression::Zstd.into(),
+            snapshot_chunk: None,
+        };
+        assert!(decode_frames(message).is_err());
+    }
+
+    #[test]
+    fn oversized_batch() {
+        for compression in [Compression::Gzip, Compression::Zstd] {
+            let at_limit =
+                compress(&vec![0; MAX_FRAMES_PER_MESSAGE * Frame::SIZE], compression).unwrap();
+            assert!(decompress(&at_limit, compression).is_ok());
+
+            let oversized = compress(
+                &vec![0; (MAX_FRAMES_PER_MESSAGE + 1) * Frame::SIZE],
+                compression,
+            )
+            .unwrap();
+            assert!(decompress(&oversized, compression).is_err());
+        }
+    }
+}
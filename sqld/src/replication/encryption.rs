@@ -0,0 +1,400 @@
+//! Encryption at rest of the frames of the replication log and of the snapshots.
+//!
+//! Each frame is stored as a record sealed with XChaCha20-Poly1305: the id of the key it was
+//! encrypted with, a random nonce, and the encrypted frame followed by its authentication tag.
+//! Records have a fixed size, so frames can still be located by their position in a file.
+//!
+//! The id of the key and the location of the record, that is, the database it belongs to and its
+//! position in the log or in the snapshot, are authenticated along with the frame. A record that
+//! is copied to another file or to another position fails to decode, like a corrupted one.
+//!
+//! The id of the key lets a log or a snapshot be identified as written with another key: when the
+//! key changes, they are read with the previous key and encrypted again with the new one, so the
+//! previous key is not needed anymore once they have been rewritten.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::sync::Arc;
+
+use anyhow::Context;
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use bytes::Bytes;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+use super::frame::{Frame, FrameBorrowed};
+use super::FrameNo;
+
+/// Size of an encryption key, in bytes.
+pub const KEY_SIZE: usize = 32;
+const KEY_ID_SIZE: usize = 4;
+const NONCE_SIZE: usize = 24;
+const TAG_SIZE: usize = 16;
+/// Size of the associated data of a record: the key id, the kind of file, the database id and the
+/// position of the record.
+const ASSOCIATED_DATA_SIZE: usize = KEY_ID_SIZE + 1 + 16 + 8;
+
+/// Id of a key, derived from the key, that is stored with the records it encrypted.
+type KeyId = u32;
+
+/// Where a record is stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordLocation {
+    /// The record of frame `frame_no` in the log of the database `db_id`.
+    Log { db_id: u128, frame_no: FrameNo },
+    /// The record at `index` in a snapshot of the database `db_id`.
+    Snapshot { db_id: u128, index: u64 },
+}
+
+impl RecordLocation {
+    /// Returns the data that is authenticated along with a frame stored at this location and
+    /// encrypted with the key `key_id`.
+    fn associated_data(&self, key_id: KeyId) -> [u8; ASSOCIATED_DATA_SIZE] {
+        let (kind, db_id, position) = match *self {
+            Self::Log { db_id, frame_no } => (0u8, db_id, frame_no),
+            Self::Snapshot { db_id, index } => (1u8, db_id, index),
+        };
+        let mut data = [0; ASSOCIATED_DATA_SIZE];
+        data[..KEY_ID_SIZE].copy_from_slice(&key_id.to_le_bytes());
+        data[KEY_ID_SIZE] = kind;
+        data[KEY_ID_SIZE + 1..KEY_ID_SIZE + 17].copy_from_slice(&db_id.to_le_bytes());
+        data[KEY_ID_SIZE + 17..].copy_from_slice(&position.to_le_bytes());
+
+        data
+    }
+}
+
+/// The key that frames are encrypted with.
+pub struct FrameCipher {
+    key_id: KeyId,
+    cipher: XChaCha20Poly1305,
+}
+
+impl FrameCipher {
+    pub fn new(key: &[u8; KEY_SIZE]) -> Self {
+        Self {
+            key_id: key_id(key),
+            cipher: XChaCha20Poly1305::new(Key::from_slice(key)),
+        }
+    }
+
+    /// Parses a base64 encoded key.
+    pub fn from_base64(key: &str) -> anyhow::Result<Self> {
+        let key = BASE64_STANDARD
+            .decode(key.trim())
+            .context("encryption key is not valid base64")?;
+        let key: [u8; KEY_SIZE] = key.try_into().map_err(|key: Vec<u8>| {
+            anyhow::anyhow!(
+                "encryption key is {} bytes long, expected {KEY_SIZE} bytes",
+                key.len()
+            )
+        })?;
+
+        Ok(Self::new(&key))
+    }
+
+    fn seal(&self, frame: &[u8], location: RecordLocation) -> anyhow::Result<Vec<u8>> {
+        let mut nonce = [0; NONCE_SIZE];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let payload = Payload {
+            msg: frame,
+            aad: &location.associated_data(self.key_id),
+        };
+        let ciphertext = self
+            .cipher
+            .encrypt(XNonce::from_slice(&nonce), payload)
+            .map_err(|_| anyhow::anyhow!("failed to encrypt frame"))?;
+
+        let mut record = Vec::with_capacity(KEY_ID_SIZE + NONCE_SIZE + ciphertext.len());
+        record.extend_from_slice(&self.key_id.to_le_bytes());
+        record.extend_from_slice(&nonce);
+        record.extend_from_slice(&ciphertext);
+
+        Ok(record)
+    }
+
+    fn open(&self, record: &[u8], location: RecordLocation) -> anyhow::Result<Vec<u8>> {
+        let nonce = &record[KEY_ID_SIZE..KEY_ID_SIZE + NONCE_SIZE];
+        let payload = Payload {
+            msg: &record[KEY_ID_SIZE + NONCE_SIZE..],
+            aad: &location.associated_data(self.key_id),
+        };
+        self.cipher
+            .decrypt(XNonce::from_slice(nonce), payload)
+            .map_err(|_| anyhow::anyhow!("failed to decrypt frame: corrupted or misplaced frame"))
+    }
+}
+
+fn key_id(key: &[u8; KEY_SIZE]) -> KeyId {
+    let digest = Sha256::digest(key);
+    KeyId::from_le_bytes(digest[..KEY_ID_SIZE].try_into().unwrap())
+}
+
+fn record_key_id(record: &[u8]) -> KeyId {
+    KeyId::from_le_bytes(record[..KEY_ID_SIZE].try_into().unwrap())
+}
+
+/// How the frames of a log or of a snapshot file relate to the current key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum KeyStatus {
+    /// The frames are encrypted with the current key, or are not encrypted if encryption is
+    /// disabled: the file can be kept as it is.
+    Current,
+    /// The frames are encrypted with the previous key, or encryption was enabled or disabled since
+    /// they were written: the file must be encrypted again before it is written to.
+    Readable,
+    /// The frames are encrypted with a key that is not known, and can't be read.
+    Unknown,
+}
+
+/// How the frames of the logs and snapshots are stored: encrypted with a key, or in plaintext.
+/// Cloning this is cheap.
+#[derive(Clone, Default)]
+pub struct FrameEncryption {
+    cipher: Option<Arc<FrameCipher>>,
+    /// The key before a rotation, that frames that were not encrypted again yet can be read with.
+    previous: Option<Arc<FrameCipher>>,
+}
+
+impl fmt::Debug for FrameEncryption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FrameEncryption")
+            .field("enabled", &self.is_enabled())
+            .finish()
+    }
+}
+
+impl FrameEncryption {
+    pub fn new(cipher: FrameCipher) -> Self {
+        Self {
+            cipher: Some(Arc::new(cipher)),
+            previous: None,
+        }
+    }
+
+    /// Lets the frames encrypted with the key before a rotation be read, so that they can be
+    /// encrypted again with the current key.
+    pub fn with_previous_key(self, previous: FrameCipher) -> Self {
+        Self {
+            previous: Some(Arc::new(previous)),
+            ..self
+        }
+    }
+
+    /// Returns the key that a record was encrypted with, if it is known.
+    fn cipher_of(&self, record: &[u8]) -> Option<&FrameCipher> {
+        let key_id = record_key_id(record);
+        [&self.cipher, &self.previous]
+            .into_iter()
+            .flatten()
+            .map(|cipher| &**cipher)
+            .find(|cipher| cipher.key_id == key_id)
+    }
+
+    /// Whether new logs and snapshots are encrypted.
+    pub fn is_enabled(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    /// Returns the size of the record of a frame in a file, whether its frames are `encrypted`.
+    pub fn record_size(encrypted: bool) -> usize {
+        if encrypted {
+            KEY_ID_SIZE + NONCE_SIZE + Frame::SIZE + TAG_SIZE
+        } else {
+            Frame::SIZE
+        }
+    }
+
+    /// Returns the record of a frame stored at `location` in a file, whether its frames are
+    /// `encrypted`.
+    pub fn encode<'a>(
+        &self,
+        frame: &'a FrameBorrowed,
+        encrypted: bool,
+        location: RecordLocation,
+    ) -> anyhow::Result<Cow<'a, [u8]>> {
+        if !encrypted {
+            return Ok(Cow::Borrowed(frame.as_slice()));
+        }
+        let cipher = self
+            .cipher
+            .as_ref()
+            .context("cannot write an encrypted frame: no encryption key")?;
+
+        Ok(Cow::Owned(cipher.seal(frame.as_slice(), location)?))
+    }
+
+    /// Returns the frame of a record read at `location` in a file, whether its frames are
+    /// `encrypted`.
+    pub fn decode(
+        &self,
+        record: Bytes,
+        encrypted: bool,
+        location: RecordLocation,
+    ) -> anyhow::Result<Frame> {
+        anyhow::ensure!(
+            record.len() == Self::record_size(encrypted),
+            "invalid frame record size"
+        );
+        if !encrypted {
+            return Frame::try_from_bytes(record);
+        }
+        let cipher = self.cipher_of(&record).with_context(|| {
+            format!(
+                "frame is encrypted with an unknown key: {:08x}",
+                record_key_id(&record)
+            )
+        })?;
+
+        Frame::try_from_bytes(cipher.open(&record, location)?.into())
+    }
+
+    /// Returns whether a file can be appended to, or kept, as it is, as the first record of the
+    /// file, if any, tells: its frames must be encrypted with the key, or must not be encrypted if
+    /// encryption is disabled. Otherwise, the file must be encrypted again, if it can be read.
+    pub fn key_status(&self, encrypted: bool, first_record: Option<&[u8]>) -> KeyStatus {
+        let current = match &self.cipher {
+            Some(cipher) => {
+                encrypted
+                    && first_record.map_or(true, |record| record_key_id(record) == cipher.key_id)
+            }
+            None => !encrypted,
+        };
+
+        if current {
+            KeyStatus::Current
+        } else if !encrypted || first_record.map_or(true, |record| self.cipher_of(record).is_some())
+        {
+            KeyStatus::Readable
+        } else {
+            KeyStatus::Unknown
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::replication::frame::FrameHeader;
+    use crate::replication::WAL_PAGE_SIZE;
+
+    use super::*;
+
+    const LOCATION: RecordLocation = RecordLocation::Log {
+        db_id: 1,
+        frame_no: 42,
+    };
+
+    fn frame() -> Frame {
+        let header = FrameHeader {
+            frame_no: 42,
+            checksum: 1234,
+            page_no: 3,
+            size_after: 4,
+        };
+        Frame::from_parts(&header, &[7; WAL_PAGE_SIZE as usize])
+    }
+
+    #[test]
+    fn encrypt_frames() {
+        let encryption = FrameEncryption::new(FrameCipher::new(&[1; KEY_SIZE]));
+        let frame = frame();
+
+        let record = encryption
+            .encode(&frame, true, LOCATION)
+            .unwrap()
+            .into_owned();
+        assert_eq!(record.len(), FrameEncryption::record_size(true));
+        assert!(!record
+            .windows(WAL_PAGE_SIZE as usize)
+            .any(|window| window == frame.page()));
+        let decoded = encryption
+            .decode(record.clone().into(), true, LOCATION)
+            .unwrap();
+        assert_eq!(decoded.as_slice(), frame.as_slice());
+        assert_eq!(
+            encryption.key_status(true, Some(&record[..])),
+            KeyStatus::Current
+        );
+        assert_eq!(encryption.key_status(true, None), KeyStatus::Current);
+        assert_eq!(encryption.key_status(false, None), KeyStatus::Readable);
+
+        let mut corrupted = record.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(encryption.decode(corrupted.into(), true, LOCATION).is_err());
+
+        // after a key rotation, the frames can only be read with the previous key
+        let rotated = FrameEncryption::new(FrameCipher::new(&[2; KEY_SIZE]));
+        assert_eq!(
+            rotated.key_status(true, Some(&record[..])),
+            KeyStatus::Unknown
+        );
+        assert!(rotated
+            .decode(record.clone().into(), true, LOCATION)
+            .is_err());
+        assert!(FrameEncryption::default()
+            .decode(record.clone().into(), true, LOCATION)
+            .is_err());
+        let rotated = rotated.with_previous_key(FrameCipher::new(&[1; KEY_SIZE]));
+        assert_eq!(
+            rotated.key_status(true, Some(&record[..])),
+            KeyStatus::Readable
+        );
+        let decoded = rotated.decode(record.into(), true, LOCATION).unwrap();
+        assert_eq!(decoded.as_slice(), frame.as_slice());
+
+        let plaintext = FrameEncryption::default();
+        let record = plaintext.encode(&frame, false, LOCATION).unwrap();
+        assert_eq!(&*record, frame.as_slice());
+        assert_eq!(
+            plaintext.key_status(false, Some(&*record)),
+            KeyStatus::Current
+        );
+        assert_eq!(plaintext.key_status(true, None), KeyStatus::Readable);
+        assert_eq!(
+            encryption.key_status(false, Some(&*record)),
+            KeyStatus::Readable
+        );
+    }
+
+    #[test]
+    fn misplaced_records_fail_to_decode() {
+        let encryption = FrameEncryption::new(FrameCipher::new(&[1; KEY_SIZE]));
+        let record = encryption
+            .encode(&frame(), true, LOCATION)
+            .unwrap()
+            .into_owned();
+
+        for location in [
+            RecordLocation::Log {
+                db_id: 2,
+                frame_no: 42,
+            },
+            RecordLocation::Log {
+                db_id: 1,
+                frame_no: 43,
+            },
+            RecordLocation::Snapshot {
+                db_id: 1,
+                index: 42,
+            },
+        ] {
+            assert!(encryption
+                .decode(record.clone().into(), true, location)
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn parse_key() {
+        let key = BASE64_STANDARD.encode([1; KEY_SIZE]);
+        let cipher = FrameCipher::from_base64(&format!("{key}\n")).unwrap();
+        assert_eq!(cipher.key_id, key_id(&[1; KEY_SIZE]));
+
+        assert!(FrameCipher::from_base64("").is_err());
+        assert!(FrameCipher::from_base64("not base64!").is_err());
+        assert!(FrameCipher::from_base64(&BASE64_STANDARD.encode([1; 16])).is_err());
+    }
+}
@@ -0,0 +1,152 @@
+//! The history of the commits of a database: the time at which each transaction was committed to
+//! the replication log, so that the database can be restored as of a point in time.
+//!
+//! Each commit is a record of the frame_no of the last frame of the transaction, and of the time
+//! of the commit in milliseconds since the UNIX epoch. Records are not synced to disk: the records
+//! lost in a crash only make the time of the commits before them less precise.
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use parking_lot::Mutex;
+use tempfile::NamedTempFile;
+
+use super::FrameNo;
+
+const HISTORY_FILE_NAME: &str = "commit_history";
+const RECORD_SIZE: usize = 16;
+
+/// A commit: the frame_no of the last frame of the transaction, and the time it was committed.
+pub type Commit = (FrameNo, SystemTime);
+
+pub struct CommitHistory {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl CommitHistory {
+    pub fn open(db_path: &Path) -> anyhow::Result<Self> {
+        let path = db_path.join(HISTORY_FILE_NAME);
+        let file = open_append(&path)?;
+        // drop the partial record of a crash, so that the next records are aligned
+        let len = file.metadata()?.len();
+        file.set_len(len - len % RECORD_SIZE as u64)?;
+
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Records that the transaction ending with `frame_no` was committed at `time`.
+    pub fn record(&self, frame_no: FrameNo, time: SystemTime) -> anyhow::Result<()> {
+        let record = encode_commit(frame_no, time)?;
+        self.file.lock().write_all(&record)?;
+
+        Ok(())
+    }
+
+    /// Returns the recorded commits, in commit order.
+    pub fn commits(&self) -> anyhow::Result<Vec<Commit>> {
+        let _file = self.file.lock();
+        read_commits(self.path.parent().unwrap())
+    }
+
+    /// Forgets all the commits, when the log starts again from the database file.
+    pub fn clear(&self) -> anyhow::Result<()> {
+        self.file.lock().set_len(0)?;
+
+        Ok(())
+    }
+
+    /// Forgets the commits before `frame_no`, once the database can't be restored as of these
+    /// commits anymore.
+    pub fn truncate_before(&self, frame_no: FrameNo) -> anyhow::Result<()> {
+        let mut file = self.file.lock();
+        let commits = read_commits(self.path.parent().unwrap())?;
+        let mut temp = BufWriter::new(NamedTempFile::new_in(self.path.parent().unwrap())?);
+        for (commit_frame_no, time) in commits {
+            if commit_frame_no >= frame_no {
+                temp.write_all(&encode_commit(commit_frame_no, time)?)?;
+            }
+        }
+        temp.into_inner()?.persist(&self.path)?;
+        *file = open_append(&self.path)?;
+
+        Ok(())
+    }
+}
+
+/// Returns the commits recorded in the history of the database at `db_path`, in commit order.
+pub fn read_commits(db_path: &Path) -> anyhow::Result<Vec<Commit>> {
+    let data = match std::fs::read(db_path.join(HISTORY_FILE_NAME)) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(data
+        .chunks_exact(RECORD_SIZE)
+        .map(|record| {
+            let frame_no = FrameNo::from_le_bytes(record[..8].try_into().unwrap());
+            let millis = u64::from_le_bytes(record[8..].try_into().unwrap());
+            (frame_no, UNIX_EPOCH + Duration::from_millis(millis))
+        })
+        .collect())
+}
+
+fn encode_commit(frame_no: FrameNo, time: SystemTime) -> anyhow::Result<[u8; RECORD_SIZE]> {
+    let millis = time.duration_since(UNIX_EPOCH)?.as_millis() as u64;
+    let mut record = [0; RECORD_SIZE];
+    record[..8].copy_from_slice(&frame_no.to_le_bytes());
+    record[8..].copy_from_slice(&millis.to_le_bytes());
+
+    Ok(record)
+}
+
+fn open_append(path: &Path) -> anyhow::Result<File> {
+    Ok(OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(path)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn record_commits() {
+        let tmp = tempfile::tempdir().unwrap();
+        let history = CommitHistory::open(tmp.path()).unwrap();
+        let time = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        for (frame_no, secs) in [(2, 10), (5, 20), (9, 30)] {
+            history.record(frame_no, time(secs)).unwrap();
+        }
+        assert_eq!(
+            history.commits().unwrap(),
+            vec![(2, time(10)), (5, time(20)), (9, time(30))]
+        );
+
+        history.truncate_before(5).unwrap();
+        history.record(12, time(40)).unwrap();
+        assert_eq!(
+            read_commits(tmp.path()).unwrap(),
+            vec![(5, time(20)), (9, time(30)), (12, time(40))]
+        );
+
+        // a partial record is dropped when the history is opened again
+        drop(history);
+        let path = tmp.path().join(HISTORY_FILE_NAME);
+        open_append(&path).unwrap().write_all(&[1; 5]).unwrap();
+        let history = CommitHistory::open(tmp.path()).unwrap();
+        history.record(13, time(50)).unwrap();
+        assert_eq!(history.commits().unwrap().len(), 4);
+
+        history.clear().unwrap();
+        assert!(history.commits().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,538 @@
+//! Inspection of the replication log and snapshots of a database, for the `sqld` utils commands,
+//! and restoration of the database as of a point of its history.
+
+use std::cmp::Reverse;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::prelude::FileExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::{bail, ensure, Context};
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use super::encryption::FrameEncryption;
+use super::frame::Frame;
+use super::history::read_commits;
+use super::primary::logger::LogFile;
+use super::snapshot::{parse_snapshot_name, snapshot_dir_path, snapshot_list, SnapshotFile};
+use super::{frame_checksum, FrameNo, ENCRYPTED_WAL_MAGIC, WAL_PAGE_SIZE};
+
+const LOG_FILE_NAME: &str = "wallog";
+
+fn open_log(db_path: &Path, encryption: FrameEncryption) -> anyhow::Result<LogFile> {
+    let log_path = db_path.join(LOG_FILE_NAME);
+    let file = File::open(&log_path)
+        .with_context(|| format!("could not open the log at `{}`", log_path.display()))?;
+    // an empty log would be initialized by LogFile::new
+    ensure!(file.metadata()?.len() > 0, "the log is empty");
+
+    LogFile::new(file, u64::MAX, None, encryption)
+}
+
+/// Prints the header of the replication log of the database at `db_path`.
+pub fn print_log_header(db_path: &Path, out: &mut impl Write) -> anyhow::Result<()> {
+    let header = LogFile::read_header(&File::open(db_path.join(LOG_FILE_NAME))?)?;
+    let [_, major, minor, patch] = header.sqld_version;
+
+    writeln!(out, "db_id:          {}", Uuid::from_u128(header.db_id))?;
+    writeln!(out, "start_frame_no: {}", header.start_frame_no)?;
+    writeln!(out, "frame_count:    {}", header.frame_count)?;
+    writeln!(out, "last_frame_no:  {}", header.last_frame_no())?;
+    writeln!(out, "start_checksum: {:#018x}", header.start_checksum)?;
+    writeln!(out, "version:        {}", header.version)?;
+    writeln!(out, "sqld_version:   {major}.{minor}.{patch}")?;
+    writeln!(out, "page_size:      {}", header.page_size)?;
+    writeln!(
+        out,
+        "encrypted:      {}",
+        header.magic == ENCRYPTED_WAL_MAGIC
+    )?;
+
+    Ok(())
+}
+
+/// Prints the frames of the replication log of the database at `db_path`, and verifies that each
+/// frame follows the previous one, and that its checksum matches the checksum of the previous
+/// frame and its page. Fails if a frame is invalid.
+pub fn print_log_frames(
+    db_path: &Path,
+    encryption: FrameEncryption,
+    out: &mut impl Write,
+) -> anyhow::Result<()> {
+    let log = open_log(db_path, encryption)?;
+    let header = *log.header();
+    // The checksum before the first frame is unknown if the log continues from a snapshot that
+    // carries no checksum.
+    let mut previous_checksum =
+        (header.start_frame_no == 0 || header.start_checksum != 0).then_some(header.start_checksum);
+    let mut invalid = 0;
+
+    writeln!(
+        out,
+        "{:>12} {:>10} {:>10} {:>18}  status",
+        "frame_no", "page_no", "size_after", "checksum"
+    )?;
+    for (frame, expected_frame_no) in log.frames_iter()?.zip(header.start_frame_no..) {
+        let frame = frame?;
+        let frame_header = frame.header();
+        let status = if frame_header.frame_no != expected_frame_no {
+            format!("invalid frame_no, expected {expected_frame_no}")
+        } else {
+            match previous_checksum {
+                Some(previous) => {
+                    let checksum = frame_checksum(previous, frame.page());
+                    if checksum == frame_header.checksum {
+                        "ok".to_string()
+                    } else {
+                        format!("invalid checksum, expected {checksum:#018x}")
+                    }
+                }
+                None => "unverified".to_string(),
+            }
+        };
+        if status.starts_with("invalid") {
+            invalid += 1;
+        }
+        writeln!(
+            out,
+            "{:>12} {:>10} {:>10} {:>#18x}  {status}",
+            frame_header.frame_no,
+            frame_header.page_no,
+            frame_header.size_after,
+            frame_header.checksum
+        )?;
+        // continue from the checksum of the frame, to report each invalid frame
+        previous_checksum = Some(frame_header.checksum);
+    }
+
+    ensure!(
+        invalid == 0,
+        "{invalid} of the {} frames of the log are invalid",
+        header.frame_count
+    );
+
+    Ok(())
+}
+
+/// Returns the snapshots of the database at `db_path`, sorted by start frame_no, with their file
+/// names.
+fn snapshots(
+    db_path: &Path,
+    encryption: &FrameEncryption,
+) -> anyhow::Result<Vec<(String, SnapshotFile)>> {
+    if !snapshot_dir_path(db_path).exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut snapshots = Vec::new();
+    for name in snapshot_list(db_path)? {
+        if parse_snapshot_name(&name).is_none() {
+            continue;
+        }
+        let path = snapshot_dir_path(db_path).join(&name);
+        let snapshot = SnapshotFile::open(&path, encryption.clone())?;
+        snapshots.push((name, snapshot));
+    }
+    snapshots.sort_by_key(|(_, snapshot)| snapshot.header().start_frame_no);
+
+    Ok(snapshots)
+}
+
+/// Prints the snapshots of the database at `db_path`.
+pub fn print_snapshots(
+    db_path: &Path,
+    encryption: FrameEncryption,
+    out: &mut impl Write,
+) -> anyhow::Result<()> {
+    writeln!(
+        out,
+        "{:<36} {:>14} {:>14} {:>12} {:>10}  encrypted  name",
+        "db_id", "start_frame_no", "end_frame_no", "frame_count", "size_after"
+    )?;
+    for (name, snapshot) in snapshots(db_path, &encryption)? {
+        let header = snapshot.header();
+        writeln!(
+            out,
+            "{:<36} {:>14} {:>14} {:>12} {:>10}  {:<9}  {name}",
+            Uuid::from_u128(header.db_id),
+            header.start_frame_no,
+            header.end_frame_no,
+            header.frame_count,
+            header.size_after,
+            header.encrypted != 0,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// A point of the history of a database, that it can be restored as of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestorePoint {
+    /// The last transaction of the log.
+    Latest,
+    /// The last transaction committed at or before a frame. The frame can't be in the middle of a
+    /// snapshot, which only keeps the last version of each page.
+    FrameNo(FrameNo),
+    /// The last transaction committed at or before a time, or, if it is in the middle of a
+    /// snapshot, the end of the last snapshot before it.
+    Time(SystemTime),
+}
+
+/// Writes the database at `db_path`, as of `point`, to a new SQLite database file at `output`.
+/// The pages are taken from the snapshots and the replication log.
+///
+/// Returns the frame_no of the last frame of the database.
+pub fn materialize(
+    db_path: &Path,
+    encryption: FrameEncryption,
+    point: RestorePoint,
+    output: &Path,
+) -> anyhow::Result<FrameNo> {
+    Sources::open(db_path, encryption)?.materialize(point, output)
+}
+
+/// The snapshots and the replication log of a database, that it is rebuilt from.
+pub(crate) struct Sources {
+    db_path: PathBuf,
+    snapshots: Vec<(String, SnapshotFile)>,
+    log: LogFile,
+}
+
+impl Sources {
+    pub(crate) fn open(db_path: &Path, encryption: FrameEncryption) -> anyhow::Result<Self> {
+        let mut snapshots = snapshots(db_path, &encryption)?;
+        // A merged snapshot exists alongside the snapshots it merged until they are removed: the
+        // snapshots covered by another one are skipped.
+        snapshots.sort_by_key(|(_, snapshot)| {
+            let header = snapshot.header();
+            (header.start_frame_no, Reverse(header.end_frame_no))
+        });
+        let mut end_frame_no = None;
+        snapshots.retain(|(_, snapshot)| {
+            let end = snapshot.header().end_frame_no;
+            let keep = end_frame_no.map_or(true, |covered| end > covered);
+            if keep {
+                end_frame_no = Some(end);
+            }
+            keep
+        });
+        let log = open_log(db_path, encryption)?;
+
+        Ok(Self {
+            db_path: db_path.to_path_buf(),
+            snapshots,
+            log,
+        })
+    }
+
+    /// Writes the database as of `point` to a new SQLite database file at `output`, and returns
+    /// the frame_no of its last frame.
+    pub(crate) fn materialize(
+        &self,
+        point: RestorePoint,
+        output: &Path,
+    ) -> anyhow::Result<FrameNo> {
+        let target = match point {
+            RestorePoint::Latest => FrameNo::MAX,
+            RestorePoint::FrameNo(frame_no) => frame_no,
+            RestorePoint::Time(time) => self.frame_no_at(time)?,
+        };
+
+        let out = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(output)
+            .with_context(|| format!("could not create `{}`", output.display()))?;
+        let res = write_database(&self.snapshots, &self.log, target, &out);
+        if res.is_err() {
+            drop(out);
+            let _ = std::fs::remove_file(output);
+        }
+
+        res
+    }
+
+    /// Returns the last frame of the last state of the database, committed at or before `time`,
+    /// that can be restored.
+    fn frame_no_at(&self, time: SystemTime) -> anyhow::Result<FrameNo> {
+        let commits = read_commits(&self.db_path)?;
+        let commit = commits
+            .iter()
+            .rev()
+            .find(|(_, commit_time)| *commit_time <= time);
+        let time = DateTime::<Utc>::from(time).to_rfc3339();
+        let Some(&(commit_frame_no, _)) = commit else {
+            bail!("no transaction was recorded as committed at or before {time}");
+        };
+
+        // the state of the database inside a snapshot can't be restored, the end of a snapshot
+        // before it is
+        let mut frame_no = None;
+        for (_, snapshot) in &self.snapshots {
+            let end_frame_no = snapshot.header().end_frame_no;
+            if end_frame_no <= commit_frame_no {
+                frame_no = frame_no.max(Some(end_frame_no));
+            }
+        }
+        for frame in self.log.frames_iter()? {
+            let frame = frame?;
+            let header = frame.header();
+            if header.frame_no > commit_frame_no {
+                break;
+            }
+            if header.size_after != 0 {
+                frame_no = frame_no.max(Some(header.frame_no));
+            }
+        }
+
+        frame_no.with_context(|| format!("the database can't be restored as of {time}"))
+    }
+}
+
+fn write_database(
+    snapshots: &[(String, SnapshotFile)],
+    log: &LogFile,
+    target: FrameNo,
+    out: &File,
+) -> anyhow::Result<FrameNo> {
+    let write_page = |frame: &Frame| {
+        let offset = (frame.header().page_no as u64 - 1) * WAL_PAGE_SIZE as u64;
+        out.write_all_at(frame.page(), offset)
+    };
+    // the next frame to apply, and the last frame that was applied with the size of the database
+    let mut next_frame_no = 0;
+    let mut last = None;
+
+    for (name, snapshot) in snapshots {
+        let header = *snapshot.header();
+        if header.start_frame_no > target {
+            break;
+        }
+        ensure!(
+            header.end_frame_no <= target,
+            "frame {target} is in snapshot `{name}`, which only has the last version of the pages of frames {} to {}",
+            header.start_frame_no,
+            header.end_frame_no
+        );
+        ensure!(
+            header.start_frame_no <= next_frame_no,
+            "frames {next_frame_no} to {} are missing",
+            header.start_frame_no - 1
+        );
+        for frame in snapshot.frames_iter() {
+            write_page(&Frame::try_from_bytes(frame?)?)?;
+        }
+        next_frame_no = next_frame_no.max(header.end_frame_no + 1);
+        last = Some((header.end_frame_no, header.size_after));
+    }
+
+    let log_header = *log.header();
+    if log_header.frame_count > 0 && log_header.start_frame_no <= target {
+        ensure!(
+            log_header.start_frame_no <= next_frame_no,
+            "frames {next_frame_no} to {} are missing",
+            log_header.start_frame_no - 1
+        );
+        // frames before the end of the snapshots are older than the snapshots
+        let mut last_commit = None;
+        for frame in log.frames_iter()? {
+            let frame = frame?;
+            let header = frame.header();
+            if header.frame_no > target {
+                break;
+            }
+            if header.frame_no >= next_frame_no && header.size_after != 0 {
+                last_commit = Some((header.frame_no, header.size_after));
+            }
+        }
+
+        if let Some((commit_frame_no, _)) = last_commit {
+            for frame in log.frames_iter()? {
+                let frame = frame?;
+                let frame_no = frame.header().frame_no;
+                if frame_no > commit_frame_no {
+                    break;
+                }
+                if frame_no >= next_frame_no {
+                    write_page(&frame)?;
+                }
+            }
+            last = last_commit;
+        }
+    }
+
+    let Some((last_frame_no, size_after)) = last else {
+        bail!("no transaction was committed at or before frame {target}");
+    };
+    out.set_len(size_after as u64 * WAL_PAGE_SIZE as u64)?;
+    out.sync_all()?;
+
+    Ok(last_frame_no)
+}
+
+/// Returns the directory of the database of `namespace`, in the data directory at `path`.
+pub fn namespace_db_path(path: &Path, namespace: &str) -> anyhow::Result<PathBuf> {
+    let db_path = path.join("dbs").join(namespace);
+    if !db_path.exists() {
+        bail!("no database for namespace `{namespace}`");
+    }
+
+    Ok(db_path)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use bytes::Bytes;
+
+    use crate::replication::history::CommitHistory;
+    use crate::replication::primary::logger::WalPage;
+    use crate::replication::snapshot::{LogCompactor, MergePolicy};
+
+    use super::*;
+
+    fn push_pages(log: &mut LogFile, pages: &[(u32, u8)], size_after: u32) {
+        for (i, (page_no, byte)) in pages.iter().enumerate() {
+            log.push_page(&WalPage {
+                page_no: *page_no,
+                size_after: if i == pages.len() - 1 { size_after } else { 0 },
+                data: Bytes::from(vec![*byte; WAL_PAGE_SIZE as usize]),
+            })
+            .unwrap();
+        }
+        log.commit().unwrap();
+    }
+
+    fn pages(path: &Path) -> Vec<u8> {
+        let data = std::fs::read(path).unwrap();
+        data.chunks(WAL_PAGE_SIZE as usize).map(|p| p[0]).collect()
+    }
+
+    #[test]
+    fn materialize_database() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_path = tmp.path().join("db");
+        std::fs::create_dir(&db_path).unwrap();
+        let open = |path: &Path| {
+            let file = OpenOptions::new()
+                .create(true)
+                .read(true)
+                .write(true)
+                .open(path)
+                .unwrap();
+            LogFile::new(file, u64::MAX, None, FrameEncryption::default()).unwrap()
+        };
+
+        // frames 0 to 4 are compacted into a snapshot
+        let compacted_path = tmp.path().join("compacted");
+        let mut log = open(&compacted_path);
+        let db_id = log.header().db_id;
+        push_pages(&mut log, &[(1, 1), (2, 1), (3, 1)], 3);
+        push_pages(&mut log, &[(1, 2), (2, 2)], 3);
+        let history = Arc::new(CommitHistory::open(&db_path).unwrap());
+        let compactor = LogCompactor::new(
+            &db_path,
+            db_id,
+            Box::new(|_| Ok(())),
+            Default::default(),
+            history.clone(),
+            Arc::new(MergePolicy::default),
+        )
+        .unwrap();
+        compactor.compact(log, compacted_path, 3).unwrap();
+        thread::sleep(Duration::from_secs(1));
+
+        // frames 5 to 7 are in the log
+        let mut log = open(&db_path.join(LOG_FILE_NAME));
+        log.header.start_frame_no = 5;
+        log.write_header().unwrap();
+        push_pages(&mut log, &[(2, 3), (4, 3)], 4);
+        push_pages(&mut log, &[(1, 4)], 4);
+
+        let materialize = |point, name| {
+            let output = tmp.path().join(name);
+            let res = materialize(&db_path, Default::default(), point, &output);
+            res.map(|frame_no| (frame_no, pages(&output)))
+        };
+        assert_eq!(
+            materialize(RestorePoint::Latest, "last").unwrap(),
+            (7, vec![4, 3, 1, 3])
+        );
+        assert_eq!(
+            materialize(RestorePoint::FrameNo(6), "6").unwrap(),
+            (6, vec![2, 3, 1, 3])
+        );
+        // frame 5 is not the end of a transaction
+        assert_eq!(
+            materialize(RestorePoint::FrameNo(5), "5").unwrap(),
+            (4, vec![2, 2, 1])
+        );
+        assert!(materialize(RestorePoint::FrameNo(3), "3").is_err());
+
+        // frames 2 and 4 are in the snapshot, 6 and 7 in the log
+        let time = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        for (frame_no, secs) in [(2, 10), (4, 20), (6, 30), (7, 40)] {
+            history.record(frame_no, time(secs)).unwrap();
+        }
+        assert_eq!(
+            materialize(RestorePoint::Time(time(35)), "t35").unwrap(),
+            (6, vec![2, 3, 1, 3])
+        );
+        assert_eq!(
+            materialize(RestorePoint::Time(time(20)), "t20").unwrap(),
+            (4, vec![2, 2, 1])
+        );
+        // the state after frame 2 is not in the snapshot anymore
+        assert!(materialize(RestorePoint::Time(time(15)), "t15").is_err());
+        assert!(materialize(RestorePoint::Time(time(5)), "t5").is_err());
+
+        let mut out = Vec::new();
+        print_snapshots(&db_path, Default::default(), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains(&format!("{}-0-4.snap", Uuid::from_u128(db_id))));
+    }
+
+    #[test]
+    fn verify_log_frames() {
+        let tmp = tempfile::tempdir().unwrap();
+        let log_path = tmp.path().join(LOG_FILE_NAME);
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .open(&log_path)
+            .unwrap();
+        let mut log = LogFile::new(file, u64::MAX, None, FrameEncryption::default()).unwrap();
+        push_pages(&mut log, &[(1, 1), (2, 1), (3, 1)], 3);
+
+        let mut out = Vec::new();
+        print_log_frames(tmp.path(), Default::default(), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.lines().filter(|l| l.ends_with("ok")).count(), 3);
+
+        let mut out = Vec::new();
+        print_log_header(tmp.path(), &mut out).unwrap();
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .contains("frame_count:    3"));
+
+        // corrupt the page of the second frame
+        let offset = log_path.metadata().unwrap().len() - LogFile::FRAME_SIZE as u64 - 1;
+        OpenOptions::new()
+            .write(true)
+            .open(&log_path)
+            .unwrap()
+            .write_all_at(&[42], offset)
+            .unwrap();
+        let mut out = Vec::new();
+        assert!(print_log_frames(tmp.path(), Default::default(), &mut out).is_err());
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.lines().filter(|l| l.contains("invalid")).count(), 1);
+    }
+}
@@ -1,15 +1,98 @@
+pub mod compression;
+pub mod encryption;
 pub mod frame;
+pub mod history;
+pub mod inspect;
 pub mod primary;
 pub mod replica;
 mod snapshot;
+mod status;
+
+use std::time::Duration;
 
 use crc::Crc;
 pub use primary::logger::{LogReadError, ReplicationLogger, ReplicationLoggerHook};
-pub use snapshot::{NamespacedSnapshotCallback, SnapshotCallback};
+pub use snapshot::{
+    parse_snapshot_name, MergePolicy, MergePolicyFn, SnapshotCallback, SnapshotInfo,
+};
+pub use status::{ReplicaStatus, ReplicationStatus};
+use tokio::sync::watch;
 
 pub const WAL_PAGE_SIZE: i32 = 4096;
 pub const WAL_MAGIC: u64 = u64::from_le_bytes(*b"SQLDWAL\0");
+/// Magic of the logs whose frames are encrypted.
+pub const ENCRYPTED_WAL_MAGIC: u64 = u64::from_le_bytes(*b"SQLDWALE");
 const CRC_64_GO_ISO: Crc<u64> = Crc::<u64>::new(&crc::CRC_64_GO_ISO);
 
 /// The frame uniquely identifying, monotonically increasing number
 pub type FrameNo = u64;
+
+/// Returns the checksum of `frames`, continuing from the checksum of the frames that precede them.
+pub fn frames_checksum<'a>(
+    initial: u64,
+    frames: impl IntoIterator<Item = &'a frame::Frame>,
+) -> u64 {
+    let mut digest = CRC_64_GO_ISO.digest_with_initial(initial);
+    for frame in frames {
+        digest.update(frame.as_slice());
+    }
+    digest.finalize()
+}
+
+/// Returns the rolling checksum of a frame with `page`, following a frame with the rolling
+/// checksum `previous`. The chain of a log starts from its `start_checksum`, 0 for a new log.
+pub fn frame_checksum(previous: u64, page: &[u8]) -> u64 {
+    let mut digest = CRC_64_GO_ISO.digest_with_initial(previous);
+    digest.update(page);
+    digest.finalize()
+}
+
+/// Returns the checksum of the content of a page.
+pub fn page_checksum(page: &[u8]) -> u64 {
+    CRC_64_GO_ISO.checksum(page)
+}
+
+/// How long a request that carries a minimum frame_no may wait for the database to reach it.
+pub const FRAME_NO_WAIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Returns the last frame_no reported by a frame notifier. Replicas report `FrameNo::MAX` until
+/// they have applied their first frame.
+pub fn current_frame_no(notifier: &watch::Receiver<FrameNo>) -> Option<FrameNo> {
+    let frame_no = *notifier.borrow();
+    (frame_no != FrameNo::MAX).then_some(frame_no)
+}
+
+/// Waits until the frame notifier of a database reports `frame_no`, that is, until the database
+/// has committed (on a primary) or applied (on a replica) it.
+pub async fn wait_frame_no(
+    mut notifier: watch::Receiver<FrameNo>,
+    frame_no: FrameNo,
+    timeout: Duration,
+) -> crate::Result<()> {
+    let reached = |current: &FrameNo| *current != FrameNo::MAX && *current >= frame_no;
+    match tokio::time::timeout(timeout, notifier.wait_for(reached)).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(_)) => Err(crate::Error::ReplicatorExited),
+        Err(_) => Err(crate::Error::FrameNoTimeout(frame_no)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn wait_frame_no_until_reached() {
+        let (sender, receiver) = watch::channel(FrameNo::MAX);
+        assert_eq!(current_frame_no(&receiver), None);
+
+        let wait = tokio::spawn(wait_frame_no(receiver.clone(), 5, Duration::from_secs(5)));
+        sender.send(3).unwrap();
+        sender.send(5).unwrap();
+        wait.await.unwrap().unwrap();
+        assert_eq!(current_frame_no(&receiver), Some(5));
+
+        let res = wait_frame_no(receiver, 10, Duration::from_millis(10)).await;
+        assert!(matches!(res, Err(crate::Error::FrameNoTimeout(10))));
+    }
+}
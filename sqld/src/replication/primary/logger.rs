@@ -4,7 +4,9 @@ use std::io::Write;
 use std::mem::size_of;
 use std::os::unix::prelude::FileExt;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::SystemTime;
 
 use anyhow::{bail, ensure};
 use bytemuck::{bytes_of, pod_read_unaligned, Pod, Zeroable};
@@ -15,6 +17,7 @@ use tokio::sync::watch;
 use tokio::time::{Duration, Instant};
 use uuid::Uuid;
 
+use crate::cluster::Lease;
 use crate::libsql::ffi::SQLITE_IOERR_WRITE;
 use crate::libsql::ffi::{
     sqlite3,
@@ -22,9 +25,18 @@ use crate::libsql::ffi::{
     PageHdrIter, PgHdr, Wal, SQLITE_CHECKPOINT_TRUNCATE, SQLITE_IOERR, SQLITE_OK,
 };
 use crate::libsql::wal_hook::WalHook;
-use crate::replication::frame::{Frame, FrameHeader};
-use crate::replication::snapshot::{find_snapshot_file, LogCompactor, SnapshotFile};
-use crate::replication::{FrameNo, SnapshotCallback, CRC_64_GO_ISO, WAL_MAGIC, WAL_PAGE_SIZE};
+use crate::replication::encryption::{FrameEncryption, KeyStatus, RecordLocation};
+use crate::replication::frame::{Frame, FrameBorrowed, FrameHeader};
+use crate::replication::history::CommitHistory;
+use crate::replication::inspect::{RestorePoint, Sources};
+use crate::replication::primary::quorum::{self, QuorumWaiter};
+use crate::replication::snapshot::{
+    find_snapshot_file, list_snapshots, reencrypt_snapshots, snapshots_key_status, LogCompactor,
+    MergePolicyFn, SnapshotFile, SnapshotInfo,
+};
+use crate::replication::{
+    frame_checksum, FrameNo, SnapshotCallback, ENCRYPTED_WAL_MAGIC, WAL_MAGIC, WAL_PAGE_SIZE,
+};
 
 init_static_wal_method!(REPLICATION_METHODS, ReplicationLoggerHook);
 
@@ -47,6 +59,10 @@ pub struct ReplicationLoggerHookCtx {
     buffer: Vec<WalPage>,
     logger: Arc<ReplicationLogger>,
     bottomless_replicator: Option<Arc<std::sync::Mutex<bottomless::replicator::Replicator>>>,
+    /// Makes commits wait for the replicas, with semi-synchronous replication.
+    quorum: Option<Arc<QuorumWaiter>>,
+    /// Rejects writes once the lease of this node expires, in cluster mode.
+    lease: Option<Arc<Lease>>,
 }
 
 /// This implementation of WalHook intercepts calls to `on_frame`, and writes them to a
@@ -78,6 +94,11 @@ unsafe impl WalHook for ReplicationLoggerHook {
         let last_valid_frame = wal.hdr.mxFrame;
         let ctx = Self::wal_extract_ctx(wal);
 
+        if ctx.lease.as_ref().map_or(false, |lease| !lease.is_valid()) {
+            tracing::warn!("rejecting write: this node doesn't hold the cluster lease");
+            return SQLITE_IOERR;
+        }
+
         for (page_no, data) in PageHdrIter::new(page_headers, page_size as _) {
             ctx.write_frame(page_no, data)
         }
@@ -99,14 +120,17 @@ unsafe impl WalHook for ReplicationLoggerHook {
         };
 
         if is_commit != 0 && rc == 0 {
-            if let Err(e) = ctx.commit() {
-                // If we reach this point, it means that we have commited a transaction to sqlite wal,
-                // but failed to commit it to the shadow WAL, which leaves us in an inconsistent state.
-                tracing::error!(
-                    "fatal error: log failed to commit: inconsistent replication log: {e}"
-                );
-                std::process::abort();
-            }
+            let new_frame_no = match ctx.commit() {
+                Ok(new_frame_no) => new_frame_no,
+                Err(e) => {
+                    // If we reach this point, it means that we have commited a transaction to sqlite wal,
+                    // but failed to commit it to the shadow WAL, which leaves us in an inconsistent state.
+                    tracing::error!(
+                        "fatal error: log failed to commit: inconsistent replication log: {e}"
+                    );
+                    std::process::abort();
+                }
+            };
 
             // do backup after log replication as we don't want to replicate potentially
             // inconsistent frames
@@ -121,13 +145,25 @@ unsafe impl WalHook for ReplicationLoggerHook {
                 replicator.submit_frames(frame_count as u32);
             }
 
-            if let Err(e) = ctx.logger.log_file.write().maybe_compact(
+            match ctx.logger.log_file.write().maybe_compact(
                 ctx.logger.compactor.clone(),
                 ntruncate,
                 &ctx.logger.db_path,
             ) {
-                tracing::error!("fatal error: {e}, exiting");
-                std::process::abort()
+                Ok(true) => {
+                    ctx.logger.compaction_count.fetch_add(1, Ordering::Relaxed);
+                }
+                Ok(false) => (),
+                Err(e) => {
+                    tracing::error!("fatal error: {e}, exiting");
+                    std::process::abort()
+                }
+            }
+
+            // The transaction is committed: only the acknowledgment to the client waits for the
+            // replicas, once the write lock is released.
+            if let Some(quorum) = &ctx.quorum {
+                quorum::set_pending_commit(quorum.clone(), new_frame_no - 1);
             }
         }
 
@@ -238,9 +274,15 @@ unsafe impl WalHook for ReplicationLoggerHook {
                     return SQLITE_IOERR_WRITE;
                 }
                 replicator.new_generation();
-                if let Err(e) =
-                    runtime.block_on(async move { replicator.snapshot_main_db_file().await })
-                {
+                let log_id = Uuid::from_u128(ctx.logger.log_file.read().header().db_id);
+                let next_frame_no = *ctx.logger.new_frame_notifier.borrow();
+                if let Err(e) = runtime.block_on(async move {
+                    replicator.snapshot_main_db_file().await?;
+                    // replicas can bootstrap from the snapshot of the new generation
+                    replicator
+                        .store_replication_index(log_id, next_frame_no)
+                        .await
+                }) {
                     tracing::error!("Failed to snapshot the main db file during checkpoint: {e}");
                     return SQLITE_IOERR_WRITE;
                 }
@@ -262,12 +304,16 @@ impl ReplicationLoggerHookCtx {
     pub fn new(
         logger: Arc<ReplicationLogger>,
         bottomless_replicator: Option<Arc<std::sync::Mutex<bottomless::replicator::Replicator>>>,
+        quorum: Option<Arc<QuorumWaiter>>,
+        lease: Option<Arc<Lease>>,
     ) -> Self {
         tracing::trace!("bottomless replication enabled: {bottomless_replicator:?}");
         Self {
             buffer: Default::default(),
             logger,
             bottomless_replicator,
+            quorum,
+            lease,
         }
     }
 
@@ -291,10 +337,11 @@ impl ReplicationLoggerHookCtx {
         Ok(())
     }
 
-    fn commit(&self) -> anyhow::Result<()> {
+    /// Commits the written pages, and returns the frame_no of the next frame.
+    fn commit(&self) -> anyhow::Result<FrameNo> {
         let new_frame_no = self.logger.commit()?;
-        self.logger.new_frame_notifier.send_replace(new_frame_no);
-        Ok(())
+        self.logger.notify_next_frame_no(new_frame_no);
+        Ok(new_frame_no)
     }
 
     fn rollback(&mut self) {
@@ -327,6 +374,9 @@ pub struct LogFile {
 
     /// checksum of the last commited frame
     commited_checksum: u64,
+
+    /// how new frames are encrypted. The frames of the log are encrypted if its header says so.
+    encryption: FrameEncryption,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -347,6 +397,7 @@ impl LogFile {
         file: File,
         max_log_frame_count: u64,
         max_log_duration: Option<Duration>,
+        encryption: FrameEncryption,
     ) -> anyhow::Result<Self> {
         // FIXME: we should probably take a lock on this file, to prevent anybody else to write to
         // it.
@@ -357,7 +408,11 @@ impl LogFile {
             LogFileHeader {
                 version: 2,
                 start_frame_no: 0,
-                magic: WAL_MAGIC,
+                magic: if encryption.is_enabled() {
+                    ENCRYPTED_WAL_MAGIC
+                } else {
+                    WAL_MAGIC
+                },
                 page_size: WAL_PAGE_SIZE,
                 start_checksum: 0,
                 db_id: db_id.as_u128(),
@@ -377,6 +432,7 @@ impl LogFile {
             uncommitted_frame_count: 0,
             uncommitted_checksum: 0,
             commited_checksum: 0,
+            encryption,
         };
 
         if file_end == 0 {
@@ -399,7 +455,7 @@ impl LogFile {
         let mut buf = [0; size_of::<LogFileHeader>()];
         file.read_exact_at(&mut buf, 0)?;
         let header: LogFileHeader = pod_read_unaligned(&buf);
-        if header.magic != WAL_MAGIC {
+        if header.magic != WAL_MAGIC && header.magic != ENCRYPTED_WAL_MAGIC {
             bail!("invalid replication log header");
         }
 
@@ -410,6 +466,71 @@ impl LogFile {
         &self.header
     }
 
+    /// Whether the frames of the log are encrypted.
+    pub fn is_encrypted(&self) -> bool {
+        self.header.magic == ENCRYPTED_WAL_MAGIC
+    }
+
+    /// Returns how the frames of the log in `file` relate to the current key. New frames can only
+    /// be appended to a log that is encrypted with the current key, or that is not encrypted if
+    /// encryption is disabled: otherwise the log must be encrypted again, with `reencrypt`. A log
+    /// that is encrypted with an unknown key can't be opened.
+    pub fn key_status(file: &File, encryption: &FrameEncryption) -> anyhow::Result<KeyStatus> {
+        if file.metadata()?.len() == 0 {
+            return Ok(KeyStatus::Current);
+        }
+        let header = Self::read_header(file)?;
+        let encrypted = header.magic == ENCRYPTED_WAL_MAGIC;
+        let first_record = if header.frame_count > 0 {
+            let mut buf = vec![0; FrameEncryption::record_size(encrypted)];
+            file.read_exact_at(&mut buf, size_of::<LogFileHeader>() as u64)?;
+            Some(buf)
+        } else {
+            None
+        };
+
+        Ok(encryption.key_status(encrypted, first_record.as_deref()))
+    }
+
+    /// Writes the committed frames of the log in the directory `path` again, encrypted with the
+    /// current key, or in plaintext if encryption is disabled. The frames keep their frame_no and
+    /// checksum, so the snapshots and the commit history still follow the log.
+    fn reencrypt(self, path: &Path) -> anyhow::Result<Self> {
+        let temp_log_path = path.join("temp_log");
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp_log_path)?;
+        let mut new_log_file = LogFile::new(
+            file,
+            self.max_log_frame_count,
+            self.max_log_duration,
+            self.encryption.clone(),
+        )?;
+        new_log_file.header = LogFileHeader {
+            magic: new_log_file.header.magic,
+            frame_count: 0,
+            ..self.header
+        };
+        new_log_file.commited_checksum = self.header.start_checksum;
+        new_log_file.uncommitted_checksum = self.header.start_checksum;
+        for frame in self.frames_iter()? {
+            new_log_file.push_frame(&frame?)?;
+        }
+        new_log_file.commit()?;
+        new_log_file.file.sync_all()?;
+        std::fs::rename(&temp_log_path, path.join("wallog"))?;
+
+        Ok(new_log_file)
+    }
+
+    /// size of the record of a frame in the file
+    fn record_size(&self) -> usize {
+        FrameEncryption::record_size(self.is_encrypted())
+    }
+
     pub fn commit(&mut self) -> anyhow::Result<()> {
         self.header.frame_count += self.uncommitted_frame_count;
         self.uncommitted_frame_count = 0;
@@ -432,14 +553,13 @@ impl LogFile {
     }
 
     /// Returns an iterator over the WAL frame headers
-    #[allow(dead_code)]
-    fn frames_iter(&self) -> anyhow::Result<impl Iterator<Item = anyhow::Result<Frame>> + '_> {
+    pub fn frames_iter(&self) -> anyhow::Result<impl Iterator<Item = anyhow::Result<Frame>> + '_> {
         let mut current_frame_offset = 0;
         Ok(std::iter::from_fn(move || {
             if current_frame_offset >= self.header.frame_count {
                 return None;
             }
-            let read_byte_offset = Self::absolute_byte_offset(current_frame_offset);
+            let read_byte_offset = self.absolute_byte_offset(current_frame_offset);
             current_frame_offset += 1;
             Some(self.read_frame_byte_offset(read_byte_offset))
         }))
@@ -456,16 +576,14 @@ impl LogFile {
                 return None;
             }
             current_frame_offset -= 1;
-            let read_byte_offset = Self::absolute_byte_offset(current_frame_offset);
+            let read_byte_offset = self.absolute_byte_offset(current_frame_offset);
             let frame = self.read_frame_byte_offset(read_byte_offset);
             Some(frame)
         }))
     }
 
     fn compute_checksum(&self, page: &WalPage) -> u64 {
-        let mut digest = CRC_64_GO_ISO.digest_with_initial(self.uncommitted_checksum);
-        digest.update(&page.data);
-        digest.finalize()
+        frame_checksum(self.uncommitted_checksum, &page.data)
     }
 
     pub fn push_page(&mut self, page: &WalPage) -> anyhow::Result<()> {
@@ -485,7 +603,11 @@ impl LogFile {
             "writing frame {} at offset {byte_offset}",
             frame.header().frame_no
         );
-        self.file.write_all_at(frame.as_slice(), byte_offset)?;
+        let location = self.record_location(frame.header().frame_no);
+        let record = self
+            .encryption
+            .encode(&frame, self.is_encrypted(), location)?;
+        self.file.write_all_at(&record, byte_offset)?;
 
         self.uncommitted_frame_count += 1;
         self.uncommitted_checksum = checksum;
@@ -493,9 +615,31 @@ impl LogFile {
         Ok(())
     }
 
+    /// Appends a frame that was replicated from another log, keeping its frame_no and checksum.
+    fn push_frame(&mut self, frame: &FrameBorrowed) -> anyhow::Result<()> {
+        let header = frame.header();
+        ensure!(
+            header.frame_no == self.next_frame_no(),
+            "frame {} does not follow the log, expected frame {}",
+            header.frame_no,
+            self.next_frame_no()
+        );
+
+        let location = self.record_location(header.frame_no);
+        let record = self
+            .encryption
+            .encode(frame, self.is_encrypted(), location)?;
+        self.file.write_all_at(&record, self.next_byte_offset())?;
+
+        self.uncommitted_frame_count += 1;
+        self.uncommitted_checksum = header.checksum;
+
+        Ok(())
+    }
+
     /// offset in bytes at which to write the next frame
     fn next_byte_offset(&self) -> u64 {
-        Self::absolute_byte_offset(self.header().frame_count + self.uncommitted_frame_count)
+        self.absolute_byte_offset(self.header().frame_count + self.uncommitted_frame_count)
     }
 
     fn next_frame_no(&self) -> FrameNo {
@@ -503,8 +647,8 @@ impl LogFile {
     }
 
     /// Returns the bytes position of the `nth` entry in the log
-    fn absolute_byte_offset(nth: u64) -> u64 {
-        std::mem::size_of::<LogFileHeader>() as u64 + nth * Self::FRAME_SIZE as u64
+    fn absolute_byte_offset(&self, nth: u64) -> u64 {
+        std::mem::size_of::<LogFileHeader>() as u64 + nth * self.record_size() as u64
     }
 
     fn byte_offset(&self, id: FrameNo) -> anyhow::Result<Option<u64>> {
@@ -513,7 +657,9 @@ impl LogFile {
         {
             return Ok(None);
         }
-        Ok(Self::absolute_byte_offset(id - self.header.start_frame_no).into())
+        Ok(self
+            .absolute_byte_offset(id - self.header.start_frame_no)
+            .into())
     }
 
     /// Returns bytes represening a WalFrame for frame `frame_no`
@@ -544,16 +690,18 @@ impl LogFile {
         compact
     }
 
+    /// Compacts the log if needed, and returns whether it did.
     fn maybe_compact(
         &mut self,
         compactor: LogCompactor,
         size_after: u32,
         path: &Path,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<bool> {
         if self.should_compact() {
-            self.do_compaction(compactor, size_after, path)
+            self.do_compaction(compactor, size_after, path)?;
+            Ok(true)
         } else {
-            Ok(())
+            Ok(false)
         }
     }
 
@@ -572,7 +720,12 @@ impl LogFile {
             .write(true)
             .create(true)
             .open(&temp_log_path)?;
-        let mut new_log_file = LogFile::new(file, self.max_log_frame_count, self.max_log_duration)?;
+        let mut new_log_file = LogFile::new(
+            file,
+            self.max_log_frame_count,
+            self.max_log_duration,
+            self.encryption.clone(),
+        )?;
         let new_header = LogFileHeader {
             start_frame_no: self.header.start_frame_no + self.header.frame_count,
             frame_count: 0,
@@ -590,11 +743,23 @@ impl LogFile {
     }
 
     fn read_frame_byte_offset(&self, offset: u64) -> anyhow::Result<Frame> {
-        let mut buffer = BytesMut::zeroed(LogFile::FRAME_SIZE);
+        let mut buffer = BytesMut::zeroed(self.record_size());
         self.file.read_exact_at(&mut buffer, offset)?;
         let buffer = buffer.freeze();
+        let nth = (offset - size_of::<LogFileHeader>() as u64) / self.record_size() as u64;
+        let location = self.record_location(self.header.start_frame_no + nth);
 
-        Frame::try_from_bytes(buffer)
+        self.encryption
+            .decode(buffer, self.is_encrypted(), location)
+    }
+
+    /// Returns the location of the record of frame `frame_no`, that is authenticated along with
+    /// the frame when it is encrypted.
+    fn record_location(&self, frame_no: FrameNo) -> RecordLocation {
+        RecordLocation::Log {
+            db_id: self.header.db_id,
+            frame_no,
+        }
     }
 
     fn last_commited_frame_no(&self) -> Option<FrameNo> {
@@ -610,7 +775,12 @@ impl LogFile {
         let max_log_duration = self.max_log_duration;
         // truncate file
         self.file.set_len(0)?;
-        Self::new(self.file, max_log_frame_count, max_log_duration)
+        Self::new(
+            self.file,
+            max_log_frame_count,
+            max_log_duration,
+            self.encryption,
+        )
     }
 }
 
@@ -658,7 +828,7 @@ fn atomic_rename(p1: impl AsRef<Path>, p2: impl AsRef<Path>) -> anyhow::Result<(
 #[derive(Debug, Clone, Copy, Zeroable, Pod)]
 #[repr(C)]
 pub struct LogFileHeader {
-    /// magic number: b"SQLDWAL\0" as u64
+    /// magic number: b"SQLDWAL\0" as u64, or b"SQLDWALE" if the frames are encrypted
     pub magic: u64,
     /// Initial checksum value for the rolling CRC checksum
     /// computed with the 64 bits CRC_64_GO_ISO
@@ -701,6 +871,11 @@ impl Generation {
     }
 }
 
+/// Returns the frame_no of the last committed frame, given the frame_no of the next frame.
+fn last_committed_frame_no(next_frame_no: FrameNo) -> FrameNo {
+    next_frame_no.checked_sub(1).unwrap_or(FrameNo::MAX)
+}
+
 pub struct ReplicationLogger {
     pub generation: Generation,
     pub log_file: RwLock<LogFile>,
@@ -709,6 +884,14 @@ pub struct ReplicationLogger {
     /// a notifier channel other tasks can subscribe to, and get notified when new frames become
     /// available.
     pub new_frame_notifier: watch::Sender<FrameNo>,
+    /// notified with the frame_no of the last committed frame, or `FrameNo::MAX` if no frame was
+    /// committed yet. This is the frame_no of the consistency tokens.
+    pub committed_frame_notifier: watch::Sender<FrameNo>,
+    /// number of compactions performed since the logger was opened
+    compaction_count: AtomicU64,
+    encryption: FrameEncryption,
+    /// the time of the commits, to restore the database as of a point in time
+    history: Arc<CommitHistory>,
 }
 
 impl ReplicationLogger {
@@ -718,6 +901,8 @@ impl ReplicationLogger {
         max_log_duration: Option<Duration>,
         dirty: bool,
         callback: SnapshotCallback,
+        encryption: FrameEncryption,
+        merge_policy: MergePolicyFn,
     ) -> anyhow::Result<Self> {
         let log_path = db_path.join("wallog");
         let data_path = db_path.join("data");
@@ -730,8 +915,38 @@ impl ReplicationLogger {
             .read(true)
             .open(log_path)?;
 
+        let log_key_status = LogFile::key_status(&file, &encryption)?;
+        let snapshots_key_status = snapshots_key_status(db_path, &encryption)?;
+        if log_key_status == KeyStatus::Unknown || snapshots_key_status == KeyStatus::Unknown {
+            bail!(
+                "the replication log or the snapshots of `{}` are encrypted with an unknown key. \
+                Pass the key they were encrypted with as `--previous-log-encryption-key-file` \
+                to encrypt them with the current key, or remove `wallog` and `snapshots` from the \
+                directory to start a new generation, losing the snapshots and the point-in-time \
+                history of the database.",
+                db_path.display()
+            );
+        }
+
         let max_log_frame_count = max_log_size * 1_000_000 / LogFile::FRAME_SIZE as u64;
-        let log_file = LogFile::new(file, max_log_frame_count, max_log_duration)?;
+        let mut log_file = LogFile::new(
+            file,
+            max_log_frame_count,
+            max_log_duration,
+            encryption.clone(),
+        )?;
+        if log_key_status == KeyStatus::Readable {
+            tracing::info!(
+                "replication log is not encrypted with the current key, encrypting it again."
+            );
+            log_file = log_file.reencrypt(db_path)?;
+        }
+        if snapshots_key_status == KeyStatus::Readable {
+            tracing::info!(
+                "snapshots are not encrypted with the current key, encrypting them again."
+            );
+            reencrypt_snapshots(db_path, &encryption)?;
+        }
         let header = log_file.header();
 
         let should_recover = if dirty {
@@ -748,35 +963,140 @@ impl ReplicationLogger {
         };
 
         if should_recover {
-            Self::recover(log_file, data_path, callback)
+            Self::recover(log_file, data_path, callback, merge_policy)
         } else {
-            Self::from_log_file(db_path.to_path_buf(), log_file, callback)
+            Self::from_log_file(db_path.to_path_buf(), log_file, callback, merge_policy)
+        }
+    }
+
+    /// Opens the log that a replica keeps of the frames it applies, so that it can serve them to
+    /// its own replicas. Frames keep the frame_no they were given by the primary, so the log must
+    /// continue from `next_frame_no`, the next frame the replica is going to apply. An existing log
+    /// that doesn't, that belongs to another database, or that is encrypted with an unknown key, is
+    /// discarded along with its snapshots.
+    pub fn open_replica(
+        db_path: &Path,
+        max_log_size: u64,
+        max_log_duration: Option<Duration>,
+        db_id: Uuid,
+        next_frame_no: FrameNo,
+        callback: SnapshotCallback,
+        encryption: FrameEncryption,
+        merge_policy: MergePolicyFn,
+    ) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .read(true)
+            .open(db_path.join("wallog"))?;
+
+        // the frames can be fetched again from the primary, if they can't be read anymore
+        let unknown_key = LogFile::key_status(&file, &encryption)? == KeyStatus::Unknown
+            || snapshots_key_status(db_path, &encryption)? == KeyStatus::Unknown;
+        if unknown_key {
+            file.set_len(0)?;
+        }
+
+        let max_log_frame_count = max_log_size * 1_000_000 / LogFile::FRAME_SIZE as u64;
+        let mut log_file = LogFile::new(
+            file,
+            max_log_frame_count,
+            max_log_duration,
+            encryption.clone(),
+        )?;
+        let header = *log_file.header();
+        let reset = unknown_key
+            || header.db_id != db_id.as_u128()
+            || header.last_frame_no() != next_frame_no
+            || header.sqld_version() != Version::current();
+        if !reset {
+            if LogFile::key_status(&log_file.file, &encryption)? == KeyStatus::Readable {
+                log_file = log_file.reencrypt(db_path)?;
+            }
+            if snapshots_key_status(db_path, &encryption)? == KeyStatus::Readable {
+                reencrypt_snapshots(db_path, &encryption)?;
+            }
+        }
+        if reset {
+            tracing::info!(
+                "replica log does not continue from frame {next_frame_no}, resetting it"
+            );
+            // best effort, there may be no snapshots
+            let _ = remove_dir_all(db_path.join("snapshots"));
+            log_file = log_file.reset()?;
+            log_file.header.db_id = db_id.as_u128();
+            log_file.write_header()?;
+        }
+
+        let this = Self::from_log_file(db_path.to_path_buf(), log_file, callback, merge_policy)?;
+        if reset {
+            this.history.clear()?;
+        }
+        if reset && next_frame_no > 0 {
+            // The frames applied so far are not in the log anymore: the database file is
+            // snapshotted in their place, so that new replicas can still catch up.
+            let data_path = db_path.join("data");
+            let snapshot = checkpoint_wal(&data_path)
+                .and_then(|_| this.append_snapshot(db_file_frames(&data_path, next_frame_no)?));
+            if let Err(e) = snapshot {
+                tracing::warn!("could not snapshot the replica database, frames before {next_frame_no} can't be served: {e}");
+                let mut log_file = this.log_file.write();
+                log_file.header.start_frame_no = next_frame_no;
+                log_file.write_header()?;
+                this.notify_next_frame_no(next_frame_no);
+            }
         }
+
+        Ok(this)
     }
 
     fn from_log_file(
         db_path: PathBuf,
         log_file: LogFile,
         callback: SnapshotCallback,
+        merge_policy: MergePolicyFn,
     ) -> anyhow::Result<Self> {
         let header = log_file.header();
         let generation_start_frame_no = header.start_frame_no + header.frame_count;
 
         let (new_frame_notifier, _) = watch::channel(generation_start_frame_no);
+        let (committed_frame_notifier, _) =
+            watch::channel(last_committed_frame_no(generation_start_frame_no));
 
+        let encryption = log_file.encryption.clone();
+        let history = Arc::new(CommitHistory::open(&db_path)?);
         Ok(Self {
             generation: Generation::new(generation_start_frame_no),
-            compactor: LogCompactor::new(&db_path, log_file.header.db_id, callback)?,
+            compactor: LogCompactor::new(
+                &db_path,
+                log_file.header.db_id,
+                callback,
+                encryption.clone(),
+                history.clone(),
+                merge_policy,
+            )?,
             log_file: RwLock::new(log_file),
             db_path,
             new_frame_notifier,
+            committed_frame_notifier,
+            compaction_count: AtomicU64::new(0),
+            encryption,
+            history,
         })
     }
 
+    /// Notifies the subscribers that the frames before `next_frame_no` were committed.
+    fn notify_next_frame_no(&self, next_frame_no: FrameNo) {
+        self.new_frame_notifier.send_replace(next_frame_no);
+        self.committed_frame_notifier
+            .send_replace(last_committed_frame_no(next_frame_no));
+    }
+
     fn recover(
         log_file: LogFile,
         mut data_path: PathBuf,
         callback: SnapshotCallback,
+        merge_policy: MergePolicyFn,
     ) -> anyhow::Result<Self> {
         // It is necessary to checkpoint before we restore the replication log, since the WAL may
         // contain pages that are not in the database file.
@@ -809,7 +1129,11 @@ impl ReplicationLogger {
 
         assert!(data_path.pop());
 
-        Self::from_log_file(data_path, log_file, callback)
+        let this = Self::from_log_file(data_path, log_file, callback, merge_policy)?;
+        // the frames are numbered again, the commits before the recovery can't be restored
+        this.history.clear()?;
+
+        Ok(this)
     }
 
     pub fn database_id(&self) -> anyhow::Result<Uuid> {
@@ -833,9 +1157,7 @@ impl ReplicationLogger {
         let mut iter = log_file.frames_iter()?;
         iter.try_fold(wal_header.start_checksum, |sum, frame| {
             let frame = frame?;
-            let mut digest = CRC_64_GO_ISO.digest_with_initial(sum);
-            digest.update(frame.page());
-            let cs = digest.finalize();
+            let cs = frame_checksum(sum, frame.page());
             ensure!(
                 cs == frame.header().checksum,
                 "invalid WAL file: invalid checksum"
@@ -847,12 +1169,104 @@ impl ReplicationLogger {
     /// commit the current transaction and returns the new top frame number
     fn commit(&self) -> anyhow::Result<FrameNo> {
         let mut log_file = self.log_file.write();
+        let has_frames = log_file.uncommitted_frame_count > 0;
         log_file.commit()?;
-        Ok(log_file.header().last_frame_no())
+        let frame_no = log_file.header().last_frame_no();
+        if has_frames {
+            self.record_commit(frame_no - 1);
+        }
+        Ok(frame_no)
+    }
+
+    /// Records the time of the commit of the transaction ending with `frame_no`. The commit
+    /// doesn't fail if it can't be recorded: the database can still be restored as of its frame.
+    fn record_commit(&self, frame_no: FrameNo) {
+        if let Err(e) = self.history.record(frame_no, SystemTime::now()) {
+            tracing::warn!("failed to record the time of the commit of frame {frame_no}: {e}");
+        }
+    }
+
+    /// Appends frames applied by a replica to its log. Frames are committed at each transaction
+    /// boundary, and replace the uncommitted frames of a transaction that is being replayed.
+    pub fn append_frames<'a>(
+        &self,
+        frames: impl Iterator<Item = &'a FrameBorrowed>,
+    ) -> anyhow::Result<()> {
+        let mut log_file = self.log_file.write();
+        for frame in frames {
+            let header = frame.header();
+            if header.frame_no == log_file.header().last_frame_no() {
+                log_file.rollback();
+            }
+            log_file.push_frame(frame)?;
+            if header.size_after != 0 {
+                log_file.commit()?;
+                self.record_commit(header.frame_no);
+                self.notify_next_frame_no(log_file.header().last_frame_no());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Appends a snapshot loaded by a replica to its log. The current content of the log is
+    /// compacted, and the snapshot is stored next to the resulting snapshot, covering the frames
+    /// up to the last frame of the snapshot. Frames must be in decreasing frame_no order.
+    pub fn append_snapshot(
+        &self,
+        frames: impl Iterator<Item = anyhow::Result<Frame>>,
+    ) -> anyhow::Result<()> {
+        let mut log_file = self.log_file.write();
+        log_file.rollback();
+        let last_frame = log_file.rev_frames_iter()?.next().transpose()?;
+        if let Some(last_frame) = last_frame {
+            let size_after = last_frame.header().size_after;
+            log_file.do_compaction(self.compactor.clone(), size_after, &self.db_path)?;
+            self.compaction_count.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let last_frame = self.compactor.import_snapshot(
+            &self.db_path,
+            log_file.header().db_id,
+            log_file.header().last_frame_no(),
+            frames,
+        )?;
+
+        log_file.header.start_frame_no = last_frame.frame_no + 1;
+        log_file.header.start_checksum = last_frame.checksum;
+        log_file.commited_checksum = last_frame.checksum;
+        log_file.uncommitted_checksum = last_frame.checksum;
+        log_file.write_header()?;
+        self.notify_next_frame_no(log_file.header().last_frame_no());
+
+        Ok(())
     }
 
     pub fn get_snapshot_file(&self, from: FrameNo) -> anyhow::Result<Option<SnapshotFile>> {
-        find_snapshot_file(&self.db_path, from)
+        find_snapshot_file(&self.db_path, from, &self.encryption)
+    }
+
+    /// Writes the database as of `point`, rebuilt from the snapshots and the log, to a new
+    /// database file at `output`. Returns the frame_no of the last frame of the database.
+    pub fn materialize(&self, point: RestorePoint, output: &Path) -> anyhow::Result<FrameNo> {
+        // the log is not compacted while the snapshots and the log are opened
+        let sources = {
+            let _log_file = self.log_file.read();
+            Sources::open(&self.db_path, self.encryption.clone())?
+        };
+
+        sources.materialize(point, output)
+    }
+
+    /// Returns the snapshots of the log, sorted by start frame_no.
+    pub fn snapshots(&self) -> anyhow::Result<Vec<SnapshotInfo>> {
+        list_snapshots(&self.db_path, &self.encryption)
+    }
+
+    /// Merges the snapshots that the merge policy allows to merge, whatever their number and
+    /// size. Returns the name of the merged snapshot, unless there was nothing to merge.
+    pub fn merge_snapshots(&self) -> anyhow::Result<Option<String>> {
+        self.compactor.merge()
     }
 
     pub fn get_frame(&self, frame_no: FrameNo) -> Result<Frame, LogReadError> {
@@ -879,8 +1293,19 @@ impl ReplicationLogger {
         assert!(size_after != 0);
 
         log_file.do_compaction(self.compactor.clone(), size_after, &self.db_path)?;
+        self.compaction_count.fetch_add(1, Ordering::Relaxed);
         Ok(true)
     }
+
+    /// Returns the number of frames in the log.
+    pub fn frame_count(&self) -> u64 {
+        self.log_file.read().header().frame_count
+    }
+
+    /// Returns the number of compactions performed since the logger was opened.
+    pub fn compaction_count(&self) -> u64 {
+        self.compaction_count.load(Ordering::Relaxed)
+    }
 }
 
 fn checkpoint_db(data_path: &Path) -> anyhow::Result<()> {
@@ -920,15 +1345,85 @@ fn checkpoint_db(data_path: &Path) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Checkpoints the WAL of a database, so that the database file contains all the frames applied so
+/// far. Unlike `checkpoint_db`, the database file is left untouched otherwise, since it must
+/// remain identical to the one of the primary, or to its backup.
+pub(crate) fn checkpoint_wal(data_path: &Path) -> anyhow::Result<()> {
+    let conn = rusqlite::Connection::open_with_flags(
+        data_path,
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_WRITE,
+    )?;
+    conn.busy_timeout(Duration::from_secs(5))?;
+    let mut log_size: c_int = 0;
+    let mut num_checkpointed: c_int = 0;
+    let rc = unsafe {
+        rusqlite::ffi::sqlite3_wal_checkpoint_v2(
+            conn.handle(),
+            std::ptr::null(),
+            SQLITE_CHECKPOINT_TRUNCATE,
+            &mut log_size as *mut _,
+            &mut num_checkpointed as *mut _,
+        )
+    };
+    ensure!(
+        rc == 0 && num_checkpointed == log_size,
+        "failed to checkpoint database"
+    );
+
+    Ok(())
+}
+
+/// Returns the pages of a database file as frames, in decreasing frame_no order. Frames are
+/// numbered so that the last one is `next_frame_no - 1`, and carry no checksum.
+pub(crate) fn db_file_frames(
+    data_path: &Path,
+    next_frame_no: FrameNo,
+) -> anyhow::Result<impl Iterator<Item = anyhow::Result<Frame>>> {
+    let file = File::open(data_path)?;
+    let num_pages = file.metadata()?.len() / WAL_PAGE_SIZE as u64;
+    ensure!(
+        num_pages > 0 && num_pages <= next_frame_no,
+        "database has {num_pages} pages, but only {next_frame_no} frames were applied"
+    );
+
+    Ok((0..num_pages).rev().map(move |i| {
+        let mut data = BytesMut::zeroed(WAL_PAGE_SIZE as usize);
+        file.read_exact_at(&mut data, i * WAL_PAGE_SIZE as u64)?;
+        let header = FrameHeader {
+            frame_no: next_frame_no - num_pages + i,
+            checksum: 0,
+            page_no: i as u32 + 1,
+            size_after: if i == num_pages - 1 {
+                num_pages as u32
+            } else {
+                0
+            },
+        };
+
+        Ok(Frame::from_parts(&header, &data))
+    }))
+}
+
 #[cfg(test)]
 mod test {
+    use crate::replication::encryption::FrameCipher;
+    use crate::replication::snapshot::MergePolicy;
+
     use super::*;
 
     #[test]
     fn write_and_read_from_frame_log() {
         let dir = tempfile::tempdir().unwrap();
-        let logger =
-            ReplicationLogger::open(dir.path(), 0, None, false, Box::new(|_| Ok(()))).unwrap();
+        let logger = ReplicationLogger::open(
+            dir.path(),
+            0,
+            None,
+            false,
+            Box::new(|_| Ok(())),
+            FrameEncryption::default(),
+            Arc::new(MergePolicy::default),
+        )
+        .unwrap();
 
         let frames = (0..10)
             .map(|i| WalPage {
@@ -939,6 +1434,16 @@ mod test {
             .collect::<Vec<_>>();
         logger.write_pages(&frames).unwrap();
         logger.commit().unwrap();
+        // a commit without frames is not recorded
+        logger.commit().unwrap();
+        let commits = logger.history.commits().unwrap();
+        assert_eq!(
+            commits
+                .iter()
+                .map(|(frame_no, _)| *frame_no)
+                .collect::<Vec<_>>(),
+            vec![9]
+        );
 
         let log_file = logger.log_file.write();
         for i in 0..10 {
@@ -956,8 +1461,16 @@ mod test {
     #[test]
     fn index_out_of_bounds() {
         let dir = tempfile::tempdir().unwrap();
-        let logger =
-            ReplicationLogger::open(dir.path(), 0, None, false, Box::new(|_| Ok(()))).unwrap();
+        let logger = ReplicationLogger::open(
+            dir.path(),
+            0,
+            None,
+            false,
+            Box::new(|_| Ok(())),
+            FrameEncryption::default(),
+            Arc::new(MergePolicy::default),
+        )
+        .unwrap();
         let log_file = logger.log_file.write();
         assert!(matches!(log_file.frame(1), Err(LogReadError::Ahead)));
     }
@@ -966,8 +1479,16 @@ mod test {
     #[should_panic]
     fn incorrect_frame_size() {
         let dir = tempfile::tempdir().unwrap();
-        let logger =
-            ReplicationLogger::open(dir.path(), 0, None, false, Box::new(|_| Ok(()))).unwrap();
+        let logger = ReplicationLogger::open(
+            dir.path(),
+            0,
+            None,
+            false,
+            Box::new(|_| Ok(())),
+            FrameEncryption::default(),
+            Arc::new(MergePolicy::default),
+        )
+        .unwrap();
         let entry = WalPage {
             page_no: 0,
             size_after: 0,
@@ -981,7 +1502,7 @@ mod test {
     #[test]
     fn log_file_test_rollback() {
         let f = tempfile::tempfile().unwrap();
-        let mut log_file = LogFile::new(f, 100, None).unwrap();
+        let mut log_file = LogFile::new(f, 100, None, FrameEncryption::default()).unwrap();
         (0..5)
             .map(|i| WalPage {
                 page_no: i,
@@ -1021,4 +1542,191 @@ mod test {
         log_file.commit().unwrap();
         assert_eq!(log_file.frames_iter().unwrap().count(), 6);
     }
+
+    #[test]
+    fn encrypted_log_file() {
+        let key = |byte| [byte; crate::replication::encryption::KEY_SIZE];
+        let encryption = |key| FrameEncryption::new(FrameCipher::new(&key));
+        let f = tempfile::NamedTempFile::new().unwrap();
+        let open = |encryption| LogFile::new(f.reopen().unwrap(), 100, None, encryption).unwrap();
+
+        let mut log_file = open(encryption(key(1)));
+        assert!(log_file.is_encrypted());
+        for i in 0..5 {
+            log_file
+                .push_page(&WalPage {
+                    page_no: i,
+                    size_after: 5,
+                    data: Bytes::from(vec![42; 4096]),
+                })
+                .unwrap();
+        }
+        log_file.commit().unwrap();
+        for i in 0..5 {
+            let frame = log_file.frame(i).unwrap();
+            assert_eq!(frame.header().page_no, i as u32);
+            assert!(frame.page().iter().all(|b| *b == 42));
+        }
+        let content = std::fs::read(f.path()).unwrap();
+        assert!(!content.windows(64).any(|w| w.iter().all(|b| *b == 42)));
+        drop(log_file);
+
+        // the log is reopened with the checksum of its last frame
+        let status = |encryption| LogFile::key_status(f.as_file(), &encryption).unwrap();
+        assert_eq!(status(encryption(key(1))), KeyStatus::Current);
+        let log_file = open(encryption(key(1)));
+        assert_eq!(
+            log_file.commited_checksum,
+            log_file.frame(4).unwrap().header().checksum
+        );
+        let checksum = log_file.commited_checksum;
+        drop(log_file);
+
+        // after a rotation, the log can't be read without the previous key
+        assert_eq!(status(encryption(key(2))), KeyStatus::Unknown);
+        assert!(LogFile::new(f.reopen().unwrap(), 100, None, encryption(key(2))).is_err());
+        let rotated = encryption(key(2)).with_previous_key(FrameCipher::new(&key(1)));
+        assert_eq!(status(rotated.clone()), KeyStatus::Readable);
+
+        // it is encrypted again with the new key, and keeps its frames and checksums
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::copy(f.path(), dir.path().join("wallog")).unwrap();
+        let log_file = LogFile::new(
+            File::open(dir.path().join("wallog")).unwrap(),
+            100,
+            None,
+            rotated,
+        )
+        .unwrap()
+        .reencrypt(dir.path())
+        .unwrap();
+        assert_eq!(log_file.commited_checksum, checksum);
+        drop(log_file);
+        let file = File::open(dir.path().join("wallog")).unwrap();
+        assert_eq!(
+            LogFile::key_status(&file, &encryption(key(2))).unwrap(),
+            KeyStatus::Current
+        );
+        let log_file = LogFile::new(file, 100, None, encryption(key(2))).unwrap();
+        assert_eq!(log_file.header().frame_count, 5);
+        for i in 0..5 {
+            let frame = log_file.frame(i).unwrap();
+            assert_eq!(frame.header().page_no, i as u32);
+            assert!(frame.page().iter().all(|b| *b == 42));
+        }
+        assert_eq!(log_file.commited_checksum, checksum);
+        drop(log_file);
+
+        // disabling encryption writes it in plaintext
+        let disabled = FrameEncryption::default().with_previous_key(FrameCipher::new(&key(2)));
+        let log_file = LogFile::new(
+            File::open(dir.path().join("wallog")).unwrap(),
+            100,
+            None,
+            disabled,
+        )
+        .unwrap()
+        .reencrypt(dir.path())
+        .unwrap();
+        assert!(!log_file.is_encrypted());
+        assert_eq!(log_file.frame(4).unwrap().header().checksum, checksum);
+    }
+
+    #[test]
+    fn rotate_log_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = |byte| FrameCipher::new(&[byte; crate::replication::encryption::KEY_SIZE]);
+        let open = |encryption| {
+            ReplicationLogger::open(
+                dir.path(),
+                0,
+                None,
+                false,
+                Box::new(|_| Ok(())),
+                encryption,
+                Arc::new(MergePolicy::default),
+            )
+        };
+        let logger = open(FrameEncryption::new(key(1))).unwrap();
+        let frames = (0..10)
+            .map(|i| WalPage {
+                page_no: i,
+                size_after: 0,
+                data: Bytes::from(vec![i as _; 4096]),
+            })
+            .collect::<Vec<_>>();
+        logger.write_pages(&frames).unwrap();
+        logger.commit().unwrap();
+        drop(logger);
+
+        // the log is not discarded when it can't be read with the new key
+        let error = open(FrameEncryption::new(key(2))).err().unwrap();
+        assert!(error.to_string().contains("unknown key"));
+
+        // with the previous key, it is encrypted again and keeps its frames and history
+        let logger = open(FrameEncryption::new(key(2)).with_previous_key(key(1))).unwrap();
+        assert_eq!(logger.history.commits().unwrap().len(), 1);
+        drop(logger);
+        let logger = open(FrameEncryption::new(key(2))).unwrap();
+        let log_file = logger.log_file.read();
+        assert_eq!(log_file.header().frame_count, 10);
+        assert!(log_file.frame(9).unwrap().page().iter().all(|b| *b == 9));
+    }
+
+    #[test]
+    fn replica_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_id = Uuid::new_v4();
+        let open = |next_frame_no| {
+            ReplicationLogger::open_replica(
+                dir.path(),
+                100,
+                None,
+                db_id,
+                next_frame_no,
+                Box::new(|_| Ok(())),
+                FrameEncryption::default(),
+                Arc::new(MergePolicy::default),
+            )
+            .unwrap()
+        };
+        let frames = (0..4)
+            .map(|i| {
+                let header = FrameHeader {
+                    frame_no: i,
+                    checksum: i + 100,
+                    page_no: i as u32 + 1,
+                    size_after: if i == 3 { 4 } else { 0 },
+                };
+                Frame::from_parts(&header, &[1; 4096])
+            })
+            .collect::<Vec<_>>();
+
+        let logger = open(0);
+        // frames of a transaction that is replayed replace its uncommitted frames
+        logger
+            .append_frames(frames[..2].iter().map(|f| &**f))
+            .unwrap();
+        assert_eq!(logger.frame_count(), 0);
+        logger.append_frames(frames.iter().map(|f| &**f)).unwrap();
+        assert_eq!(logger.frame_count(), 4);
+        assert_eq!(*logger.new_frame_notifier.borrow(), 4);
+        assert_eq!(*logger.committed_frame_notifier.borrow(), 3);
+        assert_eq!(logger.get_frame(3).unwrap().header().checksum, 103);
+        // frames must follow the log
+        assert!(logger
+            .append_frames(frames[1..].iter().map(|f| &**f))
+            .is_err());
+        drop(logger);
+
+        // the log is kept when it continues from the next frame to apply
+        let logger = open(4);
+        assert_eq!(logger.frame_count(), 4);
+        drop(logger);
+
+        // and is reset otherwise
+        let logger = open(10);
+        assert_eq!(logger.frame_count(), 0);
+        assert_eq!(*logger.new_frame_notifier.borrow(), 10);
+    }
 }
@@ -1,2 +1,4 @@
 pub mod frame_stream;
 pub mod logger;
+pub mod quorum;
+pub mod replicas;
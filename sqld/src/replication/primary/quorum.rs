@@ -0,0 +1,177 @@
+//! Semi-synchronous replication: a commit is only acknowledged to the client once enough replicas
+//! confirmed that they applied its frames.
+
+use std::cell::RefCell;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::replication::FrameNo;
+
+use super::replicas::ConnectedReplicas;
+
+#[derive(Debug, Clone, Copy)]
+pub struct WriteQuorum {
+    /// Number of replicas that must acknowledge a commit.
+    pub replicas: usize,
+    /// How long a commit waits for the replicas.
+    pub timeout: Duration,
+    pub on_timeout: QuorumTimeoutPolicy,
+}
+
+/// What to do when the replicas don't acknowledge a commit in time.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuorumTimeoutPolicy {
+    /// Report an error to the client. The write remains committed on the primary.
+    Fail,
+    /// Acknowledge the commit, and stop waiting for the replicas until they catch up.
+    Async,
+}
+
+thread_local! {
+    /// The last commit on this thread, that waits for the acknowledgment of the replicas. The
+    /// commit happens within SQLite, with the write lock of the WAL held, so the hook only records
+    /// it, and the connection that runs the statement waits for the replicas once the lock is
+    /// released, on the same thread.
+    static PENDING_COMMIT: RefCell<Option<(Arc<QuorumWaiter>, FrameNo)>> = RefCell::new(None);
+}
+
+/// Records that the commit of `frame_no` on this thread waits for the replicas of `waiter`.
+pub fn set_pending_commit(waiter: Arc<QuorumWaiter>, frame_no: FrameNo) {
+    PENDING_COMMIT.with(|c| *c.borrow_mut() = Some((waiter, frame_no)));
+}
+
+/// Forgets the last commit on this thread, so that only the commits that follow are waited for.
+pub fn clear_pending_commit() {
+    PENDING_COMMIT.with(|c| c.borrow_mut().take());
+}
+
+/// Waits for the replicas to acknowledge the last commit on this thread, if any. Returns false if
+/// the commit was not acknowledged, and the policy is to fail.
+pub fn wait_pending_commit() -> bool {
+    match PENDING_COMMIT.with(|c| c.borrow_mut().take()) {
+        Some((waiter, frame_no)) => waiter.wait(frame_no),
+        None => true,
+    }
+}
+
+/// Makes the commits of a database wait for the acknowledgment of its replicas.
+pub struct QuorumWaiter {
+    quorum: WriteQuorum,
+    replicas: Arc<ConnectedReplicas>,
+    /// Set to the frame of the commit that timed out, while replication is degraded to
+    /// asynchronous.
+    degraded_at: Mutex<Option<FrameNo>>,
+}
+
+impl QuorumWaiter {
+    pub fn new(quorum: WriteQuorum, replicas: Arc<ConnectedReplicas>) -> Self {
+        Self {
+            quorum,
+            replicas,
+            degraded_at: Mutex::new(None),
+        }
+    }
+
+    /// Waits for the quorum to acknowledge `frame_no`, the last frame of a commit. Returns false if
+    /// the quorum didn't acknowledge it in time, and the policy is to fail.
+    pub fn wait(&self, frame_no: FrameNo) -> bool {
+        {
+            let mut degraded_at = self.degraded_at.lock();
+            if let Some(degraded_frame_no) = *degraded_at {
+                if self.replicas.acked_count(degraded_frame_no) < self.quorum.replicas {
+                    return true;
+                }
+                tracing::info!("replicas caught up, resuming semi-synchronous replication");
+                *degraded_at = None;
+            }
+        }
+
+        let deadline = Instant::now() + self.quorum.timeout;
+        if self
+            .replicas
+            .wait_for_acks(frame_no, self.quorum.replicas, deadline)
+        {
+            return true;
+        }
+
+        match self.quorum.on_timeout {
+            QuorumTimeoutPolicy::Fail => {
+                tracing::warn!(
+                    "frame {frame_no} was not acknowledged by {} replicas within {:?}",
+                    self.quorum.replicas,
+                    self.quorum.timeout
+                );
+                false
+            }
+            QuorumTimeoutPolicy::Async => {
+                tracing::warn!(
+                    "frame {frame_no} was not acknowledged by {} replicas within {:?}, degrading to asynchronous replication",
+                    self.quorum.replicas,
+                    self.quorum.timeout
+                );
+                *self.degraded_at.lock() = Some(frame_no);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use super::*;
+
+    fn waiter(on_timeout: QuorumTimeoutPolicy) -> (QuorumWaiter, Arc<ConnectedReplicas>) {
+        let replicas = Arc::new(ConnectedReplicas::default());
+        let quorum = WriteQuorum {
+            replicas: 2,
+            timeout: Duration::from_millis(50),
+            on_timeout,
+        };
+        (QuorumWaiter::new(quorum, replicas.clone()), replicas)
+    }
+
+    #[test]
+    fn fail_on_timeout() {
+        let (waiter, replicas) = waiter(QuorumTimeoutPolicy::Fail);
+        let a: SocketAddr = "10.0.0.1:5001".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:5001".parse().unwrap();
+
+        replicas.acked(a, None, 10);
+        assert!(!waiter.wait(10));
+
+        let handle = std::thread::spawn({
+            let replicas = replicas.clone();
+            move || {
+                std::thread::sleep(Duration::from_millis(10));
+                replicas.acked(b, None, 11);
+                replicas.acked(a, None, 11);
+            }
+        });
+        assert!(waiter.wait(11));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn degrade_to_async() {
+        let (waiter, replicas) = waiter(QuorumTimeoutPolicy::Async);
+        let a: SocketAddr = "10.0.0.1:5001".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:5001".parse().unwrap();
+
+        assert!(waiter.wait(10));
+        assert_eq!(*waiter.degraded_at.lock(), Some(10));
+
+        // degraded: doesn't wait
+        let before = Instant::now();
+        assert!(waiter.wait(11));
+        assert!(before.elapsed() < Duration::from_millis(50));
+
+        replicas.acked(a, None, 11);
+        replicas.acked(b, None, 11);
+        assert!(waiter.wait(11));
+        assert_eq!(*waiter.degraded_at.lock(), None);
+    }
+}
@@ -0,0 +1,200 @@
+//! Bookkeeping of the replicas that replicate a database from this primary.
+
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use parking_lot::{Condvar, Mutex};
+use serde::Serialize;
+
+use crate::replication::FrameNo;
+
+/// Replicas without an open log stream are forgotten when they haven't been seen for this long.
+const REPLICA_EXPIRY: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectedReplica {
+    pub addr: SocketAddr,
+    /// The id the replica acknowledges frames with, that is the same whatever the address it
+    /// calls from. Unset until the replica acknowledges frames, or if it is too old to send it.
+    pub replica_id: Option<String>,
+    /// Offset of the next frame to send to the replica: the offset it last requested, advanced as
+    /// frames are streamed to it. Unset until the replica requests frames.
+    pub next_offset: Option<FrameNo>,
+    /// The last frame the replica confirmed it applied. Unset until the replica acknowledges
+    /// frames.
+    pub acked_frame_no: Option<FrameNo>,
+    /// When the replica last called the primary, in milliseconds since the unix epoch.
+    pub last_seen_ms: u64,
+    /// Number of log streams the replica has open.
+    pub open_streams: usize,
+}
+
+/// The replicas of a database, keyed by their address.
+#[derive(Default)]
+pub struct ConnectedReplicas {
+    replicas: Mutex<HashMap<SocketAddr, ConnectedReplica>>,
+    /// Notified when a replica acknowledges frames.
+    acks: Condvar,
+}
+
+impl ConnectedReplicas {
+    /// Records that the replica at `addr` called the primary.
+    pub fn seen(&self, addr: SocketAddr) {
+        self.update(addr, |_| ());
+    }
+
+    /// Records that the replica at `addr` requested the frames from `offset`.
+    pub fn requested(&self, addr: SocketAddr, offset: FrameNo) {
+        self.update(addr, |replica| replica.next_offset = Some(offset));
+    }
+
+    /// Records that the frame `frame_no` was sent to the replica at `addr`.
+    pub fn sent(&self, addr: SocketAddr, frame_no: FrameNo) {
+        self.update(addr, |replica| {
+            replica.next_offset = Some(replica.next_offset.unwrap_or(0).max(frame_no + 1))
+        });
+    }
+
+    /// Records that the replica at `addr`, identified by `replica_id`, applied the frames up to
+    /// `frame_no`.
+    pub fn acked(&self, addr: SocketAddr, replica_id: Option<String>, frame_no: FrameNo) {
+        self.update(addr, |replica| {
+            replica.replica_id = replica_id;
+            replica.acked_frame_no = Some(replica.acked_frame_no.unwrap_or(0).max(frame_no))
+        });
+        self.acks.notify_all();
+    }
+
+    /// Returns the number of replicas that applied `frame_no`.
+    pub fn acked_count(&self, frame_no: FrameNo) -> usize {
+        count_acked(&self.replicas.lock(), frame_no)
+    }
+
+    /// Waits until `count` replicas applied `frame_no`, or `deadline` passes. Returns whether
+    /// enough replicas applied the frame.
+    pub fn wait_for_acks(&self, frame_no: FrameNo, count: usize, deadline: Instant) -> bool {
+        let mut replicas = self.replicas.lock();
+        while count_acked(&replicas, frame_no) < count {
+            if self.acks.wait_until(&mut replicas, deadline).timed_out() {
+                return count_acked(&replicas, frame_no) >= count;
+            }
+        }
+
+        true
+    }
+
+    pub fn stream_opened(&self, addr: SocketAddr) {
+        self.update(addr, |replica| replica.open_streams += 1);
+    }
+
+    pub fn stream_closed(&self, addr: SocketAddr) {
+        self.update(addr, |replica| {
+            replica.open_streams = replica.open_streams.saturating_sub(1)
+        });
+    }
+
+    /// Returns the replicas, ordered by address.
+    pub fn list(&self) -> Vec<ConnectedReplica> {
+        let mut replicas = self.replicas.lock();
+        expire(&mut replicas);
+        let mut list = replicas.values().cloned().collect::<Vec<_>>();
+        list.sort_by_key(|replica| replica.addr);
+        list
+    }
+
+    fn update(&self, addr: SocketAddr, f: impl FnOnce(&mut ConnectedReplica)) {
+        let mut replicas = self.replicas.lock();
+        let replica = replicas.entry(addr).or_insert_with(|| ConnectedReplica {
+            addr,
+            replica_id: None,
+            next_offset: None,
+            acked_frame_no: None,
+            last_seen_ms: 0,
+            open_streams: 0,
+        });
+        replica.last_seen_ms = now_ms();
+        f(replica);
+        expire(&mut replicas);
+    }
+}
+
+/// Counts the replicas that applied `frame_no`. A replica that reconnected from another address
+/// is only counted once, as long as it sends its id.
+fn count_acked(replicas: &HashMap<SocketAddr, ConnectedReplica>, frame_no: FrameNo) -> usize {
+    replicas
+        .values()
+        .filter(|replica| {
+            replica
+                .acked_frame_no
+                .map_or(false, |acked| acked >= frame_no)
+        })
+        .map(|replica| match &replica.replica_id {
+            Some(id) => Ok(id),
+            None => Err(replica.addr),
+        })
+        .collect::<HashSet<_>>()
+        .len()
+}
+
+fn expire(replicas: &mut HashMap<SocketAddr, ConnectedReplica>) {
+    let expiry = now_ms().saturating_sub(REPLICA_EXPIRY.as_millis() as u64);
+    replicas.retain(|_, replica| replica.open_streams > 0 || replica.last_seen_ms >= expiry);
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn track_replicas() {
+        let replicas = ConnectedReplicas::default();
+        let a: SocketAddr = "10.0.0.1:5001".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:5001".parse().unwrap();
+
+        replicas.seen(b);
+        replicas.requested(a, 10);
+        replicas.stream_opened(a);
+        replicas.sent(a, 10);
+        replicas.sent(a, 11);
+
+        let list = replicas.list();
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[0].addr, a);
+        assert_eq!(list[0].next_offset, Some(12));
+        assert_eq!(list[0].open_streams, 1);
+        assert_eq!(list[1].next_offset, None);
+
+        replicas.acked(a, None, 11);
+        replicas.acked(a, None, 9);
+        assert_eq!(replicas.list()[0].acked_frame_no, Some(11));
+        assert_eq!(replicas.acked_count(11), 1);
+        assert_eq!(replicas.acked_count(12), 0);
+
+        replicas.stream_closed(a);
+        assert_eq!(replicas.list()[0].open_streams, 0);
+    }
+
+    #[test]
+    fn count_reconnected_replica_once() {
+        let replicas = ConnectedReplicas::default();
+        let a: SocketAddr = "10.0.0.1:5001".parse().unwrap();
+        let reconnected: SocketAddr = "10.0.0.1:5002".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:5001".parse().unwrap();
+
+        replicas.acked(a, Some("a".into()), 10);
+        replicas.acked(reconnected, Some("a".into()), 10);
+        assert_eq!(replicas.list().len(), 2);
+        assert_eq!(replicas.acked_count(10), 1);
+
+        replicas.acked(b, Some("b".into()), 10);
+        assert_eq!(replicas.acked_count(10), 2);
+    }
+}
@@ -0,0 +1,128 @@
+//! Bootstrap of a fresh replica from the bottomless backup of its database, rather than from a
+//! snapshot sent by the primary.
+//!
+//! When the primary starts a bottomless generation, it records where the snapshot of the
+//! generation is in its replication log. The replica restores that snapshot, and then replicates
+//! the rest of the log from the primary.
+
+use std::path::Path;
+
+use anyhow::Context;
+use tokio::io::AsyncReadExt;
+use uuid::Uuid;
+
+use crate::replication::primary::logger::db_file_frames;
+use crate::replication::{FrameNo, WAL_PAGE_SIZE};
+
+use super::snapshot::TempSnapshot;
+
+const SQLITE_HEADER: &[u8; 16] = b"SQLite format 3\0";
+
+/// Restores the snapshot of the latest bottomless generation of the database, and returns it as a
+/// snapshot, along with the frame_no that replication continues from. The generation must back up
+/// the database of the replication log `log_id`.
+pub async fn snapshot_from_bottomless(
+    db_path: &Path,
+    options: bottomless::replicator::Options,
+    log_id: Uuid,
+) -> anyhow::Result<(TempSnapshot, FrameNo)> {
+    let restore_dir = db_path.join("temp").join("bootstrap");
+    let _ = tokio::fs::remove_dir_all(&restore_dir).await;
+    tokio::fs::create_dir_all(&restore_dir).await?;
+
+    let res = restore_snapshot(db_path, &restore_dir, options, log_id).await;
+    let _ = tokio::fs::remove_dir_all(&restore_dir).await;
+
+    res
+}
+
+async fn restore_snapshot(
+    db_path: &Path,
+    restore_dir: &Path,
+    mut options: bottomless::replicator::Options,
+    log_id: Uuid,
+) -> anyhow::Result<(TempSnapshot, FrameNo)> {
+    // the name of the file must match the one of the primary, since it's part of the name of the
+    // backup
+    let data_path = restore_dir.join("data");
+    options.create_bucket_if_not_exists = false;
+    let replicator = bottomless::replicator::Replicator::with_options(
+        data_path
+            .to_str()
+            .context("invalid database path")?
+            .to_owned(),
+        options,
+    )
+    .await?;
+
+    let generation = replicator
+        .latest_generation_before(None)
+        .await
+        .context("no backup found")?;
+    let (backup_log_id, next_frame_no) = replicator
+        .get_replication_index(&generation)
+        .await?
+        .with_context(|| format!("generation {generation} doesn't record its replication index"))?;
+    anyhow::ensure!(
+        backup_log_id == log_id,
+        "generation {generation} backs up replication log {backup_log_id}, but the primary has replication log {log_id}"
+    );
+
+    let mut file = tokio::fs::File::create(&data_path).await?;
+    replicator
+        .download_main_db_file(&generation, &mut file)
+        .await?
+        .with_context(|| format!("generation {generation} has no database file"))?;
+    drop(file);
+    check_db_file(&data_path).await?;
+
+    let frames = db_file_frames(&data_path, next_frame_no)?;
+    let snapshot = TempSnapshot::from_stream(db_path, futures::stream::iter(frames)).await?;
+    tracing::info!("restored generation {generation}, up to frame {next_frame_no}");
+
+    Ok((snapshot, next_frame_no))
+}
+
+/// Checks that the restored file is a database that can be replicated.
+async fn check_db_file(path: &Path) -> anyhow::Result<()> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let size = file.metadata().await?.len();
+    anyhow::ensure!(
+        size > 0 && size % WAL_PAGE_SIZE as u64 == 0,
+        "invalid database file size: {size}"
+    );
+
+    let mut header = [0; 18];
+    file.read_exact(&mut header).await?;
+    anyhow::ensure!(header.starts_with(SQLITE_HEADER), "not a database file");
+    let page_size = u16::from_be_bytes([header[16], header[17]]);
+    anyhow::ensure!(
+        page_size as i32 == WAL_PAGE_SIZE,
+        "invalid page size: {page_size}"
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn check_database_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("data");
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute_batch("CREATE TABLE test (x)").unwrap();
+        drop(conn);
+        check_db_file(&path).await.unwrap();
+
+        let garbage = tmp.path().join("garbage");
+        std::fs::write(&garbage, [1; WAL_PAGE_SIZE as usize]).unwrap();
+        assert!(check_db_file(&garbage).await.is_err());
+
+        let truncated = tmp.path().join("truncated");
+        std::fs::write(&truncated, &std::fs::read(&path).unwrap()[..100]).unwrap();
+        assert!(check_db_file(&truncated).await.is_err());
+    }
+}
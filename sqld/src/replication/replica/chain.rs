@@ -0,0 +1,171 @@
+//! Verification of the frames received from the primary.
+//!
+//! Each frame of the log carries the rolling checksum of the frames of the log up to it. The
+//! replica tracks the position and checksum of the last frame it received, and checks that each
+//! frame follows it, before the frame is injected. When the checksum of the last frame is unknown,
+//! e.g. after a snapshot restored from a backup, the chain continues from the checksum of the next
+//! frame.
+
+use crate::replication::frame::FrameBorrowed;
+use crate::replication::{frame_checksum, FrameNo};
+
+use super::error::ReplicationError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ChainPosition {
+    frame_no: FrameNo,
+    /// unknown if 0, as for the frames of a database file
+    checksum: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct FrameChain {
+    /// the last frame of the last committed transaction, unset if no frame was applied
+    committed: Option<ChainPosition>,
+    /// the last frame received, possibly in an uncommitted transaction
+    last: Option<ChainPosition>,
+}
+
+impl FrameChain {
+    /// Continues the chain after frame `frame_no`, with the rolling checksum `checksum`, or from
+    /// the start of the log if no frame was applied.
+    pub fn new(frame_no: Option<FrameNo>, checksum: u64) -> Self {
+        let position = frame_no.map(|frame_no| ChainPosition { frame_no, checksum });
+        Self {
+            committed: position,
+            last: position,
+        }
+    }
+
+    /// Checks that `frame` follows the last frame received, and makes it the last frame.
+    pub fn verify(&mut self, frame: &FrameBorrowed) -> Result<(), ReplicationError> {
+        let header = frame.header();
+        let (next_frame_no, previous_checksum) = match self.last {
+            Some(last) => (last.frame_no + 1, Some(last.checksum).filter(|c| *c != 0)),
+            // the log starts with a checksum of 0
+            None => (0, Some(0)),
+        };
+
+        if header.frame_no != next_frame_no {
+            return Err(ReplicationError::InvalidFrame(format!(
+                "frame {} is out of order, expected frame {next_frame_no}",
+                header.frame_no
+            )));
+        }
+        if let Some(previous_checksum) = previous_checksum {
+            let checksum = frame_checksum(previous_checksum, frame.page());
+            if checksum != header.checksum {
+                return Err(ReplicationError::InvalidFrame(format!(
+                    "frame {} has checksum {:#x}, expected {checksum:#x}",
+                    header.frame_no, header.checksum
+                )));
+            }
+        }
+
+        self.last = Some(ChainPosition {
+            frame_no: header.frame_no,
+            checksum: header.checksum,
+        });
+        if header.size_after != 0 {
+            self.committed = self.last;
+        }
+
+        Ok(())
+    }
+
+    /// Forgets the frames received since the last commit, as they will be received again.
+    pub fn rollback(&mut self) {
+        self.last = self.committed;
+    }
+
+    /// Returns the frame_no of the last frame of the last committed transaction, if any.
+    pub fn committed_frame_no(&self) -> Option<FrameNo> {
+        self.committed.map(|position| position.frame_no)
+    }
+
+    /// Continues the chain after a snapshot, or a batch of frames that were not verified, whose
+    /// last frame is `frame`.
+    pub fn reset(&mut self, frame: &FrameBorrowed) {
+        let header = frame.header();
+        *self = Self::new(Some(header.frame_no), header.checksum);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+
+    use crate::replication::frame::{Frame, FrameHeader};
+    use crate::replication::WAL_PAGE_SIZE;
+
+    use super::*;
+
+    /// Makes a log of `count` frames, each one committing a transaction if `commit` says so.
+    fn log(count: u64, commit: impl Fn(FrameNo) -> bool) -> Vec<Frame> {
+        let mut checksum = 0;
+        (0..count)
+            .map(|frame_no| {
+                let page = vec![frame_no as u8; WAL_PAGE_SIZE as usize];
+                checksum = frame_checksum(checksum, &page);
+                let header = FrameHeader {
+                    frame_no,
+                    checksum,
+                    page_no: 1,
+                    size_after: commit(frame_no) as u32,
+                };
+                Frame::from_parts(&header, &page)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn verify_chain() {
+        let frames = log(6, |frame_no| frame_no % 2 == 1);
+        let mut chain = FrameChain::default();
+        for frame in &frames[..3] {
+            chain.verify(frame).unwrap();
+        }
+
+        // frame 2 is not committed, it is received again
+        assert!(chain.verify(&frames[2]).is_err());
+        chain.rollback();
+        chain.verify(&frames[2]).unwrap();
+        assert!(chain.verify(&frames[4]).is_err());
+        chain.verify(&frames[3]).unwrap();
+
+        // a corrupted frame
+        let mut data = frames[4].as_slice().to_vec();
+        *data.last_mut().unwrap() ^= 1;
+        let corrupted = Frame::try_from_bytes(Bytes::from(data)).unwrap();
+        assert!(matches!(
+            chain.verify(&corrupted),
+            Err(ReplicationError::InvalidFrame(_))
+        ));
+        chain.verify(&frames[4]).unwrap();
+    }
+
+    #[test]
+    fn unknown_checksum() {
+        let frames = log(6, |_| true);
+        // after a snapshot without checksums, the chain continues from the next frame
+        let mut chain = FrameChain::new(Some(2), 0);
+        let mut data = frames[3].as_slice().to_vec();
+        // corrupt the checksum in the header
+        data[8] ^= 1;
+        let unverified = Frame::try_from_bytes(Bytes::from(data)).unwrap();
+        chain.verify(&unverified).unwrap();
+        assert!(chain.verify(&frames[4]).is_err());
+
+        let mut chain = FrameChain::new(Some(2), 0);
+        chain.verify(&frames[3]).unwrap();
+        chain.verify(&frames[4]).unwrap();
+
+        // a fresh replica starts from the start of the log
+        let mut chain = FrameChain::default();
+        assert!(chain.verify(&frames[1]).is_err());
+        chain.verify(&frames[0]).unwrap();
+
+        chain.reset(&frames[4]);
+        chain.verify(&frames[5]).unwrap();
+    }
+}
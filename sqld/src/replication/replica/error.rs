@@ -4,6 +4,8 @@ pub enum ReplicationError {
     Lagging,
     #[error("Trying to replicate incompatible databases")]
     DbIncompatible,
+    #[error("Invalid frame from primary: {0}")]
+    InvalidFrame(String),
     #[error("{0}")]
     Other(#[from] anyhow::Error),
 }
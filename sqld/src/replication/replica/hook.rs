@@ -55,7 +55,7 @@ impl Drop for Headers<'_> {
 }
 
 impl Frames {
-    fn to_headers(&self) -> (Headers, u64, u32) {
+    fn to_headers(&self) -> (Headers, u64, u64, u32) {
         match self {
             Frames::Vec(frames) => make_page_header(frames.iter().map(|f| &**f)),
             Frames::Snapshot(snap) => make_page_header(snap.iter()),
@@ -79,21 +79,25 @@ pub struct InjectorHookCtx {
     pub is_txn: bool,
     /// invoked before injecting frames
     pre_commit: Box<dyn Fn(FrameNo) -> anyhow::Result<()>>,
-    /// invoked after injecting frames
-    post_commit: Box<dyn Fn(FrameNo) -> anyhow::Result<()>>,
+    /// invoked after injecting frames, with the rolling checksum of the last frame
+    post_commit: Box<dyn Fn(FrameNo, u64) -> anyhow::Result<()>>,
+    /// invoked with each batch of frames, once it is applied
+    on_applied: Box<dyn Fn(&Frames)>,
 }
 
 impl InjectorHookCtx {
     pub fn new(
         receiver: tokio::sync::mpsc::Receiver<Frames>,
         pre_commit: impl Fn(FrameNo) -> anyhow::Result<()> + 'static + Send,
-        post_commit: impl Fn(FrameNo) -> anyhow::Result<()> + 'static + Send,
+        post_commit: impl Fn(FrameNo, u64) -> anyhow::Result<()> + 'static + Send,
+        on_applied: impl Fn(&Frames) + 'static + Send,
     ) -> Self {
         Self {
             receiver,
             is_txn: false,
             pre_commit: Box::new(pre_commit),
             post_commit: Box::new(post_commit),
+            on_applied: Box::new(on_applied),
         }
     }
 
@@ -101,6 +105,7 @@ impl InjectorHookCtx {
         &mut self,
         mut page_headers: Headers,
         last_frame_no: u64,
+        last_checksum: u64,
         size_after: u32,
         sync_flags: i32,
         orig: XWalFrameFn,
@@ -125,7 +130,7 @@ impl InjectorHookCtx {
         if ret == 0 {
             debug_assert!(page_headers.all_applied());
             if size_after != 0 {
-                (self.post_commit)(last_frame_no)?;
+                (self.post_commit)(last_frame_no, last_checksum)?;
                 self.is_txn = false;
             }
             tracing::trace!("applied frame batch");
@@ -154,11 +159,12 @@ unsafe impl WalHook for InjectorHook {
         loop {
             match ctx.receiver.blocking_recv() {
                 Some(frames) => {
-                    let (headers, last_frame_no, size_after) = frames.to_headers();
+                    let (headers, last_frame_no, last_checksum, size_after) = frames.to_headers();
 
                     let ret = ctx.inject_pages(
                         headers,
                         last_frame_no,
+                        last_checksum,
                         size_after,
                         sync_flags,
                         orig,
@@ -170,6 +176,8 @@ unsafe impl WalHook for InjectorHook {
                         return SQLITE_ERROR;
                     }
 
+                    (ctx.on_applied)(&frames);
+
                     if !ctx.is_txn {
                         return SQLITE_CONTINUE_REPLICATION;
                     }
@@ -189,13 +197,14 @@ unsafe impl WalHook for InjectorHook {
 
 /// Turn a list of `WalFrame` into a list of PgHdr.
 /// The caller has the responsibility to free the returned headers.
-/// return (headers, last_frame_no, size_after)
+/// return (headers, last_frame_no, last_checksum, size_after)
 fn make_page_header<'a>(
     frames: impl Iterator<Item = &'a FrameBorrowed>,
-) -> (Headers<'a>, u64, u32) {
+) -> (Headers<'a>, u64, u64, u32) {
     let mut first_pg: *mut PgHdr = std::ptr::null_mut();
     let mut current_pg;
     let mut last_frame_no = 0;
+    let mut last_checksum = 0;
     let mut size_after = 0;
 
     let mut headers_count = 0;
@@ -203,6 +212,7 @@ fn make_page_header<'a>(
     for frame in frames {
         if frame.header().frame_no > last_frame_no {
             last_frame_no = frame.header().frame_no;
+            last_checksum = frame.header().checksum;
             size_after = frame.header().size_after;
         }
 
@@ -236,7 +246,7 @@ fn make_page_header<'a>(
     tracing::trace!("built {headers_count} page headers");
 
     let headers = unsafe { Headers::new(first_pg) };
-    (headers, last_frame_no, size_after)
+    (headers, last_frame_no, last_checksum, size_after)
 }
 
 /// Debug assertion. Make sure that all the pages have been applied
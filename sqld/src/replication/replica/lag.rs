@@ -0,0 +1,193 @@
+//! Estimation of how far a replica is behind its primary.
+//!
+//! The replica periodically learns the frame_no of the primary from its hello responses. Once the
+//! replica has applied all the frames the primary had when it was asked, the replica is known to
+//! have been up to date at that time; the time staleness of the replica is the time elapsed since
+//! then.
+
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::connection::config::MaxStaleness;
+use crate::replication::FrameNo;
+
+#[derive(Default)]
+pub struct ReplicationLag {
+    inner: Mutex<LagInner>,
+}
+
+#[derive(Default)]
+struct LagInner {
+    /// The last next_frame_no reported by the primary, and when it was requested.
+    primary: Option<(FrameNo, Instant)>,
+    /// The last time at which the replica was known to have applied all the frames of the
+    /// primary.
+    caught_up_at: Option<Instant>,
+    /// Generation of the primary log and its first frame_no, from the last handshake.
+    generation: Option<(String, FrameNo)>,
+    /// Whether the database is being recovered, see `set_recovering`.
+    recovering: bool,
+    /// The last invalid frame received from the primary.
+    invalid_frame: Option<String>,
+}
+
+impl ReplicationLag {
+    /// Records that the next frame committed on the primary will be `next_frame_no`, as asked at
+    /// `at`. `applied` is the last frame_no applied by the replica.
+    pub fn observe_primary(&self, next_frame_no: FrameNo, at: Instant, applied: Option<FrameNo>) {
+        let mut inner = self.inner.lock();
+        // settle the previous observation first, so that catching up with it is not lost
+        inner.settle(applied);
+        if inner.primary.map_or(true, |(_, prev_at)| prev_at <= at) {
+            inner.primary = Some((next_frame_no, at));
+        }
+        inner.settle(applied);
+    }
+
+    /// Returns the frame_no that the next frame committed on the primary will have, as last
+    /// reported by the primary.
+    pub fn primary_next_frame_no(&self) -> Option<FrameNo> {
+        self.inner.lock().primary.map(|(frame_no, _)| frame_no)
+    }
+
+    pub fn set_generation(&self, generation_id: String, start_index: FrameNo) {
+        self.inner.lock().generation = Some((generation_id, start_index));
+    }
+
+    pub fn generation_id(&self) -> Option<String> {
+        self.generation().map(|(generation_id, _)| generation_id)
+    }
+
+    /// Returns the generation of the primary log and its first frame_no.
+    pub fn generation(&self) -> Option<(String, FrameNo)> {
+        self.inner.lock().generation.clone()
+    }
+
+    /// Marks the database of the replica as being recovered: it can't replicate from the primary
+    /// anymore, and its reads are stale until the recovery completes, whatever its frame_no.
+    pub fn set_recovering(&self, recovering: bool) {
+        self.inner.lock().recovering = recovering;
+    }
+
+    pub fn is_recovering(&self) -> bool {
+        self.inner.lock().recovering
+    }
+
+    /// Records that the primary sent an invalid frame, which triggered a resync of the database.
+    pub fn record_invalid_frame(&self, error: String) {
+        self.inner.lock().invalid_frame = Some(error);
+    }
+
+    /// Returns the error of the last invalid frame received from the primary, if any.
+    pub fn invalid_frame(&self) -> Option<String> {
+        self.inner.lock().invalid_frame.clone()
+    }
+
+    /// Returns how many frames of the primary a replica that applied up to `applied` misses.
+    pub fn frames_behind(&self, applied: Option<FrameNo>) -> Option<u64> {
+        let primary_next = self.primary_next_frame_no()?;
+        Some(primary_next.saturating_sub(next_frame_no(applied)))
+    }
+
+    /// Returns the time elapsed since a replica that applied up to `applied` was last known to be
+    /// up to date with the primary.
+    pub fn time_behind(&self, applied: Option<FrameNo>) -> Option<Duration> {
+        let mut inner = self.inner.lock();
+        inner.settle(applied);
+        inner.caught_up_at.map(|at| at.elapsed())
+    }
+
+    /// Returns whether a replica that applied up to `applied` is staler than `max` allows. Until
+    /// the primary has reported its frame_no, or while it is recovered, the replica is considered
+    /// too stale for any bound.
+    pub fn exceeds(&self, max: MaxStaleness, applied: Option<FrameNo>) -> bool {
+        if !max.is_bounded() {
+            return false;
+        }
+
+        if self.is_recovering() {
+            return true;
+        }
+
+        let Some(frames_behind) = self.frames_behind(applied) else {
+            return true;
+        };
+
+        if let Some(max_frames) = max.frames {
+            if frames_behind > max_frames {
+                return true;
+            }
+        }
+
+        if let Some(max_time) = max.time {
+            match self.time_behind(applied) {
+                Some(time_behind) if time_behind <= max_time => (),
+                _ => return true,
+            }
+        }
+
+        false
+    }
+}
+
+impl LagInner {
+    fn settle(&mut self, applied: Option<FrameNo>) {
+        if let Some((primary_next, at)) = self.primary {
+            if next_frame_no(applied) >= primary_next {
+                self.caught_up_at = Some(self.caught_up_at.map_or(at, |prev| prev.max(at)));
+            }
+        }
+    }
+}
+
+fn next_frame_no(applied: Option<FrameNo>) -> FrameNo {
+    applied.map_or(0, |frame_no| frame_no + 1)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn staleness_bounds() {
+        let lag = ReplicationLag::default();
+        let frames = MaxStaleness {
+            frames: Some(10),
+            ..Default::default()
+        };
+        let time = MaxStaleness {
+            time: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+
+        assert!(!lag.exceeds(MaxStaleness::default(), None));
+        // the primary frame_no is not known yet
+        assert!(lag.exceeds(frames, Some(5)));
+
+        let asked_at = Instant::now();
+        lag.observe_primary(100, asked_at, Some(50));
+        assert!(lag.exceeds(frames, Some(50)));
+        assert!(!lag.exceeds(frames, Some(95)));
+        assert!(lag.exceeds(time, Some(95)));
+
+        // catching up with the primary after the fact counts from when it was asked
+        assert!(!lag.exceeds(time, Some(99)));
+        lag.observe_primary(120, Instant::now(), Some(99));
+        assert!(!lag.exceeds(time, Some(99)));
+        assert!(lag.exceeds(frames, Some(99)));
+        assert_eq!(lag.primary_next_frame_no(), Some(120));
+        assert_eq!(lag.frames_behind(Some(99)), Some(20));
+        // caught up as of `asked_at`
+        assert!(lag.time_behind(Some(99)).unwrap() <= asked_at.elapsed());
+
+        // a replica being recovered is stale, even if it seems up to date
+        lag.set_recovering(true);
+        assert!(lag.exceeds(frames, Some(119)));
+        assert!(!lag.exceeds(MaxStaleness::default(), Some(119)));
+        lag.set_recovering(false);
+        assert!(!lag.exceeds(frames, Some(119)));
+    }
+}
@@ -0,0 +1,117 @@
+//! The log that a replica keeps of the frames it applies, so that other replicas can replicate
+//! from it.
+//!
+//! Frames are written to the log as they were received, with the frame_no and checksum they have
+//! on the primary, so that the replicas of a replica see the same log as they would on the
+//! primary.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use parking_lot::RwLock;
+use uuid::Uuid;
+
+use crate::replication::encryption::FrameEncryption;
+use crate::replication::frame::Frame;
+use crate::replication::primary::logger::ReplicationLogger;
+use crate::replication::{FrameNo, MergePolicyFn};
+
+use super::hook::Frames;
+
+pub struct ReplicaLog {
+    db_path: PathBuf,
+    max_log_size: u64,
+    max_log_duration: Option<Duration>,
+    encryption: FrameEncryption,
+    merge_policy: MergePolicyFn,
+    /// The log, and the id of the database it replicates. Unset until the replica performed its
+    /// handshake with the primary.
+    logger: RwLock<Option<(Uuid, Arc<ReplicationLogger>)>>,
+}
+
+impl ReplicaLog {
+    pub fn new(
+        db_path: PathBuf,
+        max_log_size: u64,
+        max_log_duration: Option<Duration>,
+        encryption: FrameEncryption,
+        merge_policy: MergePolicyFn,
+    ) -> Self {
+        Self {
+            db_path,
+            max_log_size,
+            max_log_duration,
+            encryption,
+            merge_policy,
+            logger: RwLock::new(None),
+        }
+    }
+
+    pub fn logger(&self) -> Option<Arc<ReplicationLogger>> {
+        self.logger
+            .read()
+            .as_ref()
+            .map(|(_, logger)| logger.clone())
+    }
+
+    /// Opens the log so that it continues from `next_frame_no`, unless it already does.
+    pub(super) fn open(&self, db_id: Uuid, next_frame_no: FrameNo) -> anyhow::Result<()> {
+        let mut logger = self.logger.write();
+        if let Some((current_db_id, current)) = &*logger {
+            if *current_db_id == db_id && *current.new_frame_notifier.borrow() == next_frame_no {
+                return Ok(());
+            }
+        }
+
+        // close the current log before opening it again
+        *logger = None;
+        let new_logger = ReplicationLogger::open_replica(
+            &self.db_path,
+            self.max_log_size,
+            self.max_log_duration,
+            db_id,
+            next_frame_no,
+            Box::new(|_| Ok(())),
+            self.encryption.clone(),
+            self.merge_policy.clone(),
+        )?;
+        *logger = Some((db_id, Arc::new(new_logger)));
+
+        Ok(())
+    }
+
+    /// Appends frames that were applied to the database. If they can't be appended, the log is
+    /// reset to continue after them.
+    pub(super) fn append(&self, frames: &Frames) {
+        let Some((db_id, logger)) = self.logger.read().clone() else {
+            return;
+        };
+
+        let res = match frames {
+            Frames::Vec(frames) => logger.append_frames(frames.iter().map(|f| &**f)),
+            Frames::Snapshot(snap) => logger.append_snapshot(
+                snap.iter()
+                    .map(|f| Frame::try_from_bytes(Bytes::copy_from_slice(f.as_slice()))),
+            ),
+        };
+
+        if let Err(e) = res.and_then(|_| logger.maybe_compact()) {
+            tracing::error!("failed to append frames to the replica log, resetting it: {e}");
+            drop(logger);
+            let next_frame_no = last_frame_no(frames).map_or(0, |frame_no| frame_no + 1);
+            if let Err(e) = self.open(db_id, next_frame_no) {
+                tracing::error!("failed to reset the replica log: {e}");
+                *self.logger.write() = None;
+            }
+        }
+    }
+}
+
+fn last_frame_no(frames: &Frames) -> Option<FrameNo> {
+    match frames {
+        Frames::Vec(frames) => frames.iter().map(|f| f.header().frame_no).max(),
+        Frames::Snapshot(snap) => snap.iter().map(|f| f.header().frame_no).max(),
+    }
+}
@@ -1,5 +1,4 @@
 use std::fs::{File, OpenOptions};
-use std::io::ErrorKind;
 use std::mem::size_of;
 use std::os::unix::prelude::FileExt;
 use std::path::Path;
@@ -28,8 +27,16 @@ pub struct WalIndexMeta {
     generation_id: u128,
     /// Uuid of the database this instance is a replica of
     database_id: u128,
+    /// Rolling checksum of the frame `post_commit_frame_no`, as computed by the primary. 0 if it
+    /// is unknown, e.g. for the meta files written before it was recorded.
+    pub post_commit_checksum: u64,
+    /// First frame_no of the generation `generation_id`, 0 if it is unknown.
+    pub generation_start_frame_no: FrameNo,
 }
 
+/// Size of the meta files written before the checksum of the last frame was recorded.
+const LEGACY_META_SIZE: usize = 48;
+
 impl WalIndexMeta {
     pub fn read_from_path(db_path: &Path) -> anyhow::Result<(Option<Self>, File)> {
         let path = db_path.join("client_wal_index");
@@ -43,19 +50,45 @@ impl WalIndexMeta {
     }
 
     fn read(file: &File) -> anyhow::Result<Option<Self>> {
+        let len = file.metadata()?.len() as usize;
+        if len == 0 {
+            return Ok(None);
+        }
+        anyhow::ensure!(
+            len == LEGACY_META_SIZE || len == size_of::<WalIndexMeta>(),
+            "invalid index meta file"
+        );
+
+        // the fields missing from legacy files are zeroed
         let mut buf = [0; size_of::<WalIndexMeta>()];
-        let meta = match file.read_exact_at(&mut buf, 0) {
-            Ok(()) => {
-                file.read_exact_at(&mut buf, 0)?;
-                let meta: Self = try_pod_read_unaligned(&buf)
-                    .map_err(|_| anyhow::anyhow!("invalid index meta file"))?;
-                Some(meta)
-            }
-            Err(e) if e.kind() == ErrorKind::UnexpectedEof => None,
-            Err(e) => Err(e)?,
-        };
-
-        Ok(meta)
+        file.read_exact_at(&mut buf[..len], 0)?;
+        let meta: Self =
+            try_pod_read_unaligned(&buf).map_err(|_| anyhow::anyhow!("invalid index meta file"))?;
+
+        Ok(Some(meta))
+    }
+
+    /// Creates the meta of a replica that applied the frames of database `database_id` up to
+    /// `frame_no`, from a primary of unknown generation.
+    pub fn resume(database_id: Uuid, frame_no: FrameNo) -> Self {
+        Self {
+            pre_commit_frame_no: frame_no,
+            post_commit_frame_no: frame_no,
+            generation_id: 0,
+            database_id: database_id.as_u128(),
+            post_commit_checksum: 0,
+            generation_start_frame_no: 0,
+        }
+    }
+
+    /// Returns the id of the database this instance is a replica of.
+    pub fn database_id(&self) -> Uuid {
+        Uuid::from_u128(self.database_id)
+    }
+
+    /// Returns the generation of the primary the frames were replicated from, if known.
+    pub fn generation_id(&self) -> Option<Uuid> {
+        (self.generation_id != 0).then(|| Uuid::from_u128(self.generation_id))
     }
 
     /// attempts to merge two meta files.
@@ -76,6 +109,7 @@ impl WalIndexMeta {
         } else if self.pre_commit_frame_no <= hello.generation_start_index {
             // Ok: generation changed, but we aren't ahead of primary
             self.generation_id = hello_gen_id;
+            self.generation_start_frame_no = hello.generation_start_index;
             Ok(self)
         } else {
             Err(ReplicationError::Lagging)
@@ -95,6 +129,37 @@ impl WalIndexMeta {
             post_commit_frame_no: FrameNo::MAX,
             generation_id,
             database_id,
+            post_commit_checksum: 0,
+            generation_start_frame_no: hello.generation_start_index,
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use bytemuck::bytes_of;
+
+    use super::*;
+
+    #[test]
+    fn read_legacy_meta() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut meta = WalIndexMeta::resume(Uuid::new_v4(), 41);
+        meta.post_commit_checksum = 42;
+        let (_, file) = WalIndexMeta::read_from_path(tmp.path()).unwrap();
+        file.write_all_at(bytes_of(&meta), 0).unwrap();
+        let (read, _) = WalIndexMeta::read_from_path(tmp.path()).unwrap();
+        assert_eq!(read.unwrap().post_commit_checksum, 42);
+
+        // meta files written before the checksum was recorded
+        file.set_len(LEGACY_META_SIZE as u64).unwrap();
+        let (read, _) = WalIndexMeta::read_from_path(tmp.path()).unwrap();
+        let read = read.unwrap();
+        assert_eq!(read.post_commit_frame_no, 41);
+        assert_eq!(read.database_id(), meta.database_id());
+        assert_eq!(read.post_commit_checksum, 0);
+
+        file.set_len(10).unwrap();
+        assert!(WalIndexMeta::read_from_path(tmp.path()).is_err());
+    }
+}
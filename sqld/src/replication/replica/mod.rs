@@ -1,8 +1,20 @@
+mod bootstrap;
+mod chain;
 mod error;
 mod hook;
 mod injector;
+mod lag;
+mod log;
 mod meta;
+mod multiplex;
+mod promote;
+mod recovery;
 mod replicator;
 mod snapshot;
+mod table_tracker;
 
+pub use lag::ReplicationLag;
+pub use log::ReplicaLog;
+pub use multiplex::MultiplexedReplication;
+pub use promote::{database_progress, prepare_demotion, prepare_promotion, DatabaseProgress};
 pub use replicator::Replicator;
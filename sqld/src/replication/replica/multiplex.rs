@@ -0,0 +1,334 @@
+//! Replication of many namespaces over a single `Subscribe` stream to the primary.
+//!
+//! The replicator of each namespace subscribes to its namespace through the shared stream, rather
+//! than performing its own handshake and opening its own `LogEntries` stream. The events of the
+//! stream are routed to the subscription they belong to. Rather than holding back the events of
+//! the other namespaces, a subscriber that falls too far behind is unsubscribed: its subscription
+//! ends, and its replicator subscribes again, from the frames it has applied.
+//!
+//! When the stream is interrupted, all the subscriptions end, and the replicators subscribe again
+//! once the stream is reestablished.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::StreamExt;
+use tonic::transport::Channel;
+
+use crate::namespace::NamespaceEvent;
+use crate::replication::compression::SUPPORTED_COMPRESSIONS;
+use crate::replication::FrameNo;
+use crate::rpc::replication_log::rpc::replication_log_client::ReplicationLogClient;
+use crate::rpc::replication_log::rpc::subscribe_response::Event;
+use crate::rpc::replication_log::rpc::{
+    NamespaceSubscription, SubscribeRequest, SubscribeResponse,
+};
+
+/// Number of events buffered for each subscription. A subscription whose buffer is full when it
+/// receives an event is unsubscribed.
+const SUBSCRIPTION_CHANNEL_CAPACITY: usize = 16;
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct MultiplexedReplication {
+    client: ReplicationLogClient<Channel>,
+    /// whether to replicate all the namespaces of the primary
+    watch_namespaces: bool,
+    /// cleared if the primary doesn't support `Subscribe`
+    supported: AtomicBool,
+    next_subscription_id: AtomicU64,
+    state: Mutex<MultiplexState>,
+    namespace_events: mpsc::UnboundedSender<NamespaceEvent>,
+}
+
+#[derive(Default)]
+struct MultiplexState {
+    /// requests of the current stream, unset while it is not established
+    requests: Option<mpsc::UnboundedSender<SubscribeRequest>>,
+    /// the current subscription of each namespace, and where to send its events
+    subscriptions: HashMap<Bytes, (u64, mpsc::Sender<Event>)>,
+}
+
+impl MultiplexState {
+    /// Ends the current subscription of `namespace`, and tells the primary to stop sending its
+    /// events.
+    fn unsubscribe(&mut self, namespace: &Bytes) {
+        self.subscriptions.remove(namespace);
+        if let Some(requests) = &self.requests {
+            let _ = requests.send(SubscribeRequest {
+                unsubscribe: vec![namespace.clone()],
+                ..Default::default()
+            });
+        }
+    }
+}
+
+impl MultiplexedReplication {
+    /// Returns the multiplexer, and the receiver of the creation and deletion of namespaces on the
+    /// primary, if `watch_namespaces` is set.
+    pub fn new(
+        channel: Channel,
+        uri: tonic::transport::Uri,
+        watch_namespaces: bool,
+    ) -> (Arc<Self>, mpsc::UnboundedReceiver<NamespaceEvent>) {
+        let (namespace_events, receiver) = mpsc::unbounded_channel();
+        let this = Self {
+            client: ReplicationLogClient::with_origin(channel, uri),
+            watch_namespaces,
+            supported: AtomicBool::new(true),
+            next_subscription_id: AtomicU64::new(1),
+            state: Default::default(),
+            namespace_events,
+        };
+
+        (Arc::new(this), receiver)
+    }
+
+    /// Whether the primary supports multiplexed replication. If it doesn't, the namespaces are
+    /// replicated separately.
+    pub fn is_supported(&self) -> bool {
+        self.supported.load(Ordering::Relaxed)
+    }
+
+    /// Subscribes to the frames of `namespace`, from `next_offset`. Replaces the previous
+    /// subscription of the namespace, if any.
+    pub fn subscribe(
+        self: &Arc<Self>,
+        namespace: Bytes,
+        next_offset: FrameNo,
+    ) -> anyhow::Result<Subscription> {
+        let id = self.next_subscription_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = mpsc::channel(SUBSCRIPTION_CHANNEL_CAPACITY);
+        let mut state = self.state.lock();
+        let Some(requests) = &state.requests else {
+            anyhow::bail!("not connected to the primary");
+        };
+        let req = SubscribeRequest {
+            subscribe: vec![NamespaceSubscription {
+                namespace: namespace.clone(),
+                next_offset,
+                subscription_id: id,
+            }],
+            ..Default::default()
+        };
+        if requests.send(req).is_err() {
+            anyhow::bail!("not connected to the primary");
+        }
+        state.subscriptions.insert(namespace.clone(), (id, sender));
+
+        Ok(Subscription {
+            multiplex: self.clone(),
+            namespace,
+            id,
+            receiver,
+        })
+    }
+
+    fn unsubscribe(&self, namespace: &Bytes, id: u64) {
+        let mut state = self.state.lock();
+        if !matches!(state.subscriptions.get(namespace), Some((current, _)) if *current == id) {
+            return;
+        }
+        state.unsubscribe(namespace);
+    }
+
+    /// Maintains the stream to the primary, and routes its events.
+    pub async fn run(self: Arc<Self>) -> anyhow::Result<()> {
+        loop {
+            if let Err(e) = self.run_stream().await {
+                if e.code() == tonic::Code::Unimplemented {
+                    tracing::warn!(
+                        "the primary doesn't support multiplexed replication, replicating namespaces separately"
+                    );
+                    self.supported.store(false, Ordering::Relaxed);
+                    return Ok(());
+                }
+                tracing::warn!("multiplexed replication stream failed: {e}");
+            }
+
+            // the subscriptions end with the stream
+            let mut state = self.state.lock();
+            state.requests = None;
+            state.subscriptions.clear();
+            drop(state);
+
+            tokio::time::sleep(RECONNECT_INTERVAL).await;
+        }
+    }
+
+    async fn run_stream(&self) -> Result<(), tonic::Status> {
+        let (requests, receiver) = mpsc::unbounded_channel();
+        let _ = requests.send(SubscribeRequest {
+            watch_namespaces: self.watch_namespaces,
+            supported_compressions: SUPPORTED_COMPRESSIONS.map(Into::into).to_vec(),
+            ..Default::default()
+        });
+        let mut stream = self
+            .client
+            .clone()
+            .subscribe(UnboundedReceiverStream::new(receiver))
+            .await?
+            .into_inner();
+        self.state.lock().requests = Some(requests);
+        tracing::info!("multiplexed replication stream established");
+
+        while let Some(response) = stream.next().await {
+            self.dispatch(response?);
+        }
+
+        Ok(())
+    }
+
+    fn dispatch(&self, response: SubscribeResponse) {
+        let SubscribeResponse {
+            namespace,
+            subscription_id,
+            event,
+        } = response;
+        match event {
+            Some(Event::Created(_)) => {
+                let _ = self
+                    .namespace_events
+                    .send(NamespaceEvent::Created(namespace));
+            }
+            Some(Event::Deleted(_)) => {
+                let _ = self
+                    .namespace_events
+                    .send(NamespaceEvent::Deleted(namespace));
+            }
+            Some(event) => {
+                let mut state = self.state.lock();
+                let sender = match state.subscriptions.get(&namespace) {
+                    Some((id, sender)) if *id == subscription_id => sender,
+                    // the event of a subscription that was replaced or cancelled
+                    _ => return,
+                };
+                if let Err(TrySendError::Full(_)) = sender.try_send(event) {
+                    tracing::warn!(
+                        "replication of namespace `{}` is lagging, resubscribing",
+                        String::from_utf8_lossy(&namespace)
+                    );
+                    state.unsubscribe(&namespace);
+                }
+            }
+            None => (),
+        }
+    }
+}
+
+/// The subscription of a replicator to the frames of its namespace. Dropping it unsubscribes.
+pub struct Subscription {
+    multiplex: Arc<MultiplexedReplication>,
+    namespace: Bytes,
+    id: u64,
+    receiver: mpsc::Receiver<Event>,
+}
+
+impl Subscription {
+    /// Returns the next event of the subscription, or `None` once it ended.
+    pub async fn next(&mut self) -> Option<Event> {
+        self.receiver.recv().await
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.multiplex.unsubscribe(&self.namespace, self.id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rpc::replication_log::rpc::{HelloResponse, NamespaceCreated};
+
+    use super::*;
+
+    fn hello(next_frame_no: FrameNo) -> Event {
+        Event::Hello(HelloResponse {
+            next_frame_no: Some(next_frame_no),
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn route_events() {
+        let channel = Channel::from_static("http://127.0.0.1:1").connect_lazy();
+        let uri = "http://127.0.0.1:1".parse().unwrap();
+        let (multiplex, mut namespace_events) = MultiplexedReplication::new(channel, uri, true);
+        assert!(multiplex.subscribe("ns".into(), 0).is_err());
+
+        let (requests, mut sent) = mpsc::unbounded_channel();
+        multiplex.state.lock().requests = Some(requests);
+        let first = multiplex.subscribe("ns".into(), 0).unwrap();
+        let mut second = multiplex.subscribe("ns".into(), 10).unwrap();
+        let subscribed = sent.recv().await.unwrap().subscribe[0].clone();
+        assert_eq!(subscribed.subscription_id, first.id);
+        assert_eq!(sent.recv().await.unwrap().subscribe[0].next_offset, 10);
+
+        // the events of the replaced subscription are dropped
+        for (subscription_id, next_frame_no) in [(first.id, 1), (second.id, 2)] {
+            multiplex.dispatch(SubscribeResponse {
+                namespace: "ns".into(),
+                subscription_id,
+                event: Some(hello(next_frame_no)),
+            });
+        }
+        assert_eq!(second.next().await, Some(hello(2)));
+
+        // dropping a replaced subscription doesn't unsubscribe
+        drop(first);
+        assert!(sent.try_recv().is_err());
+        drop(second);
+        assert_eq!(
+            sent.recv().await.unwrap().unsubscribe,
+            vec![Bytes::from("ns")]
+        );
+
+        multiplex.dispatch(SubscribeResponse {
+            namespace: "other".into(),
+            subscription_id: 0,
+            event: Some(Event::Created(NamespaceCreated {})),
+        });
+        assert_eq!(
+            namespace_events.recv().await,
+            Some(NamespaceEvent::Created("other".into()))
+        );
+    }
+
+    #[tokio::test]
+    async fn unsubscribe_lagging_subscription() {
+        let channel = Channel::from_static("http://127.0.0.1:1").connect_lazy();
+        let uri = "http://127.0.0.1:1".parse().unwrap();
+        let (multiplex, _namespace_events) = MultiplexedReplication::new(channel, uri, false);
+        let (requests, mut sent) = mpsc::unbounded_channel();
+        multiplex.state.lock().requests = Some(requests);
+        let mut subscription = multiplex.subscribe("ns".into(), 0).unwrap();
+        sent.recv().await.unwrap();
+
+        for next_frame_no in 0..=SUBSCRIPTION_CHANNEL_CAPACITY as FrameNo {
+            multiplex.dispatch(SubscribeResponse {
+                namespace: "ns".into(),
+                subscription_id: subscription.id,
+                event: Some(hello(next_frame_no)),
+            });
+        }
+        assert_eq!(
+            sent.recv().await.unwrap().unsubscribe,
+            vec![Bytes::from("ns")]
+        );
+
+        // the buffered events are delivered, then the subscription ends
+        for next_frame_no in 0..SUBSCRIPTION_CHANNEL_CAPACITY as FrameNo {
+            assert_eq!(subscription.next().await, Some(hello(next_frame_no)));
+        }
+        assert_eq!(subscription.next().await, None);
+        drop(subscription);
+        assert!(sent.try_recv().is_err());
+    }
+}
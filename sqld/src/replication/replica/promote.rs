@@ -0,0 +1,212 @@
+//! Changes of role of a database between replica and primary.
+
+use std::fs::File;
+use std::os::unix::prelude::FileExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytemuck::bytes_of;
+use uuid::Uuid;
+
+use crate::replication::encryption::FrameEncryption;
+use crate::replication::primary::logger::{LogFile, ReplicationLogger};
+use crate::replication::{FrameNo, MergePolicy};
+
+use super::meta::WalIndexMeta;
+
+const META_FILE_NAME: &str = "client_wal_index";
+const LOG_FILE_NAME: &str = "wallog";
+
+/// Prepares the database of a replica at `db_path` to be opened as a primary. The replication log
+/// is made to continue from the last frame applied by the replica, keeping the id of the database,
+/// so that the replicas re-pointed at the promoted replica can keep replicating from where they
+/// are. The replication state of the replica is removed.
+pub fn prepare_promotion(
+    db_path: &Path,
+    max_log_size: u64,
+    max_log_duration: Option<Duration>,
+    encryption: FrameEncryption,
+) -> anyhow::Result<()> {
+    if !db_path.join(META_FILE_NAME).try_exists()? {
+        // already a primary
+        return Ok(());
+    }
+
+    let (meta, _) = WalIndexMeta::read_from_path(db_path)?;
+    if let Some(meta) = meta {
+        let next_frame_no = match meta.post_commit_frame_no {
+            FrameNo::MAX => 0,
+            frame_no => frame_no + 1,
+        };
+        tracing::info!(
+            "promoting replica at `{}`, the log continues from frame {next_frame_no}",
+            db_path.display()
+        );
+        // The log is left on disk for the primary to open, which starts a new generation from
+        // there.
+        ReplicationLogger::open_replica(
+            db_path,
+            max_log_size,
+            max_log_duration,
+            meta.database_id(),
+            next_frame_no,
+            Box::new(|_| Ok(())),
+            encryption,
+            Arc::new(MergePolicy::default),
+        )?;
+    }
+
+    std::fs::remove_file(db_path.join(META_FILE_NAME))?;
+
+    Ok(())
+}
+
+/// Prepares the database of a primary at `db_path` to be opened as a replica. The replica resumes
+/// from the last frame of the replication log. If the new primary doesn't have that frame, the
+/// replica is reset when it connects to it.
+pub fn prepare_demotion(db_path: &Path) -> anyhow::Result<()> {
+    let log_path = db_path.join(LOG_FILE_NAME);
+    if db_path.join(META_FILE_NAME).try_exists()? || !log_path.try_exists()? {
+        // already a replica, or an empty database
+        return Ok(());
+    }
+
+    let header = LogFile::read_header(&File::open(&log_path)?)?;
+    if header.last_frame_no() == 0 {
+        return Ok(());
+    }
+
+    tracing::info!(
+        "demoting primary at `{}`, resuming from frame {}",
+        db_path.display(),
+        header.last_frame_no() - 1
+    );
+    let meta = WalIndexMeta::resume(Uuid::from_u128(header.db_id), header.last_frame_no() - 1);
+    let (_, file) = WalIndexMeta::read_from_path(db_path)?;
+    file.write_all_at(bytes_of(&meta), 0)?;
+
+    Ok(())
+}
+
+/// How far the copy of a database on a node is, to compare the copies of the nodes of a cluster.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DatabaseProgress {
+    pub database_id: Uuid,
+    /// The generation of the primary that the frames were replicated from, if known.
+    pub generation_id: Option<Uuid>,
+    /// The first frame_no of that generation, 0 if unknown.
+    pub generation_start_frame_no: FrameNo,
+    pub next_frame_no: FrameNo,
+}
+
+/// Returns the progress of the database at `db_path`, whether it is a replica or a primary, or
+/// `None` if it has no frames yet.
+pub fn database_progress(db_path: &Path) -> anyhow::Result<Option<DatabaseProgress>> {
+    if db_path.join(META_FILE_NAME).try_exists()? {
+        let (meta, _) = WalIndexMeta::read_from_path(db_path)?;
+        return Ok(meta.and_then(|meta| match meta.post_commit_frame_no {
+            FrameNo::MAX => None,
+            frame_no => Some(DatabaseProgress {
+                database_id: meta.database_id(),
+                generation_id: meta.generation_id(),
+                generation_start_frame_no: meta.generation_start_frame_no,
+                next_frame_no: frame_no + 1,
+            }),
+        }));
+    }
+
+    let log_path = db_path.join(LOG_FILE_NAME);
+    if log_path.try_exists()? {
+        let header = LogFile::read_header(&File::open(&log_path)?)?;
+        if header.last_frame_no() > 0 {
+            // the generation of a primary starts when it opens the log
+            return Ok(Some(DatabaseProgress {
+                database_id: Uuid::from_u128(header.db_id),
+                generation_id: None,
+                generation_start_frame_no: 0,
+                next_frame_no: header.last_frame_no(),
+            }));
+        }
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write_meta(db_path: &Path, meta: WalIndexMeta) {
+        let (_, file) = WalIndexMeta::read_from_path(db_path).unwrap();
+        file.write_all_at(bytes_of(&meta), 0).unwrap();
+    }
+
+    fn promote(db_path: &Path) {
+        prepare_promotion(db_path, 100, None, FrameEncryption::default()).unwrap();
+    }
+
+    #[test]
+    fn promoted_log_continues_from_replica() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_id = Uuid::new_v4();
+        write_meta(tmp.path(), WalIndexMeta::resume(db_id, 41));
+
+        promote(tmp.path());
+
+        assert!(!tmp.path().join(META_FILE_NAME).exists());
+        let header =
+            LogFile::read_header(&File::open(tmp.path().join(LOG_FILE_NAME)).unwrap()).unwrap();
+        assert_eq!(Uuid::from_u128(header.db_id), db_id);
+        assert_eq!(header.last_frame_no(), 42);
+        assert_eq!(
+            database_progress(tmp.path()).unwrap(),
+            Some(DatabaseProgress {
+                database_id: db_id,
+                generation_id: None,
+                generation_start_frame_no: 0,
+                next_frame_no: 42,
+            })
+        );
+
+        // promoting a primary is a noop
+        promote(tmp.path());
+        assert_eq!(
+            database_progress(tmp.path())
+                .unwrap()
+                .unwrap()
+                .next_frame_no,
+            42
+        );
+    }
+
+    #[test]
+    fn promote_empty_replica() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut meta = WalIndexMeta::resume(Uuid::new_v4(), 0);
+        meta.pre_commit_frame_no = FrameNo::MAX;
+        meta.post_commit_frame_no = FrameNo::MAX;
+        write_meta(tmp.path(), meta);
+        assert_eq!(database_progress(tmp.path()).unwrap(), None);
+
+        promote(tmp.path());
+
+        assert!(!tmp.path().join(META_FILE_NAME).exists());
+        assert_eq!(database_progress(tmp.path()).unwrap(), None);
+    }
+
+    #[test]
+    fn demotion_resumes_from_promoted_log() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_id = Uuid::new_v4();
+        write_meta(tmp.path(), WalIndexMeta::resume(db_id, 41));
+        promote(tmp.path());
+
+        prepare_demotion(tmp.path()).unwrap();
+
+        let (meta, _) = WalIndexMeta::read_from_path(tmp.path()).unwrap();
+        let meta = meta.unwrap();
+        assert_eq!(meta.database_id(), db_id);
+        assert_eq!(meta.post_commit_frame_no, 41);
+    }
+}
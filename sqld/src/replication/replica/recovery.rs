@@ -0,0 +1,205 @@
+//! Recovery of a replica whose database can't replicate from the primary anymore, because it is
+//! ahead of the primary, or replicates another database.
+//!
+//! Rather than wiping the database and replicating it from scratch, the replica compares the
+//! checksums of the pages of the primary's snapshot from frame 0 with its own pages, and only
+//! transfers the pages that differ. The snapshot is then applied in a single transaction, on top
+//! of the stale database, which keeps serving reads until then. Since the snapshot from frame 0
+//! contains all the pages of the database, applying it leaves the database in the state of the
+//! primary, whatever its previous content.
+
+use std::fs::File;
+use std::io::ErrorKind;
+use std::os::unix::prelude::FileExt;
+use std::path::Path;
+
+use anyhow::Context;
+use bytes::Bytes;
+use futures::StreamExt;
+use tonic::transport::Channel;
+
+use crate::replication::compression;
+use crate::replication::frame::{Frame, FrameHeader};
+use crate::replication::{page_checksum, FrameNo, WAL_PAGE_SIZE};
+use crate::rpc::replication_log::rpc::replication_log_client::ReplicationLogClient;
+use crate::rpc::replication_log::rpc::{Compression, LogOffset, PageDigest, SnapshotDigest};
+
+use super::snapshot::{PartialSnapshot, TempSnapshot};
+
+const RECOVERY_DIR: &str = "recovery";
+
+/// Builds the snapshot of the primary from frame 0, with the pages of the database of the replica
+/// that match it, and the other pages from the primary. Returns the snapshot, along with the
+/// frame_no of its last frame.
+pub async fn recovery_snapshot(
+    client: &mut ReplicationLogClient<Channel>,
+    db_path: &Path,
+    namespace: Bytes,
+    compression: Compression,
+) -> anyhow::Result<(TempSnapshot, FrameNo)> {
+    let recovery_dir = db_path.join(RECOVERY_DIR);
+    let _ = tokio::fs::remove_dir_all(&recovery_dir).await;
+    tokio::fs::create_dir_all(&recovery_dir).await?;
+
+    let offset = LogOffset {
+        next_offset: 0,
+        namespace,
+        compression: compression.into(),
+        snapshot_id: None,
+        resume_from: 0,
+        pages: Vec::new(),
+    };
+    let SnapshotDigest { snapshot_id, pages } =
+        client.snapshot_digest(offset.clone()).await?.into_inner();
+    let last_frame_no = pages
+        .iter()
+        .map(|page| page.frame_no)
+        .max()
+        .context("the snapshot of the primary is empty")?;
+
+    let data_path = db_path.join("data");
+    let (local, missing) = {
+        let data_path = data_path.clone();
+        tokio::task::spawn_blocking(move || diff_pages(&data_path, pages)).await??
+    };
+    tracing::info!(
+        "recovering the database from snapshot {snapshot_id}: transferring {} pages, reusing {} pages",
+        missing.len(),
+        local.len()
+    );
+
+    let received = if missing.is_empty() {
+        None
+    } else {
+        let mut partial = PartialSnapshot::open(&recovery_dir, 0)?;
+        let mut messages = client
+            .snapshot(LogOffset {
+                pages: missing,
+                ..offset
+            })
+            .await?
+            .into_inner();
+        while let Some(message) = messages.next().await {
+            let message = message?;
+            let chunk = message.snapshot_chunk.clone();
+            anyhow::ensure!(
+                chunk.as_ref().map(|chunk| &chunk.snapshot_id) == Some(&snapshot_id),
+                "the snapshot of the primary changed during recovery"
+            );
+            let frames = compression::decode_frames(message)?;
+            partial.append(chunk.as_ref(), &frames)?;
+        }
+        Some(partial.finish()?)
+    };
+
+    let data = File::open(&data_path)?;
+    let received_frames = received.iter().flat_map(|snapshot| {
+        snapshot
+            .iter()
+            .map(|frame| Frame::try_from_bytes(Bytes::copy_from_slice(frame.as_slice())))
+    });
+    let local_frames = local.iter().map(|page| local_frame(&data, page));
+    let snapshot = TempSnapshot::from_stream(
+        &recovery_dir,
+        futures::stream::iter(received_frames.chain(local_frames)),
+    )
+    .await?;
+
+    Ok((snapshot, last_frame_no))
+}
+
+/// Splits the pages of a snapshot between the ones that the database file has already, and the
+/// numbers of the ones that it doesn't.
+fn diff_pages(
+    data_path: &Path,
+    pages: Vec<PageDigest>,
+) -> anyhow::Result<(Vec<PageDigest>, Vec<u32>)> {
+    let file = File::open(data_path)?;
+    let mut buf = vec![0; WAL_PAGE_SIZE as usize];
+    let mut local = Vec::new();
+    let mut missing = Vec::new();
+    for page in pages {
+        if read_page(&file, page.page_no, &mut buf)? && page_checksum(&buf) == page.checksum {
+            local.push(page);
+        } else {
+            missing.push(page.page_no);
+        }
+    }
+
+    Ok((local, missing))
+}
+
+/// Reads the page `page_no` of a database file, and returns whether it exists.
+fn read_page(file: &File, page_no: u32, buf: &mut [u8]) -> anyhow::Result<bool> {
+    anyhow::ensure!(page_no > 0, "invalid page number: 0");
+    match file.read_exact_at(buf, (page_no as u64 - 1) * WAL_PAGE_SIZE as u64) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Makes the frame of a snapshot out of the page of the database file that matches it.
+fn local_frame(file: &File, page: &PageDigest) -> anyhow::Result<Frame> {
+    let mut buf = vec![0; WAL_PAGE_SIZE as usize];
+    // the page may have changed since it was compared, if the WAL was checkpointed since
+    anyhow::ensure!(
+        read_page(file, page.page_no, &mut buf)? && page_checksum(&buf) == page.checksum,
+        "page {} of the database changed during recovery",
+        page.page_no
+    );
+    let header = FrameHeader {
+        frame_no: page.frame_no,
+        checksum: page.frame_checksum,
+        page_no: page.page_no,
+        size_after: page.size_after,
+    };
+
+    Ok(Frame::from_parts(&header, &buf))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn digest(page_no: u32, page: &[u8]) -> PageDigest {
+        PageDigest {
+            page_no,
+            frame_no: page_no as FrameNo,
+            size_after: 0,
+            checksum: page_checksum(page),
+            frame_checksum: 0,
+        }
+    }
+
+    #[test]
+    fn diff_database_pages() {
+        let tmp = tempfile::tempdir().unwrap();
+        let data_path = tmp.path().join("data");
+        let page = |byte| vec![byte; WAL_PAGE_SIZE as usize];
+        std::fs::write(&data_path, [page(1), page(2), page(3)].concat()).unwrap();
+
+        // page 2 differs, and page 4 is past the end of the file
+        let pages = vec![
+            digest(1, &page(1)),
+            digest(2, &page(7)),
+            digest(3, &page(3)),
+            digest(4, &page(4)),
+        ];
+        let (local, missing) = diff_pages(&data_path, pages).unwrap();
+        assert_eq!(
+            local.iter().map(|page| page.page_no).collect::<Vec<_>>(),
+            vec![1, 3]
+        );
+        assert_eq!(missing, vec![2, 4]);
+
+        let file = File::open(&data_path).unwrap();
+        let frame = local_frame(&file, &local[1]).unwrap();
+        assert_eq!(frame.header().page_no, 3);
+        assert_eq!(frame.page(), &page(3)[..]);
+
+        // the page was overwritten since it was compared
+        std::fs::write(&data_path, [page(1), page(2), page(9)].concat()).unwrap();
+        assert!(local_frame(&file, &local[1]).is_err());
+    }
+}
@@ -1,30 +1,46 @@
 use std::os::unix::prelude::FileExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use anyhow::bail;
+use anyhow::{bail, Context};
 use bytemuck::bytes_of;
 use bytes::Bytes;
 use futures::StreamExt;
 use tokio::sync::{mpsc, oneshot, watch, Mutex};
 use tokio::task::JoinSet;
 use tonic::transport::Channel;
+use uuid::Uuid;
 
+use crate::connection::table_changes::TableChanges;
+use crate::replication::compression::{self, SUPPORTED_COMPRESSIONS};
 use crate::replication::frame::Frame;
 use crate::replication::replica::error::ReplicationError;
-use crate::replication::replica::snapshot::TempSnapshot;
-use crate::replication::FrameNo;
+use crate::replication::replica::snapshot::PartialSnapshot;
+use crate::replication::{current_frame_no, FrameNo};
+use crate::rpc::replication_log::rpc::subscribe_response::Event;
 use crate::rpc::replication_log::rpc::{
-    replication_log_client::ReplicationLogClient, HelloRequest, LogOffset,
+    replication_log_client::ReplicationLogClient, AckRequest, Compression, Frame as RpcFrame,
+    HelloRequest, HelloResponse, LogOffset,
 };
 use crate::rpc::replication_log::NEED_SNAPSHOT_ERROR_MSG;
 
+use super::bootstrap;
+use super::chain::FrameChain;
 use super::hook::{Frames, InjectorHookCtx};
 use super::injector::FrameInjector;
+use super::lag::ReplicationLag;
+use super::log::ReplicaLog;
 use super::meta::WalIndexMeta;
+use super::multiplex::MultiplexedReplication;
+use super::recovery;
+use super::snapshot::TempSnapshot;
+use super::table_tracker::TableTracker;
 
 const HANDSHAKE_MAX_RETRIES: usize = 100;
+/// Interval at which the replica asks the primary for its frame_no, to estimate its lag.
+const PRIMARY_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_REPLICA_REPLICATION_BUFFER_LEN: usize = 10_000_000 / 4096; // ~10MB
 
 type Client = ReplicationLogClient<Channel>;
 
@@ -37,12 +53,24 @@ pub struct Replicator {
     namespace: Bytes,
     meta: Arc<Mutex<Option<WalIndexMeta>>>,
     pub current_frame_no_notifier: watch::Receiver<FrameNo>,
+    pub lag: Arc<ReplicationLag>,
+    /// compression of the frames, as negotiated with the primary
+    compression: Compression,
+    /// bottomless backups to bootstrap the database from, if it is fresh
+    bootstrap: Option<bottomless::replicator::Options>,
+    /// the log of the applied frames, when this replica serves other replicas
+    replica_log: Option<Arc<ReplicaLog>>,
+    /// the stream shared with the other namespaces, to replicate over
+    multiplex: Option<Arc<MultiplexedReplication>>,
     frames_sender: mpsc::Sender<Frames>,
+    /// verifies the frames received from the primary before they are injected
+    chain: FrameChain,
     /// hard reset channel: send the namespace there, to reset it
     hard_reset: mpsc::Sender<Bytes>,
 }
 
 impl Replicator {
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         db_path: PathBuf,
         channel: Channel,
@@ -50,9 +78,19 @@ impl Replicator {
         namespace: Bytes,
         join_set: &mut JoinSet<anyhow::Result<()>>,
         hard_reset: mpsc::Sender<Bytes>,
+        replica_log: Option<Arc<ReplicaLog>>,
+        bootstrap: Option<bottomless::replicator::Options>,
+        multiplex: Option<Arc<MultiplexedReplication>>,
+        table_changes: Arc<TableChanges>,
     ) -> anyhow::Result<Self> {
         let client = Client::with_origin(channel, uri);
         let (meta, meta_file) = WalIndexMeta::read_from_path(&db_path)?;
+        let chain = match meta {
+            Some(meta) if meta.post_commit_frame_no != FrameNo::MAX => {
+                FrameChain::new(Some(meta.post_commit_frame_no), meta.post_commit_checksum)
+            }
+            _ => FrameChain::default(),
+        };
         let meta_file = Arc::new(meta_file);
         let (applied_frame_notifier, current_frame_no_notifier) =
             watch::channel(meta.map(|m| m.post_commit_frame_no).unwrap_or(FrameNo::MAX));
@@ -77,30 +115,49 @@ impl Replicator {
         let post_commit = {
             let meta = meta.clone();
             let meta_file = meta_file;
-            let notifier = applied_frame_notifier;
-            move |fno| {
+            move |fno, checksum| {
                 let mut lock = meta.blocking_lock();
                 let meta = lock
                     .as_mut()
                     .expect("commit called before meta inialization");
                 assert_eq!(meta.pre_commit_frame_no, fno);
                 meta.post_commit_frame_no = fno;
+                meta.post_commit_checksum = checksum;
                 meta_file.write_all_at(bytes_of(meta), 0)?;
-                let _ = notifier.send(fno);
 
                 Ok(())
             }
         };
 
+        let tracker = Arc::new(parking_lot::Mutex::new(TableTracker::new(table_changes)));
+        let on_applied = {
+            let replica_log = replica_log.clone();
+            let tracker = tracker.clone();
+            move |frames: &Frames| {
+                tracker.lock().add_frames(frames);
+                if let Some(replica_log) = &replica_log {
+                    replica_log.append(frames);
+                }
+            }
+        };
+
         let (snd, rcv) = oneshot::channel();
         join_set.spawn_blocking({
             let db_path = db_path.clone();
+            let meta = meta.clone();
             move || -> anyhow::Result<()> {
-                let mut ctx = InjectorHookCtx::new(receiver, pre_commit, post_commit);
+                let mut ctx = InjectorHookCtx::new(receiver, pre_commit, post_commit, on_applied);
                 let mut injector = FrameInjector::new(&db_path, &mut ctx)?;
                 let _ = snd.send(());
 
-                while injector.step()? {}
+                // the changed tables are recorded before the new frame_no is published, so that
+                // the subscriptions woken by it see the changes.
+                while injector.step()? {
+                    tracker.lock().commit(&db_path);
+                    if let Some(meta) = *meta.blocking_lock() {
+                        let _ = applied_frame_notifier.send(meta.post_commit_frame_no);
+                    }
+                }
 
                 Ok(())
             }
@@ -109,21 +166,58 @@ impl Replicator {
         // injector is ready:
         rcv.await?;
 
+        let lag = Arc::new(ReplicationLag::default());
+        join_set.spawn(poll_primary_frame_no(
+            client.clone(),
+            namespace.clone(),
+            lag.clone(),
+            current_frame_no_notifier.clone(),
+            multiplex.clone(),
+        ));
+        join_set.spawn(ack_applied_frames(
+            client.clone(),
+            namespace.clone(),
+            replica_id(&db_path)?,
+            current_frame_no_notifier.clone(),
+        ));
+
         Ok(Self {
             namespace,
             client,
             db_path,
             current_frame_no_notifier,
+            lag,
+            compression: Compression::None,
+            bootstrap,
+            replica_log,
+            multiplex,
             meta,
             frames_sender,
+            chain,
             hard_reset,
         })
     }
 
     pub async fn run(mut self) -> anyhow::Result<()> {
         loop {
+            if let Some(multiplex) = self.multiplex.clone() {
+                if multiplex.is_supported() {
+                    if let Err(e) = self.replicate_multiplexed(&multiplex).await {
+                        tracing::warn!("replication error: {e}");
+                    }
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            }
+
             self.try_perform_handshake().await?;
 
+            if let Some(options) = self.bootstrap.take() {
+                if self.current_frame_no().is_none() {
+                    self.bootstrap_from_bottomless(options).await?;
+                }
+            }
+
             if let Err(e) = self.replicate().await {
                 // Replication encountered an error. We log the error, and then shut down the
                 // injector and propagate a potential panic from there.
@@ -137,45 +231,17 @@ impl Replicator {
         let mut error_printed = false;
         for _ in 0..HANDSHAKE_MAX_RETRIES {
             tracing::info!("Attempting to perform handshake with primary.");
+            let asked_at = Instant::now();
             match self
                 .client
                 .hello(HelloRequest {
                     namespace: self.namespace.clone(),
+                    supported_compressions: SUPPORTED_COMPRESSIONS.map(Into::into).to_vec(),
                 })
                 .await
             {
                 Ok(resp) => {
-                    let hello = resp.into_inner();
-
-                    let mut lock = self.meta.lock().await;
-                    let meta = match *lock {
-                        Some(meta) => match meta.merge_from_hello(hello) {
-                            Ok(meta) => meta,
-                            Err(e @ ReplicationError::Lagging) => {
-                                tracing::error!("Replica ahead of primary: hard-reseting replica");
-                                self.hard_reset
-                                    .send(self.namespace.clone())
-                                    .await
-                                    .expect("reset loop exited");
-
-                                anyhow::bail!(e);
-                            }
-                            Err(e @ ReplicationError::DbIncompatible) => {
-                                tracing::error!("Primary is attempting to replicate a different database, overwriting replica.");
-                                self.hard_reset
-                                    .send(self.namespace.clone())
-                                    .await
-                                    .expect("reset loop exited");
-
-                                anyhow::bail!(e);
-                            }
-                            Err(e) => anyhow::bail!(e),
-                        },
-                        None => WalIndexMeta::new_from_hello(hello)?,
-                    };
-
-                    *lock = Some(meta);
-
+                    self.handle_hello(resp.into_inner(), asked_at).await?;
                     return Ok(());
                 }
                 Err(e) if !error_printed => {
@@ -190,30 +256,141 @@ impl Replicator {
         bail!("couldn't connect to primary after {HANDSHAKE_MAX_RETRIES} tries.");
     }
 
+    /// Checks that the database of the replica can replicate from the primary, and records the
+    /// state of the primary, as reported by `hello`.
+    async fn handle_hello(
+        &mut self,
+        hello: HelloResponse,
+        asked_at: Instant,
+    ) -> anyhow::Result<()> {
+        self.compression = hello.compression();
+        if let Some(next_frame_no) = hello.next_frame_no {
+            let applied = current_frame_no(&self.current_frame_no_notifier);
+            self.lag.observe_primary(next_frame_no, asked_at, applied);
+        }
+        self.lag
+            .set_generation(hello.generation_id.clone(), hello.generation_start_index);
+
+        let merged = self
+            .meta
+            .lock()
+            .await
+            .map(|meta| meta.merge_from_hello(hello.clone()));
+        let meta = match merged {
+            Some(Ok(meta)) => meta,
+            Some(Err(e @ (ReplicationError::Lagging | ReplicationError::DbIncompatible))) => {
+                if matches!(e, ReplicationError::Lagging) {
+                    tracing::error!("Replica ahead of primary: recovering replica");
+                } else {
+                    tracing::error!(
+                        "Primary is attempting to replicate a different database, recovering replica."
+                    );
+                }
+                match self.recover(WalIndexMeta::new_from_hello(hello)?).await {
+                    Ok(meta) => meta,
+                    Err(err) => {
+                        tracing::error!("failed to recover replica, hard-reseting replica: {err}");
+                        self.hard_reset
+                            .send(self.namespace.clone())
+                            .await
+                            .expect("reset loop exited");
+
+                        anyhow::bail!(e);
+                    }
+                }
+            }
+            Some(Err(e)) => anyhow::bail!(e),
+            None => WalIndexMeta::new_from_hello(hello)?,
+        };
+
+        *self.meta.lock().await = Some(meta);
+
+        if let Some(replica_log) = &self.replica_log {
+            let next_offset = self.next_offset();
+            if let Err(e) = replica_log.open(meta.database_id(), next_offset) {
+                tracing::error!("failed to open the replica log: {e}");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns whether the database must be recovered before it can replicate from the primary
+    /// that sent `hello`.
+    async fn needs_recovery(&self, hello: &HelloResponse) -> bool {
+        let meta = *self.meta.lock().await;
+        matches!(
+            meta.map(|meta| meta.merge_from_hello(hello.clone())),
+            Some(Err(
+                ReplicationError::Lagging | ReplicationError::DbIncompatible
+            ))
+        )
+    }
+
+    /// Replaces the database, that can't replicate from the primary anymore, with the snapshot of
+    /// the primary, transferring only the pages that differ. The stale database serves reads,
+    /// flagged as recovering, until the snapshot is applied. `meta` identifies the database to
+    /// recover. Returns the meta of the recovered database.
+    async fn recover(&mut self, meta: WalIndexMeta) -> anyhow::Result<WalIndexMeta> {
+        self.lag.set_recovering(true);
+        let (snapshot, last_frame_no) = recovery::recovery_snapshot(
+            &mut self.client,
+            &self.db_path,
+            self.namespace.clone(),
+            self.compression,
+        )
+        .await?;
+
+        // the snapshot starts the log of the recovered database
+        if let Some(replica_log) = &self.replica_log {
+            if let Err(e) = replica_log.open(meta.database_id(), 0) {
+                tracing::error!("failed to open the replica log: {e}");
+            }
+        }
+
+        // the meta is written along with the snapshot, when it is applied
+        *self.meta.lock().await = Some(meta);
+        // wait for the snapshot to be applied, even if the database had reached the same frame_no
+        self.current_frame_no_notifier.borrow_and_update();
+        self.inject_snapshot(snapshot).await;
+        loop {
+            self.current_frame_no_notifier.changed().await?;
+            if self.current_frame_no() == Some(last_frame_no) {
+                break;
+            }
+        }
+        self.lag.set_recovering(false);
+        tracing::info!("recovered the database up to frame {last_frame_no}");
+
+        self.meta
+            .lock()
+            .await
+            .context("recovered database has no meta")
+    }
+
     async fn replicate(&mut self) -> anyhow::Result<()> {
-        const MAX_REPLICA_REPLICATION_BUFFER_LEN: usize = 10_000_000 / 4096; // ~10MB
+        self.restart_chain().await?;
         let offset = LogOffset {
             // if current == FrameNo::Max then it means that we're starting fresh
             next_offset: self.next_offset(),
             namespace: self.namespace.clone(),
+            compression: self.compression.into(),
+            snapshot_id: None,
+            resume_from: 0,
+            pages: Vec::new(),
         };
         let mut stream = self.client.log_entries(offset).await?.into_inner();
 
         let mut buffer = Vec::new();
         loop {
             match stream.next().await {
-                Some(Ok(frame)) => {
-                    let frame = Frame::try_from_bytes(frame.data)?;
-                    buffer.push(frame.clone());
-                    if frame.header().size_after != 0
-                        || buffer.len() > MAX_REPLICA_REPLICATION_BUFFER_LEN
-                    {
-                        let _ = self
-                            .frames_sender
-                            .send(Frames::Vec(std::mem::take(&mut buffer)))
-                            .await;
+                Some(Ok(message)) => match self.receive_frames(message, &mut buffer).await {
+                    Err(ReplicationError::InvalidFrame(e)) => {
+                        drop(stream);
+                        return self.resync(e).await;
                     }
-                }
+                    res => res?,
+                },
                 Some(Err(err))
                     if err.code() == tonic::Code::FailedPrecondition
                         && err.message() == NEED_SNAPSHOT_ERROR_MSG =>
@@ -230,24 +407,218 @@ impl Replicator {
         }
     }
 
+    /// Replicates over the stream shared with the other namespaces, until the subscription ends.
+    async fn replicate_multiplexed(
+        &mut self,
+        multiplex: &Arc<MultiplexedReplication>,
+    ) -> anyhow::Result<()> {
+        'subscribe: loop {
+            self.restart_chain().await?;
+            let asked_at = Instant::now();
+            let mut subscription =
+                multiplex.subscribe(self.namespace.clone(), self.next_offset())?;
+            // the subscription starts with the handshake
+            match subscription.next().await {
+                Some(Event::Hello(hello)) => {
+                    if self.needs_recovery(&hello).await {
+                        // the subscription would hold back the other namespaces during the
+                        // recovery
+                        drop(subscription);
+                        self.handle_hello(hello, asked_at).await?;
+                        continue 'subscribe;
+                    }
+                    self.handle_hello(hello, asked_at).await?
+                }
+                Some(Event::Error(e)) => bail!("subscription failed: {}", e.message),
+                Some(_) => bail!("subscription didn't start with a hello"),
+                None => return Ok(()),
+            }
+
+            if let Some(options) = self.bootstrap.take() {
+                if self.current_frame_no().is_none() {
+                    self.bootstrap_from_bottomless(options).await?;
+                    // replicate from after the bootstrapped frames
+                    continue 'subscribe;
+                }
+            }
+
+            let mut buffer = Vec::new();
+            loop {
+                match subscription.next().await {
+                    Some(Event::Frame(message)) => {
+                        match self.receive_frames(message, &mut buffer).await {
+                            Err(ReplicationError::InvalidFrame(e)) => {
+                                drop(subscription);
+                                self.resync(e).await?;
+                                continue 'subscribe;
+                            }
+                            res => res?,
+                        }
+                    }
+                    Some(Event::Hello(hello)) => {
+                        if let Some(next_frame_no) = hello.next_frame_no {
+                            let applied = self.current_frame_no();
+                            self.lag
+                                .observe_primary(next_frame_no, Instant::now(), applied);
+                        }
+                    }
+                    Some(Event::Error(e))
+                        if e.code == tonic::Code::FailedPrecondition as i32
+                            && e.message == NEED_SNAPSHOT_ERROR_MSG =>
+                    {
+                        tracing::debug!("loading snapshot");
+                        drop(subscription);
+                        self.load_snapshot().await?;
+                        continue 'subscribe;
+                    }
+                    Some(Event::Error(e)) => bail!("subscription failed: {}", e.message),
+                    Some(_) => (),
+                    None => return Ok(()),
+                }
+            }
+        }
+    }
+
+    /// Buffers the frames of a message, and sends them to the injector at transaction
+    /// boundaries. Frames that don't follow the chain of the frames received so far are not
+    /// injected.
+    async fn receive_frames(
+        &mut self,
+        message: RpcFrame,
+        buffer: &mut Vec<Frame>,
+    ) -> Result<(), ReplicationError> {
+        for frame in compression::decode_frames(message)? {
+            self.chain.verify(&frame)?;
+            let is_commit = frame.header().size_after != 0;
+            buffer.push(frame);
+            if is_commit || buffer.len() > MAX_REPLICA_REPLICATION_BUFFER_LEN {
+                let _ = self
+                    .frames_sender
+                    .send(Frames::Vec(std::mem::take(buffer)))
+                    .await;
+            }
+        }
+
+        Ok(())
+    }
+
     async fn load_snapshot(&mut self) -> anyhow::Result<()> {
         let next_offset = self.next_offset();
-        let frames = self
+        let mut partial = PartialSnapshot::open(&self.db_path, next_offset)?;
+        let res = self.receive_snapshot(&mut partial, next_offset).await;
+        // keep the frames received so far, to resume the transfer if it was interrupted
+        partial.save()?;
+        res?;
+
+        let snap = partial.finish()?;
+        self.inject_snapshot(snap).await;
+
+        Ok(())
+    }
+
+    /// Prepares the chain of frames for a new stream, which continues after the last committed
+    /// frame: the frames of the uncommitted transaction, if any, are received again. Waits for
+    /// the committed frames to be applied, so that the stream starts after them.
+    async fn restart_chain(&mut self) -> anyhow::Result<()> {
+        self.chain.rollback();
+        let Some(committed) = self.chain.committed_frame_no() else {
+            return Ok(());
+        };
+        while self.current_frame_no() < Some(committed) {
+            self.current_frame_no_notifier.changed().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends a snapshot to the injector. The chain of frames continues after its last frame.
+    async fn inject_snapshot(&mut self, snapshot: TempSnapshot) {
+        if let Some(last) = snapshot.iter().max_by_key(|frame| frame.header().frame_no) {
+            self.chain.reset(last);
+        }
+        let _ = self.frames_sender.send(Frames::Snapshot(snapshot)).await;
+    }
+
+    /// Resynchronizes the database after the primary sent an invalid frame, which was not
+    /// injected: the database is recovered from the snapshot of the primary, as if it had diverged
+    /// from it.
+    async fn resync(&mut self, error: String) -> anyhow::Result<()> {
+        tracing::error!("invalid frame from primary, resynchronizing replica: {error}");
+        self.lag.record_invalid_frame(error);
+        let meta = (*self.meta.lock().await).context("replica has no meta")?;
+        if let Err(e) = self.recover(meta).await {
+            tracing::error!("failed to resynchronize replica, hard-reseting replica: {e}");
+            self.hard_reset
+                .send(self.namespace.clone())
+                .await
+                .expect("reset loop exited");
+
+            bail!(e);
+        }
+
+        Ok(())
+    }
+
+    async fn receive_snapshot(
+        &mut self,
+        partial: &mut PartialSnapshot,
+        next_offset: FrameNo,
+    ) -> anyhow::Result<()> {
+        let (snapshot_id, resume_from) = partial.progress();
+        let mut messages = self
             .client
             .snapshot(LogOffset {
                 next_offset,
                 namespace: self.namespace.clone(),
+                compression: self.compression.into(),
+                snapshot_id,
+                resume_from,
+                pages: Vec::new(),
             })
             .await?
             .into_inner();
 
-        let stream = frames.map(|data| match data {
-            Ok(frame) => Frame::try_from_bytes(frame.data),
-            Err(e) => anyhow::bail!(e),
-        });
-        let snap = TempSnapshot::from_stream(&self.db_path, stream).await?;
+        while let Some(message) = messages.next().await {
+            let message = message?;
+            let chunk = message.snapshot_chunk.clone();
+            let frames = compression::decode_frames(message)?;
+            partial.append(chunk.as_ref(), &frames)?;
+        }
+
+        Ok(())
+    }
+
+    /// Seeds the fresh database of the replica with the bottomless backup of the primary, so that
+    /// only the frames that follow the backup are replicated from the primary. If the backup is
+    /// unusable, the whole database is replicated from the primary instead.
+    async fn bootstrap_from_bottomless(
+        &mut self,
+        options: bottomless::replicator::Options,
+    ) -> anyhow::Result<()> {
+        let Some(log_id) = self.meta.lock().await.map(|meta| meta.database_id()) else {
+            return Ok(());
+        };
+        let (snapshot, next_frame_no) =
+            match bootstrap::snapshot_from_bottomless(&self.db_path, options, log_id).await {
+                Ok(snapshot) => snapshot,
+                Err(e) => {
+                    tracing::warn!(
+                        "could not bootstrap from bottomless, replicating from the primary: {e}"
+                    );
+                    return Ok(());
+                }
+            };
+        let Some(last_frame_no) = next_frame_no.checked_sub(1) else {
+            tracing::warn!("the bottomless backup has no frames, replicating from the primary");
+            return Ok(());
+        };
+        self.inject_snapshot(snapshot).await;
 
-        let _ = self.frames_sender.send(Frames::Snapshot(snap)).await;
+        // wait for the snapshot to be applied, so that replication continues after it
+        while self.current_frame_no() != Some(last_frame_no) {
+            self.current_frame_no_notifier.changed().await?;
+        }
+        tracing::info!("bootstrapped from bottomless up to frame {next_frame_no}");
 
         Ok(())
     }
@@ -261,3 +632,76 @@ impl Replicator {
         (current != FrameNo::MAX).then_some(current)
     }
 }
+
+/// Periodically asks the primary for its frame_no, so that the lag of the replica remains known
+/// while the primary doesn't receive any writes.
+async fn poll_primary_frame_no(
+    mut client: Client,
+    namespace: Bytes,
+    lag: Arc<ReplicationLag>,
+    applied: watch::Receiver<FrameNo>,
+    multiplex: Option<Arc<MultiplexedReplication>>,
+) -> anyhow::Result<()> {
+    let mut interval = tokio::time::interval(PRIMARY_POLL_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        // the multiplexed stream reports the frame_no of the primary
+        if multiplex.as_ref().map_or(false, |m| m.is_supported()) {
+            continue;
+        }
+        let asked_at = Instant::now();
+        let req = HelloRequest {
+            namespace: namespace.clone(),
+            supported_compressions: Vec::new(),
+        };
+        match client.hello(req).await {
+            Ok(resp) => {
+                if let Some(next_frame_no) = resp.into_inner().next_frame_no {
+                    lag.observe_primary(next_frame_no, asked_at, current_frame_no(&applied));
+                }
+            }
+            Err(e) => tracing::debug!("could not get the frame_no of the primary: {e}"),
+        }
+    }
+}
+
+/// Returns the id that identifies this replica of the database to the primary, whatever the
+/// connection it calls from. It is created the first time the database is replicated.
+fn replica_id(db_path: &Path) -> anyhow::Result<String> {
+    let path = db_path.join("replica_id");
+    match std::fs::read_to_string(&path) {
+        Ok(id) => Ok(id.trim().to_string()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let id = Uuid::new_v4().to_string();
+            std::fs::write(&path, &id)?;
+            Ok(id)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Confirms to the primary the frames that the replica applied, so that commits waiting for the
+/// replicas with semi-synchronous replication can complete.
+async fn ack_applied_frames(
+    mut client: Client,
+    namespace: Bytes,
+    replica_id: String,
+    mut applied: watch::Receiver<FrameNo>,
+) -> anyhow::Result<()> {
+    while applied.changed().await.is_ok() {
+        let Some(frame_no) = current_frame_no(&applied) else {
+            continue;
+        };
+        let req = AckRequest {
+            namespace: namespace.clone(),
+            frame_no,
+            replica_id: Some(replica_id.clone()),
+        };
+        if let Err(e) = client.ack(req).await {
+            tracing::debug!("could not acknowledge frames to the primary: {e}");
+        }
+    }
+
+    Ok(())
+}
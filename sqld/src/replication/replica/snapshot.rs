@@ -1,10 +1,21 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
 
+use bytes::BytesMut;
 use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
 use tempfile::NamedTempFile;
 use tokio::io::{AsyncWriteExt, BufWriter};
 
 use crate::replication::frame::{Frame, FrameBorrowed};
+use crate::replication::{frames_checksum, FrameNo};
+use crate::rpc::replication_log::rpc::SnapshotChunk;
+
+/// Number of frames received between two saves of the progress of a snapshot transfer.
+const PARTIAL_SNAPSHOT_SAVE_INTERVAL: u64 = 256;
+const PARTIAL_SNAPSHOT_FILE: &str = "snapshot.partial";
+const PARTIAL_SNAPSHOT_META_FILE: &str = "snapshot.partial.json";
 
 #[derive(Debug)]
 pub struct TempSnapshot {
@@ -37,6 +48,14 @@ impl TempSnapshot {
         Ok(Self { path, map })
     }
 
+    /// Takes ownership of a file of frames.
+    fn from_path(path: PathBuf) -> anyhow::Result<Self> {
+        let file = File::open(&path)?;
+        let map = unsafe { memmap::Mmap::map(&file)? };
+
+        Ok(Self { path, map })
+    }
+
     pub fn iter(&self) -> impl Iterator<Item = &FrameBorrowed> {
         self.map.chunks(Frame::SIZE).map(FrameBorrowed::from_bytes)
     }
@@ -48,3 +67,338 @@ impl Drop for TempSnapshot {
         let _ = std::fs::remove_file(path);
     }
 }
+
+/// A snapshot being transferred from the primary. The frames received so far are kept on disk,
+/// along with the progress of the transfer, so that an interrupted transfer resumes where it
+/// stopped, rather than from scratch.
+pub struct PartialSnapshot {
+    temp_dir: PathBuf,
+    file: File,
+    meta: PartialSnapshotMeta,
+    /// number of frames received since the progress was last saved
+    unsaved: u64,
+    /// whether the primary sends the snapshot without chunks, in which case the transfer can't be
+    /// resumed
+    legacy: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PartialSnapshotMeta {
+    /// frame_no the snapshot was requested from
+    next_offset: FrameNo,
+    /// id of the snapshot, unset until the first chunk is received
+    snapshot_id: Option<String>,
+    /// number of frames in the transfer
+    frame_count: u64,
+    /// number of frames received so far
+    received: u64,
+    /// checksum of the frames received so far, chained frame by frame
+    checksum: u64,
+}
+
+impl PartialSnapshot {
+    /// Opens the snapshot transfer that starts from `next_offset`, resuming the previous one if
+    /// it started from the same frame.
+    pub fn open(db_path: &Path, next_offset: FrameNo) -> anyhow::Result<Self> {
+        let temp_dir = db_path.join("temp");
+        std::fs::create_dir_all(&temp_dir)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .open(temp_dir.join(PARTIAL_SNAPSHOT_FILE))?;
+
+        let meta = std::fs::read(temp_dir.join(PARTIAL_SNAPSHOT_META_FILE))
+            .ok()
+            .and_then(|data| serde_json::from_slice::<PartialSnapshotMeta>(&data).ok())
+            .filter(|meta| {
+                meta.next_offset == next_offset
+                    && file
+                        .metadata()
+                        .map_or(false, |m| m.len() >= meta.received * Frame::SIZE as u64)
+            });
+
+        let mut this = Self {
+            temp_dir,
+            file,
+            meta: PartialSnapshotMeta::default(),
+            unsaved: 0,
+            legacy: false,
+        };
+        match meta {
+            Some(meta) => {
+                // drop the frames received after the progress was last saved
+                this.file.set_len(meta.received * Frame::SIZE as u64)?;
+                tracing::debug!(
+                    "resuming snapshot transfer from frame {}/{}",
+                    meta.received,
+                    meta.frame_count
+                );
+                this.meta = meta;
+            }
+            None => this.reset(next_offset, None, 0)?,
+        }
+
+        Ok(this)
+    }
+
+    /// The id of the snapshot being transferred, and the number of frames already received.
+    pub fn progress(&self) -> (Option<String>, u64) {
+        (self.meta.snapshot_id.clone(), self.meta.received)
+    }
+
+    fn reset(
+        &mut self,
+        next_offset: FrameNo,
+        snapshot_id: Option<String>,
+        frame_count: u64,
+    ) -> anyhow::Result<()> {
+        self.file.set_len(0)?;
+        self.meta = PartialSnapshotMeta {
+            next_offset,
+            snapshot_id,
+            frame_count,
+            received: 0,
+            checksum: 0,
+        };
+        self.unsaved = 0;
+        self.save()
+    }
+
+    /// Appends the frames of a message of the transfer, after checking that they are the ones
+    /// that follow the frames received so far.
+    pub fn append(
+        &mut self,
+        chunk: Option<&SnapshotChunk>,
+        frames: &[Frame],
+    ) -> anyhow::Result<()> {
+        match chunk {
+            Some(chunk) => {
+                // the primary restarts the transfer if the snapshot changed
+                if chunk.offset == 0
+                    && (self.meta.received != 0
+                        || self.meta.snapshot_id.as_ref() != Some(&chunk.snapshot_id))
+                {
+                    self.reset(
+                        self.meta.next_offset,
+                        Some(chunk.snapshot_id.clone()),
+                        chunk.frame_count,
+                    )?;
+                }
+                anyhow::ensure!(
+                    self.meta.snapshot_id.as_ref() == Some(&chunk.snapshot_id)
+                        && chunk.offset == self.meta.received,
+                    "unexpected snapshot chunk: expected frame {} of snapshot {:?}, got frame {} of snapshot {}",
+                    self.meta.received,
+                    self.meta.snapshot_id,
+                    chunk.offset,
+                    chunk.snapshot_id
+                );
+                anyhow::ensure!(
+                    frames_checksum(0, frames) == chunk.checksum,
+                    "invalid checksum for snapshot frames {}..{}",
+                    chunk.offset,
+                    chunk.offset + frames.len() as u64
+                );
+            }
+            None if !self.legacy => {
+                self.legacy = true;
+                self.reset(self.meta.next_offset, None, 0)?;
+            }
+            None => (),
+        }
+
+        for frame in frames {
+            self.file.write_all(frame.as_slice())?;
+            self.meta.checksum = frames_checksum(self.meta.checksum, [frame]);
+        }
+        self.meta.received += frames.len() as u64;
+        self.unsaved += frames.len() as u64;
+        if self.unsaved >= PARTIAL_SNAPSHOT_SAVE_INTERVAL {
+            self.save()?;
+        }
+
+        Ok(())
+    }
+
+    /// Saves the progress of the transfer, so that it can be resumed.
+    pub fn save(&mut self) -> anyhow::Result<()> {
+        // the frames must be on disk before the progress that accounts for them
+        self.file.sync_data()?;
+        let tmp_path = self
+            .temp_dir
+            .join(format!("{}.tmp", PARTIAL_SNAPSHOT_META_FILE));
+        std::fs::write(&tmp_path, serde_json::to_vec(&self.meta)?)?;
+        std::fs::rename(tmp_path, self.temp_dir.join(PARTIAL_SNAPSHOT_META_FILE))?;
+        self.unsaved = 0;
+
+        Ok(())
+    }
+
+    /// Verifies that all the frames of the snapshot were received intact, and returns the
+    /// snapshot, ready to be injected.
+    pub fn finish(mut self) -> anyhow::Result<TempSnapshot> {
+        if !self.legacy {
+            anyhow::ensure!(
+                self.meta.snapshot_id.is_some() && self.meta.received == self.meta.frame_count,
+                "incomplete snapshot transfer: received {} of {} frames",
+                self.meta.received,
+                self.meta.frame_count
+            );
+        }
+        if let Err(e) = self.verify() {
+            self.discard();
+            return Err(e);
+        }
+
+        let path = NamedTempFile::new_in(&self.temp_dir)?
+            .into_temp_path()
+            .keep()?;
+        std::fs::rename(self.temp_dir.join(PARTIAL_SNAPSHOT_FILE), &path)?;
+        let _ = std::fs::remove_file(self.temp_dir.join(PARTIAL_SNAPSHOT_META_FILE));
+
+        TempSnapshot::from_path(path)
+    }
+
+    /// Checks the frames on disk against the checksum of the frames that were received.
+    fn verify(&mut self) -> anyhow::Result<()> {
+        self.file.sync_data()?;
+        let mut file = File::open(self.temp_dir.join(PARTIAL_SNAPSHOT_FILE))?;
+        anyhow::ensure!(
+            file.metadata()?.len() == self.meta.received * Frame::SIZE as u64,
+            "invalid snapshot file size"
+        );
+
+        let mut checksum = 0;
+        for _ in 0..self.meta.received {
+            let mut buf = BytesMut::zeroed(Frame::SIZE);
+            file.read_exact(&mut buf)?;
+            let frame = Frame::try_from_bytes(buf.freeze())?;
+            checksum = frames_checksum(checksum, [&frame]);
+        }
+        anyhow::ensure!(
+            checksum == self.meta.checksum,
+            "snapshot file is corrupted, restarting the transfer"
+        );
+
+        Ok(())
+    }
+
+    /// Discards the transfer, so that the next one starts from scratch.
+    pub fn discard(self) {
+        let _ = std::fs::remove_file(self.temp_dir.join(PARTIAL_SNAPSHOT_FILE));
+        let _ = std::fs::remove_file(self.temp_dir.join(PARTIAL_SNAPSHOT_META_FILE));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::replication::frame::FrameHeader;
+
+    use super::*;
+
+    fn frames(frame_nos: impl Iterator<Item = FrameNo>) -> Vec<Frame> {
+        frame_nos
+            .map(|frame_no| {
+                let header = FrameHeader {
+                    frame_no,
+                    checksum: 0,
+                    page_no: frame_no as u32 + 1,
+                    size_after: 0,
+                };
+                Frame::from_parts(&header, &[frame_no as u8; 4096])
+            })
+            .collect()
+    }
+
+    fn chunk(snapshot_id: &str, offset: u64, frames: &[Frame]) -> SnapshotChunk {
+        SnapshotChunk {
+            snapshot_id: snapshot_id.into(),
+            offset,
+            frame_count: 10,
+            checksum: frames_checksum(0, frames),
+        }
+    }
+
+    #[test]
+    fn resume_transfer() {
+        let tmp = tempfile::tempdir().unwrap();
+        let all = frames((0..10).rev());
+
+        let mut partial = PartialSnapshot::open(tmp.path(), 0).unwrap();
+        assert_eq!(partial.progress(), (None, 0));
+        partial
+            .append(Some(&chunk("a", 0, &all[..4])), &all[..4])
+            .unwrap();
+        // out of order
+        assert!(partial
+            .append(Some(&chunk("a", 6, &all[6..])), &all[6..])
+            .is_err());
+        // corrupted
+        let mut bad = chunk("a", 4, &all[4..6]);
+        bad.checksum += 1;
+        assert!(partial.append(Some(&bad), &all[4..6]).is_err());
+        partial.save().unwrap();
+        assert!(partial.finish().is_err());
+
+        // the transfer resumes after the frames that were received
+        let mut partial = PartialSnapshot::open(tmp.path(), 0).unwrap();
+        assert_eq!(partial.progress(), (Some("a".into()), 4));
+        partial
+            .append(Some(&chunk("a", 4, &all[4..])), &all[4..])
+            .unwrap();
+        let snapshot = partial.finish().unwrap();
+        let received = snapshot
+            .iter()
+            .map(|f| f.header().frame_no)
+            .collect::<Vec<_>>();
+        assert_eq!(received, (0..10).rev().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn restart_transfer() {
+        let tmp = tempfile::tempdir().unwrap();
+        let all = frames((0..10).rev());
+
+        let mut partial = PartialSnapshot::open(tmp.path(), 0).unwrap();
+        partial
+            .append(Some(&chunk("a", 0, &all[..4])), &all[..4])
+            .unwrap();
+        partial.save().unwrap();
+
+        // a transfer from another frame doesn't resume
+        let partial = PartialSnapshot::open(tmp.path(), 5).unwrap();
+        assert_eq!(partial.progress(), (None, 0));
+
+        // the snapshot changed: the transfer starts over
+        let mut partial = PartialSnapshot::open(tmp.path(), 5).unwrap();
+        partial
+            .append(Some(&chunk("a", 0, &all[..4])), &all[..4])
+            .unwrap();
+        partial.append(Some(&chunk("b", 0, &all)), &all).unwrap();
+        assert_eq!(partial.progress(), (Some("b".into()), 10));
+        partial.finish().unwrap();
+    }
+
+    #[test]
+    fn detect_corrupted_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let all = frames((0..10).rev());
+
+        let mut partial = PartialSnapshot::open(tmp.path(), 0).unwrap();
+        partial.append(Some(&chunk("a", 0, &all)), &all).unwrap();
+        partial.save().unwrap();
+        drop(partial);
+
+        let path = tmp.path().join("temp").join(PARTIAL_SNAPSHOT_FILE);
+        let mut data = std::fs::read(&path).unwrap();
+        data[Frame::SIZE + 100] ^= 1;
+        std::fs::write(&path, data).unwrap();
+
+        let partial = PartialSnapshot::open(tmp.path(), 0).unwrap();
+        assert!(partial.finish().is_err());
+        // the corrupted transfer was discarded
+        let partial = PartialSnapshot::open(tmp.path(), 0).unwrap();
+        assert_eq!(partial.progress(), (None, 0));
+    }
+}
@@ -0,0 +1,195 @@
+//! Tracking of the tables modified by the transactions injected into a replica.
+//!
+//! The frames of a transaction are the pages it wrote, so the tables it modified are found by
+//! mapping its pages to the b-trees they belong to, with the `dbstat` virtual table. The map is
+//! kept between transactions. A transaction that allocates or frees pages, or changes the schema,
+//! writes the first page of the database, whose header keeps track of them. Since pages may then
+//! have moved from a b-tree to another, such a transaction is assumed to modify every table, and
+//! the map is rebuilt before it is used again.
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::Arc;
+
+use rusqlite::OpenFlags;
+
+use crate::connection::table_changes::TableChanges;
+
+use super::hook::Frames;
+
+/// The first page of the database, which holds its header and the root of the schema table.
+const HEADER_PAGE_NO: u32 = 1;
+
+pub struct TableTracker {
+    table_changes: Arc<TableChanges>,
+    /// The table that each page belongs to, by page number, or `None` if it must be rebuilt.
+    pages: Option<HashMap<u32, Arc<str>>>,
+    /// Set if the pages can't be mapped to tables, in which case every transaction is assumed to
+    /// modify every table.
+    disabled: bool,
+    /// The pages written by the transaction being injected.
+    written: HashSet<u32>,
+    /// Set if the transaction being injected is a snapshot.
+    snapshot: bool,
+}
+
+impl TableTracker {
+    pub fn new(table_changes: Arc<TableChanges>) -> Self {
+        Self {
+            table_changes,
+            pages: None,
+            disabled: false,
+            written: HashSet::new(),
+            snapshot: false,
+        }
+    }
+
+    /// Records the pages written by a batch of frames of the transaction being injected.
+    pub fn add_frames(&mut self, frames: &Frames) {
+        match frames {
+            Frames::Vec(frames) => self
+                .written
+                .extend(frames.iter().map(|frame| frame.header().page_no)),
+            Frames::Snapshot(_) => self.snapshot = true,
+        }
+    }
+
+    /// Records the tables modified by the transaction that was just injected into the database
+    /// at `db_path`.
+    pub fn commit(&mut self, db_path: &Path) {
+        let written = std::mem::take(&mut self.written);
+        let snapshot = std::mem::replace(&mut self.snapshot, false);
+        if self.disabled || snapshot || written.contains(&HEADER_PAGE_NO) {
+            self.pages = None;
+            self.table_changes.record([], true);
+            return;
+        }
+
+        let pages = match self.pages {
+            Some(ref pages) => pages,
+            None => match page_tables(db_path) {
+                Ok(pages) => self.pages.insert(pages),
+                Err(e) => {
+                    tracing::warn!("could not map the pages of the replica to tables, every transaction is assumed to modify every table: {e}");
+                    self.disabled = true;
+                    self.table_changes.record([], true);
+                    return;
+                }
+            },
+        };
+
+        let tables: Option<HashSet<Arc<str>>> = written
+            .iter()
+            .map(|page| pages.get(page).cloned())
+            .collect();
+        match tables {
+            Some(tables) => self
+                .table_changes
+                .record(tables.iter().map(|table| table.to_string()), false),
+            // a page that doesn't belong to any table or index, such as a page of the schema
+            None => {
+                self.pages = None;
+                self.table_changes.record([], true);
+            }
+        }
+    }
+}
+
+/// Maps each page of the tables and indexes of the database to the table it belongs to.
+fn page_tables(db_path: &Path) -> rusqlite::Result<HashMap<u32, Arc<str>>> {
+    let conn = rusqlite::Connection::open_with_flags(
+        db_path.join("data"),
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )?;
+    let mut stmt = conn.prepare(
+        "SELECT dbstat.pageno, sqlite_schema.tbl_name FROM dbstat
+        JOIN sqlite_schema ON dbstat.name = sqlite_schema.name",
+    )?;
+
+    let mut tables = HashMap::<String, Arc<str>>::new();
+    let mut pages = HashMap::new();
+    let mut rows = stmt.query(())?;
+    while let Some(row) = rows.next()? {
+        let table: String = row.get(1)?;
+        let table = tables
+            .entry(table.to_lowercase())
+            .or_insert_with_key(|table| table.as_str().into())
+            .clone();
+        pages.insert(row.get(0)?, table);
+    }
+
+    Ok(pages)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::replication::frame::{Frame, FrameHeader};
+    use crate::replication::WAL_PAGE_SIZE;
+
+    fn frames(pages: &[u32]) -> Frames {
+        Frames::Vec(
+            pages
+                .iter()
+                .map(|&page_no| {
+                    let header = FrameHeader {
+                        frame_no: 0,
+                        checksum: 0,
+                        page_no,
+                        size_after: 0,
+                    };
+                    Frame::from_parts(&header, &[0; WAL_PAGE_SIZE as usize])
+                })
+                .collect(),
+        )
+    }
+
+    fn root_page(conn: &rusqlite::Connection, table: &str) -> u32 {
+        conn.query_row(
+            "SELECT rootpage FROM sqlite_schema WHERE name = ?",
+            [table],
+            |row| row.get(0),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn maps_pages_to_tables() {
+        let tmp = tempfile::tempdir().unwrap();
+        let conn = rusqlite::Connection::open(tmp.path().join("data")).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE t1 (x); CREATE TABLE T2 (x); CREATE INDEX i2 ON t2 (x);
+            INSERT INTO t1 VALUES (1); INSERT INTO t2 VALUES (1);",
+        )
+        .unwrap();
+
+        let table_changes = Arc::new(TableChanges::default());
+        let mut tracker = TableTracker::new(table_changes.clone());
+        let deps = |table: &str| HashSet::from([table.to_string()]);
+
+        let seq = table_changes.current();
+        tracker.add_frames(&frames(&[root_page(&conn, "i2")]));
+        tracker.commit(tmp.path());
+        assert!(table_changes.changed_since(&deps("t2"), seq));
+        assert!(!table_changes.changed_since(&deps("t1"), seq));
+
+        // the header page changes when pages move between tables
+        let seq = table_changes.current();
+        tracker.add_frames(&frames(&[HEADER_PAGE_NO, root_page(&conn, "t2")]));
+        tracker.commit(tmp.path());
+        assert!(table_changes.changed_since(&deps("t1"), seq));
+        assert!(tracker.pages.is_none());
+
+        let seq = table_changes.current();
+        tracker.add_frames(&frames(&[root_page(&conn, "t1")]));
+        tracker.commit(tmp.path());
+        assert!(table_changes.changed_since(&deps("t1"), seq));
+        assert!(!table_changes.changed_since(&deps("t2"), seq));
+
+        // a page that isn't mapped to a table
+        let seq = table_changes.current();
+        tracker.add_frames(&frames(&[1000]));
+        tracker.commit(tmp.path());
+        assert!(table_changes.changed_since(&deps("t2"), seq));
+    }
+}
@@ -9,25 +9,67 @@ use std::str::FromStr;
 use std::sync::mpsc;
 use std::sync::Arc;
 use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
 
-use anyhow::Context;
+use anyhow::{bail, ensure, Context};
 use bytemuck::{bytes_of, pod_read_unaligned, Pod, Zeroable};
 use bytes::{Bytes, BytesMut};
 use crossbeam::channel::bounded;
 use once_cell::sync::Lazy;
 use regex::Regex;
+use serde::Serialize;
 use tempfile::NamedTempFile;
 use uuid::Uuid;
 
-use super::frame::Frame;
+use super::encryption::{FrameEncryption, KeyStatus, RecordLocation};
+use super::frame::{Frame, FrameHeader};
+use super::history::CommitHistory;
 use super::primary::logger::LogFile;
 use super::FrameNo;
 
 /// This is the ratio of the space required to store snapshot vs size of the actual database.
-/// When this ratio is exceeded, compaction is triggered.
+/// When this ratio is exceeded, compaction is triggered, unless the merge policy sets a maximum
+/// size.
 const SNAPHOT_SPACE_AMPLIFICATION_FACTOR: u64 = 2;
-/// The maximum amount of snapshot allowed before a compaction is required
-const MAX_SNAPSHOT_NUMBER: usize = 32;
+/// The maximum amount of snapshot allowed before a compaction is required, by default
+pub const MAX_SNAPSHOT_NUMBER: usize = 32;
+
+/// When the snapshots of a database are merged into one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MergePolicy {
+    /// Snapshots are merged when there are more than this many.
+    pub max_count: usize,
+    /// Snapshots are merged when they have at least this many frames in total. By default, when
+    /// they have `SNAPHOT_SPACE_AMPLIFICATION_FACTOR` times the pages of the database.
+    pub max_frames: Option<u64>,
+    /// Snapshots that end with a commit made less than this long ago are not merged, so that the
+    /// database can still be restored as of the commits since then.
+    pub min_age: Option<Duration>,
+}
+
+impl Default for MergePolicy {
+    fn default() -> Self {
+        Self {
+            max_count: MAX_SNAPSHOT_NUMBER,
+            max_frames: None,
+            min_age: None,
+        }
+    }
+}
+
+/// Returns the current merge policy of a database, which can change while the log is open.
+pub type MergePolicyFn = Arc<dyn Fn() -> MergePolicy + Send + Sync>;
+
+/// A snapshot file, as listed by the admin API.
+#[derive(Debug, Clone, Serialize)]
+pub struct SnapshotInfo {
+    pub name: String,
+    pub start_frame_no: FrameNo,
+    pub end_frame_no: FrameNo,
+    pub frame_count: u64,
+    /// Size of the file, in bytes.
+    pub size: u64,
+}
 
 #[derive(Debug, Copy, Clone, Zeroable, Pod, PartialEq, Eq)]
 #[repr(C)]
@@ -42,16 +84,18 @@ pub struct SnapshotFileHeader {
     pub frame_count: u64,
     /// safe of the database after applying the snapshot
     pub size_after: u32,
-    pub _pad: u32,
+    /// 1 if the frames are encrypted, 0 otherwise
+    pub encrypted: u32,
 }
 
 pub struct SnapshotFile {
     file: File,
     header: SnapshotFileHeader,
+    encryption: FrameEncryption,
 }
 
 /// returns (db_id, start_frame_no, end_frame_no) for the given snapshot name
-fn parse_snapshot_name(name: &str) -> Option<(Uuid, u64, u64)> {
+pub fn parse_snapshot_name(name: &str) -> Option<(Uuid, u64, u64)> {
     static SNAPSHOT_FILE_MATCHER: Lazy<Regex> = Lazy::new(|| {
         Regex::new(
             r#"(?x)
@@ -76,7 +120,7 @@ fn parse_snapshot_name(name: &str) -> Option<(Uuid, u64, u64)> {
     ))
 }
 
-fn snapshot_list(db_path: &Path) -> anyhow::Result<impl Iterator<Item = String>> {
+pub fn snapshot_list(db_path: &Path) -> anyhow::Result<impl Iterator<Item = String>> {
     let mut entries = std::fs::read_dir(snapshot_dir_path(db_path))?;
     Ok(std::iter::from_fn(move || {
         for entry in entries.by_ref() {
@@ -95,6 +139,7 @@ fn snapshot_list(db_path: &Path) -> anyhow::Result<impl Iterator<Item = String>>
 pub fn find_snapshot_file(
     db_path: &Path,
     frame_no: FrameNo,
+    encryption: &FrameEncryption,
 ) -> anyhow::Result<Option<SnapshotFile>> {
     let snapshot_dir_path = snapshot_dir_path(db_path);
     for name in snapshot_list(db_path)? {
@@ -103,7 +148,7 @@ pub fn find_snapshot_file(
         if (start_frame_no..=end_frame_no).contains(&frame_no) {
             let snapshot_path = snapshot_dir_path.join(&name);
             tracing::debug!("found snapshot for frame {frame_no} at {snapshot_path:?}");
-            let snapshot_file = SnapshotFile::open(&snapshot_path)?;
+            let snapshot_file = SnapshotFile::open(&snapshot_path, encryption.clone())?;
             return Ok(Some(snapshot_file));
         }
     }
@@ -111,32 +156,207 @@ pub fn find_snapshot_file(
     Ok(None)
 }
 
+/// Returns how the snapshots of the database relate to the current key: the status of the
+/// snapshot that is the furthest from it.
+pub fn snapshots_key_status(
+    db_path: &Path,
+    encryption: &FrameEncryption,
+) -> anyhow::Result<KeyStatus> {
+    let mut status = KeyStatus::Current;
+    if !snapshot_dir_path(db_path).exists() {
+        return Ok(status);
+    }
+
+    for name in snapshot_list(db_path)? {
+        if parse_snapshot_name(&name).is_none() {
+            continue;
+        }
+        let snapshot =
+            SnapshotFile::open(&snapshot_dir_path(db_path).join(name), encryption.clone())?;
+        status = status.max(snapshot.key_status()?);
+    }
+
+    Ok(status)
+}
+
+/// Encrypts the snapshots of the database that were written with the previous key again, with
+/// the current key, or in plaintext if encryption is disabled.
+pub fn reencrypt_snapshots(db_path: &Path, encryption: &FrameEncryption) -> anyhow::Result<()> {
+    for name in snapshot_list(db_path)? {
+        if parse_snapshot_name(&name).is_none() {
+            continue;
+        }
+        let path = snapshot_dir_path(db_path).join(&name);
+        let snapshot = SnapshotFile::open(&path, encryption.clone())?;
+        match snapshot.key_status()? {
+            KeyStatus::Current => (),
+            KeyStatus::Readable => snapshot.reencrypt(&path)?,
+            KeyStatus::Unknown => bail!("snapshot `{name}` is encrypted with an unknown key"),
+        }
+    }
+
+    Ok(())
+}
+
 impl SnapshotFile {
-    pub fn open(path: &Path) -> anyhow::Result<Self> {
+    pub fn open(path: &Path, encryption: FrameEncryption) -> anyhow::Result<Self> {
         let file = File::open(path)?;
         let mut header_buf = [0; size_of::<SnapshotFileHeader>()];
         file.read_exact_at(&mut header_buf, 0)?;
         let header: SnapshotFileHeader = pod_read_unaligned(&header_buf);
 
-        Ok(Self { file, header })
+        Ok(Self {
+            file,
+            header,
+            encryption,
+        })
+    }
+
+    pub fn header(&self) -> &SnapshotFileHeader {
+        &self.header
+    }
+
+    fn is_encrypted(&self) -> bool {
+        self.header.encrypted != 0
+    }
+
+    /// Returns how the frames of the snapshot relate to the current key.
+    pub fn key_status(&self) -> anyhow::Result<KeyStatus> {
+        let first_record = if self.header.frame_count > 0 {
+            Some(self.read_record(0)?)
+        } else {
+            None
+        };
+
+        Ok(self
+            .encryption
+            .key_status(self.is_encrypted(), first_record.as_deref()))
+    }
+
+    /// Writes the snapshot at `path` again, with its frames encrypted with the current key, or in
+    /// plaintext if encryption is disabled.
+    fn reencrypt(&self, path: &Path) -> anyhow::Result<()> {
+        let mut target = BufWriter::new(NamedTempFile::new_in(path.parent().unwrap())?);
+        let header = SnapshotFileHeader {
+            encrypted: self.encryption.is_enabled() as u32,
+            ..self.header
+        };
+        target.write_all(bytes_of(&header))?;
+        for index in 0..self.header.frame_count {
+            let location = self.record_location(index);
+            let frame =
+                self.encryption
+                    .decode(self.read_record(index)?, self.is_encrypted(), location)?;
+            target.write_all(&self.encryption.encode(
+                &frame,
+                header.encrypted != 0,
+                location,
+            )?)?;
+        }
+        target.flush()?;
+        let file = target.into_inner()?;
+        file.as_file().sync_all()?;
+        file.persist(path)?;
+
+        Ok(())
+    }
+
+    /// Returns the location of the record at `index`, that is authenticated along with its frame
+    /// when it is encrypted.
+    fn record_location(&self, index: u64) -> RecordLocation {
+        RecordLocation::Snapshot {
+            db_id: self.header.db_id,
+            index,
+        }
+    }
+
+    /// Reads the record of the frame at `index`, as it is stored in the file.
+    fn read_record(&self, index: u64) -> anyhow::Result<Bytes> {
+        let record_size = FrameEncryption::record_size(self.is_encrypted());
+        let read_offset = size_of::<SnapshotFileHeader>() as u64 + index * record_size as u64;
+        let mut buf = BytesMut::zeroed(record_size);
+        self.file.read_exact_at(&mut buf, read_offset)?;
+
+        Ok(buf.freeze())
+    }
+
+    /// Identifies the snapshot: snapshots with the same id contain the same frames.
+    pub fn id(&self) -> String {
+        format!(
+            "{}-{}-{}-{}",
+            Uuid::from_u128(self.header.db_id),
+            self.header.start_frame_no,
+            self.header.end_frame_no,
+            self.header.frame_count
+        )
+    }
+
+    /// Returns the number of frames with a frame_no greater or equal to `frame_no`, that is, the
+    /// number of frames returned by `frames_iter_from(frame_no)`.
+    pub fn frame_count_from(&self, frame_no: FrameNo) -> anyhow::Result<u64> {
+        // frames are sorted by decreasing frame_no
+        let (mut low, mut high) = (0, self.header.frame_count);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if self.frame_header(mid)?.frame_no >= frame_no {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+
+        Ok(low)
+    }
+
+    fn frame_header(&self, index: u64) -> anyhow::Result<FrameHeader> {
+        if self.is_encrypted() {
+            // the header can only be read along with the rest of the frame
+            let frame = Frame::try_from_bytes(self.frame_at(index)?)?;
+            return Ok(*frame.header());
+        }
+
+        let mut buf = [0; size_of::<FrameHeader>()];
+        let read_offset =
+            size_of::<SnapshotFileHeader>() as u64 + index * LogFile::FRAME_SIZE as u64;
+        self.file.read_exact_at(&mut buf, read_offset)?;
+
+        Ok(pod_read_unaligned(&buf))
     }
 
     /// Iterator on the frames contained in the snapshot file, in reverse frame_no order.
     pub fn frames_iter(&self) -> impl Iterator<Item = anyhow::Result<Bytes>> + '_ {
-        let mut current_offset = 0;
-        std::iter::from_fn(move || {
-            if current_offset >= self.header.frame_count {
-                return None;
-            }
-            let read_offset = size_of::<SnapshotFileHeader>() as u64
-                + current_offset * LogFile::FRAME_SIZE as u64;
-            current_offset += 1;
-            let mut buf = BytesMut::zeroed(LogFile::FRAME_SIZE);
-            match self.file.read_exact_at(&mut buf, read_offset as _) {
-                Ok(_) => Some(Ok(buf.freeze())),
-                Err(e) => Some(Err(e.into())),
+        self.frames_iter_at(0)
+    }
+
+    /// Like `frames_iter`, but skips the first `index` frames.
+    pub fn frames_iter_at(&self, index: u64) -> impl Iterator<Item = anyhow::Result<Bytes>> + '_ {
+        (index..self.header.frame_count).map(|index| self.frame_at(index))
+    }
+
+    /// Reads the frame at `index`, in the order of `frames_iter`.
+    pub fn frame_at(&self, index: u64) -> anyhow::Result<Bytes> {
+        let record = self.read_record(index)?;
+        let frame =
+            self.encryption
+                .decode(record, self.is_encrypted(), self.record_location(index))?;
+
+        Ok(frame.bytes())
+    }
+
+    /// Returns the indexes of the frames of `pages`, among the first `count` frames.
+    pub fn frame_indexes_of_pages(
+        &self,
+        count: u64,
+        pages: &HashSet<u32>,
+    ) -> anyhow::Result<Vec<u64>> {
+        let mut indexes = Vec::new();
+        for index in 0..count.min(self.header.frame_count) {
+            if pages.contains(&self.frame_header(index)?.page_no) {
+                indexes.push(index);
             }
-        })
+        }
+
+        Ok(indexes)
     }
 
     /// Like `frames_iter`, but stops as soon as a frame with frame_no <= `frame_no` is reached
@@ -163,89 +383,224 @@ impl SnapshotFile {
 
 #[derive(Clone)]
 pub struct LogCompactor {
-    sender: crossbeam::channel::Sender<(LogFile, PathBuf, u32)>,
+    sender: crossbeam::channel::Sender<CompactorTask>,
+    encryption: FrameEncryption,
+}
+
+enum CompactorTask {
+    /// Compact the log file at the given path into a snapshot.
+    Compact {
+        file: LogFile,
+        log_path: PathBuf,
+        size_after: u32,
+    },
+    /// Register a snapshot that was already written to the snapshot directory.
+    Register {
+        snapshot_name: String,
+        frame_count: u64,
+        size_after: u32,
+    },
+    /// Merge the snapshots that the merge policy allows to merge, whatever their number and size.
+    Merge {
+        reply: mpsc::Sender<anyhow::Result<Option<String>>>,
+    },
 }
 
 pub type SnapshotCallback = Box<dyn Fn(&Path) -> anyhow::Result<()> + Send + Sync>;
-pub type NamespacedSnapshotCallback =
-    Arc<dyn Fn(&Path, &Bytes) -> anyhow::Result<()> + Send + Sync>;
 
 impl LogCompactor {
-    pub fn new(db_path: &Path, db_id: u128, callback: SnapshotCallback) -> anyhow::Result<Self> {
+    pub fn new(
+        db_path: &Path,
+        db_id: u128,
+        callback: SnapshotCallback,
+        encryption: FrameEncryption,
+        history: Arc<CommitHistory>,
+        merge_policy: MergePolicyFn,
+    ) -> anyhow::Result<Self> {
         // we create a 0 sized channel, in order to create backpressure when we can't
         // keep up with snapshop creation: if there isn't any ongoind comptaction task processing,
         // the compact does not block, and the log is compacted in the background. Otherwise, the
         // block until there is a free slot to perform compaction.
-        let (sender, receiver) = bounded::<(LogFile, PathBuf, u32)>(0);
-        let mut merger = SnapshotMerger::new(db_path, db_id)?;
+        let (sender, receiver) = bounded::<CompactorTask>(0);
+        let mut merger =
+            SnapshotMerger::new(db_path, db_id, encryption.clone(), history, merge_policy)?;
         let db_path = db_path.to_path_buf();
         let snapshot_dir_path = snapshot_dir_path(&db_path);
         let _handle = std::thread::spawn(move || {
-            while let Ok((file, log_path, size_after)) = receiver.recv() {
-                match perform_compaction(&db_path, file, db_id) {
-                    Ok((snapshot_name, snapshot_frame_count)) => {
-                        tracing::info!("snapshot `{snapshot_name}` successfully created");
-
-                        let snapshot_file = snapshot_dir_path.join(&snapshot_name);
-                        if let Err(e) = (*callback)(&snapshot_file) {
-                            tracing::error!("failed to call snapshot callback: {e}");
-                            break;
+            while let Ok(task) = receiver.recv() {
+                let (snapshot_name, snapshot_frame_count, size_after, log_path) = match task {
+                    CompactorTask::Compact {
+                        file,
+                        log_path,
+                        size_after,
+                    } => match perform_compaction(&db_path, file, db_id, &encryption) {
+                        Ok((snapshot_name, snapshot_frame_count)) => {
+                            tracing::info!("snapshot `{snapshot_name}` successfully created");
+                            (
+                                snapshot_name,
+                                snapshot_frame_count,
+                                size_after,
+                                Some(log_path),
+                            )
                         }
-
-                        if let Err(e) = merger.register_snapshot(
-                            snapshot_name,
-                            snapshot_frame_count,
-                            size_after,
-                        ) {
-                            tracing::error!(
-                                "failed to register snapshot with snapshot merger: {e}"
-                            );
+                        Err(e) => {
+                            tracing::error!("fatal error creating snapshot: {e}");
                             break;
                         }
-
-                        if let Err(e) = std::fs::remove_file(&log_path) {
-                            tracing::error!(
-                                "failed to remove old log file `{}`: {e}",
-                                log_path.display()
-                            );
+                    },
+                    CompactorTask::Register {
+                        snapshot_name,
+                        frame_count,
+                        size_after,
+                    } => (snapshot_name, frame_count, size_after, None),
+                    CompactorTask::Merge { reply } => {
+                        if let Err(e) = merger.merge(reply) {
+                            tracing::error!("failed to merge snapshots: {e}");
                             break;
                         }
+                        continue;
                     }
-                    Err(e) => {
-                        tracing::error!("fatal error creating snapshot: {e}");
+                };
+
+                let snapshot_file = snapshot_dir_path.join(&snapshot_name);
+                if let Err(e) = (*callback)(&snapshot_file) {
+                    tracing::error!("failed to call snapshot callback: {e}");
+                    break;
+                }
+
+                if let Err(e) =
+                    merger.register_snapshot(snapshot_name, snapshot_frame_count, size_after)
+                {
+                    tracing::error!("failed to register snapshot with snapshot merger: {e}");
+                    break;
+                }
+
+                if let Some(log_path) = log_path {
+                    if let Err(e) = std::fs::remove_file(&log_path) {
+                        tracing::error!(
+                            "failed to remove old log file `{}`: {e}",
+                            log_path.display()
+                        );
                         break;
                     }
                 }
             }
         });
 
-        Ok(Self { sender })
+        Ok(Self { sender, encryption })
     }
 
     /// Sends a compaction task to the background compaction thread. Blocks if a compaction task is
     /// already ongoing.
     pub fn compact(&self, file: LogFile, path: PathBuf, size_after: u32) -> anyhow::Result<()> {
         self.sender
-            .send((file, path, size_after))
+            .send(CompactorTask::Compact {
+                file,
+                log_path: path,
+                size_after,
+            })
             .context("failed to compact log: log compactor thread exited")?;
 
         Ok(())
     }
+
+    /// Merges the snapshots that the merge policy allows to merge, whatever their number and
+    /// size, and returns the name of the merged snapshot, unless there was nothing to merge.
+    /// Blocks until the ongoing compaction and the merge are done.
+    pub fn merge(&self) -> anyhow::Result<Option<String>> {
+        let (reply, receiver) = mpsc::channel();
+        self.sender
+            .send(CompactorTask::Merge { reply })
+            .context("failed to merge snapshots: log compactor thread exited")?;
+
+        receiver
+            .recv()
+            .context("failed to merge snapshots: snapshot merger thread exited")?
+    }
+
+    /// Writes the frames of a snapshot received from upstream to the snapshot directory, and
+    /// registers it as if it was the result of a compaction. The snapshot is recorded as starting
+    /// at `start_frame_no`, so that it covers the frames between the end of the log and the
+    /// snapshot. Frames must be in decreasing frame_no order.
+    ///
+    /// Returns the last frame in the snapshot.
+    pub fn import_snapshot(
+        &self,
+        db_path: &Path,
+        db_id: u128,
+        start_frame_no: FrameNo,
+        frames: impl Iterator<Item = anyhow::Result<Frame>>,
+    ) -> anyhow::Result<FrameHeader> {
+        let mut builder = SnapshotBuilder::new(db_path, db_id, &self.encryption)?;
+        let mut last_frame = None;
+        let mut prev_frame_no = FrameNo::MAX;
+        builder.append_frames(frames.map(|frame| {
+            let frame = frame?;
+            let header = *frame.header();
+            ensure!(
+                header.frame_no < prev_frame_no,
+                "snapshot frames are not in decreasing frame_no order"
+            );
+            prev_frame_no = header.frame_no;
+            last_frame.get_or_insert(header);
+            Ok(frame)
+        }))?;
+        let Some(last_frame) = last_frame else {
+            bail!("cannot import an empty snapshot")
+        };
+        ensure!(
+            last_frame.frame_no >= start_frame_no,
+            "snapshot ends before the log"
+        );
+        builder.header.start_frame_no = start_frame_no;
+        let (snapshot_name, frame_count) = builder.finish()?;
+
+        self.sender
+            .send(CompactorTask::Register {
+                snapshot_name,
+                frame_count,
+                size_after: last_frame.size_after,
+            })
+            .context("failed to register snapshot: log compactor thread exited")?;
+
+        Ok(last_frame)
+    }
+}
+
+enum MergerTask {
+    /// A new snapshot, with its frame count and the page count of the database after it.
+    Register(String, u64, u32),
+    /// Merge the snapshots that can be merged now, and reply with the name of the merged snapshot.
+    Merge(mpsc::Sender<anyhow::Result<Option<String>>>),
 }
 
 struct SnapshotMerger {
-    /// Sending part of a channel of (snapshot_name, snapshot_frame_count, db_page_count) to the merger thread
-    sender: mpsc::Sender<(String, u64, u32)>,
+    /// Sending part of a channel of tasks to the merger thread
+    sender: mpsc::Sender<MergerTask>,
     handle: Option<JoinHandle<anyhow::Result<()>>>,
 }
 
 impl SnapshotMerger {
-    fn new(db_path: &Path, db_id: u128) -> anyhow::Result<Self> {
+    fn new(
+        db_path: &Path,
+        db_id: u128,
+        encryption: FrameEncryption,
+        history: Arc<CommitHistory>,
+        merge_policy: MergePolicyFn,
+    ) -> anyhow::Result<Self> {
         let (sender, receiver) = mpsc::channel();
 
         let db_path = db_path.to_path_buf();
-        let handle =
-            std::thread::spawn(move || Self::run_snapshot_merger_loop(receiver, &db_path, db_id));
+        let handle = std::thread::spawn(move || {
+            Self::run_snapshot_merger_loop(
+                receiver,
+                &db_path,
+                db_id,
+                &encryption,
+                &history,
+                &*merge_policy,
+            )
+        });
 
         Ok(Self {
             sender,
@@ -253,37 +608,129 @@ impl SnapshotMerger {
         })
     }
 
-    fn should_compact(snapshots: &[(String, u64)], db_page_count: u32) -> bool {
+    fn should_compact(
+        snapshots: &[(String, u64)],
+        db_page_count: u32,
+        policy: &MergePolicy,
+    ) -> bool {
         let snapshots_size: u64 = snapshots.iter().map(|(_, s)| *s).sum();
-        snapshots_size >= SNAPHOT_SPACE_AMPLIFICATION_FACTOR * db_page_count as u64
-            || snapshots.len() > MAX_SNAPSHOT_NUMBER
+        let max_frames = policy
+            .max_frames
+            .unwrap_or(SNAPHOT_SPACE_AMPLIFICATION_FACTOR * db_page_count as u64);
+        snapshots_size >= max_frames || snapshots.len() > policy.max_count
+    }
+
+    /// Returns the number of snapshots, from the oldest, that can be merged: all of them, unless
+    /// the merge policy has a minimum age, in which case the snapshots ending with a commit made
+    /// more recently are kept as they are.
+    fn mergeable_snapshot_count(
+        snapshots: &[(String, u64)],
+        history: &CommitHistory,
+        min_age: Option<Duration>,
+    ) -> anyhow::Result<usize> {
+        let Some(min_age) = min_age else {
+            return Ok(snapshots.len());
+        };
+        let commits = history.commits()?;
+        let now = SystemTime::now();
+
+        Ok(snapshots
+            .iter()
+            .take_while(|(name, _)| {
+                let (_, _, end_frame_no) = parse_snapshot_name(name).unwrap();
+                match commits
+                    .iter()
+                    .find(|(frame_no, _)| *frame_no >= end_frame_no)
+                {
+                    Some((_, time)) => now
+                        .duration_since(*time)
+                        .map_or(false, |age| age >= min_age),
+                    // the time of the commit is unknown, the snapshot can't be restored by time
+                    None => true,
+                }
+            })
+            .count())
     }
 
     fn run_snapshot_merger_loop(
-        receiver: mpsc::Receiver<(String, u64, u32)>,
+        receiver: mpsc::Receiver<MergerTask>,
         db_path: &Path,
         db_id: u128,
+        encryption: &FrameEncryption,
+        history: &CommitHistory,
+        merge_policy: &(dyn Fn() -> MergePolicy + Send + Sync),
     ) -> anyhow::Result<()> {
-        let mut snapshots = Self::init_snapshot_info_list(db_path)?;
-        while let Ok((name, size, db_page_count)) = receiver.recv() {
-            snapshots.push((name, size));
-            if Self::should_compact(&snapshots, db_page_count) {
-                let compacted_snapshot_info = Self::merge_snapshots(&snapshots, db_path, db_id)?;
-                snapshots.clear();
-                snapshots.push(compacted_snapshot_info);
+        let mut snapshots = Self::init_snapshot_info_list(db_path, encryption)?;
+        while let Ok(task) = receiver.recv() {
+            let policy = merge_policy();
+            match task {
+                MergerTask::Register(name, size, db_page_count) => {
+                    snapshots.push((name, size));
+                    if Self::should_compact(&snapshots, db_page_count, &policy) {
+                        Self::merge_mergeable_snapshots(
+                            &mut snapshots,
+                            db_path,
+                            db_id,
+                            encryption,
+                            history,
+                            &policy,
+                        )?;
+                    }
+                }
+                MergerTask::Merge(reply) => {
+                    let res = Self::merge_mergeable_snapshots(
+                        &mut snapshots,
+                        db_path,
+                        db_id,
+                        encryption,
+                        history,
+                        &policy,
+                    );
+                    let _ = reply.send(res);
+                }
             }
         }
 
         Ok(())
     }
 
+    /// Merges the snapshots that are old enough for `policy`, if there are at least two of them,
+    /// and returns the name of the merged snapshot.
+    fn merge_mergeable_snapshots(
+        snapshots: &mut Vec<(String, u64)>,
+        db_path: &Path,
+        db_id: u128,
+        encryption: &FrameEncryption,
+        history: &CommitHistory,
+        policy: &MergePolicy,
+    ) -> anyhow::Result<Option<String>> {
+        let mergeable = Self::mergeable_snapshot_count(snapshots, history, policy.min_age)?;
+        if mergeable < 2 {
+            return Ok(None);
+        }
+        let compacted_snapshot_info =
+            Self::merge_snapshots(&snapshots[..mergeable], db_path, db_id, encryption)?;
+        let name = compacted_snapshot_info.0.clone();
+        // the database can't be restored as of the commits inside the merged snapshot
+        let (_, _, end_frame_no) = parse_snapshot_name(&name).unwrap();
+        if let Err(e) = history.truncate_before(end_frame_no) {
+            tracing::warn!("failed to truncate the commit history: {e}");
+        }
+        snapshots.splice(..mergeable, [compacted_snapshot_info]);
+
+        Ok(Some(name))
+    }
+
     /// Reads the snapshot dir and returns the list of snapshots along with their size, sorted in
     /// chronological order.
     ///
     /// TODO: if the process was kill in the midst of merging snapshot, then the compacted snapshot
     /// can exist alongside the snapshots it's supposed to have compacted. This is the place to
     /// perform the cleanup.
-    fn init_snapshot_info_list(db_path: &Path) -> anyhow::Result<Vec<(String, u64)>> {
+    fn init_snapshot_info_list(
+        db_path: &Path,
+        encryption: &FrameEncryption,
+    ) -> anyhow::Result<Vec<(String, u64)>> {
         let snapshot_dir_path = snapshot_dir_path(db_path);
         if !snapshot_dir_path.exists() {
             return Ok(Vec::new());
@@ -292,7 +739,7 @@ impl SnapshotMerger {
         let mut temp = Vec::new();
         for snapshot_name in snapshot_list(db_path)? {
             let snapshot_path = snapshot_dir_path.join(&snapshot_name);
-            let snapshot = SnapshotFile::open(&snapshot_path)?;
+            let snapshot = SnapshotFile::open(&snapshot_path, encryption.clone())?;
             temp.push((
                 snapshot_name,
                 snapshot.header.frame_count,
@@ -312,11 +759,12 @@ impl SnapshotMerger {
         snapshots: &[(String, u64)],
         db_path: &Path,
         db_id: u128,
+        encryption: &FrameEncryption,
     ) -> anyhow::Result<(String, u64)> {
-        let mut builder = SnapshotBuilder::new(db_path, db_id)?;
+        let mut builder = SnapshotBuilder::new(db_path, db_id, encryption)?;
         let snapshot_dir_path = snapshot_dir_path(db_path);
         for (name, _) in snapshots.iter().rev() {
-            let snapshot = SnapshotFile::open(&snapshot_dir_path.join(name))?;
+            let snapshot = SnapshotFile::open(&snapshot_dir_path.join(name), encryption.clone())?;
             let iter = snapshot.frames_iter().map(|b| Frame::try_from_bytes(b?));
             builder.append_frames(iter)?;
         }
@@ -342,18 +790,29 @@ impl SnapshotMerger {
         snapshot_frame_count: u64,
         db_page_count: u32,
     ) -> anyhow::Result<()> {
-        if self
-            .sender
-            .send((snapshot_name, snapshot_frame_count, db_page_count))
-            .is_err()
-        {
+        self.send(MergerTask::Register(
+            snapshot_name,
+            snapshot_frame_count,
+            db_page_count,
+        ))
+        .context("failed to register snapshot with log merger")
+    }
+
+    /// Asks the merger thread to merge the snapshots that can be merged, and to reply to `reply`.
+    fn merge(&mut self, reply: mpsc::Sender<anyhow::Result<Option<String>>>) -> anyhow::Result<()> {
+        self.send(MergerTask::Merge(reply))
+            .context("failed to merge snapshots")
+    }
+
+    fn send(&mut self, task: MergerTask) -> anyhow::Result<()> {
+        if self.sender.send(task).is_err() {
             if let Some(handle) = self.handle.take() {
                 handle
                     .join()
                     .map_err(|_| anyhow::anyhow!("snapshot merger thread panicked"))??;
             }
 
-            anyhow::bail!("failed to register snapshot with log merger: thread exited");
+            anyhow::bail!("snapshot merger thread exited");
         }
 
         Ok(())
@@ -367,14 +826,45 @@ struct SnapshotBuilder {
     snapshot_file: BufWriter<NamedTempFile>,
     db_path: PathBuf,
     last_seen_frame_no: u64,
+    encryption: FrameEncryption,
 }
 
-fn snapshot_dir_path(db_path: &Path) -> PathBuf {
+pub fn snapshot_dir_path(db_path: &Path) -> PathBuf {
     db_path.join("snapshots")
 }
 
+/// Returns the snapshots of the database at `db_path`, sorted by start frame_no.
+pub fn list_snapshots(
+    db_path: &Path,
+    encryption: &FrameEncryption,
+) -> anyhow::Result<Vec<SnapshotInfo>> {
+    let snapshot_dir_path = snapshot_dir_path(db_path);
+    if !snapshot_dir_path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut snapshots = Vec::new();
+    for name in snapshot_list(db_path)? {
+        if parse_snapshot_name(&name).is_none() {
+            continue;
+        }
+        let path = snapshot_dir_path.join(&name);
+        let snapshot = SnapshotFile::open(&path, encryption.clone())?;
+        snapshots.push(SnapshotInfo {
+            name,
+            start_frame_no: snapshot.header.start_frame_no,
+            end_frame_no: snapshot.header.end_frame_no,
+            frame_count: snapshot.header.frame_count,
+            size: snapshot.file.metadata()?.len(),
+        });
+    }
+    snapshots.sort_by_key(|snapshot| snapshot.start_frame_no);
+
+    Ok(snapshots)
+}
+
 impl SnapshotBuilder {
-    fn new(db_path: &Path, db_id: u128) -> anyhow::Result<Self> {
+    fn new(db_path: &Path, db_id: u128, encryption: &FrameEncryption) -> anyhow::Result<Self> {
         let snapshot_dir_path = snapshot_dir_path(db_path);
         std::fs::create_dir_all(&snapshot_dir_path)?;
         let mut target = BufWriter::new(NamedTempFile::new_in(&snapshot_dir_path)?);
@@ -389,11 +879,12 @@ impl SnapshotBuilder {
                 end_frame_no: u64::MIN,
                 frame_count: 0,
                 size_after: 0,
-                _pad: 0,
+                encrypted: encryption.is_enabled() as u32,
             },
             snapshot_file: target,
             db_path: db_path.to_path_buf(),
             last_seen_frame_no: u64::MAX,
+            encryption: encryption.clone(),
         })
     }
 
@@ -423,7 +914,14 @@ impl SnapshotBuilder {
 
             if !self.seen_pages.contains(&frame.header().page_no) {
                 self.seen_pages.insert(frame.header().page_no);
-                self.snapshot_file.write_all(frame.as_slice())?;
+                let location = RecordLocation::Snapshot {
+                    db_id: self.header.db_id,
+                    index: self.header.frame_count,
+                };
+                let record =
+                    self.encryption
+                        .encode(&frame, self.header.encrypted != 0, location)?;
+                self.snapshot_file.write_all(&record)?;
                 self.header.frame_count += 1;
             }
         }
@@ -453,8 +951,9 @@ fn perform_compaction(
     db_path: &Path,
     file_to_compact: LogFile,
     db_id: u128,
+    encryption: &FrameEncryption,
 ) -> anyhow::Result<(String, u64)> {
-    let mut builder = SnapshotBuilder::new(db_path, db_id)?;
+    let mut builder = SnapshotBuilder::new(db_path, db_id, encryption)?;
     builder.append_frames(file_to_compact.rev_frames_iter()?)?;
     builder.finish()
 }
@@ -468,6 +967,7 @@ mod test {
     use bytes::Bytes;
     use tempfile::tempdir;
 
+    use crate::replication::encryption::{FrameCipher, KEY_SIZE};
     use crate::replication::frame::FrameHeader;
     use crate::replication::primary::logger::WalPage;
     use crate::replication::snapshot::SnapshotFile;
@@ -477,7 +977,13 @@ mod test {
     #[test]
     fn compact_file_create_snapshot() {
         let temp = tempfile::NamedTempFile::new().unwrap();
-        let mut log_file = LogFile::new(temp.as_file().try_clone().unwrap(), 0, None).unwrap();
+        let mut log_file = LogFile::new(
+            temp.as_file().try_clone().unwrap(),
+            0,
+            None,
+            FrameEncryption::default(),
+        )
+        .unwrap();
         let db_id = Uuid::new_v4();
         log_file.header.db_id = db_id.as_u128();
         log_file.write_header().unwrap();
@@ -498,8 +1004,15 @@ mod test {
         log_file.commit().unwrap();
 
         let dump_dir = tempdir().unwrap();
-        let compactor =
-            LogCompactor::new(dump_dir.path(), db_id.as_u128(), Box::new(|_| Ok(()))).unwrap();
+        let compactor = LogCompactor::new(
+            dump_dir.path(),
+            db_id.as_u128(),
+            Box::new(|_| Ok(())),
+            FrameEncryption::default(),
+            Arc::new(CommitHistory::open(dump_dir.path()).unwrap()),
+            Arc::new(MergePolicy::default),
+        )
+        .unwrap();
         compactor
             .compact(log_file, temp.path().to_path_buf(), 25)
             .unwrap();
@@ -533,7 +1046,7 @@ mod test {
         assert_eq!(seen_frames.len(), 25);
         assert_eq!(seen_page_no.len(), 25);
 
-        let snapshot_file = SnapshotFile::open(&snapshot_path).unwrap();
+        let snapshot_file = SnapshotFile::open(&snapshot_path, FrameEncryption::default()).unwrap();
 
         let frames = snapshot_file.frames_iter_from(0);
         let mut expected_frame_no = 49;
@@ -545,5 +1058,157 @@ mod test {
         }
 
         assert_eq!(expected_frame_no, 24);
+
+        // frames 30 to 49
+        assert_eq!(snapshot_file.frame_count_from(30).unwrap(), 20);
+        assert_eq!(snapshot_file.frame_count_from(0).unwrap(), 25);
+        assert_eq!(snapshot_file.frame_count_from(50).unwrap(), 0);
+
+        let frames = snapshot_file
+            .frames_iter_at(5)
+            .map(|f| Frame::try_from_bytes(f.unwrap()).unwrap().header().frame_no)
+            .collect::<Vec<_>>();
+        assert_eq!(frames, (25..=44).rev().collect::<Vec<_>>());
+        assert_eq!(snapshot_file.id(), format!("{db_id}-0-49-25"));
+
+        // frame 49 - index has page 24 - index
+        let pages = HashSet::from([24, 20, 3]);
+        assert_eq!(
+            snapshot_file.frame_indexes_of_pages(25, &pages).unwrap(),
+            vec![0, 4, 21]
+        );
+        assert_eq!(
+            snapshot_file.frame_indexes_of_pages(5, &pages).unwrap(),
+            vec![0, 4]
+        );
+        let frame = Frame::try_from_bytes(snapshot_file.frame_at(4).unwrap()).unwrap();
+        assert_eq!(frame.header().page_no, 20);
+    }
+
+    #[test]
+    fn compact_encrypted_log() {
+        let current = FrameEncryption::new(FrameCipher::new(&[1; KEY_SIZE]));
+        let temp = tempfile::NamedTempFile::new().unwrap();
+        let mut log_file = LogFile::new(temp.reopen().unwrap(), 0, None, current.clone()).unwrap();
+        let db_id = Uuid::new_v4();
+        for i in 0..10 {
+            let page = WalPage {
+                page_no: i % 5,
+                size_after: 5,
+                data: std::iter::repeat(42).take(4096).collect(),
+            };
+            log_file.push_page(&page).unwrap();
+        }
+        log_file.commit().unwrap();
+
+        let dump_dir = tempdir().unwrap();
+        let compactor = LogCompactor::new(
+            dump_dir.path(),
+            db_id.as_u128(),
+            Box::new(|_| Ok(())),
+            current.clone(),
+            Arc::new(CommitHistory::open(dump_dir.path()).unwrap()),
+            Arc::new(MergePolicy::default),
+        )
+        .unwrap();
+        compactor
+            .compact(log_file, temp.path().to_path_buf(), 5)
+            .unwrap();
+
+        thread::sleep(Duration::from_secs(1));
+
+        let snapshot_path =
+            snapshot_dir_path(dump_dir.path()).join(format!("{}-{}-{}.snap", db_id, 0, 9));
+        let content = read(&snapshot_path).unwrap();
+        assert!(!content.windows(64).any(|w| w.iter().all(|b| *b == 42)));
+
+        let snapshot_file = SnapshotFile::open(&snapshot_path, current.clone()).unwrap();
+        let frames = snapshot_file
+            .frames_iter()
+            .map(|f| Frame::try_from_bytes(f.unwrap()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            frames
+                .iter()
+                .map(|f| f.header().frame_no)
+                .collect::<Vec<_>>(),
+            (5..10).rev().collect::<Vec<_>>()
+        );
+        assert!(frames.iter().all(|f| f.page().iter().all(|b| *b == 42)));
+        assert_eq!(snapshot_file.frame_count_from(7).unwrap(), 3);
+
+        // after a rotation, the snapshots can only be read with the previous key
+        let rotated = FrameEncryption::new(FrameCipher::new(&[2; KEY_SIZE]));
+        let status = |encryption| snapshots_key_status(dump_dir.path(), encryption).unwrap();
+        assert_eq!(status(&current), KeyStatus::Current);
+        assert_eq!(status(&rotated), KeyStatus::Unknown);
+        assert_eq!(status(&FrameEncryption::default()), KeyStatus::Unknown);
+        assert!(reencrypt_snapshots(dump_dir.path(), &rotated).is_err());
+        let snapshot_file = SnapshotFile::open(&snapshot_path, rotated.clone()).unwrap();
+        assert!(snapshot_file.frame_at(0).is_err());
+
+        // they are encrypted again with the new key, and keep their frames
+        let rotated = rotated.with_previous_key(FrameCipher::new(&[1; KEY_SIZE]));
+        assert_eq!(status(&rotated), KeyStatus::Readable);
+        reencrypt_snapshots(dump_dir.path(), &rotated).unwrap();
+        let rotated = FrameEncryption::new(FrameCipher::new(&[2; KEY_SIZE]));
+        assert_eq!(status(&rotated), KeyStatus::Current);
+        let snapshot_file = SnapshotFile::open(&snapshot_path, rotated.clone()).unwrap();
+        let reencrypted = snapshot_file
+            .frames_iter()
+            .map(|f| Frame::try_from_bytes(f.unwrap()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            reencrypted.iter().map(|f| f.as_slice()).collect::<Vec<_>>(),
+            frames.iter().map(|f| f.as_slice()).collect::<Vec<_>>()
+        );
+
+        // disabling encryption writes them in plaintext
+        let disabled = FrameEncryption::default();
+        assert_eq!(status(&disabled), KeyStatus::Unknown);
+        let disabled = disabled.with_previous_key(FrameCipher::new(&[2; KEY_SIZE]));
+        reencrypt_snapshots(dump_dir.path(), &disabled).unwrap();
+        assert_eq!(status(&FrameEncryption::default()), KeyStatus::Current);
+        let content = read(&snapshot_path).unwrap();
+        assert!(content.windows(64).any(|w| w.iter().all(|b| *b == 42)));
+    }
+
+    #[test]
+    fn merge_policy() {
+        let tmp = tempdir().unwrap();
+        let db_id = Uuid::new_v4();
+        let snapshots = vec![
+            (format!("{db_id}-0-4.snap"), 5),
+            (format!("{db_id}-5-9.snap"), 5),
+            (format!("{db_id}-10-14.snap"), 5),
+        ];
+        let history = CommitHistory::open(tmp.path()).unwrap();
+        let now = SystemTime::now();
+        history.record(4, now - Duration::from_secs(7200)).unwrap();
+        history.record(7, now - Duration::from_secs(5400)).unwrap();
+        history.record(9, now - Duration::from_secs(60)).unwrap();
+        history.record(14, now).unwrap();
+
+        // only the first snapshot is older than the minimum age
+        let min_age = Some(Duration::from_secs(3600));
+        assert_eq!(
+            SnapshotMerger::mergeable_snapshot_count(&snapshots, &history, min_age).unwrap(),
+            1
+        );
+        assert_eq!(
+            SnapshotMerger::mergeable_snapshot_count(&snapshots, &history, None).unwrap(),
+            3
+        );
+
+        let default = MergePolicy::default();
+        assert!(!SnapshotMerger::should_compact(&snapshots, 10, &default));
+        assert!(SnapshotMerger::should_compact(&snapshots, 5, &default));
+        let policy = MergePolicy {
+            max_count: 2,
+            max_frames: Some(100),
+            min_age: None,
+        };
+        assert!(SnapshotMerger::should_compact(&snapshots, 5, &policy));
+        assert!(!SnapshotMerger::should_compact(&snapshots[..2], 5, &policy));
     }
 }
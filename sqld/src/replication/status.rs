@@ -0,0 +1,47 @@
+//! Replication status of a database, as reported by the admin API.
+
+use serde::Serialize;
+
+use super::primary::replicas::ConnectedReplica;
+use super::FrameNo;
+
+/// frame_no values follow the convention of the frame notifiers, also used for the `x-frame-no`
+/// header: a replica is up to date when its `applied_frame_no + 1` reaches the `frame_no` of the
+/// primary.
+#[derive(Debug, Serialize)]
+#[serde(tag = "role", rename_all = "snake_case")]
+pub enum ReplicationStatus {
+    Primary {
+        frame_no: FrameNo,
+        generation_id: String,
+        replicas: Vec<ReplicaStatus>,
+    },
+    Replica {
+        /// Unset until the replica applies its first frame.
+        applied_frame_no: Option<FrameNo>,
+        /// Generation of the primary log, unset until the replica completes a handshake.
+        generation_id: Option<String>,
+        /// The last frame_no reported by the primary.
+        primary_frame_no: Option<FrameNo>,
+        /// Number of frames of the primary that the replica misses.
+        lag_frames: Option<u64>,
+        /// Time elapsed since the replica was last known to be up to date with the primary.
+        lag_ms: Option<u64>,
+        /// Whether the database is being recovered, because it can't replicate from the primary
+        /// anymore. Its reads are stale until the recovery completes.
+        recovering: bool,
+        /// The last invalid frame received from the primary, e.g. with an invalid checksum. Such
+        /// frames are not applied, and the database is resynchronized with the primary instead.
+        invalid_frame: Option<String>,
+        /// The replicas replicating from this replica, if it serves them.
+        replicas: Vec<ReplicaStatus>,
+    },
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReplicaStatus {
+    #[serde(flatten)]
+    pub replica: ConnectedReplica,
+    /// Number of frames of the log that were not sent to the replica yet.
+    pub lag_frames: Option<u64>,
+}
@@ -0,0 +1,51 @@
+pub mod rpc {
+    #![allow(clippy::all)]
+    tonic::include_proto!("cluster");
+}
+
+use std::sync::Arc;
+
+use tonic::Status;
+
+use crate::cluster::Cluster;
+
+use self::rpc::cluster_server::Cluster as ClusterRpc;
+use self::rpc::{HeartbeatRequest, HeartbeatResponse, VoteRequest, VoteResponse};
+
+/// Serves the leader election of a cluster to the other nodes.
+pub struct ClusterService {
+    cluster: Arc<Cluster>,
+}
+
+impl ClusterService {
+    pub fn new(cluster: Arc<Cluster>) -> Self {
+        Self { cluster }
+    }
+}
+
+#[tonic::async_trait]
+impl ClusterRpc for ClusterService {
+    async fn request_vote(
+        &self,
+        req: tonic::Request<VoteRequest>,
+    ) -> Result<tonic::Response<VoteResponse>, Status> {
+        let cluster = self.cluster.clone();
+        // voting reads the progress of the databases from disk
+        let resp = tokio::task::spawn_blocking(move || cluster.handle_vote(req.into_inner()))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .map_err(|e| Status::internal(e.to_string()))?;
+        Ok(tonic::Response::new(resp))
+    }
+
+    async fn heartbeat(
+        &self,
+        req: tonic::Request<HeartbeatRequest>,
+    ) -> Result<tonic::Response<HeartbeatResponse>, Status> {
+        let resp = self
+            .cluster
+            .handle_heartbeat(req.into_inner())
+            .map_err(|e| Status::internal(e.to_string()))?;
+        Ok(tonic::Response::new(resp))
+    }
+}
@@ -4,25 +4,30 @@ use std::path::PathBuf;
 use std::sync::Arc;
 use tower::util::option_layer;
 
-use crate::namespace::{NamespaceStore, PrimaryNamespaceMaker};
+use crate::cluster::Cluster;
+use crate::namespace::{MakeNamespace, NamespaceStore};
+use crate::rpc::cluster::rpc::cluster_server::ClusterServer;
+use crate::rpc::cluster::ClusterService;
 use crate::rpc::proxy::rpc::proxy_server::ProxyServer;
 use crate::rpc::proxy::ProxyService;
 pub use crate::rpc::replication_log::rpc::replication_log_server::ReplicationLogServer;
 use crate::rpc::replication_log::ReplicationLogService;
 use crate::utils::services::idle_shutdown::IdleShutdownLayer;
 
+pub mod cluster;
 pub mod proxy;
 pub mod replication_log;
 
 #[allow(clippy::too_many_arguments)]
-pub async fn run_rpc_server(
+pub async fn run_rpc_server<F: MakeNamespace>(
     addr: SocketAddr,
     tls: bool,
     cert_path: Option<PathBuf>,
     key_path: Option<PathBuf>,
     ca_cert_path: Option<PathBuf>,
     idle_shutdown_layer: Option<IdleShutdownLayer>,
-    namespaces: Arc<NamespaceStore<PrimaryNamespaceMaker>>,
+    namespaces: Arc<NamespaceStore<F>>,
+    cluster: Option<Arc<Cluster>>,
 ) -> anyhow::Result<()> {
     let proxy_service = ProxyService::new(namespaces.clone());
     let logger_service =
@@ -30,6 +35,42 @@ pub async fn run_rpc_server(
 
     tracing::info!("serving write proxy server at {addr}");
 
+    server_builder(tls, cert_path, key_path, ca_cert_path)?
+        .layer(&option_layer(idle_shutdown_layer))
+        .add_service(ProxyServer::new(proxy_service))
+        .add_service(ReplicationLogServer::new(logger_service))
+        .add_optional_service(cluster.map(|c| ClusterServer::new(ClusterService::new(c))))
+        .serve(addr)
+        .await?;
+
+    Ok(())
+}
+
+/// Serves only the leader election, while a node of a cluster doesn't know its role yet.
+pub async fn run_cluster_rpc_server(
+    addr: SocketAddr,
+    tls: bool,
+    cert_path: Option<PathBuf>,
+    key_path: Option<PathBuf>,
+    ca_cert_path: Option<PathBuf>,
+    cluster: Arc<Cluster>,
+) -> anyhow::Result<()> {
+    tracing::info!("serving cluster election at {addr}");
+
+    server_builder(tls, cert_path, key_path, ca_cert_path)?
+        .add_service(ClusterServer::new(ClusterService::new(cluster)))
+        .serve(addr)
+        .await?;
+
+    Ok(())
+}
+
+fn server_builder(
+    tls: bool,
+    cert_path: Option<PathBuf>,
+    key_path: Option<PathBuf>,
+    ca_cert_path: Option<PathBuf>,
+) -> anyhow::Result<tonic::transport::Server> {
     let mut builder = tonic::transport::Server::builder();
     if tls {
         let cert_pem = std::fs::read_to_string(cert_path.unwrap())?;
@@ -46,12 +87,6 @@ pub async fn run_rpc_server(
             .tls_config(tls_config)
             .context("Failed to read the TSL config of RPC server")?;
     }
-    builder
-        .layer(&option_layer(idle_shutdown_layer))
-        .add_service(ProxyServer::new(proxy_service))
-        .add_service(ReplicationLogServer::new(logger_service))
-        .serve(addr)
-        .await?;
 
-    Ok(())
+    Ok(builder)
 }
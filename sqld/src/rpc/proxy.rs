@@ -1,18 +1,20 @@
 use std::collections::HashMap;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
 use async_lock::{RwLock, RwLockUpgradableReadGuard};
+use tracing::Instrument as _;
 use uuid::Uuid;
 
 use crate::auth::{Authenticated, Authorized};
-use crate::connection::libsql::LibSqlConnection;
-use crate::connection::{Connection, TrackedConnection};
+use crate::connection::Connection;
 use crate::database::Database;
-use crate::namespace::{NamespaceStore, PrimaryNamespaceMaker};
+use crate::namespace::{MakeNamespace, NamespaceStore};
 use crate::query_result_builder::{
     Column, QueryBuilderConfig, QueryResultBuilder, QueryResultBuilderError,
 };
+use crate::telemetry;
 
 use self::rpc::proxy_server::Proxy;
 use self::rpc::query_result::RowResult;
@@ -44,7 +46,8 @@ pub mod rpc {
         fn from(other: SqldError) -> Self {
             match other {
                 SqldError::LibSqlInvalidQueryParams(_) => ErrorCode::SqlError,
-                SqldError::LibSqlTxTimeout => ErrorCode::TxTimeout,
+                SqldError::LibSqlTxTimeout(_) => ErrorCode::TxTimeout,
+                SqldError::StatementTimeout => ErrorCode::StatementTimeout,
                 SqldError::LibSqlTxBusy => ErrorCode::TxBusy,
                 _ => ErrorCode::Internal,
             }
@@ -255,13 +258,15 @@ pub mod rpc {
     }
 }
 
-pub struct ProxyService {
-    clients: RwLock<HashMap<Uuid, Arc<TrackedConnection<LibSqlConnection>>>>,
-    namespaces: Arc<NamespaceStore<PrimaryNamespaceMaker>>,
+/// Executes the programs forwarded by replicas. On a replica that serves other replicas, writes
+/// are forwarded further upstream.
+pub struct ProxyService<F: MakeNamespace> {
+    clients: RwLock<HashMap<Uuid, Arc<<F::Database as Database>::Connection>>>,
+    namespaces: Arc<NamespaceStore<F>>,
 }
 
-impl ProxyService {
-    pub fn new(namespaces: Arc<NamespaceStore<PrimaryNamespaceMaker>>) -> Self {
+impl<F: MakeNamespace> ProxyService<F> {
+    pub fn new(namespaces: Arc<NamespaceStore<F>>) -> Self {
         Self {
             clients: Default::default(),
             namespaces,
@@ -424,13 +429,11 @@ impl QueryResultBuilder for ExecuteResultBuilder {
     }
 }
 
-#[tonic::async_trait]
-impl Proxy for ProxyService {
-    async fn execute(
+impl<F: MakeNamespace> ProxyService<F> {
+    async fn execute_program_req(
         &self,
-        req: tonic::Request<rpc::ProgramReq>,
+        req: rpc::ProgramReq,
     ) -> Result<tonic::Response<ExecuteResults>, tonic::Status> {
-        let req = req.into_inner();
         let pgm = crate::connection::program::Program::try_from(req.pgm.unwrap())
             .map_err(|e| tonic::Status::new(tonic::Code::InvalidArgument, e.to_string()))?;
         let client_id = Uuid::from_str(&req.client_id).unwrap();
@@ -450,7 +453,7 @@ impl Proxy for ProxyService {
             .namespaces
             .with(req.namespace.clone(), |ns| {
                 let connection_maker = ns.db.connection_maker();
-                let notifier = ns.db.logger.new_frame_notifier.subscribe();
+                let notifier = ns.db.frame_notifier();
                 (connection_maker, notifier)
             })
             .await
@@ -475,20 +478,46 @@ impl Proxy for ProxyService {
 
         tracing::debug!("executing request for {client_id}");
 
-        let builder = ExecuteResultBuilder::default();
-        let (results, state) = db
-            .execute_program(pgm, auth, builder)
-            .await
-            // TODO: this is no necessarily a permission denied error!
-            .map_err(|e| tonic::Status::new(tonic::Code::PermissionDenied, e.to_string()))?;
+        db.set_txn_timeout(req.txn_timeout_ms.map(Duration::from_millis));
 
-        let current_frame_no = *new_frame_notifier.borrow();
+        let builder = ExecuteResultBuilder::default();
+        let (results, state) =
+            db.execute_program(pgm, auth, builder)
+                .await
+                .map_err(|e| match e {
+                    crate::error::Error::StatementTimeout => {
+                        tonic::Status::new(tonic::Code::DeadlineExceeded, e.to_string())
+                    }
+                    // TODO: this is no necessarily a permission denied error!
+                    e => tonic::Status::new(tonic::Code::PermissionDenied, e.to_string()),
+                })?;
+
+        // a replica reports the frame_no of its last write upstream, so that the frames it wrote
+        // are waited for downstream even before they are replicated back to it.
+        let current_frame_no = db
+            .last_write_frame_no()
+            .unwrap_or_else(|| *new_frame_notifier.borrow());
         Ok(tonic::Response::new(ExecuteResults {
             current_frame_no,
             results: results.into_ret(),
             state: rpc::execute_results::State::from(state).into(),
         }))
     }
+}
+
+#[tonic::async_trait]
+impl<F: MakeNamespace> Proxy for ProxyService<F> {
+    async fn execute(
+        &self,
+        req: tonic::Request<rpc::ProgramReq>,
+    ) -> Result<tonic::Response<ExecuteResults>, tonic::Status> {
+        // Join the trace of the replica that forwarded the program.
+        let span = tracing::info_span!("proxy_execute", client_id = %req.get_ref().client_id);
+        telemetry::set_remote_parent(&span, &req);
+        self.execute_program_req(req.into_inner())
+            .instrument(span)
+            .await
+    }
 
     //TODO: also handle cleanup on peer disconnect
     async fn disconnect(
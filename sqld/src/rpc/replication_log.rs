@@ -3,29 +3,41 @@ pub mod rpc {
     tonic::include_proto!("wal_log");
 }
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 use bytes::Bytes;
 use futures::stream::BoxStream;
-use tokio::sync::mpsc;
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::{AbortHandle, JoinSet};
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_stream::StreamExt;
-use tonic::Status;
+use tonic::{Status, Streaming};
 
 use crate::auth::Auth;
-use crate::namespace::{NamespaceStore, PrimaryNamespaceMaker};
+use crate::database::{Database, LogSource};
+use crate::namespace::{MakeNamespace, NamespaceEvent, NamespaceStore};
+use crate::replication::compression::{self, MAX_FRAMES_PER_MESSAGE};
 use crate::replication::primary::frame_stream::FrameStream;
-use crate::replication::LogReadError;
+use crate::replication::primary::replicas::ConnectedReplicas;
+use crate::replication::{frames_checksum, page_checksum, LogReadError};
 use crate::utils::services::idle_shutdown::IdleShutdownLayer;
 
 use self::rpc::replication_log_server::ReplicationLog;
-use self::rpc::{Frame, Frames, HelloRequest, HelloResponse, LogOffset};
-
-pub struct ReplicationLogService {
-    namespaces: Arc<NamespaceStore<PrimaryNamespaceMaker>>,
+use self::rpc::subscribe_response::Event;
+use self::rpc::{
+    AckRequest, AckResponse, Compression, Frame, Frames, HelloRequest, HelloResponse, LogOffset,
+    NamespaceCreated, NamespaceDeleted, NamespaceSubscription, PageDigest, SnapshotChunk,
+    SnapshotDigest, SubscribeRequest, SubscribeResponse, SubscriptionError,
+};
+
+/// Serves the replication log of the databases of a primary, or of a replica that serves other
+/// replicas.
+pub struct ReplicationLogService<F: MakeNamespace> {
+    namespaces: Arc<NamespaceStore<F>>,
     replicas_with_hello: RwLock<HashSet<(SocketAddr, Bytes)>>,
     idle_shutdown_layer: Option<IdleShutdownLayer>,
     auth: Option<Arc<Auth>>,
@@ -34,9 +46,15 @@ pub struct ReplicationLogService {
 pub const NO_HELLO_ERROR_MSG: &str = "NO_HELLO";
 pub const NEED_SNAPSHOT_ERROR_MSG: &str = "NEED_SNAPSHOT";
 
-impl ReplicationLogService {
+/// Interval at which the subscriptions of a `Subscribe` stream report the frame_no of their
+/// namespace.
+const SUBSCRIPTION_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// Number of events buffered for a `Subscribe` stream.
+const SUBSCRIBE_CHANNEL_CAPACITY: usize = 256;
+
+impl<F: MakeNamespace> ReplicationLogService<F> {
     pub fn new(
-        namespaces: Arc<NamespaceStore<PrimaryNamespaceMaker>>,
+        namespaces: Arc<NamespaceStore<F>>,
         idle_shutdown_layer: Option<IdleShutdownLayer>,
         auth: Option<Arc<Auth>>,
     ) -> Self {
@@ -55,6 +73,33 @@ impl ReplicationLogService {
 
         Ok(())
     }
+
+    async fn log_source(&self, namespace: Bytes) -> Result<LogSource, Status> {
+        log_source(&self.namespaces, namespace).await
+    }
+}
+
+async fn log_source<F: MakeNamespace>(
+    namespaces: &NamespaceStore<F>,
+    namespace: Bytes,
+) -> Result<LogSource, Status> {
+    match namespaces.with(namespace, |ns| ns.db.log_source()).await {
+        Ok(Some(source)) => Ok(source),
+        Ok(None) => Err(Status::unavailable("replication log not available")),
+        Err(e) => Err(Status::internal(format!(
+            "failed to create database connection: {e}"
+        ))),
+    }
+}
+
+fn hello_response(source: &LogSource, compression: Compression) -> HelloResponse {
+    HelloResponse {
+        database_id: source.logger.database_id().unwrap().to_string(),
+        generation_start_index: source.generation_start_index,
+        generation_id: source.generation_id.clone(),
+        next_frame_no: Some(*source.logger.new_frame_notifier.borrow()),
+        compression: compression.into(),
+    }
 }
 
 fn map_frame_stream_output(
@@ -63,33 +108,142 @@ fn map_frame_stream_output(
     match r {
         Ok(frame) => Ok(Frame {
             data: frame.bytes(),
+            compression: Compression::None.into(),
+            snapshot_chunk: None,
         }),
-        Err(LogReadError::SnapshotRequired) => Err(Status::new(
-            tonic::Code::FailedPrecondition,
-            NEED_SNAPSHOT_ERROR_MSG,
+        Err(e) => Err(map_log_read_error(e)),
+    }
+}
+
+/// Turns a stream of frames into messages, compressed with `compression`.
+fn frame_messages<S>(
+    frames: S,
+    compression: Compression,
+    replicas: Arc<ConnectedReplicas>,
+    replica_addr: SocketAddr,
+) -> BoxStream<'static, Result<Frame, Status>>
+where
+    S: futures::stream::Stream<Item = Result<crate::replication::frame::Frame, LogReadError>>
+        + Send
+        + Unpin
+        + 'static,
+{
+    match compression {
+        Compression::None => Box::pin(frames.map(move |frame| {
+            if let Ok(frame) = &frame {
+                replicas.sent(replica_addr, frame.header().frame_no);
+            }
+            map_frame_stream_output(frame)
+        })),
+        // compress together the frames that are ready to be sent
+        compression => Box::pin(futures::StreamExt::flat_map(
+            futures::StreamExt::ready_chunks(frames, MAX_FRAMES_PER_MESSAGE),
+            move |chunk| {
+                let mut frames = Vec::with_capacity(chunk.len());
+                let mut error = None;
+                for frame in chunk {
+                    match frame {
+                        Ok(frame) => frames.push(frame),
+                        Err(e) => {
+                            error = Some(Err(map_log_read_error(e)));
+                            break;
+                        }
+                    }
+                }
+                if let Some(frame) = frames.last() {
+                    replicas.sent(replica_addr, frame.header().frame_no);
+                }
+                let messages = match compression::encode_frames(&frames, compression) {
+                    Ok(messages) => messages.into_iter().map(Ok).collect(),
+                    Err(e) => vec![Err(Status::internal(e.to_string()))],
+                };
+                tokio_stream::iter(messages.into_iter().chain(error))
+            },
         )),
-        Err(LogReadError::Error(e)) => Err(Status::new(tonic::Code::Internal, e.to_string())),
+    }
+}
+
+fn map_log_read_error(e: LogReadError) -> Status {
+    match e {
+        LogReadError::SnapshotRequired => {
+            Status::new(tonic::Code::FailedPrecondition, NEED_SNAPSHOT_ERROR_MSG)
+        }
+        LogReadError::Error(e) => Status::new(tonic::Code::Internal, e.to_string()),
         // this error should be caught before, but we handle it nicely anyways
-        Err(LogReadError::Ahead) => Err(Status::new(
-            tonic::Code::OutOfRange,
-            "frame not yet available",
-        )),
+        LogReadError::Ahead => Status::new(tonic::Code::OutOfRange, "frame not yet available"),
+    }
+}
+
+/// Sends the frames of a snapshot transfer, starting with frame `offset` of the transfer. Each
+/// message carries its position in the transfer and a checksum, so that the replica can verify it,
+/// and resume the transfer if it is interrupted.
+fn send_snapshot(
+    frames: &mut impl Iterator<Item = anyhow::Result<Bytes>>,
+    compression: Compression,
+    mut chunk: SnapshotChunk,
+    sender: &mpsc::Sender<Result<Frame, Status>>,
+) {
+    // uncompressed messages carry a single frame
+    let frames_per_message = if compression == Compression::None {
+        1
+    } else {
+        MAX_FRAMES_PER_MESSAGE
+    };
+    loop {
+        let batch = frames
+            .by_ref()
+            .take(frames_per_message)
+            .map(|data| data.and_then(crate::replication::frame::Frame::try_from_bytes))
+            .collect::<anyhow::Result<Vec<_>>>()
+            .and_then(|batch| {
+                let checksum = frames_checksum(0, &batch);
+                let messages = compression::encode_frames(&batch, compression)?;
+                Ok((batch.len() as u64, checksum, messages))
+            });
+        match batch {
+            Ok((0, _, _)) => break,
+            Ok((len, checksum, messages)) => {
+                chunk.checksum = checksum;
+                for mut message in messages {
+                    message.snapshot_chunk = Some(chunk.clone());
+                    if sender.blocking_send(Ok(message)).is_err() {
+                        return;
+                    }
+                }
+                chunk.offset += len;
+            }
+            Err(e) => {
+                let _ =
+                    sender.blocking_send(Err(Status::new(tonic::Code::Internal, e.to_string())));
+                break;
+            }
+        }
     }
 }
 
 pub struct StreamGuard<S> {
     s: S,
     idle_shutdown_layer: Option<IdleShutdownLayer>,
+    replicas: Arc<ConnectedReplicas>,
+    replica_addr: SocketAddr,
 }
 
 impl<S> StreamGuard<S> {
-    fn new(s: S, mut idle_shutdown_layer: Option<IdleShutdownLayer>) -> Self {
+    fn new(
+        s: S,
+        mut idle_shutdown_layer: Option<IdleShutdownLayer>,
+        replicas: Arc<ConnectedReplicas>,
+        replica_addr: SocketAddr,
+    ) -> Self {
         if let Some(isl) = idle_shutdown_layer.as_mut() {
             isl.add_connected_replica()
         }
+        replicas.stream_opened(replica_addr);
         Self {
             s,
             idle_shutdown_layer,
+            replicas,
+            replica_addr,
         }
     }
 }
@@ -99,6 +253,7 @@ impl<S> Drop for StreamGuard<S> {
         if let Some(isl) = self.idle_shutdown_layer.as_mut() {
             isl.remove_connected_replica()
         }
+        self.replicas.stream_closed(self.replica_addr);
     }
 }
 
@@ -114,9 +269,10 @@ impl<S: futures::stream::Stream + Unpin> futures::stream::Stream for StreamGuard
 }
 
 #[tonic::async_trait]
-impl ReplicationLog for ReplicationLogService {
+impl<F: MakeNamespace> ReplicationLog for ReplicationLogService<F> {
     type LogEntriesStream = BoxStream<'static, Result<Frame, Status>>;
     type SnapshotStream = BoxStream<'static, Result<Frame, Status>>;
+    type SubscribeStream = BoxStream<'static, Result<SubscribeResponse, Status>>;
 
     async fn log_entries(
         &self,
@@ -135,26 +291,20 @@ impl ReplicationLog for ReplicationLogService {
             }
         }
 
-        let logger = match self
-            .namespaces
-            .with(req.namespace, |ns| ns.db.logger.clone())
-            .await
-        {
-            Ok(logger) => logger,
-            Err(e) => {
-                return Err(Status::internal(format!(
-                    "failed to create database connection: {e}"
-                )));
-            }
-        };
+        let LogSource {
+            logger, replicas, ..
+        } = self.log_source(req.namespace).await?;
 
-        let stream = StreamGuard::new(
+        replicas.requested(replica_addr, req.next_offset);
+        let frames = StreamGuard::new(
             FrameStream::new(logger, req.next_offset, true),
             self.idle_shutdown_layer.clone(),
-        )
-        .map(map_frame_stream_output);
+            replicas.clone(),
+            replica_addr,
+        );
+        let stream = frame_messages(frames, req.compression(), replicas, replica_addr);
 
-        Ok(tonic::Response::new(Box::pin(stream)))
+        Ok(tonic::Response::new(stream))
     }
 
     async fn batch_log_entries(
@@ -174,26 +324,28 @@ impl ReplicationLog for ReplicationLogService {
             }
         }
 
-        let logger = match self
-            .namespaces
-            .with(req.namespace, |ns| ns.db.logger.clone())
-            .await
-        {
-            Ok(logger) => logger,
-            Err(e) => {
-                return Err(Status::internal(format!(
-                    "failed to create database connection: {e}"
-                )));
-            }
-        };
+        let LogSource {
+            logger, replicas, ..
+        } = self.log_source(req.namespace).await?;
 
+        replicas.requested(replica_addr, req.next_offset);
+        let compression = req.compression();
         let frames = StreamGuard::new(
             FrameStream::new(logger.clone(), req.next_offset, false),
             self.idle_shutdown_layer.clone(),
+            replicas.clone(),
+            replica_addr,
         )
-        .map(map_frame_stream_output)
+        .map(|frame| {
+            if let Ok(frame) = &frame {
+                replicas.sent(replica_addr, frame.header().frame_no);
+            }
+            frame.map_err(map_log_read_error)
+        })
         .collect::<Result<Vec<_>, _>>()
         .await?;
+        let frames = compression::encode_frames(&frames, compression)
+            .map_err(|e| Status::internal(e.to_string()))?;
 
         Ok(tonic::Response::new(Frames { frames }))
     }
@@ -214,19 +366,30 @@ impl ReplicationLog for ReplicationLogService {
             guard.insert((replica_addr, req.namespace.clone()));
         }
 
-        let logger = self
-            .namespaces
-            .with(req.namespace, |ns| ns.db.logger.clone())
-            .await
-            .unwrap();
+        let source = self.log_source(req.namespace).await?;
+        source.replicas.seen(replica_addr);
 
-        let response = HelloResponse {
-            database_id: logger.database_id().unwrap().to_string(),
-            generation_start_index: logger.generation.start_index,
-            generation_id: logger.generation.id.to_string(),
-        };
+        let compression = compression::negotiate(req.supported_compressions());
 
-        Ok(tonic::Response::new(response))
+        Ok(tonic::Response::new(hello_response(&source, compression)))
+    }
+
+    async fn ack(
+        &self,
+        req: tonic::Request<AckRequest>,
+    ) -> Result<tonic::Response<AckResponse>, Status> {
+        self.authenticate(&req)?;
+
+        let replica_addr = req
+            .remote_addr()
+            .ok_or(Status::internal("No remote RPC address"))?;
+        let req = req.into_inner();
+        let source = self.log_source(req.namespace).await?;
+        source
+            .replicas
+            .acked(replica_addr, req.replica_id, req.frame_no);
+
+        Ok(tonic::Response::new(AckResponse {}))
     }
 
     async fn snapshot(
@@ -236,35 +399,73 @@ impl ReplicationLog for ReplicationLogService {
         self.authenticate(&req)?;
 
         let (sender, receiver) = mpsc::channel(10);
+        let replica_addr = req.remote_addr();
         let req = req.into_inner();
-        let ns = req.namespace;
-        let logger = self
-            .namespaces
-            .with(ns, |ns| ns.db.logger.clone())
-            .await
-            .unwrap();
+        let compression = req.compression();
+        let LogSource {
+            logger, replicas, ..
+        } = self.log_source(req.namespace.clone()).await?;
         let offset = req.next_offset;
+        if let Some(replica_addr) = replica_addr {
+            replicas.requested(replica_addr, offset);
+        }
         match tokio::task::spawn_blocking(move || logger.get_snapshot_file(offset)).await {
             Ok(Ok(Some(snapshot))) => {
                 tokio::task::spawn_blocking(move || {
-                    let mut frames = snapshot.frames_iter_from(offset);
-                    loop {
-                        match frames.next() {
-                            Some(Ok(data)) => {
-                                let _ = sender.blocking_send(Ok(Frame { data }));
-                            }
-                            Some(Err(e)) => {
-                                let _ = sender.blocking_send(Err(Status::new(
-                                    tonic::Code::Internal,
-                                    e.to_string(),
-                                )));
-                                break;
-                            }
-                            None => {
-                                break;
-                            }
+                    let snapshot_id = snapshot.id();
+                    let frame_count = snapshot.frame_count_from(offset);
+                    // the transfer is restricted to the frames of the requested pages, if any
+                    let indexes = frame_count.and_then(|frame_count| {
+                        let pages = req.pages.iter().copied().collect::<HashSet<_>>();
+                        let indexes = if pages.is_empty() {
+                            None
+                        } else {
+                            Some(snapshot.frame_indexes_of_pages(frame_count, &pages)?)
+                        };
+                        Ok((frame_count, indexes))
+                    });
+                    let (frame_count, indexes) = match indexes {
+                        Ok((_, Some(indexes))) => (indexes.len() as u64, Some(indexes)),
+                        Ok((frame_count, None)) => (frame_count, None),
+                        Err(e) => {
+                            let _ = sender.blocking_send(Err(Status::new(
+                                tonic::Code::Internal,
+                                e.to_string(),
+                            )));
+                            return;
                         }
+                    };
+                    // the transfer can only be resumed if the snapshot didn't change since
+                    let resume_from = if req.snapshot_id.as_ref() == Some(&snapshot_id) {
+                        req.resume_from.min(frame_count)
+                    } else {
+                        0
+                    };
+                    if resume_from > 0 {
+                        tracing::debug!(
+                            "resuming the transfer of snapshot {snapshot_id} from frame {resume_from}/{frame_count}"
+                        );
                     }
+
+                    let mut frames: Box<dyn Iterator<Item = _>> = match &indexes {
+                        Some(indexes) => Box::new(
+                            indexes[resume_from as usize..]
+                                .iter()
+                                .map(|index| snapshot.frame_at(*index)),
+                        ),
+                        None => Box::new(
+                            snapshot
+                                .frames_iter_at(resume_from)
+                                .take((frame_count - resume_from) as usize),
+                        ),
+                    };
+                    let chunk = SnapshotChunk {
+                        snapshot_id,
+                        offset: resume_from,
+                        frame_count,
+                        checksum: 0,
+                    };
+                    send_snapshot(&mut frames, compression, chunk, &sender);
                 });
 
                 Ok(tonic::Response::new(Box::pin(ReceiverStream::new(
@@ -276,4 +477,273 @@ impl ReplicationLog for ReplicationLogService {
             Ok(Err(e)) => Err(Status::new(tonic::Code::Internal, e.to_string())),
         }
     }
+
+    async fn snapshot_digest(
+        &self,
+        req: tonic::Request<LogOffset>,
+    ) -> Result<tonic::Response<SnapshotDigest>, Status> {
+        self.authenticate(&req)?;
+
+        let req = req.into_inner();
+        let LogSource { logger, .. } = self.log_source(req.namespace.clone()).await?;
+        let offset = req.next_offset;
+        let digest = tokio::task::spawn_blocking(move || {
+            let Some(snapshot) = logger.get_snapshot_file(offset)? else {
+                return Ok(None);
+            };
+            let frame_count = snapshot.frame_count_from(offset)?;
+            let pages = snapshot
+                .frames_iter()
+                .take(frame_count as usize)
+                .map(|data| {
+                    let frame = crate::replication::frame::Frame::try_from_bytes(data?)?;
+                    let header = frame.header();
+                    Ok(PageDigest {
+                        page_no: header.page_no,
+                        frame_no: header.frame_no,
+                        size_after: header.size_after,
+                        checksum: page_checksum(frame.page()),
+                        frame_checksum: header.checksum,
+                    })
+                })
+                .collect::<anyhow::Result<_>>()?;
+
+            anyhow::Ok(Some(SnapshotDigest {
+                snapshot_id: snapshot.id(),
+                pages,
+            }))
+        })
+        .await;
+
+        match digest {
+            Ok(Ok(Some(digest))) => Ok(tonic::Response::new(digest)),
+            Ok(Ok(None)) => Err(Status::new(tonic::Code::Unavailable, "snapshot not found")),
+            Err(e) => Err(Status::new(tonic::Code::Internal, e.to_string())),
+            Ok(Err(e)) => Err(Status::new(tonic::Code::Internal, e.to_string())),
+        }
+    }
+    async fn subscribe(
+        &self,
+        req: tonic::Request<Streaming<SubscribeRequest>>,
+    ) -> Result<tonic::Response<Self::SubscribeStream>, Status> {
+        self.authenticate(&req)?;
+
+        let replica_addr = req
+            .remote_addr()
+            .ok_or(Status::internal("No remote RPC address"))?;
+        let (sender, receiver) = mpsc::channel(SUBSCRIBE_CHANNEL_CAPACITY);
+        let subscriber = Subscriber {
+            namespaces: self.namespaces.clone(),
+            idle_shutdown_layer: self.idle_shutdown_layer.clone(),
+            replica_addr,
+            sender,
+            compression: Compression::None,
+            watch_namespaces: false,
+            tasks: JoinSet::new(),
+            subscriptions: HashMap::new(),
+        };
+        tokio::spawn(subscriber.run(req.into_inner()));
+
+        Ok(tonic::Response::new(Box::pin(ReceiverStream::new(
+            receiver,
+        ))))
+    }
+}
+
+/// Serves a `Subscribe` stream: replicates the namespaces that the replica subscribes to, each in
+/// its own task, and interleaves their events on the stream.
+struct Subscriber<F: MakeNamespace> {
+    namespaces: Arc<NamespaceStore<F>>,
+    idle_shutdown_layer: Option<IdleShutdownLayer>,
+    replica_addr: SocketAddr,
+    sender: mpsc::Sender<Result<SubscribeResponse, Status>>,
+    /// compression of the frames, negotiated with the first request
+    compression: Compression,
+    watch_namespaces: bool,
+    tasks: JoinSet<()>,
+    subscriptions: HashMap<Bytes, AbortHandle>,
+}
+
+impl<F: MakeNamespace> Subscriber<F> {
+    async fn run(mut self, mut requests: Streaming<SubscribeRequest>) {
+        let mut events = self.namespaces.subscribe_events();
+        let mut is_first = true;
+        loop {
+            tokio::select! {
+                req = requests.message() => match req {
+                    Ok(Some(req)) => {
+                        if std::mem::take(&mut is_first) {
+                            self.compression = compression::negotiate(req.supported_compressions());
+                            self.watch_namespaces = req.watch_namespaces;
+                            if self.watch_namespaces && self.announce_namespaces().await.is_err() {
+                                break;
+                            }
+                        }
+                        for namespace in req.unsubscribe {
+                            self.unsubscribe(&namespace);
+                        }
+                        for subscription in req.subscribe {
+                            self.subscribe(subscription);
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        tracing::debug!("subscription stream of {} failed: {e}", self.replica_addr);
+                        break;
+                    }
+                },
+                event = events.recv() => {
+                    let res = match event {
+                        Ok(NamespaceEvent::Created(namespace)) => {
+                            self.notify(namespace, Event::Created(NamespaceCreated {})).await
+                        }
+                        Ok(NamespaceEvent::Deleted(namespace)) => {
+                            self.unsubscribe(&namespace);
+                            self.notify(namespace, Event::Deleted(NamespaceDeleted {})).await
+                        }
+                        // some events were missed: announce all the namespaces again
+                        Err(broadcast::error::RecvError::Lagged(_)) => {
+                            self.announce_namespaces().await
+                        }
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    };
+                    if res.is_err() {
+                        break;
+                    }
+                },
+                Some(_) = self.tasks.join_next() => (),
+                _ = self.sender.closed() => break,
+            }
+        }
+    }
+
+    /// Sends a namespace event to the replica, if it watches namespaces.
+    async fn notify(&self, namespace: Bytes, event: Event) -> Result<(), ()> {
+        if !self.watch_namespaces {
+            return Ok(());
+        }
+        let response = SubscribeResponse {
+            namespace,
+            subscription_id: 0,
+            event: Some(event),
+        };
+        self.sender.send(Ok(response)).await.map_err(|_| ())
+    }
+
+    /// Notifies the replica of the creation of all the existing namespaces.
+    async fn announce_namespaces(&self) -> Result<(), ()> {
+        match self.namespaces.list().await {
+            Ok(namespaces) => {
+                for namespace in namespaces {
+                    self.notify(namespace, Event::Created(NamespaceCreated {}))
+                        .await?;
+                }
+                Ok(())
+            }
+            Err(e) => {
+                let _ = self
+                    .sender
+                    .send(Err(Status::internal(format!(
+                        "failed to list namespaces: {e}"
+                    ))))
+                    .await;
+                Err(())
+            }
+        }
+    }
+
+    fn unsubscribe(&mut self, namespace: &Bytes) {
+        if let Some(task) = self.subscriptions.remove(namespace) {
+            task.abort();
+        }
+    }
+
+    fn subscribe(&mut self, subscription: NamespaceSubscription) {
+        self.unsubscribe(&subscription.namespace);
+        let namespace = subscription.namespace.clone();
+        let task = self.tasks.spawn(replicate_subscription(
+            self.namespaces.clone(),
+            subscription,
+            self.compression,
+            self.idle_shutdown_layer.clone(),
+            self.replica_addr,
+            self.sender.clone(),
+        ));
+        self.subscriptions.insert(namespace, task);
+    }
+}
+
+/// Sends the events of a subscription: a hello, and then the frames of the namespace, along with
+/// periodic hellos, until the subscription fails or is cancelled.
+async fn replicate_subscription<F: MakeNamespace>(
+    namespaces: Arc<NamespaceStore<F>>,
+    subscription: NamespaceSubscription,
+    compression: Compression,
+    idle_shutdown_layer: Option<IdleShutdownLayer>,
+    replica_addr: SocketAddr,
+    sender: mpsc::Sender<Result<SubscribeResponse, Status>>,
+) {
+    let NamespaceSubscription {
+        namespace,
+        next_offset,
+        subscription_id,
+    } = subscription;
+    let event = |event| -> Result<SubscribeResponse, Status> {
+        Ok(SubscribeResponse {
+            namespace: namespace.clone(),
+            subscription_id,
+            event: Some(event),
+        })
+    };
+    let error = |status: Status| {
+        event(Event::Error(SubscriptionError {
+            code: status.code() as i32,
+            message: status.message().into(),
+        }))
+    };
+
+    let source = match log_source(&namespaces, namespace.clone()).await {
+        Ok(source) => source,
+        Err(status) => {
+            let _ = sender.send(error(status)).await;
+            return;
+        }
+    };
+    source.replicas.seen(replica_addr);
+    source.replicas.requested(replica_addr, next_offset);
+    if sender
+        .send(event(Event::Hello(hello_response(&source, compression))))
+        .await
+        .is_err()
+    {
+        return;
+    }
+
+    let frames = StreamGuard::new(
+        FrameStream::new(source.logger.clone(), next_offset, true),
+        idle_shutdown_layer,
+        source.replicas.clone(),
+        replica_addr,
+    );
+    let mut messages = frame_messages(frames, compression, source.replicas.clone(), replica_addr);
+    let mut heartbeat = tokio::time::interval(SUBSCRIPTION_HEARTBEAT_INTERVAL);
+    heartbeat.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // the hello was just sent
+    heartbeat.tick().await;
+    loop {
+        let response = tokio::select! {
+            message = messages.next() => match message {
+                Some(Ok(message)) => event(Event::Frame(message)),
+                Some(Err(status)) => {
+                    let _ = sender.send(error(status)).await;
+                    return;
+                }
+                None => return,
+            },
+            _ = heartbeat.tick() => event(Event::Hello(hello_response(&source, compression))),
+        };
+        if sender.send(response).await.is_err() {
+            return;
+        }
+    }
 }
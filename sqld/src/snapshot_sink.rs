@@ -0,0 +1,683 @@
+//! Sinks that the snapshots of the replication log are shipped to.
+//!
+//! Every namespace can be configured with a list of sinks, on top of the sinks of all namespaces
+//! (`--snapshot-exec`). The sinks that copy files or run commands on the server can only be
+//! declared on the command line (`--snapshot-sinks-file`): through the admin API, a namespace can
+//! only enable them by name, or upload its snapshots to the bottomless bucket.
+//!
+//! When the log is compacted into a new snapshot, the snapshot file is linked into the
+//! `snapshot_sinks/files` directory of the namespace, so that it outlives the merges of the
+//! snapshots, and a delivery to every sink is enqueued in `snapshot_sinks/queue`. Deliveries are
+//! performed in the background, in order for each sink and independently of the other sinks, and
+//! failed deliveries are retried with an exponential backoff. They are moved to
+//! `snapshot_sinks/failed` after too many attempts, where they can be inspected through the admin
+//! API, and their files are kept until they are cleared. A compaction never waits for the sinks,
+//! and never fails because of them.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Context as _;
+use bottomless::replicator::BucketClient;
+use bytes::Bytes;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use tokio::sync::OnceCell;
+
+use crate::delivery_queue::{self, now_ms, Deliver, DeliveryQueue, RetryPolicy};
+use crate::replication::parse_snapshot_name;
+
+const CONFIG_FILE: &str = "snapshot_sinks.json";
+const FILES_DIR: &str = "files";
+const FAILED_DIR: &str = "failed";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotSinkConfig {
+    /// Name of the sink, that its deliveries refer to.
+    pub name: String,
+    #[serde(flatten)]
+    pub kind: SnapshotSinkKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SnapshotSinkKind {
+    /// Copies the snapshots to `<path>/<namespace>`, e.g. on an NFS mount. If `retain` is set,
+    /// only that many of the most recent snapshots are kept there.
+    Directory {
+        path: PathBuf,
+        #[serde(default)]
+        retain: Option<usize>,
+    },
+    /// Uploads the snapshots to `snapshots/<namespace>/` in the bucket of bottomless.
+    Bottomless,
+    /// Runs `command` with the path of the snapshot file and the namespace as arguments.
+    Exec { command: String },
+    /// The sink with the same name declared in `--snapshot-sinks-file`.
+    Declared,
+}
+
+/// The snapshot sinks configured on the command line.
+#[derive(Debug, Default)]
+pub struct SnapshotSinksOptions {
+    /// The sinks of all namespaces.
+    pub default_sinks: Vec<SnapshotSinkConfig>,
+    /// The sinks that namespaces can enable by name.
+    pub declared_sinks: Vec<SnapshotSinkConfig>,
+}
+
+impl SnapshotSinksOptions {
+    /// Reads the sinks that namespaces can enable from a JSON file, with the same format as the
+    /// sinks of a namespace in the admin API.
+    pub fn read_declared_sinks(&mut self, path: &Path) -> anyhow::Result<()> {
+        let data = std::fs::read(path)?;
+        let sinks: Vec<SnapshotSinkConfig> =
+            serde_json::from_slice(&data).context("invalid snapshot sinks file")?;
+        let mut names = HashSet::new();
+        for sink in &sinks {
+            anyhow::ensure!(
+                sink.kind != SnapshotSinkKind::Declared,
+                "snapshot sink `{}` must be a directory, bottomless or exec sink",
+                sink.name
+            );
+            anyhow::ensure!(
+                names.insert(&sink.name),
+                "duplicate snapshot sink name `{}`",
+                sink.name
+            );
+        }
+        self.declared_sinks = sinks;
+        Ok(())
+    }
+}
+
+/// A snapshot to deliver to a sink.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotTransfer {
+    pub sink: String,
+    /// Name of the snapshot file.
+    pub snapshot: String,
+}
+
+/// A pending (or failed) delivery of a snapshot to a sink.
+pub type SnapshotDelivery = delivery_queue::Delivery<SnapshotTransfer>;
+
+/// The deliveries to a sink, as reported by the admin API.
+#[derive(Debug, Clone, Serialize)]
+pub struct SnapshotSinkStatus {
+    pub name: String,
+    /// The last snapshot delivered to the sink since startup.
+    pub last_delivered: Option<String>,
+    /// Unix timestamp in milliseconds of the delivery of `last_delivered`.
+    pub last_delivered_ms: Option<u64>,
+    pub pending: Vec<SnapshotDelivery>,
+    pub failed: Vec<SnapshotDelivery>,
+}
+
+pub struct SnapshotSinks {
+    namespace: String,
+    dir: PathBuf,
+    options: Arc<SnapshotSinksOptions>,
+    config: Mutex<Arc<Vec<SnapshotSinkConfig>>>,
+    /// Options of the bottomless bucket. Read from the environment if unset.
+    bottomless: Option<bottomless::replicator::Options>,
+    bucket: OnceCell<BucketClient>,
+    queue: Arc<DeliveryQueue<SnapshotTransfer>>,
+    /// Held while snapshot files are linked and their deliveries enqueued, so that the files are
+    /// not removed as unreferenced in between.
+    files_lock: Mutex<()>,
+    /// The last snapshot delivered to each sink, and when.
+    delivered: Mutex<HashMap<String, (String, u64)>>,
+}
+
+impl SnapshotSinks {
+    /// Opens the snapshot sinks of the namespace stored in `dir`.
+    pub fn open(
+        dir: &Path,
+        namespace: &Bytes,
+        options: Arc<SnapshotSinksOptions>,
+        bottomless: Option<bottomless::replicator::Options>,
+        retry: RetryPolicy,
+    ) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(dir.join(FILES_DIR))?;
+
+        let mut config: Vec<SnapshotSinkConfig> = match std::fs::read(dir.join(CONFIG_FILE)) {
+            Ok(data) => serde_json::from_slice(&data).context("invalid snapshot sinks config")?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        // the config may have been set before the sinks had to be declared on the command line
+        config.retain(|sink| match validate_sink(&options, sink) {
+            Ok(()) => true,
+            Err(e) => {
+                tracing::warn!("ignoring snapshot sink `{}`: {e}", sink.name);
+                false
+            }
+        });
+
+        let this = Self {
+            namespace: String::from_utf8_lossy(namespace).into_owned(),
+            dir: dir.to_path_buf(),
+            options,
+            config: Mutex::new(Arc::new(config)),
+            bottomless,
+            bucket: OnceCell::new(),
+            queue: Arc::new(DeliveryQueue::open(dir, FAILED_DIR, retry)?),
+            files_lock: Mutex::new(()),
+            delivered: Mutex::new(HashMap::new()),
+        };
+        this.remove_unreferenced_files()?;
+
+        Ok(this)
+    }
+
+    /// Returns the sinks configured for this namespace, without the sinks of all namespaces.
+    pub fn config(&self) -> Arc<Vec<SnapshotSinkConfig>> {
+        self.config.lock().clone()
+    }
+
+    /// Checks that the sinks can be configured for this namespace: only the bottomless sink and
+    /// the declared sinks can be, with names that are not taken by the sinks of all namespaces.
+    pub fn validate_config(&self, config: &[SnapshotSinkConfig]) -> anyhow::Result<()> {
+        let mut names = HashSet::new();
+        for sink in self.options.default_sinks.iter().chain(config.iter()) {
+            anyhow::ensure!(
+                names.insert(&sink.name),
+                "duplicate snapshot sink name `{}`",
+                sink.name
+            );
+        }
+        for sink in config {
+            validate_sink(&self.options, sink)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn set_config(&self, config: Vec<SnapshotSinkConfig>) -> anyhow::Result<()> {
+        self.validate_config(&config)?;
+
+        let path = self.dir.join(CONFIG_FILE);
+        let tmp_path = self.dir.join(format!("{CONFIG_FILE}~"));
+        std::fs::write(&tmp_path, serde_json::to_vec_pretty(&config)?)?;
+        std::fs::rename(&tmp_path, &path)?;
+        *self.config.lock() = Arc::new(config);
+        Ok(())
+    }
+
+    /// Returns all the sinks that snapshots are delivered to, with the declared sinks that the
+    /// namespace enabled.
+    fn sinks(&self) -> Vec<SnapshotSinkConfig> {
+        let config = self.config();
+        let declared = config.iter().filter_map(|sink| match sink.kind {
+            SnapshotSinkKind::Declared => self
+                .options
+                .declared_sinks
+                .iter()
+                .find(|declared| declared.name == sink.name),
+            _ => Some(sink),
+        });
+        self.options
+            .default_sinks
+            .iter()
+            .chain(declared)
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the deliveries to each sink.
+    pub fn status(&self) -> anyhow::Result<Vec<SnapshotSinkStatus>> {
+        let pending = self.queue.pending()?;
+        let failed = self.queue.dead_letters()?;
+        let delivered = self.delivered.lock();
+
+        Ok(self
+            .sinks()
+            .into_iter()
+            .map(|sink| {
+                let last_delivered = delivered.get(&sink.name);
+                SnapshotSinkStatus {
+                    last_delivered: last_delivered.map(|(snapshot, _)| snapshot.clone()),
+                    last_delivered_ms: last_delivered.map(|(_, time)| *time),
+                    pending: pending
+                        .iter()
+                        .filter(|d| d.item.sink == sink.name)
+                        .cloned()
+                        .collect(),
+                    failed: failed
+                        .iter()
+                        .filter(|d| d.item.sink == sink.name)
+                        .cloned()
+                        .collect(),
+                    name: sink.name,
+                }
+            })
+            .collect())
+    }
+
+    /// Removes all the failed deliveries and their files, and returns how many were removed.
+    pub fn clear_failed(&self) -> anyhow::Result<usize> {
+        let count = self.queue.clear_dead_letters()?;
+        self.remove_unreferenced_files()?;
+        Ok(count)
+    }
+
+    /// Enqueues the delivery of a new snapshot file to every sink. This is called by the log
+    /// compactor, so it only links the file and records the deliveries.
+    pub fn enqueue(&self, snapshot_file: &Path) -> anyhow::Result<()> {
+        let sinks = self.sinks();
+        if sinks.is_empty() {
+            return Ok(());
+        }
+        let snapshot = snapshot_file
+            .file_name()
+            .and_then(|name| name.to_str())
+            .context("invalid snapshot file name")?;
+
+        let _lock = self.files_lock.lock();
+        let path = self.dir.join(FILES_DIR).join(snapshot);
+        if !path.try_exists()? && std::fs::hard_link(snapshot_file, &path).is_err() {
+            // the file system doesn't support hard links
+            let tmp_path = path.with_extension("snap~");
+            std::fs::copy(snapshot_file, &tmp_path)?;
+            std::fs::rename(&tmp_path, &path)?;
+        }
+
+        for sink in sinks {
+            self.queue.push(SnapshotTransfer {
+                sink: sink.name,
+                snapshot: snapshot.to_string(),
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Delivers the enqueued snapshots to the sinks.
+    pub async fn run(self: Arc<Self>) -> anyhow::Result<()> {
+        self.queue.clone().run(Arc::new(Deliverer(self))).await
+    }
+
+    async fn deliver(
+        &self,
+        sink: &SnapshotSinkConfig,
+        delivery: &SnapshotDelivery,
+    ) -> anyhow::Result<()> {
+        let file = self.dir.join(FILES_DIR).join(&delivery.item.snapshot);
+        match &sink.kind {
+            SnapshotSinkKind::Directory { path, retain } => {
+                let dir = path.join(&self.namespace);
+                let retain = *retain;
+                tokio::task::spawn_blocking(move || copy_to_directory(&file, &dir, retain)).await?
+            }
+            SnapshotSinkKind::Bottomless => {
+                let bucket = self
+                    .bucket
+                    .get_or_try_init(|| async {
+                        let options = match &self.bottomless {
+                            Some(options) => options.clone(),
+                            None => bottomless::replicator::Options::from_env()?,
+                        };
+                        Ok::<_, anyhow::Error>(BucketClient::new(&options).await)
+                    })
+                    .await?;
+                let key = format!("snapshots/{}/{}", self.namespace, delivery.item.snapshot);
+                bucket.put_file(&key, &file).await
+            }
+            SnapshotSinkKind::Exec { command } => {
+                // the process is killed if the delivery times out
+                let status = Command::new(command)
+                    .arg(&file)
+                    .arg(&self.namespace)
+                    .kill_on_drop(true)
+                    .status()
+                    .await?;
+                anyhow::ensure!(
+                    status.success(),
+                    "snapshot exec process failed with status {status}"
+                );
+                Ok(())
+            }
+            SnapshotSinkKind::Declared => unreachable!("declared sinks are resolved by name"),
+        }
+    }
+
+    /// Removes the snapshot files that no pending or failed delivery refers to anymore.
+    fn remove_unreferenced_files(&self) -> anyhow::Result<()> {
+        let _lock = self.files_lock.lock();
+        let referenced: HashSet<_> = self
+            .queue
+            .pending()?
+            .into_iter()
+            .chain(self.queue.dead_letters()?)
+            .map(|delivery| delivery.item.snapshot)
+            .collect();
+        for entry in std::fs::read_dir(self.dir.join(FILES_DIR))? {
+            let entry = entry?;
+            if !referenced.contains(entry.file_name().to_string_lossy().as_ref()) {
+                std::fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Delivers the snapshots of the queue to the sinks.
+struct Deliverer(Arc<SnapshotSinks>);
+
+#[async_trait::async_trait]
+impl Deliver<SnapshotTransfer> for Deliverer {
+    fn destination(&self, transfer: &SnapshotTransfer) -> String {
+        transfer.sink.clone()
+    }
+
+    async fn deliver(&self, delivery: &SnapshotDelivery) -> anyhow::Result<()> {
+        let sinks = self.0.sinks();
+        let Some(sink) = sinks.iter().find(|sink| sink.name == delivery.item.sink) else {
+            // the sink was removed from the config
+            return Ok(());
+        };
+        self.0.deliver(sink, delivery).await?;
+        self.0.delivered.lock().insert(
+            delivery.item.sink.clone(),
+            (delivery.item.snapshot.clone(), now_ms()),
+        );
+        Ok(())
+    }
+
+    async fn dequeued(&self) {
+        let sinks = self.0.clone();
+        let result = tokio::task::spawn_blocking(move || sinks.remove_unreferenced_files()).await;
+        if let Err(e) = result.map_err(anyhow::Error::from).and_then(|r| r) {
+            tracing::error!("failed to remove delivered snapshot files: {e}");
+        }
+    }
+}
+
+/// Checks that a sink can be configured for a namespace through the admin API.
+fn validate_sink(options: &SnapshotSinksOptions, sink: &SnapshotSinkConfig) -> anyhow::Result<()> {
+    match sink.kind {
+        SnapshotSinkKind::Bottomless => Ok(()),
+        SnapshotSinkKind::Declared => {
+            anyhow::ensure!(
+                options
+                    .declared_sinks
+                    .iter()
+                    .any(|declared| declared.name == sink.name),
+                "snapshot sink `{}` is not declared",
+                sink.name
+            );
+            Ok(())
+        }
+        SnapshotSinkKind::Directory { .. } | SnapshotSinkKind::Exec { .. } => anyhow::bail!(
+            "snapshot sink `{}` can only be declared with `--snapshot-sinks-file`",
+            sink.name
+        ),
+    }
+}
+
+/// Copies a snapshot file to `dir`, and removes the oldest snapshots there beyond `retain`.
+fn copy_to_directory(file: &Path, dir: &Path, retain: Option<usize>) -> anyhow::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join(file.file_name().context("invalid snapshot file name")?);
+    let tmp_path = path.with_extension("snap~");
+    std::fs::copy(file, &tmp_path)?;
+    std::fs::File::open(&tmp_path)?.sync_all()?;
+    std::fs::rename(&tmp_path, &path)?;
+
+    if let Some(retain) = retain {
+        // frame numbers start over with a new log, so snapshots are ordered by copy time
+        let mut snapshots = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if let Some((_, _, end_frame_no)) = name.to_str().and_then(parse_snapshot_name) {
+                snapshots.push((entry.metadata()?.modified()?, end_frame_no, entry.path()));
+            }
+        }
+        snapshots.sort_unstable_by_key(|(modified, end_frame_no, _)| (*modified, *end_frame_no));
+        let excess = snapshots.len().saturating_sub(retain);
+        for (_, _, path) in &snapshots[..excess] {
+            std::fs::remove_file(path)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::delivery_queue::test_retry;
+
+    /// Writes a snapshot file to `dir`, covering the frames from `start` to `end`.
+    fn snapshot_file(dir: &Path, db_id: Uuid, start: u64, end: u64) -> PathBuf {
+        let path = dir.join(format!("{db_id}-{start}-{end}.snap"));
+        std::fs::write(&path, [start as u8; 64]).unwrap();
+        path
+    }
+
+    fn exec(name: &str, command: &str) -> SnapshotSinkConfig {
+        SnapshotSinkConfig {
+            name: name.into(),
+            kind: SnapshotSinkKind::Exec {
+                command: command.into(),
+            },
+        }
+    }
+
+    fn sink(name: &str, kind: SnapshotSinkKind) -> SnapshotSinkConfig {
+        SnapshotSinkConfig {
+            name: name.into(),
+            kind,
+        }
+    }
+
+    fn default_sinks(sinks: Vec<SnapshotSinkConfig>) -> Arc<SnapshotSinksOptions> {
+        Arc::new(SnapshotSinksOptions {
+            default_sinks: sinks,
+            declared_sinks: Vec::new(),
+        })
+    }
+
+    /// Waits until there is no pending delivery left.
+    async fn wait_for_deliveries(sinks: &SnapshotSinks) -> Vec<SnapshotSinkStatus> {
+        loop {
+            let status = sinks.status().unwrap();
+            if status.iter().all(|sink| sink.pending.is_empty()) {
+                return status;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn copy_to_directory_with_retention() {
+        let tmp = tempdir().unwrap();
+        let snapshots = tmp.path().join("snapshots");
+        let out = tmp.path().join("out");
+        std::fs::create_dir_all(&snapshots).unwrap();
+        let directory = SnapshotSinkKind::Directory {
+            path: out.clone(),
+            retain: Some(2),
+        };
+        let options = SnapshotSinksOptions {
+            default_sinks: Vec::new(),
+            declared_sinks: vec![sink("nfs", directory.clone())],
+        };
+        let sinks = Arc::new(
+            SnapshotSinks::open(
+                &tmp.path().join("sinks"),
+                &"test".into(),
+                Arc::new(options),
+                None,
+                test_retry(),
+            )
+            .unwrap(),
+        );
+        // the admin API can only enable the declared sinks
+        assert!(sinks.set_config(vec![sink("nfs", directory)]).is_err());
+        assert!(sinks
+            .set_config(vec![sink("other", SnapshotSinkKind::Declared)])
+            .is_err());
+        sinks
+            .set_config(vec![sink("nfs", SnapshotSinkKind::Declared)])
+            .unwrap();
+        tokio::spawn(sinks.clone().run());
+
+        let db_id = Uuid::new_v4();
+        let mut names = Vec::new();
+        for (start, end) in [(0, 9), (10, 19), (20, 29)] {
+            let file = snapshot_file(&snapshots, db_id, start, end);
+            sinks.enqueue(&file).unwrap();
+            // the snapshot can be merged, and removed, before it is delivered
+            std::fs::remove_file(&file).unwrap();
+            names.push(file.file_name().unwrap().to_str().unwrap().to_string());
+            wait_for_deliveries(&sinks).await;
+        }
+
+        let status = sinks.status().unwrap();
+        assert_eq!(status.len(), 1);
+        assert_eq!(status[0].last_delivered.as_ref(), Some(&names[2]));
+        assert!(status[0].failed.is_empty());
+
+        let mut copied: Vec<_> = std::fs::read_dir(out.join("test"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        copied.sort();
+        let mut expected = names[1..].to_vec();
+        expected.sort();
+        assert_eq!(copied, expected);
+        assert_eq!(
+            std::fs::read(out.join("test").join(&names[2])).unwrap(),
+            [20; 64]
+        );
+        // the delivered files are removed once no delivery refers to them
+        let files_dir = tmp.path().join("sinks").join(FILES_DIR);
+        while std::fs::read_dir(&files_dir).unwrap().count() != 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_deliveries() {
+        let tmp = tempdir().unwrap();
+        let sinks = Arc::new(
+            SnapshotSinks::open(
+                &tmp.path().join("sinks"),
+                &"test".into(),
+                default_sinks(vec![exec("exec", "false")]),
+                None,
+                test_retry(),
+            )
+            .unwrap(),
+        );
+        tokio::spawn(sinks.clone().run());
+        let file = snapshot_file(tmp.path(), Uuid::new_v4(), 0, 9);
+        sinks.enqueue(&file).unwrap();
+
+        let status = wait_for_deliveries(&sinks).await;
+        assert_eq!(status[0].name, "exec");
+        assert!(status[0].last_delivered.is_none());
+        assert_eq!(status[0].failed.len(), 1);
+        assert_eq!(status[0].failed[0].attempts, 2);
+        assert!(status[0].failed[0].last_error.is_some());
+        // the file is kept for the failed delivery
+        let files_dir = tmp.path().join("sinks").join(FILES_DIR);
+        assert!(files_dir.join(file.file_name().unwrap()).exists());
+
+        assert_eq!(sinks.clear_failed().unwrap(), 1);
+        assert!(sinks.status().unwrap()[0].failed.is_empty());
+        assert_eq!(std::fs::read_dir(&files_dir).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn hung_sink_does_not_block_others() {
+        let tmp = tempdir().unwrap();
+        let script = tmp.path().join("hang.sh");
+        std::fs::write(&script, "#!/bin/sh\nsleep 60\n").unwrap();
+        let mut permissions = std::fs::metadata(&script).unwrap().permissions();
+        std::os::unix::fs::PermissionsExt::set_mode(&mut permissions, 0o755);
+        std::fs::set_permissions(&script, permissions).unwrap();
+
+        let out = tmp.path().join("out");
+        let directory = SnapshotSinkKind::Directory {
+            path: out.clone(),
+            retain: None,
+        };
+        let retry = RetryPolicy {
+            request_timeout: Duration::from_millis(100),
+            ..test_retry()
+        };
+        let sinks = Arc::new(
+            SnapshotSinks::open(
+                &tmp.path().join("sinks"),
+                &"test".into(),
+                default_sinks(vec![
+                    exec("hang", script.to_str().unwrap()),
+                    sink("nfs", directory),
+                ]),
+                None,
+                retry,
+            )
+            .unwrap(),
+        );
+        tokio::spawn(sinks.clone().run());
+        let file = snapshot_file(tmp.path(), Uuid::new_v4(), 0, 9);
+        sinks.enqueue(&file).unwrap();
+
+        // the directory sink is delivered to while the command hangs
+        let status = loop {
+            let status = sinks.status().unwrap();
+            if status[1].last_delivered.is_some() {
+                break status;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        assert_eq!(status[0].name, "hang");
+        assert!(status[0].last_delivered.is_none());
+        assert!(out.join("test").join(file.file_name().unwrap()).exists());
+
+        let status = wait_for_deliveries(&sinks).await;
+        assert_eq!(status[0].failed.len(), 1);
+        assert!(status[0].failed[0]
+            .last_error
+            .as_ref()
+            .unwrap()
+            .contains("timed out"));
+    }
+
+    #[test]
+    fn queued_deliveries_survive_restart() {
+        let tmp = tempdir().unwrap();
+        let dir = tmp.path().join("sinks");
+        let options = default_sinks(vec![exec("exec", "true")]);
+        let sinks =
+            SnapshotSinks::open(&dir, &"test".into(), options.clone(), None, test_retry()).unwrap();
+        assert!(sinks
+            .set_config(vec![sink("exec", SnapshotSinkKind::Bottomless)])
+            .is_err());
+        sinks
+            .set_config(vec![sink("s3", SnapshotSinkKind::Bottomless)])
+            .unwrap();
+        let file = snapshot_file(tmp.path(), Uuid::new_v4(), 0, 9);
+        sinks.enqueue(&file).unwrap();
+        drop(sinks);
+        std::fs::remove_file(&file).unwrap();
+
+        let sinks = SnapshotSinks::open(&dir, &"test".into(), options, None, test_retry()).unwrap();
+        assert_eq!(sinks.config().len(), 1);
+        let status = sinks.status().unwrap();
+        assert_eq!(status.len(), 2);
+        assert!(status.iter().all(|sink| sink.pending.len() == 1));
+        assert!(dir.join(FILES_DIR).join(file.file_name().unwrap()).exists());
+    }
+}
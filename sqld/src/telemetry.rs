@@ -0,0 +1,200 @@
+//! OpenTelemetry trace export.
+//!
+//! When an OTLP endpoint is configured, the spans of sqld are exported to it. The trace context
+//! is propagated in the metadata of the gRPC requests that replicas send to the primary, so that
+//! the work done by the primary on behalf of a replica joins the trace of the replica.
+
+use opentelemetry::propagation::{Extractor, Injector};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::{trace, Resource};
+use tonic::metadata::{KeyAndValueRef, MetadataKey, MetadataMap, MetadataValue};
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// Creates a layer that exports spans to the OTLP collector listening on `endpoint` over gRPC.
+///
+/// Only the spans of sqld are exported, and this must be called from within a tokio runtime.
+pub fn otlp_layer<S>(endpoint: &str) -> anyhow::Result<impl Layer<S>>
+where
+    S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+{
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            trace::config().with_resource(Resource::new([KeyValue::new("service.name", "sqld")])),
+        )
+        .install_batch(opentelemetry_sdk::runtime::Tokio)?;
+
+    let filter = Targets::new().with_target("sqld", tracing::Level::INFO);
+    Ok(tracing_opentelemetry::layer()
+        .with_tracer(tracer)
+        .with_filter(filter))
+}
+
+/// Flushes the spans that have not been exported yet.
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+/// Adds the trace context of `span` to the metadata of an outgoing gRPC request.
+pub fn inject_context<T>(span: &Span, req: &mut tonic::Request<T>) {
+    let context = span.context();
+    opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut MetadataInjector(req.metadata_mut()))
+    });
+}
+
+/// Makes `span` a child of the trace context found in the metadata of an incoming gRPC request,
+/// if any.
+pub fn set_remote_parent<T>(span: &Span, req: &tonic::Request<T>) {
+    let context = opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.extract(&MetadataExtractor(req.metadata()))
+    });
+    span.set_parent(context);
+}
+
+struct MetadataInjector<'a>(&'a mut MetadataMap);
+
+impl Injector for MetadataInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(key), Ok(value)) = (
+            MetadataKey::from_bytes(key.as_bytes()),
+            MetadataValue::try_from(value),
+        ) {
+            self.0.insert(key, value);
+        }
+    }
+}
+
+struct MetadataExtractor<'a>(&'a MetadataMap);
+
+impl Extractor for MetadataExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0
+            .iter()
+            .filter_map(|entry| match entry {
+                KeyAndValueRef::Ascii(key, _) => Some(key.as_str()),
+                KeyAndValueRef::Binary(..) => None,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use opentelemetry::propagation::TextMapPropagator;
+    use opentelemetry::trace::TraceContextExt;
+    use opentelemetry_proto::tonic::collector::trace::v1::trace_service_server::{
+        TraceService, TraceServiceServer,
+    };
+    use opentelemetry_proto::tonic::collector::trace::v1::{
+        ExportTraceServiceRequest, ExportTraceServiceResponse,
+    };
+    use opentelemetry_proto::tonic::trace::v1::Span as ExportedSpan;
+    use tokio::sync::mpsc;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+
+    /// Stands in for an OTLP collector, and forwards the spans it receives.
+    struct Collector(mpsc::UnboundedSender<ExportedSpan>);
+
+    #[tonic::async_trait]
+    impl TraceService for Collector {
+        async fn export(
+            &self,
+            req: tonic::Request<ExportTraceServiceRequest>,
+        ) -> Result<tonic::Response<ExportTraceServiceResponse>, tonic::Status> {
+            for resource_spans in req.into_inner().resource_spans {
+                for scope_spans in resource_spans.scope_spans {
+                    for span in scope_spans.spans {
+                        let _ = self.0.send(span);
+                    }
+                }
+            }
+            Ok(tonic::Response::new(ExportTraceServiceResponse {
+                partial_success: None,
+            }))
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn export_propagated_trace() {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let incoming = futures::stream::unfold(listener, |listener| async move {
+            let conn = listener.accept().await.map(|(stream, _)| stream);
+            Some((conn, listener))
+        });
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(TraceServiceServer::new(Collector(sender)))
+                .serve_with_incoming(incoming),
+        );
+
+        let subscriber =
+            tracing_subscriber::registry().with(otlp_layer(&format!("http://{addr}")).unwrap());
+        tracing::subscriber::with_default(subscriber, || {
+            // the replica forwards a program to the primary, which serves it in its own span
+            let replica_span = tracing::info_span!("proxy_execute");
+            let mut req = tonic::Request::new(());
+            inject_context(&replica_span, &mut req);
+            let primary_span = tracing::info_span!("proxy_execute", client_id = "replica");
+            set_remote_parent(&primary_span, &req);
+            drop(primary_span);
+            drop(replica_span);
+        });
+        tokio::task::spawn_blocking(shutdown).await.unwrap();
+
+        let replica = receiver.recv().await.unwrap();
+        let primary = receiver.recv().await.unwrap();
+        let (replica, primary) = if primary.parent_span_id.is_empty() {
+            (primary, replica)
+        } else {
+            (replica, primary)
+        };
+        assert!(replica.parent_span_id.is_empty());
+        assert_eq!(primary.trace_id, replica.trace_id);
+        assert_eq!(primary.parent_span_id, replica.span_id);
+    }
+
+    #[test]
+    fn metadata_roundtrip() {
+        let mut metadata = MetadataMap::new();
+        let mut injector = MetadataInjector(&mut metadata);
+        injector.set(
+            "traceparent",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01".into(),
+        );
+        injector.set("invalid key\n", "ignored".into());
+
+        let extractor = MetadataExtractor(&metadata);
+        assert_eq!(extractor.keys(), vec!["traceparent"]);
+
+        let propagator = TraceContextPropagator::new();
+        let context = propagator.extract(&extractor);
+        let span_context = context.span().span_context().clone();
+        assert!(span_context.is_remote());
+        assert_eq!(
+            span_context.trace_id().to_string(),
+            "0af7651916cd43dd8448eb211c80319c"
+        );
+    }
+}
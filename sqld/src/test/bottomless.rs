@@ -314,6 +314,102 @@ async fn rollback_restore() {
     }
 }
 
+#[tokio::test]
+async fn replica_bootstrap() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    const BUCKET: &str = "testreplicabootstrap";
+    const PRIMARY_PATH: &str = "replica_bootstrap_primary.sqld";
+    const REPLICA_PATH: &str = "replica_bootstrap_replica.sqld";
+    const PRIMARY_PORT: u16 = 15003;
+    const REPLICA_PORT: u16 = 15004;
+    const GRPC_PORT: u16 = 15005;
+
+    async fn count_rows(conn: &Url) -> Result<Value> {
+        let result = sql(conn, ["SELECT count(*) AS count FROM t"]).await?;
+        let rs = result.into_iter().next().unwrap().into_result_set()?;
+        Ok(rs.rows[0].cells["count"].clone())
+    }
+
+    let _ = S3BucketCleaner::new(BUCKET).await;
+    assert_bucket_occupancy(BUCKET, true).await;
+
+    let bottomless_options = bottomless::replicator::Options {
+        create_bucket_if_not_exists: true,
+        verify_crc: true,
+        use_compression: bottomless::replicator::CompressionKind::Gzip,
+        bucket_name: BUCKET.to_string(),
+        max_batch_interval: Duration::from_millis(250),
+        ..bottomless::replicator::Options::from_env().unwrap()
+    };
+    let primary_conn = Url::parse(&format!("http://localhost:{}", PRIMARY_PORT)).unwrap();
+    let replica_conn = Url::parse(&format!("http://localhost:{}", REPLICA_PORT)).unwrap();
+    let primary_config = Config {
+        bottomless_replication: Some(bottomless_options.clone()),
+        db_path: PRIMARY_PATH.into(),
+        http_addr: Some(([0, 0, 0, 0], PRIMARY_PORT).into()),
+        rpc_server_addr: Some(([127, 0, 0, 1], GRPC_PORT).into()),
+        ..Config::default()
+    };
+    let replica_config = Config {
+        bootstrap_from_bottomless: Some(bottomless_options),
+        db_path: REPLICA_PATH.into(),
+        http_addr: Some(([0, 0, 0, 0], REPLICA_PORT).into()),
+        writer_rpc_addr: Some(format!("http://127.0.0.1:{}", GRPC_PORT)),
+        ..Config::default()
+    };
+
+    {
+        tracing::info!("---STEP 1: create a database, fill it with data, wait for backup---");
+        let cleaner = DbFileCleaner::new(PRIMARY_PATH);
+        let db_job = start_db(1, &primary_config);
+        sleep(Duration::from_secs(2)).await;
+
+        let stmts = std::iter::once("CREATE TABLE t(id INT PRIMARY KEY, name TEXT);".to_string())
+            .chain((0..100).map(|i| format!("INSERT INTO t(id, name) VALUES({i}, 'name {i}');")));
+        let _ = sql(&primary_conn, stmts).await.unwrap();
+
+        sleep(Duration::from_secs(2)).await;
+        db_job.abort();
+        drop(cleaner);
+    }
+
+    {
+        tracing::info!(
+            "---STEP 2: restore the primary, and bootstrap a replica from its backup---"
+        );
+        let _primary_cleaner = DbFileCleaner::new(PRIMARY_PATH);
+        let primary_job = start_db(2, &primary_config);
+        sleep(Duration::from_secs(2)).await;
+
+        // the restored database starts a generation that replicas can bootstrap from
+        assert_bucket_contains(BUCKET, ".replication_index").await;
+
+        let _replica_cleaner = DbFileCleaner::new(REPLICA_PATH);
+        let replica_job = start_db(3, &replica_config);
+        sleep(Duration::from_secs(2)).await;
+        assert_eq!(
+            count_rows(&replica_conn).await.unwrap(),
+            Value::Integer(100)
+        );
+
+        // the replica then replicates from the primary
+        let _ = sql(
+            &primary_conn,
+            ["INSERT INTO t(id, name) VALUES(100, 'name 100');"],
+        )
+        .await
+        .unwrap();
+        sleep(Duration::from_secs(1)).await;
+        assert_eq!(
+            count_rows(&replica_conn).await.unwrap(),
+            Value::Integer(101)
+        );
+
+        replica_job.abort();
+        primary_job.abort();
+    }
+}
+
 async fn sql<I, S>(url: &Url, stmts: I) -> Result<Vec<QueryResult>>
 where
     I: IntoIterator<Item = S>,
@@ -352,6 +448,25 @@ async fn assert_bucket_occupancy(bucket: &str, expect_empty: bool) {
     }
 }
 
+/// Checks that the bucket contains an object with a key ending with `suffix`.
+async fn assert_bucket_contains(bucket: &str, suffix: &str) {
+    use aws_sdk_s3::Client;
+
+    let loader = aws_config::from_env().endpoint_url(S3_URL);
+    let conf = aws_sdk_s3::config::Builder::from(&loader.load().await)
+        .force_path_style(true)
+        .build();
+    let client = Client::from_conf(conf);
+    let out = client.list_objects().bucket(bucket).send().await.unwrap();
+    assert!(
+        out.contents()
+            .unwrap_or_default()
+            .iter()
+            .any(|o| o.key().map_or(false, |key| key.ends_with(suffix))),
+        "no object ending with {suffix} in bucket {bucket}"
+    );
+}
+
 /// Guardian struct used for cleaning up the test data from
 /// database file dir at the beginning and end of a test.
 struct DbFileCleaner(PathBuf);
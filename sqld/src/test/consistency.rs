@@ -0,0 +1,81 @@
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+
+use crate::{run_server, Config};
+
+const MIN_FRAME_NO_HEADER: &str = "x-min-frame-no";
+const FRAME_NO_HEADER: &str = "x-frame-no";
+
+fn start_db(config: Config) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        if let Err(e) = run_server(config).await {
+            panic!("server failed: {e}");
+        }
+    })
+}
+
+/// Executes `stmt` on the server at `url`, and returns the result and the consistency token of
+/// the response.
+async fn query(url: &str, stmt: &str, min_frame_no: Option<&str>) -> (Value, String) {
+    let mut req = reqwest::Client::new()
+        .post(url)
+        .json(&json!({ "statements": [stmt] }));
+    if let Some(frame_no) = min_frame_no {
+        req = req.header(MIN_FRAME_NO_HEADER, frame_no);
+    }
+    let resp = req.send().await.unwrap();
+    assert!(resp.status().is_success(), "{stmt}: {}", resp.status());
+    let frame_no = resp.headers()[FRAME_NO_HEADER]
+        .to_str()
+        .unwrap()
+        .to_string();
+    (resp.json().await.unwrap(), frame_no)
+}
+
+async fn count_rows(url: &str, min_frame_no: &str) -> Value {
+    let (result, _) = query(url, "SELECT count(*) FROM t", Some(min_frame_no)).await;
+    result[0]["results"]["rows"][0][0].clone()
+}
+
+#[tokio::test]
+async fn read_your_writes_across_nodes() {
+    const PRIMARY_PORT: u16 = 15011;
+    const REPLICA_PORT: u16 = 15012;
+    const GRPC_PORT: u16 = 15013;
+
+    let primary_dir = tempfile::tempdir().unwrap();
+    let replica_dir = tempfile::tempdir().unwrap();
+    let primary_url = format!("http://127.0.0.1:{PRIMARY_PORT}");
+    let replica_url = format!("http://127.0.0.1:{REPLICA_PORT}");
+    let primary_job = start_db(Config {
+        db_path: primary_dir.path().join("primary.sqld"),
+        http_addr: Some(([127, 0, 0, 1], PRIMARY_PORT).into()),
+        rpc_server_addr: Some(([127, 0, 0, 1], GRPC_PORT).into()),
+        ..Config::default()
+    });
+    sleep(Duration::from_secs(1)).await;
+    let replica_job = start_db(Config {
+        db_path: replica_dir.path().join("replica.sqld"),
+        http_addr: Some(([127, 0, 0, 1], REPLICA_PORT).into()),
+        writer_rpc_addr: Some(format!("http://127.0.0.1:{GRPC_PORT}")),
+        ..Config::default()
+    });
+    sleep(Duration::from_secs(1)).await;
+
+    // the token of a write on the primary is reached by the replica once it applied the write,
+    // without waiting for a later write.
+    query(&primary_url, "CREATE TABLE t (x)", None).await;
+    let (_, token) = query(&primary_url, "INSERT INTO t VALUES (1)", None).await;
+    assert_eq!(count_rows(&replica_url, &token).await, json!(1));
+
+    // and the token of a write forwarded by the replica is reached by both nodes
+    let (_, token) = query(&replica_url, "INSERT INTO t VALUES (2)", None).await;
+    assert_eq!(count_rows(&primary_url, &token).await, json!(2));
+    assert_eq!(count_rows(&replica_url, &token).await, json!(2));
+
+    replica_job.abort();
+    primary_job.abort();
+}
@@ -1 +1,2 @@
 mod bottomless;
+mod consistency;
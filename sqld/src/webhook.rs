@@ -0,0 +1,335 @@
+//! Outbound webhooks on table changes.
+//!
+//! Every namespace can be configured with a list of webhooks. When a transaction commits, the
+//! rows it modified are matched against the tables and operations of each webhook, and a
+//! delivery is enqueued for every webhook with matching changes. Deliveries are persisted in the
+//! `webhooks/queue` directory of the namespace once the transaction committed, before it is
+//! acknowledged, so that no change is lost if the server stops before they are delivered, and
+//! they are then POSTed to the webhook URL in order. Failed deliveries are retried with an exponential backoff, and moved
+//! to the `webhooks/dead` directory after too many attempts, where they can be inspected through
+//! the admin API.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use anyhow::Context as _;
+use bytes::Bytes;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::connection::table_changes::{CapturedRows, ChangeOp, RowChange, RowListener};
+use crate::delivery_queue::{self, Deliver, DeliveryQueue, RetryPolicy};
+
+const CONFIG_FILE: &str = "webhooks.json";
+const DEAD_DIR: &str = "dead";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WebhookConfig {
+    /// The URL that change batches are POSTed to.
+    pub url: String,
+    /// The tables this webhook is interested in. All tables if empty.
+    #[serde(default)]
+    pub tables: Vec<String>,
+    /// The operations this webhook is interested in. All operations if empty.
+    #[serde(default)]
+    pub ops: Vec<ChangeOp>,
+}
+
+impl WebhookConfig {
+    fn matches(&self, change: &RowChange) -> bool {
+        (self.tables.is_empty()
+            || self
+                .tables
+                .iter()
+                .any(|t| t.eq_ignore_ascii_case(&change.table)))
+            && (self.ops.is_empty() || self.ops.contains(&change.op))
+    }
+}
+
+/// The body of the requests sent to webhooks.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WebhookPayload {
+    pub namespace: String,
+    pub changes: Vec<RowChange>,
+    /// Set if the transaction modified more rows than could be captured.
+    pub truncated: bool,
+}
+
+/// A change batch to POST to a webhook.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookRequest {
+    pub url: String,
+    pub payload: WebhookPayload,
+}
+
+/// A pending (or dead) delivery of a change batch to a webhook.
+pub type Delivery = delivery_queue::Delivery<WebhookRequest>;
+
+pub struct Webhooks {
+    namespace: String,
+    dir: PathBuf,
+    config: Mutex<Arc<Vec<WebhookConfig>>>,
+    /// Set if any webhook is configured.
+    capture_rows: AtomicBool,
+    retry: RetryPolicy,
+    queue: Arc<DeliveryQueue<WebhookRequest>>,
+}
+
+impl Webhooks {
+    /// Opens the webhooks of the namespace stored in `dir`.
+    pub fn open(dir: &Path, namespace: &Bytes, retry: RetryPolicy) -> anyhow::Result<Self> {
+        let config: Vec<WebhookConfig> = match std::fs::read(dir.join(CONFIG_FILE)) {
+            Ok(data) => serde_json::from_slice(&data).context("invalid webhooks config")?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let queue = Arc::new(DeliveryQueue::open(dir, DEAD_DIR, retry)?);
+
+        Ok(Self {
+            namespace: String::from_utf8_lossy(namespace).into_owned(),
+            dir: dir.to_path_buf(),
+            capture_rows: AtomicBool::new(!config.is_empty()),
+            config: Mutex::new(Arc::new(config)),
+            retry,
+            queue,
+        })
+    }
+
+    pub fn config(&self) -> Arc<Vec<WebhookConfig>> {
+        self.config.lock().clone()
+    }
+
+    pub fn set_config(&self, config: Vec<WebhookConfig>) -> anyhow::Result<()> {
+        let path = self.dir.join(CONFIG_FILE);
+        let tmp_path = self.dir.join(format!("{CONFIG_FILE}~"));
+        std::fs::write(&tmp_path, serde_json::to_vec_pretty(&config)?)?;
+        std::fs::rename(&tmp_path, &path)?;
+        self.capture_rows
+            .store(!config.is_empty(), Ordering::Relaxed);
+        *self.config.lock() = Arc::new(config);
+        Ok(())
+    }
+
+    /// Returns the deliveries that exhausted their attempts, oldest first.
+    pub fn dead_letters(&self) -> anyhow::Result<Vec<Delivery>> {
+        self.queue.dead_letters()
+    }
+
+    /// Removes all the dead letters, and returns how many were removed.
+    pub fn clear_dead_letters(&self) -> anyhow::Result<usize> {
+        self.queue.clear_dead_letters()
+    }
+
+    /// Delivers the enqueued changes.
+    pub async fn run(self: Arc<Self>) -> anyhow::Result<()> {
+        let client = reqwest::Client::builder()
+            .timeout(self.retry.request_timeout)
+            .build()
+            .context("failed to create webhook client")?;
+        self.queue.clone().run(Arc::new(Sender { client })).await
+    }
+
+    fn enqueue(&self, rows: CapturedRows) -> anyhow::Result<()> {
+        let config = self.config();
+        for webhook in config.iter() {
+            let changes: Vec<_> = rows
+                .rows
+                .iter()
+                .filter(|c| webhook.matches(c))
+                .cloned()
+                .collect();
+            if changes.is_empty() {
+                continue;
+            }
+
+            self.queue.push(WebhookRequest {
+                url: webhook.url.clone(),
+                payload: WebhookPayload {
+                    namespace: self.namespace.clone(),
+                    changes,
+                    truncated: rows.truncated,
+                },
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+impl RowListener for Webhooks {
+    fn captures_rows(&self) -> bool {
+        self.capture_rows.load(Ordering::Relaxed)
+    }
+
+    fn record_rows(&self, rows: CapturedRows) {
+        if let Err(e) = self.enqueue(rows) {
+            tracing::error!("failed to enqueue webhook delivery: {e}");
+        }
+    }
+}
+
+struct Sender {
+    client: reqwest::Client,
+}
+
+#[async_trait::async_trait]
+impl Deliver<WebhookRequest> for Sender {
+    fn destination(&self, request: &WebhookRequest) -> String {
+        request.url.clone()
+    }
+
+    async fn deliver(&self, delivery: &Delivery) -> anyhow::Result<()> {
+        let response = self
+            .client
+            .post(&delivery.item.url)
+            .header("x-sqld-delivery-id", delivery.id.to_string())
+            .json(&delivery.item.payload)
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            anyhow::bail!("webhook responded with status {status}");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use axum::extract::State;
+    use axum::http::StatusCode;
+    use axum::routing::post;
+    use axum::Json;
+    use tempfile::tempdir;
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::connection::table_changes::TableChanges;
+    use crate::delivery_queue::test_retry;
+
+    /// Starts a local HTTP server that stands in for a webhook endpoint. It records the payloads
+    /// it receives, and responds with `status`.
+    async fn stand_in(status: StatusCode) -> (SocketAddr, mpsc::UnboundedReceiver<WebhookPayload>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let router = axum::Router::new()
+            .route(
+                "/hook",
+                post(
+                    |State(sender): State<mpsc::UnboundedSender<WebhookPayload>>,
+                     Json(payload): Json<WebhookPayload>| async move {
+                        sender.send(payload).unwrap();
+                        status
+                    },
+                ),
+            )
+            .with_state(sender);
+        let server =
+            hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(router.into_make_service());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        (addr, receiver)
+    }
+
+    fn rows(table: &str, op: ChangeOp, rowid: i64) -> CapturedRows {
+        CapturedRows {
+            rows: vec![RowChange {
+                table: table.into(),
+                op,
+                rowid,
+            }],
+            truncated: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn deliver_matching_changes() {
+        let (addr, mut received) = stand_in(StatusCode::OK).await;
+        let tmp = tempdir().unwrap();
+        let webhooks = Arc::new(Webhooks::open(tmp.path(), &"test".into(), test_retry()).unwrap());
+        let table_changes = TableChanges::with_row_listener(webhooks.clone());
+        assert!(!table_changes.captures_rows());
+
+        webhooks
+            .set_config(vec![WebhookConfig {
+                url: format!("http://{addr}/hook"),
+                tables: vec!["Users".into()],
+                ops: vec![ChangeOp::Insert],
+            }])
+            .unwrap();
+        assert!(table_changes.captures_rows());
+
+        tokio::spawn(webhooks.clone().run());
+        table_changes.record_rows(rows("orders", ChangeOp::Insert, 1));
+        table_changes.record_rows(rows("users", ChangeOp::Delete, 2));
+        table_changes.record_rows(rows("users", ChangeOp::Insert, 3));
+
+        let payload = received.recv().await.unwrap();
+        assert_eq!(payload.namespace, "test");
+        assert_eq!(payload.changes, rows("users", ChangeOp::Insert, 3).rows);
+        assert!(webhooks.dead_letters().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_deliveries_become_dead_letters() {
+        let (addr, mut received) = stand_in(StatusCode::INTERNAL_SERVER_ERROR).await;
+        let tmp = tempdir().unwrap();
+        let webhooks = Arc::new(Webhooks::open(tmp.path(), &"test".into(), test_retry()).unwrap());
+        webhooks
+            .set_config(vec![WebhookConfig {
+                url: format!("http://{addr}/hook"),
+                tables: Vec::new(),
+                ops: Vec::new(),
+            }])
+            .unwrap();
+
+        tokio::spawn(webhooks.clone().run());
+        webhooks.record_rows(rows("users", ChangeOp::Update, 1));
+
+        // one attempt, and one retry
+        received.recv().await.unwrap();
+        received.recv().await.unwrap();
+
+        let dead_letters = loop {
+            let dead_letters = webhooks.dead_letters().unwrap();
+            if !dead_letters.is_empty() {
+                break dead_letters;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].attempts, 2);
+        assert!(dead_letters[0].last_error.is_some());
+
+        assert_eq!(webhooks.clear_dead_letters().unwrap(), 1);
+        assert!(webhooks.dead_letters().unwrap().is_empty());
+    }
+
+    #[test]
+    fn queued_deliveries_survive_restart() {
+        let tmp = tempdir().unwrap();
+        let webhooks = Arc::new(Webhooks::open(tmp.path(), &"test".into(), test_retry()).unwrap());
+        let table_changes = TableChanges::with_row_listener(webhooks.clone());
+        webhooks
+            .set_config(vec![WebhookConfig {
+                url: "http://localhost:1/hook".into(),
+                tables: Vec::new(),
+                ops: Vec::new(),
+            }])
+            .unwrap();
+        // the changes are persisted on commit, before any delivery task runs
+        table_changes.record_rows(rows("users", ChangeOp::Insert, 1));
+        drop(table_changes);
+        drop(webhooks);
+
+        let webhooks = Webhooks::open(tmp.path(), &"test".into(), test_retry()).unwrap();
+        assert_eq!(webhooks.config().len(), 1);
+        assert!(webhooks.captures_rows());
+        let queued = webhooks.queue.pending().unwrap();
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].item.payload.changes[0].table, "users");
+    }
+}